@@ -0,0 +1,124 @@
+extern crate hyper;
+extern crate serde_json;
+
+use self::serde_json::Value;
+use common;
+use drive_error;
+use oauth;
+use oauth::GetToken;
+use std::error::Error;
+use std::io::Read;
+
+const ACTIVITY_QUERY_URL: &'static str = "https://driveactivity.googleapis.com/v2/activity:query";
+
+/// Fetches the `max_events` most recent Drive Activity events for items under
+/// the folder `ancestor_id`, formatted as one line per event, oldest first.
+/// Requires a token with the drive.activity.readonly scope.
+pub fn query_activity_log(
+  auth: &mut oauth::GoogleAuthenticator,
+  ancestor_id: &str,
+  max_events: usize,
+) -> Result<String, Box<Error>> {
+  let client = common::new_hyper_tls_client();
+  let mut lines: Vec<String> = Vec::new();
+  let mut page_token: Option<String> = None;
+  while lines.len() < max_events {
+    let mut query = serde_json::Map::new();
+    query.insert(
+      "ancestorName".into(),
+      Value::String(format!("items/{}", ancestor_id)),
+    );
+    query.insert("pageSize".into(), Value::from(max_events - lines.len()));
+    if let Some(token) = page_token.take() {
+      query.insert("pageToken".into(), Value::String(token));
+    }
+    let body = try!(serde_json::to_string(&Value::Object(query)));
+
+    let token = auth.api_key().unwrap();
+    let mut resp = try!(client
+      .post(ACTIVITY_QUERY_URL)
+      .header(hyper::header::ContentType::json())
+      .header(hyper::header::Authorization(hyper::header::Bearer {
+        token: token,
+      }))
+      .body(&body)
+      .send());
+    if !resp.status.is_success() {
+      let mut body: String = String::new();
+      try!(resp.read_to_string(&mut body));
+      let err = drive_error::DriveError::parse(resp.status, &body);
+      warn!("Activity query error: {}", err);
+      return Err(Box::new(err));
+    }
+    let result: Value = try!(serde_json::from_reader(&mut resp));
+
+    if let Some(activities) = result["activities"].as_array() {
+      for activity in activities {
+        lines.push(format_activity(activity));
+      }
+    }
+
+    page_token = result["nextPageToken"].as_str().map(String::from);
+    if page_token.is_none() {
+      break;
+    }
+  }
+  // activities are returned newest first.
+  lines.truncate(max_events);
+  lines.reverse();
+  Ok(lines.concat())
+}
+
+// formats a DriveActivity as a "<time> <actors> <action> <targets>" line.
+fn format_activity(activity: &Value) -> String {
+  let time = activity["timestamp"]
+    .as_str()
+    .or(activity["timeRange"]["endTime"].as_str())
+    .unwrap_or("-");
+  // the action detail is an object with a single key naming the action.
+  let action = activity["primaryActionDetail"]
+    .as_object()
+    .and_then(|detail| detail.keys().next())
+    .map(|action| action.as_str())
+    .unwrap_or("unknown");
+  let actors: Vec<&str> = activity["actors"]
+    .as_array()
+    .map(|actors| actors.iter().map(format_actor).collect())
+    .unwrap_or_default();
+  let targets: Vec<&str> = activity["targets"]
+    .as_array()
+    .map(|targets| {
+      targets
+        .iter()
+        .map(|target| {
+          target["driveItem"]["title"]
+            .as_str()
+            .or(target["drive"]["title"].as_str())
+            .unwrap_or("-")
+        })
+        .collect()
+    })
+    .unwrap_or_default();
+  format!(
+    "{} {} {} {}\n",
+    time,
+    actors.join(","),
+    action,
+    targets.join(",")
+  )
+}
+
+// names the actor of an activity. Other users are only known by their
+// people/ id, since resolving names needs the People API.
+fn format_actor(actor: &Value) -> &str {
+  let known_user = &actor["user"]["knownUser"];
+  if known_user["isCurrentUser"].as_bool() == Some(true) {
+    "me"
+  } else if let Some(name) = known_user["personName"].as_str() {
+    name
+  } else if actor["anonymous"].is_object() {
+    "anonymous"
+  } else {
+    "unknown"
+  }
+}
@@ -0,0 +1,195 @@
+extern crate hyper;
+extern crate serde_json;
+
+use self::serde_json::Value;
+use common;
+use drive_error;
+use google_drive3;
+use oauth;
+use oauth::GetToken;
+use std::error::Error;
+use std::io::Read;
+
+const FILE_GET_URL: &'static str = "https://www.googleapis.com/drive/v3/files";
+const FILE_UPLOAD_URL: &'static str = "https://www.googleapis.com/upload/drive/v3/files";
+
+// separates the metadata and content parts of a multipart upload.
+const MULTIPART_BOUNDARY: &'static str = "gdrivefs_multipart_boundary";
+
+/// appProperties key holding the permission bits of a file, in octal.
+pub const MODE_KEY: &'static str = "gdrivefsMode";
+
+/// appProperties key marking a symlink. Its value is the link target, or is
+/// empty if the target was too long and is stored as the file content.
+pub const SYMLINK_KEY: &'static str = "gdrivefsSymlink";
+
+/// Drive limits each appProperties entry to this many bytes of key and value.
+pub const MAX_ENTRY_LEN: usize = 124;
+
+/// Sets the appProperties `properties` on `file_id`, leaving any others
+/// untouched.
+pub fn patch(
+  auth: &mut oauth::GoogleAuthenticator,
+  file_id: &str,
+  properties: &[(&str, &str)],
+) -> Result<(), Box<Error>> {
+  let mut body = serde_json::Map::new();
+  body.insert("appProperties".into(), to_object(properties));
+  let url = format!("{}/{}?supportsTeamDrives=true", FILE_GET_URL, file_id);
+  try!(send_json(auth, hyper::method::Method::Patch, &url, body));
+  Ok(())
+}
+
+/// Creates a file `name` in the folder `parent_id` holding `content`, and
+/// carrying the appProperties `properties`. Returns the new file's metadata
+/// `fields`.
+pub fn create_file(
+  auth: &mut oauth::GoogleAuthenticator,
+  parent_id: &str,
+  name: &str,
+  mime_type: &str,
+  properties: &[(&str, &str)],
+  content: &[u8],
+  fields: &str,
+) -> Result<google_drive3::File, Box<Error>> {
+  let mut body = serde_json::Map::new();
+  body.insert("name".into(), Value::String(name.into()));
+  body.insert("mimeType".into(), Value::String(mime_type.into()));
+  body.insert(
+    "parents".into(),
+    Value::Array(vec![Value::String(parent_id.into())]),
+  );
+  body.insert("appProperties".into(), to_object(properties));
+  let result = if content.is_empty() {
+    // metadata-only creates aren't exposed by the drive3 crate.
+    let url = format!("{}?supportsTeamDrives=true&fields={}", FILE_GET_URL, fields);
+    try!(send_json(auth, hyper::method::Method::Post, &url, body))
+  } else {
+    let url = format!(
+      "{}?uploadType=multipart&supportsTeamDrives=true&fields={}",
+      FILE_UPLOAD_URL, fields
+    );
+    let metadata = try!(serde_json::to_string(&Value::Object(body)));
+    let mut multipart: Vec<u8> = Vec::new();
+    multipart.extend_from_slice(
+      format!(
+        "--{0}\r\nContent-Type: application/json; charset=UTF-8\r\n\r\n{1}\r\n--{0}\r\nContent-Type: {2}\r\n\r\n",
+        MULTIPART_BOUNDARY, metadata, mime_type
+      ).as_bytes(),
+    );
+    multipart.extend_from_slice(content);
+    multipart.extend_from_slice(format!("\r\n--{}--", MULTIPART_BOUNDARY).as_bytes());
+    let content_type = format!("multipart/related; boundary={}", MULTIPART_BOUNDARY);
+    try!(send(
+      auth,
+      hyper::method::Method::Post,
+      &url,
+      hyper::header::ContentType(content_type.parse().unwrap()),
+      &multipart
+    ))
+  };
+  Ok(try!(serde_json::from_value(result)))
+}
+
+/// Renames `file_id` to `name` and moves it from the folder `old_parent_id`
+/// to `new_parent_id`, which may be the same. The file keeps its id, so its
+/// sharing, comments and revisions are kept.
+pub fn move_file(
+  auth: &mut oauth::GoogleAuthenticator,
+  file_id: &str,
+  name: &str,
+  old_parent_id: &str,
+  new_parent_id: &str,
+) -> Result<(), Box<Error>> {
+  let mut body = serde_json::Map::new();
+  body.insert("name".into(), Value::String(name.into()));
+  let mut url = format!("{}/{}?supportsTeamDrives=true", FILE_GET_URL, file_id);
+  if old_parent_id != new_parent_id {
+    url.push_str(&format!(
+      "&addParents={}&removeParents={}",
+      new_parent_id, old_parent_id
+    ));
+  }
+  try!(send_json(auth, hyper::method::Method::Patch, &url, body));
+  Ok(())
+}
+
+/// Downloads the content of `file_id`. Only meant for small files, such as
+/// symlinks with long targets.
+pub fn download(
+  auth: &mut oauth::GoogleAuthenticator,
+  file_id: &str,
+) -> Result<Vec<u8>, Box<Error>> {
+  let url = format!(
+    "{}/{}?alt=media&supportsTeamDrives=true",
+    FILE_GET_URL, file_id
+  );
+  let token = auth.api_key().unwrap();
+  let mut resp = try!(common::new_hyper_tls_client()
+    .get(&url)
+    .header(hyper::header::Authorization(hyper::header::Bearer {
+      token: token,
+    }))
+    .send());
+  let mut content: Vec<u8> = Vec::new();
+  try!(resp.read_to_end(&mut content));
+  if !resp.status.is_success() {
+    let err = drive_error::DriveError::parse(resp.status, &String::from_utf8_lossy(&content));
+    warn!("Download error: {}", err);
+    return Err(Box::new(err));
+  }
+  Ok(content)
+}
+
+fn to_object(properties: &[(&str, &str)]) -> Value {
+  Value::Object(
+    properties
+      .iter()
+      .map(|&(key, value)| (String::from(key), Value::String(value.into())))
+      .collect(),
+  )
+}
+
+// sends |body| as JSON to |url|, returning the parsed response.
+fn send_json(
+  auth: &mut oauth::GoogleAuthenticator,
+  method: hyper::method::Method,
+  url: &str,
+  body: serde_json::Map<String, Value>,
+) -> Result<Value, Box<Error>> {
+  let body = try!(serde_json::to_string(&Value::Object(body)));
+  send(
+    auth,
+    method,
+    url,
+    hyper::header::ContentType::json(),
+    body.as_bytes(),
+  )
+}
+
+// sends |body| to |url|, returning the parsed JSON response.
+fn send(
+  auth: &mut oauth::GoogleAuthenticator,
+  method: hyper::method::Method,
+  url: &str,
+  content_type: hyper::header::ContentType,
+  body: &[u8],
+) -> Result<Value, Box<Error>> {
+  let token = auth.api_key().unwrap();
+  let mut resp = try!(common::new_hyper_tls_client()
+    .request(method, url)
+    .header(content_type)
+    .header(hyper::header::Authorization(hyper::header::Bearer {
+      token: token,
+    }))
+    .body(body)
+    .send());
+  if !resp.status.is_success() {
+    let mut body: String = String::new();
+    try!(resp.read_to_string(&mut body));
+    let err = drive_error::DriveError::parse(resp.status, &body);
+    warn!("appProperties request error: {}", err);
+    return Err(Box::new(err));
+  }
+  Ok(try!(serde_json::from_reader(&mut resp)))
+}
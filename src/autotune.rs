@@ -0,0 +1,82 @@
+use constants;
+use std::cmp;
+use std::sync::atomic;
+use std::time::Duration;
+
+// number of chunk downloads measured before tuning.
+const PROBE_SAMPLES: usize = 8;
+// downloads smaller than this (e.g. the end of a file) say little about
+// throughput, so aren't measured.
+const MIN_SAMPLE_BYTES: u64 = 64 * 1024;
+// chunks are sized so that the request latency is at most 1/(1+this) of the
+// time spent on each chunk.
+const TRANSFER_TO_LATENCY_RATIO: u64 = 9;
+const MIN_CHUNK_BYTES: u64 = 256 * 1024;
+const MAX_CHUNK_BYTES: u64 = 64 * 1024 * 1024;
+// seconds of downloading kept queued as readahead.
+const READAHEAD_SECS: u64 = 2;
+
+// totals over the measured downloads.
+static SAMPLES: atomic::AtomicUsize = atomic::ATOMIC_USIZE_INIT;
+static LATENCY_MICROS: atomic::AtomicUsize = atomic::ATOMIC_USIZE_INIT;
+static TRANSFER_MICROS: atomic::AtomicUsize = atomic::ATOMIC_USIZE_INIT;
+static TRANSFER_BYTES: atomic::AtomicUsize = atomic::ATOMIC_USIZE_INIT;
+static LOGGED: atomic::AtomicBool = atomic::ATOMIC_BOOL_INIT;
+
+fn micros(duration: Duration) -> usize {
+  duration.as_secs() as usize * 1_000_000 + duration.subsec_nanos() as usize / 1000
+}
+
+/// Records a chunk download of `bytes` bytes, whose response started after
+/// `latency` and which took `elapsed` in total. Only the first few downloads
+/// are measured.
+pub fn record_download(latency: Duration, elapsed: Duration, bytes: u64) {
+  if bytes < MIN_SAMPLE_BYTES || SAMPLES.load(atomic::Ordering::SeqCst) >= PROBE_SAMPLES {
+    return;
+  }
+  LATENCY_MICROS.fetch_add(micros(latency), atomic::Ordering::SeqCst);
+  TRANSFER_MICROS.fetch_add(
+    micros(elapsed.checked_sub(latency).unwrap_or_default()),
+    atomic::Ordering::SeqCst,
+  );
+  TRANSFER_BYTES.fetch_add(bytes as usize, atomic::Ordering::SeqCst);
+  SAMPLES.fetch_add(1, atomic::Ordering::SeqCst);
+}
+
+/// The read block multiplier and readahead queue size suited to the
+/// measured latency and throughput, once enough downloads were measured.
+/// The readahead is kept below `cache_blocks`, so it can't evict the chunks
+/// being read.
+pub fn tuning(cache_blocks: usize) -> Option<(u32, usize)> {
+  let samples = SAMPLES.load(atomic::Ordering::SeqCst);
+  if samples < PROBE_SAMPLES {
+    return None;
+  }
+  let latency_micros = (LATENCY_MICROS.load(atomic::Ordering::SeqCst) / samples) as u64;
+  let transfer_micros = cmp::max(TRANSFER_MICROS.load(atomic::Ordering::SeqCst) as u64, 1);
+  let bytes_per_sec =
+    TRANSFER_BYTES.load(atomic::Ordering::SeqCst) as u64 * 1_000_000 / transfer_micros;
+
+  let chunk_bytes = bytes_per_sec * latency_micros / 1_000_000 * TRANSFER_TO_LATENCY_RATIO;
+  let chunk_bytes = cmp::min(cmp::max(chunk_bytes, MIN_CHUNK_BYTES), MAX_CHUNK_BYTES);
+  let read_block_multiplier =
+    (chunk_bytes / constants::BLOCK_SIZE as u64).next_power_of_two() as u32;
+  let chunk_bytes = read_block_multiplier as u64 * constants::BLOCK_SIZE as u64;
+
+  let readahead_chunks = (READAHEAD_SECS * bytes_per_sec + chunk_bytes - 1) / chunk_bytes;
+  let readahead_queue_size = cmp::min(
+    cmp::max(readahead_chunks as usize, 1),
+    cache_blocks.saturating_sub(1),
+  );
+
+  if !LOGGED.swap(true, atomic::Ordering::SeqCst) {
+    info!(
+      "auto-tune: {}ms latency, {}KB/s, reading {}KB chunks with {} chunks of readahead",
+      latency_micros / 1000,
+      bytes_per_sec / 1024,
+      chunk_bytes / 1024,
+      readahead_queue_size
+    );
+  }
+  Some((read_block_multiplier, readahead_queue_size))
+}
@@ -3,10 +3,12 @@ extern crate docopt;
 extern crate env_logger;
 extern crate fuse;
 extern crate gdrivefs;
+extern crate libc;
 #[macro_use]
 extern crate log;
 #[macro_use]
 extern crate serde_derive;
+extern crate serde_json;
 extern crate time;
 
 use gdrivefs::oauth;
@@ -15,11 +17,98 @@ const USAGE: &'static str = "
 gdrivefs: A fuse filesystem backed by Google Drive.
 
 Usage:
-  gdrivefs [options] <mountpoint>
+  gdrivefs ctl --control-socket=<path> show
+  gdrivefs ctl --control-socket=<path> status [--json]
+  gdrivefs ctl --control-socket=<path> tune <setting>...
+  gdrivefs ctl --control-socket=<path> restore <file> <revision>
+  gdrivefs auth import-rclone [--json] [options] <rclone-conf> [--remote=<remote>]
+  gdrivefs export [options] <drive-path> <archive>
+  gdrivefs hash [options] <drive-path>
+  gdrivefs check [--json] [options] [--union-token-file=<token_file>]... [--tenant=<tenant>]...
+  gdrivefs [options] [--union-token-file=<token_file>]... [--standby-client=<files>]... [--read-policy=<rule>]... <mountpoint>
+  gdrivefs [options] [--read-policy=<rule>]... (--tenant=<tenant>)...
+  gdrivefs [options] [--read-policy=<rule>]... --mounts=<file>
   gdrivefs (-h | --help)
 
 <mountpoint> must exist.
 
+gdrivefs ctl talks to a mount started with --control-socket. show prints
+the current read options, and tune changes them for files opened afterwards,
+with each <setting> one of readahead=<chunks>, chunk=<bytes>[K|M|G],
+cache=<chunks> or max-read-handles=<n>. status prints the reads that
+succeeded, were retried and failed, the drive errors by kind, and the number
+of files over their download quota, over the last --error-report-mins of the
+mount, then the number of items whose metadata is kept in memory and an
+estimate of the memory it takes, why the mount turned read-only if it did,
+and how often --changes-sync started over after its page token was refused,
+with the last reason. With --json, status prints an object with a boolean
+ok and either the status fields, with numbers as numbers, or an error.
+Reads of a file over its download quota,
+which Drive imposes on files downloaded too often, fail with EDQUOT until it
+resets. restore makes the revision with id <revision> the current content of
+<file>, a path relative to the root of the mount.
+
+gdrivefs auth import-rclone sets up gdrivefs with the client id and token of
+a drive remote in the rclone config <rclone-conf>, writing them to the files
+given by --client-id-file, --client-secret-file and --token-file, none of
+which may exist yet. The remote must use its own client id rather than
+rclone's. With --json it prints an object with a boolean ok and either the
+remote and the files written, or an error.
+
+gdrivefs export writes the folder at <drive-path>, relative to the root of
+the drive, to the tar archive <archive>, or to stdout if <archive> is -. The
+content is downloaded directly rather than through a mount, with as many
+as --export-jobs files downloaded in parallel. Google Docs, shortcuts and
+symlinks are left out.
+
+gdrivefs hash prints the md5 checksums of the file or folder at <drive-path>
+in the format of md5sum, using the checksums drive keeps rather than
+downloading. Paths are printed relative to the root of the drive, so a local
+copy can be checked with md5sum -c from the matching folder. Google Docs,
+Sheets, Slides and Drawings are exported with --export-docs, in the formats
+given by --export-formats, and the checksum of the export is printed under
+the name a mount shows them with. Other items without a checksum are left
+out.
+
+gdrivefs check verifies the client id and secret files, the token files,
+that the tokens are accepted by the Drive API, that /dev/fuse can be
+opened and, if given, that --disk-cache-dir is writable, printing what to fix for each failure. It exits non-zero if any
+check fails. With --json it prints an object with a boolean ok and a checks
+array of {name, ok, detail} objects instead.
+
+Instead of a single <mountpoint>, several accounts can be served from one
+process with --tenant=<uid>:<token_file>:<mountpoint>, repeated once per
+tenant. Each tenant gets its own mount, token and caches, and its mount is
+only accessible by <uid>.
+
+Mounts of different accounts, folders or read options can also be served
+from one process with --mounts=<file>, an ini file with one section per
+mount named after its mountpoint, e.g.:
+
+  [/mnt/work]
+  token_file = /home/me/.gdrivefs/work.token
+  root_folder = Projects
+  readahead = 8
+  chunk = 16M
+
+Each mount gets its own token and caches, while connections are shared.
+token_file is required. root_folder overrides --root-folder, and readahead,
+chunk, cache and max-read-handles override the read options, as with
+gdrivefs ctl tune. With uid = <uid>, the mount is only accessible by <uid>,
+as with --tenant.
+
+Passing --union-token-file merges the drives of further accounts into the
+mount, with lower priority than the --token-file account: folders with the
+same path are merged, and for any other name clash the file from the account
+given first is shown.
+
+Each --standby-client=<id_file>:<secret_file>:<token_file> adds another
+oauth client authorized for the --token-file account, e.g. from another
+Google Cloud project, with its own client id and secret files and a token
+from init_token. When Drive refuses requests for going over the quota of the
+client in use, the mount switches to the next one. Standby tokens are not
+saved back to their files.
+
 Several options can make a large performance difference, depending on the
 workload and characteristics of the system. Setting 'read-block-multipler
 to higher values will result in fewer HTTP requests, and less overhead per
@@ -30,29 +119,780 @@ down performance markedly on systems that perform their own readahead.
 
 Options:
   -h --help                           Show this screen.
+  --json                              With check, ctl status and auth import-rclone, print the results as JSON.
+  --remote=<remote>                   With auth import-rclone, the rclone remote to import, e.g. gdrive:. Needed if the config has several drive remotes.
   --client-id-file=<id_file>          Path to a file containing the oauth2 client id. [default: /usr/local/etc/gdrive_id]
   --client-secret-file=<secret_file>  Path to a file containing the oauth2 client secret. [default: /usr/local/etc/gdrive_secret]
   --token-file=<token_file>           Path to a file containing a oauth token (generated by init_token). [default: /usr/local/etc/gdrive_token]
-  --allow-other                       If true, allow non-root users to access the mounted filesystem.
+  --allow-other                       If true, allow other users to access the mounted filesystem. The default on Linux; other platforms such as FreeBSD only grant it to root. Always on with --tenant.
+  --no-allow-other                    If true, keep other users out of the mounted filesystem on Linux, where they're allowed by default. Ignored with --tenant.
+  --insecure-token-perms              If true, accept token files readable by other users.
+  --run-as=<uid_gid>                  A <uid>:<gid> pair to switch to once mounted, when started as root. The token files and --staging-dir must be writable by it, and --disk-cache-dir is handed to it. Not supported with --auto-remount, --tenant or --mounts.
+  --sandbox                           Once mounted, confine the file access of all threads to the token files and system directories, using Landlock. System calls are not filtered. Linux 5.13 or newer. Not supported with --auto-remount, --tenant or --mounts.
+  --control-socket=<path>             Path of a unix socket on which to accept gdrivefs ctl commands. Not supported with --tenant or --mounts.
+  --auto-remount                      If true, mount again when the fuse connection is aborted, keeping cached metadata.
   --dir-poll-secs=<poll-secs>         Seconds between directory refresh scans, or 0 to disable. [default: 900]
+  --differential-refresh              If true, refresh scans only fetch the items of a folder modified since its previous refresh, listing it in full every --full-refresh-every scans to drop deleted items.
+  --full-refresh-every=<n>            Scans between full listings of a folder with --differential-refresh. [default: 10]
+  --changes-sync                      If true, refresh scans fetch the changes made to the drive since the previous scan and update the items they touch, instead of listing every folder again.
+  --health-check-mins=<mins>          Minutes of failing drive requests after which tokens and connections are renewed, or 0 to disable. [default: 10]
   --readahead-queue-size=<size>       Size of the readahead queue (per-file, in number of chunks), or 0 to disable. [default: 0]
   --file-read-cache-blocks=<size>     Capacity of the per-file chunk cache (in number of chunks). [default: 10]
+  --disk-cache-dir=<dir>              Folder keeping downloaded chunks across opens, mounts and restarts. Several mounts or processes may share it. Chunks of the wrong size, or failing a checksum check of the most recently written ones, are dropped on startup. Disabled if unset.
+  --disk-cache-size=<bytes>           Size up to which --disk-cache-dir is filled, evicting the least recently read chunks beyond it, pinned ones last. [default: 10737418240]
+  --disk-cache-max-age-hours=<hours>  Hours after which chunks not read since are removed from --disk-cache-dir, unless pinned, or 0 to keep them until evicted. [default: 0]
+  --disk-cache-pin=<folders>          Comma-separated folders within the mount whose files keep their chunks in --disk-cache-dir over those of other files, regardless of --disk-cache-max-age-hours.
   --read-block-multiplier=<mult>      Number of 4k blocks to read per HTTP request. [default: 2048]
+  --max-concurrent-downloads=<n>      Maximum number of chunk downloads in progress across all files, or 0 for no limit. Reads waiting on a download go ahead of readahead. [default: 8]
+  --max-readahead-files=<n>           Number of most recently read files that readahead is done for, or 0 for no limit. Keeps scans opening many files from reading ahead on all of them. [default: 4]
+  --overload-backlog=<n>              Number of reads waiting for a download from which readahead and refreshes on open are dropped until the backlog drains, or 0 to never drop them. [default: 32]
+  --request-deadline-secs=<secs>      Seconds after which an unanswered read fails with EIO and a stalled HTTP request is abandoned, or 0 to wait indefinitely. [default: 0]
+  --pool-size=<n>                     Number of idle connections kept open per host for reuse. Bursts of chunk downloads reuse them instead of connecting again. [default: 16]
+  --keepalive-idle-secs=<secs>        Seconds an idle connection is kept for reuse before it is closed, or 0 to keep it until the server closes it. [default: 60]
+  --export-jobs=<n>                   With export, number of files downloaded in parallel. [default: 4]
+  --error-report-mins=<mins>          Minutes over which failed drive requests are counted, then logged as a single warning rather than one per failure, or 0 to only log them at debug level. [default: 5]
+  --read-retries=<n>                  Times a chunk download failing with a network or server error is retried. Retries stop while most requests fail, so outages aren't amplified. [default: 3]
+  --uncached-read-error=<action>      What reads of uncached data do when the download fails after the retries or is refused with --metered: eio, eagain, or block to wait for connectivity to return, up to --request-deadline-secs if set (refused reads then fail with EIO). [default: eio]
+  --max-read-handles=<n>              Number of files with a read thread and cache, counting recently closed files whose cache is kept for reuse. [default: 64]
+  --probe-containers                  If true, prefetch the index of mp4 and mkv videos when it isn't at the start of the file, since players seek to it first. Ignored with --metered.
+  --prefetch-on-open                  If true, start downloading the first chunk of a file and refreshing its metadata when it is opened, so the first read doesn't wait for a round trip. Ignored with --metered.
+  --early-reply                       If true, answer a read that misses the cache as soon as the bytes it asked for arrive, while the rest of the chunk keeps downloading, for faster seeks with large chunks.
+  --auto-tune                         If true, pick the read block multiplier and readahead queue size from the speed of the first downloads. Ignored with --metered.
+  --read-policy=<rule>                Read options for matching files, as <pattern>:<setting>,... where <pattern> is *.<ext>, a mime type or <type>/* and the settings are readahead=<chunks>, chunk=<bytes>[K|M|G] and cache=<chunks>. E.g. *.mkv:readahead=8,chunk=16M. The first matching rule applies.
+  --metered                           If true, keep network use low: disable readahead and read in small blocks.
+  --metered-max-file-size=<bytes>     With --metered, refuse reads of uncached data from files larger than this, or 0 for no limit. [default: 0]
+  --show-trashed                      If true, show items that are in the trash.
+  --hide-unexportable                 If true, hide Google Forms, Sites and other items that can't be downloaded or exported.
+  --export-docs                       If true, Google Docs, Sheets, Slides and Drawings are exported when read, in the formats given by --export-formats, and their names get the extension of the format. Their size shows as 0 until they are first read, then as the size of the last export until they are modified.
+  --export-formats=<formats>          Comma-separated kind=extension formats used by --export-docs, e.g. presentation=pdf. [default: document=docx,spreadsheet=xlsx,presentation=pptx,drawing=png]
+  --hide-undownloadable               If true, hide items that the owner doesn't allow you to download.
+  --no-content                        If true, show the tree with its sizes and times but refuse to open files with EACCES, to inventory a drive without downloading anything.
+  --max-read-file-size=<bytes>        Refuse to open files larger than this with EFBIG, or 0 for no limit. Unlike --metered-max-file-size, cached data can't be read either. [default: 0]
+  --max-write-file-size=<bytes>       Fail writes that would make a file larger than this with EFBIG, or 0 for no limit. Files larger than this can't be opened for writing without truncating them. [default: 0]
+  --shallow-stat                      If true, let the kernel cache file attributes for 10 minutes and refresh the metadata of files when opened instead, for much faster repeated ls -l in huge folders. Sizes and times may be stale for up to 10 minutes, even once a file is opened: the kernel only asks again for the attributes it cached when they expire, and from then on keeps those of opened files for a second.
+  --dir-order=<order>                 Order of directory listings: none for the order drive listed them in, name, mtime for oldest first, or newest for newest first. [default: none]
+  --owner-uid=<uid>                   Uid reported as the owner of items you own. [default: 0]
+  --owner-gid=<gid>                   Gid reported as the group of items you own. [default: 0]
+  --shared-uid=<uid>                  Uid reported as the owner of items shared with you. [default: 0]
+  --shared-gid=<gid>                  Gid reported as the group of items shared with you. [default: 0]
+  --permissions-xattr                 If true, expose sharing permissions as the user.gdrive.permissions xattr.
+  --labels-xattr                      If true, expose the drive labels of each file as user.gdrive.label.<label-id> xattrs. Listing the xattrs of a file then takes a drive request, so tools listing those of many files are slow.
+  --checksums-xattr                   If true, expose the checksums of binary files as user.gdrive.md5, sha1 and sha256 xattrs.
+  --cached-ranges-xattr               If true, expose the byte ranges of files held in the read cache as the user.gdrive.cached_ranges xattr.
+  --readstats-xattr                   If true, expose read counters of each file (reads, cache hits, chunks and bytes fetched, readahead wasted, errors, average fetch time) as the user.gdrive.readstats xattr, to report slow reads of one file.
+  --thumbnails-xattr                  If true, expose the thumbnail drive generated for images and videos as the user.gdrive.thumbnail xattr, so previews can be shown without downloading the file. Linux limits xattrs to 64KB, so reading a larger thumbnail fails with E2BIG.
+  --treesize-xattr                    If true, expose the total size of the files under each folder as the user.gdrive.treesize xattr, as <bytes> <files> <unlisted folders>, counting only folders already listed.
+  --ignore-file                       If true, hide the paths matching the gitignore-style rules in a .gdriveignore file at the root of the drive.
+  --shared-drives                     If true, show the shared drives you are a member of under 'Shared Drives'.
+  --allow-shared-drive-create         If true, mkdir in 'Shared Drives' creates a new shared drive.
+  --allow-dir-move                    If true, allow renaming and moving folders within a drive. Moves between My Drive and a shared drive, or between shared drives, fail with EXDEV since drive can only copy folders across them.
+  --orphans-folder                    If true, show the files you own that are in no folder under 'Orphans', so they can be moved back somewhere. Listing it scans all your files.
+  --snapshots                         If true, add a .snapshots folder to every folder, where <YYYY-MM-DD>/ shows the folder as of the end of that day (UTC), from the revisions of its files. Deleted files and pruned revisions are missing.
+  --activity-log                      If true, show recent drive activity in .gdrivefs/activity.log. Needs a token from init_token --activity-log.
+  --comments-sidecars                 If true, show the comments on each file in a read-only <name>.comments.json file.
+  --store-local-metadata              If true, chmod and symlink store permissions and link targets in the drive appProperties of files.
+  --store-full-mode                   If true, with --store-local-metadata chmod keeps the full mode of files and folders, not just the executable bit. The setuid and setgid bits are dropped.
+  --writable                          If true, allow creating, writing and truncating files. Files are staged whole in --staging-dir while open and uploaded as a new version when closed or synced. Google Docs can't be written. The mount turns read-only after 5 uploads in a row fail, as shown by gdrivefs ctl status.
+  --staging-dir=<dir>                 Folder that files open for writing are staged in [default: /tmp].
+  --keep-overwritten-revisions        If true, the revision a file had before it is opened for writing or truncated with --writable is kept forever in its Drive history, so gdrivefs ctl restore can bring it back. Drive keeps at most 200 such revisions per file.
+  --upload-mime-types=<types>         Comma-separated ext=type pairs giving the mime type of files created with --writable by extension, over the built-in types for common extensions, e.g. md=text/markdown.
+  --root-folder=<path>                Path of the folder to show at the root of the mount instead of the whole drive, relative to the root of the drive.
+  --union-token-file=<token_file>     Token file of a lower priority account to merge into the mount.
+  --standby-client=<files>            An <id_file>:<secret_file>:<token_file> triple, see above. Not supported with --tenant or --mounts.
+  --tenant=<tenant>                   A <uid>:<token_file>:<mountpoint> triple, see above.
+  --mounts=<file>                     An ini file of mounts to serve, see above.
 ";
 
+// Number of 4k blocks read per HTTP request with --metered.
+const METERED_READ_BLOCK_MULTIPLIER: u32 = 64;
+
 #[derive(Debug, Deserialize)]
 struct Args {
+  cmd_ctl: bool,
+  cmd_show: bool,
+  cmd_status: bool,
+  cmd_tune: bool,
+  arg_setting: Vec<String>,
+  cmd_restore: bool,
+  arg_file: String,
+  arg_revision: String,
+  cmd_auth: bool,
+  cmd_import_rclone: bool,
+  arg_rclone_conf: String,
+  flag_remote: Option<String>,
+  cmd_export: bool,
+  arg_drive_path: String,
+  arg_archive: String,
+  flag_export_jobs: usize,
+  cmd_hash: bool,
+  cmd_check: bool,
+  flag_json: bool,
   flag_client_id_file: String,
   flag_client_secret_file: String,
   flag_token_file: String,
   flag_allow_other: bool,
+  flag_no_allow_other: bool,
+  flag_insecure_token_perms: bool,
+  flag_run_as: Option<String>,
+  flag_sandbox: bool,
+  flag_control_socket: Option<String>,
+  flag_auto_remount: bool,
   flag_dir_poll_secs: u32,
+  flag_differential_refresh: bool,
+  flag_changes_sync: bool,
+  flag_full_refresh_every: u32,
+  flag_health_check_mins: u64,
+  flag_error_report_mins: u64,
   flag_readahead_queue_size: usize,
   flag_file_read_cache_blocks: usize,
+  flag_disk_cache_dir: Option<String>,
+  flag_disk_cache_size: u64,
+  flag_disk_cache_max_age_hours: u64,
+  flag_disk_cache_pin: Option<String>,
   flag_read_block_multiplier: u32,
+  flag_max_concurrent_downloads: usize,
+  flag_max_readahead_files: usize,
+  flag_overload_backlog: usize,
+  flag_request_deadline_secs: u64,
+  flag_pool_size: usize,
+  flag_keepalive_idle_secs: u64,
+  flag_max_read_handles: usize,
+  flag_read_retries: u32,
+  flag_uncached_read_error: String,
+  flag_probe_containers: bool,
+  flag_prefetch_on_open: bool,
+  flag_early_reply: bool,
+  flag_auto_tune: bool,
+  flag_read_policy: Vec<String>,
+  flag_metered: bool,
+  flag_metered_max_file_size: u64,
+  flag_show_trashed: bool,
+  flag_hide_unexportable: bool,
+  flag_export_docs: bool,
+  flag_export_formats: String,
+  flag_hide_undownloadable: bool,
+  flag_no_content: bool,
+  flag_max_read_file_size: u64,
+  flag_max_write_file_size: u64,
+  flag_shallow_stat: bool,
+  flag_dir_order: String,
+  flag_owner_uid: u32,
+  flag_owner_gid: u32,
+  flag_shared_uid: u32,
+  flag_shared_gid: u32,
+  flag_permissions_xattr: bool,
+  flag_labels_xattr: bool,
+  flag_checksums_xattr: bool,
+  flag_cached_ranges_xattr: bool,
+  flag_readstats_xattr: bool,
+  flag_thumbnails_xattr: bool,
+  flag_treesize_xattr: bool,
+  flag_shared_drives: bool,
+  flag_allow_shared_drive_create: bool,
+  flag_allow_dir_move: bool,
+  flag_orphans_folder: bool,
+  flag_ignore_file: bool,
+  flag_snapshots: bool,
+  flag_activity_log: bool,
+  flag_comments_sidecars: bool,
+  flag_store_local_metadata: bool,
+  flag_store_full_mode: bool,
+  flag_writable: bool,
+  flag_staging_dir: String,
+  flag_keep_overwritten_revisions: bool,
+  flag_upload_mime_types: Option<String>,
+  flag_union_token_file: Vec<String>,
+  flag_standby_client: Vec<String>,
+  flag_tenant: Vec<String>,
+  flag_mounts: Option<String>,
+  flag_root_folder: Option<String>,
   arg_mountpoint: String,
 }
 
+// One of several mounts served by the process, with its own drive account
+// and caches.
+#[derive(Debug)]
+struct MountSpec {
+  mountpoint: String,
+  token_file: String,
+  // the only local user that may access the mount, if any.
+  uid: Option<u32>,
+  // overrides --root-folder.
+  root_folder: Option<String>,
+  // <key>=<value> read settings applied on top of the read options.
+  read_settings: Vec<String>,
+}
+
+// parses a <uid>:<token_file>:<mountpoint> tenant specification.
+fn parse_tenant(spec: &str) -> Option<MountSpec> {
+  let mut parts = spec.splitn(3, ':');
+  let uid = match parts.next().and_then(|uid| uid.parse().ok()) {
+    Some(uid) => uid,
+    None => return None,
+  };
+  match (parts.next(), parts.next()) {
+    (Some(token_file), Some(mountpoint)) if !token_file.is_empty() && !mountpoint.is_empty() => {
+      Some(MountSpec {
+        mountpoint: mountpoint.into(),
+        token_file: token_file.into(),
+        uid: Some(uid),
+        root_folder: None,
+        read_settings: Vec::new(),
+      })
+    }
+    _ => None,
+  }
+}
+
+// parses a --standby-client spec, <id_file>:<secret_file>:<token_file>.
+fn parse_standby_client(spec: &str) -> Option<(String, String, String)> {
+  let parts: Vec<&str> = spec.splitn(3, ':').collect();
+  match parts[..] {
+    [id_file, secret_file, token_file]
+      if !id_file.is_empty() && !secret_file.is_empty() && !token_file.is_empty() =>
+    {
+      Some((id_file.into(), secret_file.into(), token_file.into()))
+    }
+    _ => None,
+  }
+}
+
+// loads the standby clients given by |specs|, as their client id, client
+// secret and token file.
+fn load_standby_clients(specs: &[String]) -> Vec<(String, String, String)> {
+  specs
+    .iter()
+    .map(|spec| {
+      let (id_file, secret_file, token_file) = parse_standby_client(spec)
+        .unwrap_or_else(|| panic!("Invalid --standby-client specification: {}", spec));
+      let read = |path: &str| {
+        gdrivefs::get_contents(path)
+          .unwrap_or_else(|err| panic!("Error while getting content of file: {}: {}", path, err))
+      };
+      (read(&id_file), read(&secret_file), token_file)
+    })
+    .collect()
+}
+
+// parses the --mounts file at |path|.
+fn parse_mounts_file(path: &str) -> Result<Vec<MountSpec>, Box<std::error::Error>> {
+  let text = try!(gdrivefs::get_contents(path));
+  let mut specs = Vec::new();
+  for (mountpoint, settings) in gdrivefs::common::parse_ini(&text) {
+    let mut spec = MountSpec {
+      mountpoint: mountpoint,
+      token_file: String::new(),
+      uid: None,
+      root_folder: None,
+      read_settings: Vec::new(),
+    };
+    for (key, value) in settings {
+      match key.as_str() {
+        "token_file" => spec.token_file = value,
+        "uid" => {
+          spec.uid = Some(try!(value.parse().map_err(|_| {
+            format!("[{}]: invalid uid {}", spec.mountpoint, value)
+          })))
+        }
+        "root_folder" => spec.root_folder = Some(value),
+        "readahead" | "chunk" | "cache" | "max-read-handles" => {
+          spec.read_settings.push(format!("{}={}", key, value))
+        }
+        _ => return Err(From::from(format!("[{}]: unknown setting {}", spec.mountpoint, key))),
+      }
+    }
+    if spec.token_file.is_empty() {
+      return Err(From::from(format!("[{}]: token_file is missing", spec.mountpoint)));
+    }
+    specs.push(spec);
+  }
+  if specs.is_empty() {
+    return Err(From::from(format!("{} defines no mounts", path)));
+  }
+  Ok(specs)
+}
+
+// the mounts given with --mounts or --tenant, if any.
+fn mount_specs(args: &Args) -> Result<Vec<MountSpec>, Box<std::error::Error>> {
+  if let Some(ref path) = args.flag_mounts {
+    return parse_mounts_file(path);
+  }
+  let mut specs = Vec::new();
+  for spec in &args.flag_tenant {
+    match parse_tenant(spec) {
+      Some(tenant) => specs.push(tenant),
+      None => return Err(From::from(format!("Invalid tenant specification: {}", spec))),
+    }
+  }
+  Ok(specs)
+}
+
+// parses a <uid>:<gid> pair.
+fn parse_run_as(spec: &str) -> Option<(u32, u32)> {
+  let mut parts = spec.splitn(2, ':');
+  match (
+    parts.next().and_then(|uid| uid.parse().ok()),
+    parts.next().and_then(|gid| gid.parse().ok()),
+  ) {
+    (Some(uid), Some(gid)) => Some((uid, gid)),
+    _ => None,
+  }
+}
+
+// switches the process to |uid| and |gid|, dropping root privileges once
+// they are no longer needed. Does nothing if already running as them.
+fn drop_privileges(uid: u32, gid: u32) -> std::io::Result<()> {
+  unsafe {
+    if libc::getuid() == uid && libc::getgid() == gid {
+      return Ok(());
+    }
+    // the group must be changed while still root.
+    if libc::setgroups(0, std::ptr::null()) != 0 || libc::setgid(gid) != 0 || libc::setuid(uid) != 0 {
+      let err = std::io::Error::last_os_error();
+      return Err(std::io::Error::new(
+        err.kind(),
+        format!("Could not switch to uid {} and gid {}: {}", uid, gid, err),
+      ));
+    }
+  }
+  info!("switched to uid {} and gid {}", uid, gid);
+  Ok(())
+}
+
+// The outcome of one check of gdrivefs check, as printed by --json.
+#[derive(Debug, Serialize)]
+struct CheckResult {
+  name: String,
+  ok: bool,
+  // what was found, or what to fix if the check failed.
+  detail: String,
+}
+
+// The output of gdrivefs check --json.
+#[derive(Debug, Serialize)]
+struct CheckReport {
+  ok: bool,
+  checks: Vec<CheckResult>,
+}
+
+// records the outcome of the check |name| in |checks|.
+fn report_check(
+  checks: &mut Vec<CheckResult>,
+  name: &str,
+  result: Result<String, Box<std::error::Error>>,
+) {
+  let (ok, detail) = match result {
+    Ok(detail) => (true, detail),
+    Err(err) => (false, err.to_string()),
+  };
+  checks.push(CheckResult {
+    name: name.into(),
+    ok: ok,
+    detail: detail,
+  });
+}
+
+// runs the checks of gdrivefs check and prints them, as JSON if |json|.
+// Returns whether they all passed.
+fn check(args: &Args, json: bool) -> bool {
+  let mut checks = Vec::new();
+  let client_id = gdrivefs::get_contents(&args.flag_client_id_file);
+  let client_secret = gdrivefs::get_contents(&args.flag_client_secret_file);
+  for &(path, contents) in &[
+    (&args.flag_client_id_file, &client_id),
+    (&args.flag_client_secret_file, &client_secret),
+  ] {
+    let result = match *contents {
+      Ok(_) => Ok(String::from("readable")),
+      Err(ref err) => Err(From::from(format!("Could not read it: {}", err))),
+    };
+    report_check(&mut checks, path, result);
+  }
+
+  let token_files: Vec<String> = if args.flag_tenant.is_empty() && args.flag_mounts.is_none() {
+    let mut token_files = vec![args.flag_token_file.clone()];
+    token_files.extend(args.flag_union_token_file.iter().cloned());
+    token_files
+  } else {
+    match mount_specs(args) {
+      Ok(specs) => specs.into_iter().map(|spec| spec.token_file).collect(),
+      Err(err) => {
+        report_check(&mut checks, "mounts", Err(err));
+        Vec::new()
+      }
+    }
+  };
+  for token_file in &token_files {
+    let validated =
+      gdrivefs::startup::validate_token_files(&[token_file.clone()], args.flag_insecure_token_perms);
+    let result = match (validated, &client_id, &client_secret) {
+      (Err(err), _, _) => Err(err),
+      (Ok(_), &Ok(ref client_id), &Ok(ref client_secret)) => {
+        let client = oauth::new_google_client(client_id, client_secret, None);
+        match oauth::GoogleAuthenticator::from_file(client, token_file) {
+          Ok(mut authenticator) => gdrivefs::startup::check_drive_access(&mut authenticator)
+            .map(|email| format!("signed in as {}", email)),
+          Err(err) => Err(From::from(format!(
+            "Could not load the token, generate a new one with init_token: {}",
+            err
+          ))),
+        }
+      }
+      (Ok(_), _, _) => Err(From::from(
+        "Drive access not checked without the client id and secret",
+      )),
+    };
+    report_check(&mut checks, token_file, result);
+  }
+
+  report_check(&mut checks, "fuse", gdrivefs::startup::check_fuse_device());
+  if let Some(ref dir) = args.flag_disk_cache_dir {
+    report_check(
+      &mut checks,
+      "disk cache",
+      gdrivefs::startup::check_disk_cache_dir(dir),
+    );
+  }
+
+  let report = CheckReport {
+    ok: checks.iter().all(|check| check.ok),
+    checks: checks,
+  };
+  if json {
+    println!("{}", serde_json::to_string_pretty(&report).unwrap());
+  } else {
+    for check in &report.checks {
+      println!(
+        "{}  {}: {}",
+        if check.ok { "ok  " } else { "FAIL" },
+        check.name,
+        check.detail
+      );
+    }
+  }
+  report.ok
+}
+
+// The output of gdrivefs auth import-rclone --json.
+#[derive(Debug, Serialize)]
+struct ImportReport {
+  ok: bool,
+  // the name of the remote imported, if it was.
+  remote: Option<String>,
+  client_id_file: String,
+  client_secret_file: String,
+  token_file: String,
+  // why the import failed, if it did.
+  error: Option<String>,
+}
+
+// writes the client id and token of an rclone remote to the files gdrivefs
+// reads them from and prints the outcome, as JSON if |json|. Returns whether
+// it succeeded.
+fn import_rclone(args: &Args, json: bool) -> bool {
+  let result = import_rclone_remote(args);
+  if json {
+    let report = ImportReport {
+      ok: result.is_ok(),
+      remote: result.as_ref().ok().cloned(),
+      client_id_file: args.flag_client_id_file.clone(),
+      client_secret_file: args.flag_client_secret_file.clone(),
+      token_file: args.flag_token_file.clone(),
+      error: result.as_ref().err().cloned(),
+    };
+    println!("{}", serde_json::to_string_pretty(&report).unwrap());
+  } else {
+    match result {
+      Ok(ref remote) => println!(
+        "Imported rclone remote {}: client id in {}, secret in {}, token in {}",
+        remote, args.flag_client_id_file, args.flag_client_secret_file, args.flag_token_file
+      ),
+      Err(ref err) => eprintln!("{}", err),
+    }
+  }
+  result.is_ok()
+}
+
+// does the work of import_rclone, returning the name of the remote imported
+// or why it couldn't be.
+fn import_rclone_remote(args: &Args) -> Result<String, String> {
+  let remote = try!(gdrivefs::rclone::import_remote(
+    &args.arg_rclone_conf,
+    args.flag_remote.as_ref().map(String::as_str),
+  )
+  .map_err(|err| format!("Could not import from {}: {}", args.arg_rclone_conf, err)));
+  for path in &[
+    &args.flag_client_id_file,
+    &args.flag_client_secret_file,
+    &args.flag_token_file,
+  ] {
+    if std::path::Path::new(path).exists() {
+      return Err(format!("{} already exists, pass another path or remove it", path));
+    }
+  }
+  let written = gdrivefs::set_contents(
+    &args.flag_client_id_file,
+    remote.client_id.as_bytes(),
+    0o600,
+  )
+  .and_then(|_| {
+    gdrivefs::set_contents(
+      &args.flag_client_secret_file,
+      remote.client_secret.as_bytes(),
+      0o600,
+    )
+  })
+  .and_then(|_| oauth::save_token(&args.flag_token_file, &remote.token));
+  written
+    .map(|_| remote.name)
+    .map_err(|err| format!("Could not write the imported credentials: {}", err))
+}
+
+// prints |reply| to the status command of the control socket as JSON, with
+// the fields of the status, or the error, alongside a boolean ok.
+fn print_status_json(reply: &str) {
+  let mut output = serde_json::Map::new();
+  if reply.starts_with("ok ") {
+    let mut status = serde_json::Map::new();
+    for (key, value) in gdrivefs::parse_status(&reply["ok ".len()..]) {
+      let value = match value.parse::<u64>() {
+        Ok(number) => serde_json::Value::from(number),
+        Err(_) => serde_json::Value::from(value),
+      };
+      status.insert(key, value);
+    }
+    output.insert("ok".into(), serde_json::Value::from(true));
+    output.insert("status".into(), serde_json::Value::Object(status));
+  } else {
+    let error = reply.trim();
+    let error = if error.starts_with("error: ") {
+      &error["error: ".len()..]
+    } else {
+      error
+    };
+    output.insert("ok".into(), serde_json::Value::from(false));
+    output.insert("error".into(), serde_json::Value::from(error));
+  }
+  println!("{}", serde_json::to_string_pretty(&output).unwrap());
+}
+
+// loads the token of the --token-file account, for commands that talk to
+// the drive without mounting it.
+fn load_authenticator(
+  args: &Args,
+  client_id: &str,
+  client_secret: &str,
+) -> oauth::GoogleAuthenticator {
+  let client = oauth::new_google_client(client_id, client_secret, None);
+  let token_file = gdrivefs::startup::validate_token_files(
+    &[args.flag_token_file.clone()],
+    args.flag_insecure_token_perms,
+  )
+  .unwrap_or_else(|err| panic!("{}", err))
+  .remove(0);
+  let authenticator = oauth::GoogleAuthenticator::from_file(client, &token_file).unwrap();
+  authenticator.start_auto_save(&token_file, std::time::Duration::new(60, 0));
+  authenticator
+}
+
+// system directories needed for name resolution and randomness once
+// sandboxed. Certificates are built in.
+const SANDBOX_READ_ONLY_PATHS: &[&str] = &["/etc", "/usr", "/lib", "/lib64", "/dev/urandom"];
+
+// Sends the mountpoint of a mount thread, and whether it panicked, when
+// dropped at the end of the thread.
+struct MountEnded(String, std::sync::mpsc::Sender<(String, bool)>);
+
+impl Drop for MountEnded {
+  fn drop(&mut self) {
+    let _ = self.1.send((self.0.clone(), std::thread::panicking()));
+  }
+}
+
+// mounts a filesystem merging the accounts in |token_files| (highest priority
+// first) at |mountpoint|, blocking until it is unmounted. The first account
+// switches between its client and the |standby_clients| (client id, client
+// secret, token file) when one runs out of quota.
+fn mount(
+  client_id: &str,
+  client_secret: &str,
+  token_files: &[String],
+  standby_clients: &[(String, String, String)],
+  mountpoint: &str,
+  options: gdrivefs::FileReadOptions,
+  fs_options: gdrivefs::FileSystemOptions,
+  dir_poll_secs: u32,
+  health_check_mins: u64,
+  allow_other: bool,
+  auto_remount: bool,
+  run_as: Option<(u32, u32)>,
+  sandbox: bool,
+  control_socket: Option<&str>,
+  error_report_window: Option<std::time::Duration>,
+) {
+  let mut authenticators = Vec::new();
+  for token_file in token_files {
+    let client = oauth::new_google_client(client_id, client_secret, None);
+    let authenticator = oauth::GoogleAuthenticator::from_file(client, token_file).unwrap();
+    authenticators.push(authenticator);
+  }
+  for &(ref standby_id, ref standby_secret, ref token_file) in standby_clients {
+    let client = oauth::new_google_client(standby_id, standby_secret, None);
+    let token = oauth::load_token(token_file)
+      .unwrap_or_else(|err| panic!("Could not load standby token {}: {}", token_file, err));
+    authenticators[0].add_standby(client, token);
+  }
+
+  println!("Mounting drive fs at {:?}", mountpoint);
+
+  let staging_dir = if fs_options.writable {
+    Some(fs_options.staging_dir.clone())
+  } else {
+    None
+  };
+  // the filesystem starts no threads until it's first used, so that all of
+  // them are started once mounted, and sandboxed if asked to be.
+  let driveimpl = gdrivefs::GDriveFS::new_union(authenticators.clone(), options, fs_options);
+
+  // allow_other is only granted to root by some platforms (e.g. FreeBSD's
+  // fusefs), so it isn't requested there unless asked for, see
+  // allow_other_users.
+  let mut mount_options = Vec::new();
+  if allow_other {
+    mount_options.push(std::ffi::OsStr::new("-oallow_other"));
+  }
+  let mut started = false;
+  loop {
+    let result = fuse::Session::new(
+      driveimpl.new_session(),
+      std::path::Path::new(mountpoint),
+      &mount_options,
+    )
+    .and_then(|mut session| {
+      // errors from here on drop the session, which unmounts the filesystem
+      // rather than leaving a dead mount behind.
+      if let Some((uid, gid)) = run_as {
+        try!(drop_privileges(uid, gid));
+      }
+      // files are staged as the user switched to.
+      if let Some(ref dir) = staging_dir {
+        try!(gdrivefs::check_staging_dir(dir).map_err(|err| {
+          std::io::Error::new(
+            err.kind(),
+            format!("Could not stage files in {}: {}", dir, err),
+          )
+        }));
+      }
+      if !started {
+        started = true;
+        // the socket is created before the sandbox forbids it.
+        let control_listener = match control_socket {
+          Some(path) => Some(try!(gdrivefs::bind_control_socket(path).map_err(|err| {
+            std::io::Error::new(
+              err.kind(),
+              format!("Could not listen on control socket {}: {}", path, err),
+            )
+          }))),
+          None => None,
+        };
+        // a sandbox only confines the thread applying it and the threads it
+        // starts afterwards, so it's applied before any is started.
+        if sandbox {
+          let token_paths: Vec<&str> = token_files.iter().map(|path| path.as_str()).collect();
+          try!(
+            gdrivefs::sandbox::restrict_paths(SANDBOX_READ_ONLY_PATHS, &token_paths).map_err(
+              |err| {
+                std::io::Error::new(
+                  std::io::ErrorKind::Other,
+                  format!("Could not sandbox the filesystem: {}", err),
+                )
+              }
+            )
+          );
+        }
+        for (authenticator, token_file) in authenticators.iter().zip(token_files) {
+          authenticator.start_auto_save(token_file, std::time::Duration::new(60, 0));
+        }
+        if let Some(window) = error_report_window {
+          gdrivefs::start_error_reporter(window);
+        }
+        if dir_poll_secs > 0 {
+          driveimpl.start_auto_refresh(std::time::Duration::new(dir_poll_secs as u64, 0));
+        }
+        if health_check_mins > 0 {
+          driveimpl.start_health_monitor(std::time::Duration::from_secs(health_check_mins * 60));
+        }
+        if let (Some(listener), Some(path)) = (control_listener, control_socket) {
+          try!(driveimpl.start_control_socket(listener).map_err(|err| {
+            std::io::Error::new(
+              err.kind(),
+              format!("Could not listen on control socket {}: {}", path, err),
+            )
+          }));
+        }
+      }
+      session.run()
+    });
+    if !auto_remount || !is_disconnected(mountpoint) {
+      result.expect(&format!(
+        "Could not mount fuse filesystem at {}",
+        mountpoint
+      ));
+      return;
+    }
+    warn!("fuse connection to {} was lost ({:?}), remounting", mountpoint, result);
+    detach_mount(mountpoint);
+  }
+}
+
+// whether |mountpoint| is still mounted, but its fuse connection is gone.
+fn is_disconnected(mountpoint: &str) -> bool {
+  match std::fs::metadata(mountpoint) {
+    Err(err) => err.raw_os_error() == Some(libc::ENOTCONN),
+    Ok(_) => false,
+  }
+}
+
+// whether mounts let other users in: by default on Linux, as they always
+// did there, but only when asked for elsewhere, since e.g. FreeBSD's fusefs
+// refuses allow_other to users other than root.
+fn allow_other_users(args: &Args) -> bool {
+  if args.flag_allow_other && args.flag_no_allow_other {
+    panic!("--allow-other and --no-allow-other are exclusive");
+  }
+  args.flag_allow_other || (cfg!(target_os = "linux") && !args.flag_no_allow_other)
+}
+
+// unmounts the dead mount at |mountpoint|, so it can be mounted again.
+fn detach_mount(mountpoint: &str) {
+  let status = if cfg!(target_os = "linux") {
+    std::process::Command::new("fusermount")
+      .args(&["-u", "-z", mountpoint])
+      .status()
+  } else {
+    std::process::Command::new("umount")
+      .args(&["-f", mountpoint])
+      .status()
+  };
+  match status {
+    Ok(status) if status.success() => {}
+    other => warn!("could not unmount {}: {:?}", mountpoint, other),
+  }
+}
+
+// the formats native Google items are exported in, if --export-docs.
+fn export_formats(args: &Args) -> Vec<gdrivefs::ExportFormat> {
+  if !args.flag_export_docs {
+    return Vec::new();
+  }
+  args
+    .flag_export_formats
+    .split(',')
+    .filter(|format| !format.is_empty())
+    .map(|format| format.parse().unwrap_or_else(|err| panic!("{}", err)))
+    .collect()
+}
+
 fn main() {
   env_logger::init();
 
@@ -61,42 +901,368 @@ fn main() {
     .unwrap_or_else(|e| e.exit());
 
   info!("Got args: {:?}", args);
-
-  let client = oauth::new_google_client(
-    &gdrivefs::get_contents(&args.flag_client_id_file).expect(&format!(
-      "Error while getting content of file: {}",
-      &args.flag_client_id_file
-    )),
-    &gdrivefs::get_contents(&args.flag_client_secret_file).expect(&format!(
-      "Error while getting content of file: {}",
-      &args.flag_client_secret_file
-    )),
-    None
+  gdrivefs::common::set_connection_pool(
+    args.flag_pool_size,
+    if args.flag_keepalive_idle_secs > 0 {
+      Some(std::time::Duration::from_secs(args.flag_keepalive_idle_secs))
+    } else {
+      None
+    },
   );
 
-  let authenticator = oauth::GoogleAuthenticator::from_file(client, &args.flag_token_file).unwrap();
-  authenticator.start_auto_save(&args.flag_token_file, std::time::Duration::new(60, 0));
+  if args.cmd_ctl {
+    let command = if args.cmd_tune {
+      format!("tune {}", args.arg_setting.join(" "))
+    } else if args.cmd_restore {
+      format!("restore {} {}", args.arg_file, args.arg_revision)
+    } else if args.cmd_status {
+      String::from("status")
+    } else {
+      String::from("show")
+    };
+    let path = args.flag_control_socket.as_ref().unwrap();
+    let reply = gdrivefs::send_command(path, &command)
+      .unwrap_or_else(|err| panic!("Could not reach control socket {}: {}", path, err));
+    if args.cmd_status && args.flag_json {
+      print_status_json(&reply);
+    } else {
+      print!("{}", reply);
+    }
+    std::process::exit(if reply.starts_with("ok ") { 0 } else { 1 });
+  }
 
-  println!("Mounting drive fs at {:?}", args.arg_mountpoint);
+  if args.cmd_auth {
+    std::process::exit(if import_rclone(&args, args.flag_json) { 0 } else { 1 });
+  }
 
-  let options = gdrivefs::FileReadOptions {
+  if args.cmd_check {
+    std::process::exit(if check(&args, args.flag_json) { 0 } else { 1 });
+  }
+
+  let client_id = gdrivefs::get_contents(&args.flag_client_id_file).expect(&format!(
+    "Error while getting content of file: {}",
+    &args.flag_client_id_file
+  ));
+  let client_secret = gdrivefs::get_contents(&args.flag_client_secret_file).expect(&format!(
+    "Error while getting content of file: {}",
+    &args.flag_client_secret_file
+  ));
+
+  if args.cmd_hash {
+    let mut authenticator = load_authenticator(&args, &client_id, &client_secret);
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    match gdrivefs::write_md5sums(
+      &mut authenticator,
+      &args.arg_drive_path,
+      &export_formats(&args),
+      &mut out,
+    ) {
+      Ok(0) => std::process::exit(0),
+      Ok(skipped) => {
+        eprintln!("Left out {} items without a checksum", skipped);
+        std::process::exit(0);
+      }
+      Err(err) => {
+        eprintln!("Hashing {} failed: {}", args.arg_drive_path, err);
+        std::process::exit(1);
+      }
+    }
+  }
+
+  if args.cmd_export {
+    let authenticator = load_authenticator(&args, &client_id, &client_secret);
+    let result = if args.arg_archive == "-" {
+      let stdout = std::io::stdout();
+      let out = std::io::BufWriter::new(stdout.lock());
+      gdrivefs::export_tar(&authenticator, &args.arg_drive_path, out, args.flag_export_jobs)
+    } else {
+      let file = std::fs::File::create(&args.arg_archive)
+        .unwrap_or_else(|err| panic!("Could not create {}: {}", args.arg_archive, err));
+      let out = std::io::BufWriter::new(file);
+      gdrivefs::export_tar(&authenticator, &args.arg_drive_path, out, args.flag_export_jobs)
+    };
+    match result {
+      Ok(summary) => {
+        eprintln!(
+          "Exported {} files ({} bytes) and {} folders, left out {} items without content",
+          summary.files, summary.bytes, summary.folders, summary.skipped
+        );
+        std::process::exit(0);
+      }
+      Err(err) => {
+        eprintln!("Export of {} failed: {}", args.arg_drive_path, err);
+        std::process::exit(1);
+      }
+    }
+  }
+
+  let mut options = gdrivefs::FileReadOptions {
     readahead_queue_size: args.flag_readahead_queue_size,
     file_read_cache_blocks: args.flag_file_read_cache_blocks,
     read_block_multiplier: args.flag_read_block_multiplier,
+    max_uncached_file_size: None,
+    max_concurrent_downloads: args.flag_max_concurrent_downloads,
+    max_readahead_files: args.flag_max_readahead_files,
+    overload_backlog: args.flag_overload_backlog,
+    read_deadline: None,
+    max_read_handles: args.flag_max_read_handles,
+    read_policies: args
+      .flag_read_policy
+      .iter()
+      .map(|spec| {
+        spec
+          .parse()
+          .unwrap_or_else(|err| panic!("Invalid --read-policy {}: {}", spec, err))
+      })
+      .collect(),
+    auto_tune: args.flag_auto_tune && !args.flag_metered,
+    probe_containers: args.flag_probe_containers && !args.flag_metered,
+    read_retries: args.flag_read_retries,
+    uncached_read_action: args.flag_uncached_read_error.parse().unwrap_or_else(|err| {
+      panic!(
+        "Invalid --uncached-read-error {}: {}",
+        args.flag_uncached_read_error, err
+      )
+    }),
+    prefetch_on_open: args.flag_prefetch_on_open && !args.flag_metered,
+    early_reply: args.flag_early_reply,
+  };
+  if args.flag_request_deadline_secs > 0 {
+    let deadline = std::time::Duration::from_secs(args.flag_request_deadline_secs);
+    options.read_deadline = Some(deadline);
+    gdrivefs::common::set_http_timeout(Some(deadline));
+  }
+  if args.flag_metered {
+    // only download what is actually read.
+    options.readahead_queue_size = 0;
+    options.read_block_multiplier = std::cmp::min(
+      options.read_block_multiplier,
+      METERED_READ_BLOCK_MULTIPLIER,
+    );
+    if args.flag_metered_max_file_size > 0 {
+      options.max_uncached_file_size = Some(args.flag_metered_max_file_size);
+    }
+  }
+
+  let fs_options = gdrivefs::FileSystemOptions {
+    show_trashed: args.flag_show_trashed,
+    hide_unexportable: args.flag_hide_unexportable,
+    export_formats: export_formats(&args),
+    hide_undownloadable: args.flag_hide_undownloadable,
+    no_content: args.flag_no_content,
+    max_read_file_size: if args.flag_max_read_file_size > 0 {
+      Some(args.flag_max_read_file_size)
+    } else {
+      None
+    },
+    max_write_file_size: if args.flag_max_write_file_size > 0 {
+      Some(args.flag_max_write_file_size)
+    } else {
+      None
+    },
+    shallow_stat: args.flag_shallow_stat,
+    differential_refresh: if args.flag_differential_refresh {
+      Some(args.flag_full_refresh_every)
+    } else {
+      None
+    },
+    changes_sync: args.flag_changes_sync,
+    dir_order: args
+      .flag_dir_order
+      .parse()
+      .unwrap_or_else(|err| panic!("Invalid --dir-order {}: {}", args.flag_dir_order, err)),
+    root_folder: args.flag_root_folder.clone(),
+    owner_uid: args.flag_owner_uid,
+    owner_gid: args.flag_owner_gid,
+    shared_uid: args.flag_shared_uid,
+    shared_gid: args.flag_shared_gid,
+    permissions_xattr: args.flag_permissions_xattr,
+    labels_xattr: args.flag_labels_xattr,
+    checksums_xattr: args.flag_checksums_xattr,
+    cached_ranges_xattr: args.flag_cached_ranges_xattr,
+    readstats_xattr: args.flag_readstats_xattr,
+    thumbnails_xattr: args.flag_thumbnails_xattr,
+    treesize_xattr: args.flag_treesize_xattr,
+    restrict_to_uid: None,
+    shared_drives: args.flag_shared_drives,
+    allow_shared_drive_create: args.flag_allow_shared_drive_create,
+    allow_dir_move: args.flag_allow_dir_move,
+    orphans_folder: args.flag_orphans_folder,
+    ignore_file: args.flag_ignore_file,
+    snapshots: args.flag_snapshots,
+    activity_log: args.flag_activity_log,
+    comments_sidecars: args.flag_comments_sidecars,
+    store_local_metadata: args.flag_store_local_metadata,
+    store_full_mode: args.flag_store_full_mode,
+    writable: args.flag_writable,
+    staging_dir: args.flag_staging_dir.clone(),
+    keep_overwritten_revisions: args.flag_keep_overwritten_revisions,
+    upload_mime_types: args
+      .flag_upload_mime_types
+      .as_ref()
+      .map_or(Ok(Vec::new()), |spec| gdrivefs::parse_upload_mime_types(spec))
+      .unwrap_or_else(|err| panic!("Invalid --upload-mime-types: {}", err)),
   };
 
-  let driveimpl = gdrivefs::GDriveFS::new(authenticator, options);
-  if args.flag_dir_poll_secs > 0 {
-    driveimpl.start_auto_refresh(std::time::Duration::new(args.flag_dir_poll_secs as u64, 0));
+  // remounting runs fusermount, which can't gain privileges once sandboxed.
+  if args.flag_sandbox && args.flag_auto_remount {
+    panic!("--sandbox is not supported with --auto-remount");
+  }
+  // nor once root was dropped, since the mount is owned by root.
+  if args.flag_run_as.is_some() && args.flag_auto_remount {
+    panic!("--run-as is not supported with --auto-remount");
+  }
+  // staging files are created outside of the paths a sandbox allows.
+  if args.flag_sandbox && args.flag_writable {
+    panic!("--sandbox is not supported with --writable");
+  }
+  // changes are only followed for the first account.
+  if args.flag_changes_sync && !args.flag_union_token_file.is_empty() {
+    panic!("--changes-sync is not supported with --union-token-file");
+  }
+  if args.flag_sandbox && args.flag_disk_cache_dir.is_some() {
+    panic!("--sandbox is not supported with --disk-cache-dir");
   }
 
-  // todo(jonallie): figure out how to make this the default using docopt.
-  fuse::mount(
-    driveimpl,
-    &args.arg_mountpoint,
-    &[std::ffi::OsStr::new("-oallow_other")],
-  ).expect(&format!(
-    "Could not mount fuse filesystem at {}",
-    &args.arg_mountpoint
-  ));
+  let run_as = args.flag_run_as.as_ref().map(|spec| {
+    parse_run_as(spec).expect(&format!("Invalid --run-as specification: {}", spec))
+  });
+
+  // drive errors are counted across all mounts of the process.
+  let error_report_window = if args.flag_error_report_mins > 0 {
+    Some(std::time::Duration::from_secs(args.flag_error_report_mins * 60))
+  } else {
+    None
+  };
+
+  // the disk cache is shared by all mounts of the process.
+  if let Some(ref dir) = args.flag_disk_cache_dir {
+    let max_age = if args.flag_disk_cache_max_age_hours > 0 {
+      Some(std::time::Duration::from_secs(args.flag_disk_cache_max_age_hours * 3600))
+    } else {
+      None
+    };
+    let pinned_folders: Vec<String> = args
+      .flag_disk_cache_pin
+      .iter()
+      .flat_map(|folders| folders.split(','))
+      .filter(|folder| !folder.is_empty())
+      .map(String::from)
+      .collect();
+    gdrivefs::configure_disk_cache(
+      dir,
+      args.flag_disk_cache_size,
+      max_age,
+      &pinned_folders,
+      run_as,
+    )
+      .unwrap_or_else(|err| panic!("Can't use disk cache {}: {}", dir, err));
+  }
+
+  if args.flag_tenant.is_empty() && args.flag_mounts.is_none() {
+    let mut token_files = vec![args.flag_token_file.clone()];
+    token_files.extend(args.flag_union_token_file.iter().cloned());
+    let token_files =
+      gdrivefs::startup::validate_token_files(&token_files, args.flag_insecure_token_perms)
+        .unwrap_or_else(|err| panic!("{}", err));
+    let standby_clients = load_standby_clients(&args.flag_standby_client);
+    mount(
+      &client_id,
+      &client_secret,
+      &token_files,
+      &standby_clients,
+      &args.arg_mountpoint,
+      options,
+      fs_options,
+      args.flag_dir_poll_secs,
+      args.flag_health_check_mins,
+      allow_other_users(&args),
+      args.flag_auto_remount,
+      run_as,
+      args.flag_sandbox,
+      args.flag_control_socket.as_ref().map(|path| path.as_str()),
+      error_report_window,
+    );
+    return;
+  }
+
+  // the mounts are made in parallel, so none can drop root before the
+  // others are mounted.
+  if run_as.is_some() {
+    panic!("--run-as is not supported with --tenant or --mounts");
+  }
+  if args.flag_sandbox {
+    panic!("--sandbox is not supported with --tenant or --mounts");
+  }
+  if args.flag_control_socket.is_some() {
+    panic!("--control-socket is not supported with --tenant or --mounts");
+  }
+
+  if let Some(window) = error_report_window {
+    gdrivefs::start_error_reporter(window);
+  }
+
+  // one mount per tenant or --mounts section, each with its own
+  // authenticator and caches.
+  let specs = mount_specs(&args).unwrap_or_else(|err| panic!("{}", err));
+  let (ended_tx, ended_rx) = std::sync::mpsc::channel();
+  for spec in specs {
+    let token_files = gdrivefs::startup::validate_token_files(
+      &[spec.token_file.clone()],
+      args.flag_insecure_token_perms,
+    )
+    .unwrap_or_else(|err| panic!("{}", err));
+    let client_id = client_id.clone();
+    let client_secret = client_secret.clone();
+    let mut options = options.clone();
+    gdrivefs::tune_read_options(&mut options, spec.read_settings.iter().map(|s| s.as_str()))
+      .unwrap_or_else(|err| panic!("[{}]: {}", spec.mountpoint, err));
+    let mut fs_options = fs_options.clone();
+    if let Some(uid) = spec.uid {
+      fs_options.owner_uid = uid;
+      fs_options.restrict_to_uid = Some(uid);
+    }
+    if spec.root_folder.is_some() {
+      fs_options.root_folder = spec.root_folder.clone();
+    }
+    let dir_poll_secs = args.flag_dir_poll_secs;
+    let health_check_mins = args.flag_health_check_mins;
+    // tenants access their mount as a different user than the daemon.
+    let allow_other = allow_other_users(&args) || spec.uid.is_some();
+    let auto_remount = args.flag_auto_remount;
+    let ended = MountEnded(spec.mountpoint.clone(), ended_tx.clone());
+    std::thread::Builder::new()
+      .name(format!("mount:{}", spec.mountpoint))
+      .spawn(move || {
+        let _ended = ended;
+        mount(
+          &client_id,
+          &client_secret,
+          &token_files,
+          &[],
+          &spec.mountpoint,
+          options,
+          fs_options,
+          dir_poll_secs,
+          health_check_mins,
+          allow_other,
+          auto_remount,
+          None,
+          false,
+          None,
+          None,
+        )
+      })
+      .unwrap();
+  }
+  // the mounts are waited on in the order they end, so that the first one
+  // to fail stops the process rather than going unnoticed behind another.
+  drop(ended_tx);
+  for (mountpoint, failed) in ended_rx.iter() {
+    if failed {
+      eprintln!("[{}]: mount failed, exiting", mountpoint);
+      std::process::exit(1);
+    }
+  }
 }
@@ -12,6 +12,9 @@ use std::io;
 // see: https://developers.google.com/identity/protocols/googlescopes
 const DRIVE_SCOPE: &'static str = "https://www.googleapis.com/auth/drive";
 
+// needed for the activity log, only requested with --activity-log.
+const ACTIVITY_SCOPE: &'static str = "https://www.googleapis.com/auth/drive.activity.readonly";
+
 // the auth URI used for the 'application' auth flow.
 // see: https://developers.google.com/identity/protocols/OAuth2InstalledApp#choosingredirecturi
 const OOB_AUTH_URI: &'static str = "urn:ietf:wg:oauth:2.0:oob";
@@ -20,7 +23,7 @@ const USAGE: &'static str = "
 init_token: fetch and store an oauth2 token for gdrivefs.
 
 Usage:
-  init_token [--client-id-file=<id_file>] [--client-secret-file=<secret_file>] [--token-file=<token_file>]  [--port=<port>]
+  init_token [--client-id-file=<id_file>] [--client-secret-file=<secret_file>] [--token-file=<token_file>]  [--port=<port>] [--activity-log]
   init_token (-h | --help)
 
 Options:
@@ -28,6 +31,7 @@ Options:
   --client-id-file=<id_file>          File containing a client id [default: /usr/local/etc/gdrive_id]
   --client-secret-file=<secret_file>  File containing a client secret. [default: /usr/local/etc/gdrive_secret]
   --token-file=<token_file>           Token output file. [default: /etc/gdrive_token]
+  --activity-log                      If true, also grant read access to the drive activity, for gdrivefs --activity-log.
 ";
 
 #[derive(Debug, Deserialize)]
@@ -35,6 +39,7 @@ struct Args {
   flag_client_id_file: String,
   flag_client_secret_file: String,
   flag_token_file: String,
+  flag_activity_log: bool,
 }
 
 fn main() {
@@ -56,8 +61,13 @@ fn main() {
 
   println!("Please visit the following URL to grant the required permissions");
   println!("Then paste the returned code below.");
+  let scope = if args.flag_activity_log {
+    format!("{} {}", DRIVE_SCOPE, ACTIVITY_SCOPE)
+  } else {
+    String::from(DRIVE_SCOPE)
+  };
   let auth_uri = client
-    .auth_uri(Some(DRIVE_SCOPE), None)
+    .auth_uri(Some(&scope), None)
     .expect("Error while constructing URI");
   println!("{}", auth_uri);
   println!("Code: ");
@@ -0,0 +1,145 @@
+extern crate google_drive3;
+extern crate hyper;
+extern crate md5;
+extern crate serde_json;
+
+use self::serde_json::Value;
+use common;
+use drive_error;
+use google_docs;
+use listing;
+use oauth;
+use oauth::GetToken;
+use std::error::Error;
+use std::io::{Read, Write};
+
+const FILE_GET_URL: &'static str = "https://www.googleapis.com/drive/v3/files";
+const FOLDER_MIME_TYPE: &'static str = "application/vnd.google-apps.folder";
+const MD5_FIELDS: &'static str = "id,name,mimeType,md5Checksum";
+
+/// Fetches the checksum `field` (e.g. "sha256Checksum") of `file_id`. Only
+/// binary files have checksums, so `None` is returned for anything else.
+pub fn get_checksum(
+  auth: &mut oauth::GoogleAuthenticator,
+  file_id: &str,
+  field: &str,
+) -> Result<Option<String>, Box<Error>> {
+  // these fields are newer than the drive3 crate's File, so are read raw.
+  let url = format!(
+    "{}/{}?fields={}&supportsTeamDrives=true",
+    FILE_GET_URL, file_id, field
+  );
+  let token = auth.api_key().unwrap();
+  let mut resp = try!(common::new_hyper_tls_client()
+    .get(&url)
+    .header(hyper::header::Authorization(hyper::header::Bearer {
+      token: token,
+    }))
+    .send());
+  if !resp.status.is_success() {
+    let mut body: String = String::new();
+    try!(resp.read_to_string(&mut body));
+    let err = drive_error::DriveError::parse(resp.status, &body);
+    warn!("Checksum get error: {}", err);
+    return Err(Box::new(err));
+  }
+  let result: Value = try!(serde_json::from_reader(&mut resp));
+  Ok(result[field].as_str().map(String::from))
+}
+
+/// Writes the md5 checksums of the files at `drive_path`, relative to the
+/// root of the drive, and everything under it if it's a folder, to `out` in
+/// the format of md5sum, so that a local copy can be checked with md5sum -c.
+/// The checksums are the ones drive keeps, so nothing is downloaded, except
+/// for Google Docs and other native items with a format in `export_formats`:
+/// those are exported, and the checksum of the export is written under the
+/// name with the extension of the format, as a mount shows them. Items
+/// without a checksum or export format, or whose export fails, are left out;
+/// their number is returned.
+pub fn write_md5sums<W: Write>(
+  auth: &mut oauth::GoogleAuthenticator,
+  drive_path: &str,
+  export_formats: &[google_docs::ExportFormat],
+  out: &mut W,
+) -> Result<u64, Box<Error>> {
+  let file = try!(listing::find_by_path(auth, drive_path, MD5_FIELDS));
+  let path = drive_path.trim_matches('/');
+  let mut skipped = 0;
+  try!(write_md5sums_under(
+    auth,
+    file,
+    path,
+    export_formats,
+    out,
+    &mut skipped
+  ));
+  Ok(skipped)
+}
+
+// writes the md5 checksum of |file|, shown as |path|, or of the files under
+// it if it's a folder.
+fn write_md5sums_under<W: Write>(
+  auth: &mut oauth::GoogleAuthenticator,
+  file: google_drive3::File,
+  path: &str,
+  export_formats: &[google_docs::ExportFormat],
+  out: &mut W,
+  skipped: &mut u64,
+) -> Result<(), Box<Error>> {
+  if file.mime_type.as_ref().map(String::as_str) == Some(FOLDER_MIME_TYPE) {
+    let file_id = file.id.unwrap_or_default();
+    let mut children = try!(listing::list_children(auth, &file_id, MD5_FIELDS));
+    children.sort_by(|a, b| a.name.cmp(&b.name));
+    for child in children {
+      let name = child.name.clone().unwrap_or_default();
+      let child_path = if path.is_empty() {
+        name
+      } else {
+        format!("{}/{}", path, name)
+      };
+      try!(write_md5sums_under(
+        auth,
+        child,
+        &child_path,
+        export_formats,
+        out,
+        skipped
+      ));
+    }
+    return Ok(());
+  }
+  let export_format = export_formats
+    .iter()
+    .find(|format| file.mime_type.as_ref() == Some(&format.google_mime_type));
+  let (md5, path) = match (file.md5_checksum, export_format) {
+    (Some(md5), _) => (Some(md5), path.to_string()),
+    (None, Some(format)) => {
+      let file_id = file.id.unwrap_or_default();
+      let path = format!("{}.{}", path, format.extension);
+      match google_docs::export(auth, &file_id, &format.mime_type) {
+        Ok(content) => (Some(format!("{:x}", md5::compute(&content))), path),
+        Err(err) => {
+          warn!("hash: exporting {} failed: {}", path, err);
+          (None, path)
+        }
+      }
+    }
+    (None, None) => (None, path.to_string()),
+  };
+  match md5 {
+    // like md5sum, names with a \ or newline are escaped and the line is
+    // marked with a leading \.
+    Some(ref md5) if path.contains('\\') || path.contains('\n') => try!(writeln!(
+      out,
+      "\\{}  {}",
+      md5,
+      path.replace('\\', "\\\\").replace('\n', "\\n")
+    )),
+    Some(ref md5) => try!(writeln!(out, "{}  {}", md5, path)),
+    None => {
+      warn!("hash: leaving out {}, which has no checksum", path);
+      *skipped += 1;
+    }
+  }
+  Ok(())
+}
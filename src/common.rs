@@ -6,6 +6,19 @@ use std;
 use std::io::Read;
 use std::io::Write;
 use std::os::unix::fs::OpenOptionsExt;
+use std::sync::atomic;
+
+// idle connections kept per host by default. The read threads and directory
+// listings mostly talk to the same host.
+const DEFAULT_MAX_IDLE_CONNECTIONS: usize = 16;
+
+// seconds without progress after which http requests fail, or 0 for never.
+static HTTP_TIMEOUT_SECS: atomic::AtomicUsize = atomic::ATOMIC_USIZE_INIT;
+// idle connections kept per host, or 0 for the default.
+static MAX_IDLE_CONNECTIONS: atomic::AtomicUsize = atomic::ATOMIC_USIZE_INIT;
+// seconds after which an idle connection is closed rather than reused, or 0
+// to reuse connections however long they were idle.
+static KEEPALIVE_IDLE_SECS: atomic::AtomicUsize = atomic::ATOMIC_USIZE_INIT;
 
 /// get_contents is a convenience function for reading a file to a string.
 pub fn get_contents(path: &str) -> std::io::Result<String> {
@@ -31,8 +44,127 @@ pub fn set_contents(path: &str, contents: &[u8], mode: libc::mode_t) -> std::io:
   Ok(())
 }
 
+/// parse_ini parses the text of an ini file into its sections, each a name
+/// and its `key = value` settings, in order. Lines starting with # or ; are
+/// comments.
+pub fn parse_ini(text: &str) -> Vec<(String, Vec<(String, String)>)> {
+  let mut sections: Vec<(String, Vec<(String, String)>)> = Vec::new();
+  for line in text.lines() {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+      continue;
+    }
+    if line.starts_with('[') && line.ends_with(']') {
+      sections.push((line[1..line.len() - 1].trim().into(), Vec::new()));
+      continue;
+    }
+    if let (Some(&mut (_, ref mut settings)), Some(equals)) = (sections.last_mut(), line.find('='))
+    {
+      settings.push((
+        line[..equals].trim().into(),
+        line[equals + 1..].trim().into(),
+      ));
+    }
+  }
+  sections
+}
+
+/// ini_setting returns the value of the last setting named `key` in
+/// `settings`, a section returned by parse_ini.
+pub fn ini_setting<'a>(settings: &'a [(String, String)], key: &str) -> Option<&'a str> {
+  settings
+    .iter()
+    .rev()
+    .find(|&&(ref name, _)| name == key)
+    .map(|&(_, ref value)| value.as_str())
+}
+
+/// set_http_timeout makes http requests from clients created afterwards fail
+/// when no data is sent or received for `timeout`.
+pub fn set_http_timeout(timeout: Option<std::time::Duration>) {
+  let secs = timeout.map(|timeout| timeout.as_secs() as usize).unwrap_or(0);
+  HTTP_TIMEOUT_SECS.store(secs, atomic::Ordering::SeqCst);
+}
+
+/// set_connection_pool sets the number of idle connections kept per host
+/// and how long they are kept, with `max_idle` 0 for the default and
+/// `keepalive_idle` None to keep them until the server closes them. It must
+/// be called before the first request is made.
+pub fn set_connection_pool(max_idle: usize, keepalive_idle: Option<std::time::Duration>) {
+  let secs = keepalive_idle
+    .map(|idle| idle.as_secs() as usize)
+    .unwrap_or(0);
+  MAX_IDLE_CONNECTIONS.store(max_idle, atomic::Ordering::SeqCst);
+  KEEPALIVE_IDLE_SECS.store(secs, atomic::Ordering::SeqCst);
+}
+
+type TlsConnector = hyper::net::HttpsConnector<hyper_rustls::TlsClient>;
+
+lazy_static! {
+  // TLS settings shared by all connections, pooled or not, so that they
+  // share one session cache: a new connection to a host seen before resumes
+  // the session with its ticket instead of doing a full handshake, which
+  // matters when bursts of small chunk fetches open connections in parallel.
+  static ref TLS_CLIENT: hyper_rustls::TlsClient = hyper_rustls::TlsClient::new();
+
+  // idle connections shared by all clients, so that sockets and TLS
+  // sessions outlive the clients that opened them.
+  static ref CONNECTION_POOL: hyper::client::pool::Pool<TlsConnector> = {
+    let max_idle = match MAX_IDLE_CONNECTIONS.load(atomic::Ordering::SeqCst) {
+      0 => DEFAULT_MAX_IDLE_CONNECTIONS,
+      max_idle => max_idle,
+    };
+    let mut pool = hyper::client::pool::Pool::with_connector(
+      hyper::client::pool::Config { max_idle: max_idle },
+      new_tls_connector(),
+    );
+    // the server drops connections idle for a while; reusing one of those
+    // fails the request, so they are closed on our side first.
+    pool.set_idle_timeout(match KEEPALIVE_IDLE_SECS.load(atomic::Ordering::SeqCst) {
+      0 => None,
+      secs => Some(std::time::Duration::from_secs(secs as u64)),
+    });
+    pool
+  };
+}
+
+// a connector handing out connections from CONNECTION_POOL.
+struct SharedPool;
+
+impl hyper::net::NetworkConnector for SharedPool {
+  type Stream =
+    hyper::client::pool::PooledStream<<TlsConnector as hyper::net::NetworkConnector>::Stream>;
+
+  fn connect(&self, host: &str, port: u16, scheme: &str) -> hyper::Result<Self::Stream> {
+    CONNECTION_POOL.connect(host, port, scheme)
+  }
+}
+
+/// new_hyper_tls_client creates an https client. Clients are cheap: they all
+/// take connections from a shared pool.
 pub fn new_hyper_tls_client() -> hyper::Client {
-  hyper::Client::with_connector(hyper::net::HttpsConnector::new(
-    hyper_rustls::TlsClient::new(),
-  ))
+  with_timeouts(hyper::Client::with_connector(SharedPool))
+}
+
+/// new_unpooled_hyper_tls_client creates an https client opening a new
+/// connection for each request. TLS sessions are still resumed.
+pub fn new_unpooled_hyper_tls_client() -> hyper::Client {
+  with_timeouts(hyper::Client::with_connector(new_tls_connector()))
+}
+
+fn new_tls_connector() -> TlsConnector {
+  hyper::net::HttpsConnector::new(hyper_rustls::TlsClient {
+    cfg: TLS_CLIENT.cfg.clone(),
+  })
+}
+
+// applies the timeout set by set_http_timeout to |client|.
+fn with_timeouts(mut client: hyper::Client) -> hyper::Client {
+  let timeout = match HTTP_TIMEOUT_SECS.load(atomic::Ordering::SeqCst) {
+    0 => None,
+    secs => Some(std::time::Duration::from_secs(secs as u64)),
+  };
+  client.set_read_timeout(timeout);
+  client.set_write_timeout(timeout);
+  client
 }
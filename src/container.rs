@@ -0,0 +1,224 @@
+// Locating the index of video containers, which players read right after the
+// header and which is often at the end of the file.
+
+const MKV_MAGIC: [u8; 4] = [0x1a, 0x45, 0xdf, 0xa3];
+const MKV_SEGMENT_ID: u64 = 0x1853_8067;
+const MKV_SEEK_HEAD_ID: u64 = 0x114d_9b74;
+const MKV_SEEK_ID: u64 = 0x4dbb;
+const MKV_SEEK_ID_ID: u64 = 0x53ab;
+const MKV_SEEK_POSITION_ID: u64 = 0x53ac;
+const MKV_CUES_ID: [u8; 4] = [0x1c, 0x53, 0xbb, 0x6b];
+
+/// Finds the offset of the index of an mp4 (moov atom) or mkv (cues) file
+/// from `head`, the start of the file, if the index isn't within `head`.
+pub fn index_offset(head: &[u8]) -> Option<u64> {
+  if head.starts_with(&MKV_MAGIC) {
+    mkv_cues_offset(head)
+  } else if head.len() >= 8 && &head[4..8] == b"ftyp" {
+    mp4_moov_offset(head)
+  } else {
+    None
+  }
+}
+
+fn read_be(bytes: &[u8]) -> u64 {
+  bytes
+    .iter()
+    .fold(0, |value, &byte| (value << 8) | byte as u64)
+}
+
+// walks the top level atoms. The moov atom usually follows mdat when it
+// isn't at the start, so the first atom starting past |head| is taken to be
+// the moov atom.
+fn mp4_moov_offset(head: &[u8]) -> Option<u64> {
+  let len = head.len() as u64;
+  let mut pos: u64 = 0;
+  // a 64-bit atom size can put |pos| anywhere up to the largest offset.
+  while pos < len && len - pos >= 8 {
+    let atom = &head[pos as usize..];
+    let size = match read_be(&atom[..4]) {
+      // the atom extends to the end of the file.
+      0 => return None,
+      1 if atom.len() >= 16 => read_be(&atom[8..16]),
+      1 => return None,
+      size => size,
+    };
+    if &atom[4..8] == b"moov" || size < 8 {
+      return None;
+    }
+    pos = match pos.checked_add(size) {
+      Some(pos) => pos,
+      None => return None,
+    };
+  }
+  if pos > len {
+    Some(pos)
+  } else {
+    None
+  }
+}
+
+// reads an EBML variable length integer from the start of |bytes|, keeping
+// the length marker for element ids. Returns the value and its length.
+fn read_vint(bytes: &[u8], keep_marker: bool) -> Option<(u64, usize)> {
+  let first = match bytes.first() {
+    Some(&first) if first != 0 => first,
+    _ => return None,
+  };
+  let len = first.leading_zeros() as usize + 1;
+  if bytes.len() < len {
+    return None;
+  }
+  let mut value = read_be(&bytes[..len]);
+  if !keep_marker {
+    // the marker is the highest set bit.
+    value &= (1u64 << (7 * len)) - 1;
+  }
+  Some((value, len))
+}
+
+// reads the id and data size of the EBML element at the start of |bytes|.
+// Returns them and the length of the element header.
+fn read_element(bytes: &[u8]) -> Option<(u64, u64, usize)> {
+  let (id, id_len) = match read_vint(bytes, true) {
+    Some(id) => id,
+    None => return None,
+  };
+  match read_vint(&bytes[id_len..], false) {
+    Some((size, size_len)) => Some((id, size, id_len + size_len)),
+    None => None,
+  }
+}
+
+// finds the cues through the seek head at the start of the segment.
+fn mkv_cues_offset(head: &[u8]) -> Option<u64> {
+  // skip the EBML header.
+  let (_, size, header_len) = match read_element(head) {
+    Some(element) => element,
+    None => return None,
+  };
+  let segment = header_len as u64 + size;
+  if segment >= head.len() as u64 {
+    return None;
+  }
+  let segment = &head[segment as usize..];
+  let segment_start = (head.len() - segment.len()) as u64;
+  let (id, _, header_len) = match read_element(segment) {
+    Some(element) => element,
+    None => return None,
+  };
+  if id != MKV_SEGMENT_ID {
+    return None;
+  }
+  // seek positions are relative to the start of the segment data.
+  let data_start = segment_start + header_len as u64;
+  let mut children = &segment[header_len..];
+  while let Some((id, size, header_len)) = read_element(children) {
+    if id == read_be(&MKV_CUES_ID) || header_len as u64 + size > children.len() as u64 {
+      return None;
+    }
+    let data = &children[header_len..header_len + size as usize];
+    if id == MKV_SEEK_HEAD_ID {
+      // a position past the largest offset can only come from a corrupt file.
+      return mkv_seek_head_cues(data).and_then(|position| data_start.checked_add(position));
+    }
+    children = &children[header_len + size as usize..];
+  }
+  None
+}
+
+// finds the position of the cues in the seek head |seek_head|.
+fn mkv_seek_head_cues(mut seek_head: &[u8]) -> Option<u64> {
+  while let Some((id, size, header_len)) = read_element(seek_head) {
+    if header_len as u64 + size > seek_head.len() as u64 {
+      return None;
+    }
+    let mut seek = &seek_head[header_len..header_len + size as usize];
+    seek_head = &seek_head[header_len + size as usize..];
+    if id != MKV_SEEK_ID {
+      continue;
+    }
+    let mut target = None;
+    let mut position = None;
+    while let Some((id, size, header_len)) = read_element(seek) {
+      if header_len as u64 + size > seek.len() as u64 {
+        return None;
+      }
+      let value = &seek[header_len..header_len + size as usize];
+      match id {
+        MKV_SEEK_ID_ID => target = Some(value),
+        MKV_SEEK_POSITION_ID => position = Some(read_be(value)),
+        _ => {}
+      }
+      seek = &seek[header_len + size as usize..];
+    }
+    if target == Some(&MKV_CUES_ID[..]) {
+      return position;
+    }
+  }
+  None
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // the start of an mkv file whose seek head puts the cues at |position|.
+  fn mkv_head(position: u64) -> Vec<u8> {
+    let mut head = vec![0x1a, 0x45, 0xdf, 0xa3, 0x80];
+    // a segment of unknown size.
+    head.extend_from_slice(&[
+      0x18, 0x53, 0x80, 0x67, 0x01, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    ]);
+    head.extend_from_slice(&[0x11, 0x4d, 0x9b, 0x74, 0x95, 0x4d, 0xbb, 0x92]);
+    head.extend_from_slice(&[0x53, 0xab, 0x84]);
+    head.extend_from_slice(&MKV_CUES_ID);
+    head.extend_from_slice(&[0x53, 0xac, 0x88]);
+    head.extend((0..8).rev().map(|shift| (position >> (8 * shift)) as u8));
+    head
+  }
+
+  // the start of an mp4 file with an ftyp atom of 64-bit size |size|,
+  // followed by the start of the next atom.
+  fn mp4_head(size: u64) -> Vec<u8> {
+    let mut head = vec![0, 0, 0, 1];
+    head.extend_from_slice(b"ftyp");
+    head.extend((0..8).rev().map(|shift| (size >> (8 * shift)) as u8));
+    head.extend_from_slice(b"isom\0\0\0\0mdat");
+    head
+  }
+
+  #[test]
+  fn mp4_moov_offset_follows_atom_sizes() {
+    assert_eq!(index_offset(&mp4_head(0x10000)), Some(0x10000));
+    // the next atom is within the head, but its size is cut off.
+    assert_eq!(index_offset(&mp4_head(24)), None);
+  }
+
+  #[test]
+  fn mp4_moov_offset_near_largest_offset() {
+    assert_eq!(
+      index_offset(&mp4_head(0xffff_ffff_ffff_fff8)),
+      Some(0xffff_ffff_ffff_fff8)
+    );
+    assert_eq!(
+      index_offset(&mp4_head(u64::max_value())),
+      Some(u64::max_value())
+    );
+  }
+
+  #[test]
+  fn mkv_cues_offset_is_relative_to_segment_data() {
+    assert_eq!(index_offset(&mkv_head(0x10000)), Some(17 + 0x10000));
+  }
+
+  #[test]
+  fn mkv_cues_offset_past_largest_offset() {
+    assert_eq!(index_offset(&mkv_head(u64::max_value())), None);
+    assert_eq!(index_offset(&mkv_head(u64::max_value() - 16)), None);
+    assert_eq!(
+      index_offset(&mkv_head(u64::max_value() - 17)),
+      Some(u64::max_value())
+    );
+  }
+}
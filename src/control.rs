@@ -0,0 +1,334 @@
+use constants;
+use error_rates;
+use http::FileReadOptions;
+use read_policy;
+use std::error::Error;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync;
+use std::thread;
+
+/// Restores the file at a path within the mount to the revision with the
+/// given id.
+pub type RestoreFn = Box<Fn(&str, &str) -> Result<(), Box<Error>> + Send>;
+
+/// Describes the metadata kept in memory by the mount.
+pub type StatusFn = Box<Fn() -> String + Send>;
+
+/// Binds the unix socket the control commands are sent to at `path`. The
+/// socket is only accessible by the current user. A socket left at `path` is
+/// replaced, but any other file there is an error.
+pub fn bind(path: &str) -> io::Result<UnixListener> {
+  // a socket left behind by a previous run is replaced, but nothing else.
+  match std::fs::symlink_metadata(path) {
+    Ok(ref metadata) if !metadata.file_type().is_socket() => {
+      return Err(io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        format!("{} exists and isn't a socket", path),
+      ));
+    }
+    _ => (),
+  }
+  // the socket is bound in a directory only the user can enter and moved
+  // into place once private, so nobody can connect in between.
+  let bind_dir = format!("{}.{}.tmp", path, std::process::id());
+  try!(std::fs::DirBuilder::new().mode(0o700).create(&bind_dir));
+  let bind_path = format!("{}/socket", bind_dir);
+  let listener = UnixListener::bind(&bind_path).and_then(|listener| {
+    try!(std::fs::set_permissions(
+      &bind_path,
+      std::fs::Permissions::from_mode(0o600)
+    ));
+    try!(std::fs::rename(&bind_path, path));
+    Ok(listener)
+  });
+  let _ = std::fs::remove_file(&bind_path);
+  let _ = std::fs::remove_dir(&bind_dir);
+  listener
+}
+
+/// Serves the commands sent to `listener`, see bind, from a background
+/// thread. The commands change `options`, one command per connection:
+///
+/// - `show` replies with the current settings.
+/// - `status` replies with the counts of reads and failed requests over the
+///   error reporting window, see error_rates::describe, followed by the
+///   description of the metadata in memory from `status`, why the mount
+///   turned read-only if it did, and how often the changes sync started
+///   over.
+/// - `tune <setting>...` changes the settings given, where each is one of
+///   `readahead=<chunks>`, `chunk=<bytes>[K|M|G]`, `cache=<chunks>` or
+///   `max-read-handles=<n>`, then replies with the resulting settings.
+///   Changes only affect files opened afterwards.
+/// - `restore <path> <revision id>` makes the revision current for the file
+///   at `path` within the mount, through `restore`.
+///
+/// Replies start with `ok ` or `error: `.
+pub fn start_server(
+  listener: UnixListener,
+  options: sync::Arc<sync::RwLock<FileReadOptions>>,
+  restore: RestoreFn,
+  status: StatusFn,
+) -> io::Result<()> {
+  thread::Builder::new()
+    .name(String::from("control_socket"))
+    .spawn(move || {
+      for stream in listener.incoming() {
+        match stream {
+          Ok(stream) => {
+            if let Err(err) = serve(stream, &options, &restore, &status) {
+              warn!("control socket error: {}", err);
+            }
+          }
+          Err(err) => warn!("control socket accept error: {}", err),
+        }
+      }
+    })
+    .map(|_| ())
+}
+
+// answers the command sent on |stream|.
+fn serve(
+  stream: UnixStream,
+  options: &sync::RwLock<FileReadOptions>,
+  restore: &RestoreFn,
+  status: &StatusFn,
+) -> io::Result<()> {
+  let mut command = String::new();
+  try!(BufReader::new(&stream).read_line(&mut command));
+  let mut words = command.split_whitespace();
+  let result = match words.next() {
+    Some("show") => Ok(describe(&options.read().unwrap())),
+    Some("status") => Ok(format!("{} {}", error_rates::describe(), status())),
+    Some("tune") => {
+      // the settings are described under the same write lock, since taking
+      // the read lock while it's held would deadlock.
+      let mut options = options.write().unwrap();
+      tune(&mut options, words).map(|()| describe(&options))
+    }
+    Some("restore") => {
+      // the revision id is the last word, and the path may contain spaces.
+      let args = command.trim()["restore".len()..].trim();
+      match args.rfind(' ') {
+        Some(space) => {
+          let (path, revision_id) = (args[..space].trim(), &args[space + 1..]);
+          restore(path, revision_id)
+            .map(|()| format!("restored {} to revision {}", path, revision_id))
+        }
+        None => Err(From::from("usage: restore <path> <revision id>")),
+      }
+    }
+    _ => Err(From::from(format!("unknown command: {}", command.trim()))),
+  };
+  let reply = match result {
+    Ok(message) => format!("ok {}\n", message),
+    Err(err) => format!("error: {}\n", err),
+  };
+  (&stream).write_all(reply.as_bytes())
+}
+
+/// Applies the `<key>=<value>` `settings` to `options`, as the tune command
+/// does, leaving it unchanged if any of them is invalid.
+pub fn tune<'a, I: Iterator<Item = &'a str>>(
+  options: &mut FileReadOptions,
+  settings: I,
+) -> Result<(), Box<Error>> {
+  let mut tuned = options.clone();
+  for setting in settings {
+    let mut parts = setting.splitn(2, '=');
+    match (parts.next(), parts.next()) {
+      (Some("readahead"), Some(value)) => tuned.readahead_queue_size = try!(value.parse()),
+      (Some("chunk"), Some(value)) => {
+        tuned.read_block_multiplier = try!(read_policy::parse_chunk_size(value))
+      }
+      (Some("cache"), Some(value)) => tuned.file_read_cache_blocks = try!(value.parse()),
+      (Some("max-read-handles"), Some(value)) => tuned.max_read_handles = try!(value.parse()),
+      _ => return Err(From::from(format!("invalid setting: {}", setting))),
+    }
+  }
+  info!("control socket: tuned read options to {}", describe(&tuned));
+  *options = tuned;
+  Ok(())
+}
+
+// formats the tunable settings of |options| as accepted by tune.
+fn describe(options: &FileReadOptions) -> String {
+  format!(
+    "readahead={} chunk={}K cache={} max-read-handles={}",
+    options.readahead_queue_size,
+    options.read_block_multiplier as u64 * constants::BLOCK_SIZE as u64 / 1024,
+    options.file_read_cache_blocks,
+    options.max_read_handles
+  )
+}
+
+/// Sends `command` to the control socket at `path` and returns the reply.
+pub fn send_command(path: &str, command: &str) -> io::Result<String> {
+  let stream = try!(UnixStream::connect(path));
+  try!((&stream).write_all(format!("{}\n", command).as_bytes()));
+  let mut reply = String::new();
+  try!(BufReader::new(&stream).read_line(&mut reply));
+  Ok(reply)
+}
+
+/// Quotes `value` for a `<key>="<value>"` field of the `status` reply, see
+/// parse_status.
+pub fn quote(value: &str) -> String {
+  let mut quoted = String::from("\"");
+  for c in value.chars() {
+    match c {
+      '"' | '\\' => {
+        quoted.push('\\');
+        quoted.push(c);
+      }
+      '\n' => quoted.push_str("\\n"),
+      _ => quoted.push(c),
+    }
+  }
+  quoted.push('"');
+  quoted
+}
+
+/// Splits the reply to `status`, without its leading `ok`, into its
+/// `<key>=<value>` fields, unquoting the values quoted with quote.
+pub fn parse_status(reply: &str) -> Vec<(String, String)> {
+  let mut fields = Vec::new();
+  let mut chars = reply.trim().chars().peekable();
+  loop {
+    while chars.peek() == Some(&' ') {
+      chars.next();
+    }
+    let key: String = chars.by_ref().take_while(|&c| c != '=').collect();
+    if key.is_empty() {
+      return fields;
+    }
+    let mut value = String::new();
+    if chars.peek() == Some(&'"') {
+      chars.next();
+      while let Some(c) = chars.next() {
+        match c {
+          '"' => break,
+          '\\' => match chars.next() {
+            Some('n') => value.push('\n'),
+            Some(escaped) => value.push(escaped),
+            None => break,
+          },
+          _ => value.push(c),
+        }
+      }
+    } else {
+      value = chars.by_ref().take_while(|&c| c != ' ').collect();
+    }
+    fields.push((key, value));
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use http::UncachedReadAction;
+
+  fn options() -> FileReadOptions {
+    FileReadOptions {
+      readahead_queue_size: 2,
+      file_read_cache_blocks: 10,
+      read_block_multiplier: 256,
+      max_uncached_file_size: None,
+      max_concurrent_downloads: 0,
+      max_readahead_files: 0,
+      overload_backlog: 0,
+      read_deadline: None,
+      max_read_handles: 20,
+      read_policies: Vec::new(),
+      auto_tune: false,
+      probe_containers: false,
+      read_retries: 0,
+      uncached_read_action: UncachedReadAction::Eio,
+      prefetch_on_open: false,
+      early_reply: false,
+    }
+  }
+
+  // sends |command| to serve through a socket pair and returns the reply.
+  fn command(options: &sync::RwLock<FileReadOptions>, command: &str) -> String {
+    let (client, server) = UnixStream::pair().unwrap();
+    (&client)
+      .write_all(format!("{}\n", command).as_bytes())
+      .unwrap();
+    let restore: RestoreFn = Box::new(|_, _| Err(From::from("no restore")));
+    let status: StatusFn = Box::new(|| String::from("items=0"));
+    serve(server, options, &restore, &status).unwrap();
+    let mut reply = String::new();
+    BufReader::new(&client).read_line(&mut reply).unwrap();
+    reply
+  }
+
+  #[test]
+  fn parse_status_unquotes_values() {
+    let reason = String::from("upload of \"a b\" failed:\nquota");
+    let reply = format!("window=5m failed=2 read-only={}\n", quote(&reason));
+    assert_eq!(
+      parse_status(&reply),
+      vec![
+        (String::from("window"), String::from("5m")),
+        (String::from("failed"), String::from("2")),
+        (String::from("read-only"), reason),
+      ]
+    );
+    assert!(parse_status(" \n").is_empty());
+  }
+
+  #[test]
+  fn tune_applies_settings() {
+    let mut tuned = options();
+    tune(
+      &mut tuned,
+      "readahead=4 chunk=2M cache=30 max-read-handles=5".split_whitespace(),
+    )
+    .unwrap();
+    assert_eq!(tuned.readahead_queue_size, 4);
+    assert_eq!(tuned.read_block_multiplier, 512);
+    assert_eq!(tuned.file_read_cache_blocks, 30);
+    assert_eq!(tuned.max_read_handles, 5);
+  }
+
+  #[test]
+  fn tune_leaves_options_unchanged_on_error() {
+    let mut tuned = options();
+    assert!(tune(&mut tuned, "readahead=4 cache=many".split_whitespace()).is_err());
+    assert!(tune(&mut tuned, "speed=fast".split_whitespace()).is_err());
+    assert_eq!(tuned.readahead_queue_size, 2);
+    assert_eq!(tuned.file_read_cache_blocks, 10);
+  }
+
+  #[test]
+  fn serve_tunes_and_shows_options() {
+    let options = sync::RwLock::new(options());
+    assert_eq!(
+      command(&options, "tune readahead=4 cache=30"),
+      "ok readahead=4 chunk=1024K cache=30 max-read-handles=20\n"
+    );
+    assert_eq!(
+      command(&options, "show"),
+      "ok readahead=4 chunk=1024K cache=30 max-read-handles=20\n"
+    );
+    assert!(command(&options, "tune cache=many").starts_with("error: "));
+    // a failed command doesn't poison the options for the mount.
+    assert!(options.read().is_ok());
+  }
+
+  #[test]
+  fn serve_rejects_unknown_commands() {
+    let options = sync::RwLock::new(options());
+    assert_eq!(
+      command(&options, "reboot now"),
+      "error: unknown command: reboot now\n"
+    );
+    assert_eq!(
+      command(&options, "restore onlypath"),
+      "error: usage: restore <path> <revision id>\n"
+    );
+  }
+}
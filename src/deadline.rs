@@ -0,0 +1,132 @@
+extern crate fuse;
+extern crate libc;
+
+use std::cmp;
+use std::collections::BinaryHeap;
+use std::sync;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A fuse read reply shared between the thread serving the read and the
+/// `DeadlineWatcher`; whichever takes it first sends the reply.
+#[derive(Clone)]
+pub struct PendingReply {
+  reply: sync::Arc<sync::Mutex<Option<fuse::ReplyData>>>,
+}
+
+impl PendingReply {
+  pub fn new(reply: fuse::ReplyData) -> PendingReply {
+    PendingReply {
+      reply: sync::Arc::new(sync::Mutex::new(Some(reply))),
+    }
+  }
+
+  /// Takes the reply, or returns `None` if it was already sent.
+  pub fn take(&self) -> Option<fuse::ReplyData> {
+    self.reply.lock().unwrap().take()
+  }
+
+  /// Whether the reply was already sent, e.g. failed at its deadline.
+  pub fn is_sent(&self) -> bool {
+    self.reply.lock().unwrap().is_none()
+  }
+}
+
+// a reply waiting for its deadline, ordered so the earliest is the greatest.
+struct Deadline {
+  at: Instant,
+  reply: PendingReply,
+}
+
+impl PartialEq for Deadline {
+  fn eq(&self, other: &Deadline) -> bool {
+    self.at == other.at
+  }
+}
+
+impl Eq for Deadline {}
+
+impl PartialOrd for Deadline {
+  fn partial_cmp(&self, other: &Deadline) -> Option<cmp::Ordering> {
+    Some(self.cmp(other))
+  }
+}
+
+impl Ord for Deadline {
+  fn cmp(&self, other: &Deadline) -> cmp::Ordering {
+    other.at.cmp(&self.at)
+  }
+}
+
+/// Fails reads that haven't been replied to within a deadline with EIO, so a
+/// stalled download doesn't leave the reading process stuck indefinitely.
+pub struct DeadlineWatcher {
+  deadline: Duration,
+  // sends to the thread failing reads, once started by the first read.
+  sender: sync::Mutex<Option<sync::mpsc::Sender<Deadline>>>,
+}
+
+impl DeadlineWatcher {
+  /// Fails reads after `deadline`, from a background thread started with
+  /// the first read, so none runs before the filesystem is mounted and
+  /// sandboxed.
+  pub fn new(deadline: Duration) -> DeadlineWatcher {
+    DeadlineWatcher {
+      deadline: deadline,
+      sender: sync::Mutex::new(None),
+    }
+  }
+
+  // starts the thread failing the reads sent to the returned sender.
+  fn spawn() -> sync::mpsc::Sender<Deadline> {
+    let (tx, rx) = sync::mpsc::channel::<Deadline>();
+    thread::Builder::new()
+      .name(String::from("read_deadlines"))
+      .spawn(move || {
+        let mut deadlines: BinaryHeap<Deadline> = BinaryHeap::new();
+        loop {
+          let received = match deadlines.peek().map(|next| next.at) {
+            Some(at) => {
+              let now = Instant::now();
+              if at <= now {
+                let expired = deadlines.pop().unwrap();
+                if let Some(reply) = expired.reply.take() {
+                  warn!("read missed its deadline, failing it");
+                  reply.error(libc::EIO);
+                }
+                continue;
+              }
+              match rx.recv_timeout(at - now) {
+                Ok(deadline) => Some(deadline),
+                Err(sync::mpsc::RecvTimeoutError::Timeout) => None,
+                Err(sync::mpsc::RecvTimeoutError::Disconnected) => return,
+              }
+            }
+            None => match rx.recv() {
+              Ok(deadline) => Some(deadline),
+              Err(_) => return,
+            },
+          };
+          if let Some(deadline) = received {
+            deadlines.push(deadline);
+          }
+        }
+      })
+      .unwrap();
+    tx
+  }
+
+  /// Wraps `reply` so it's failed if not sent before the deadline.
+  pub fn watch(&self, reply: fuse::ReplyData) -> PendingReply {
+    let pending = PendingReply::new(reply);
+    let mut sender = self.sender.lock().unwrap();
+    if sender.is_none() {
+      *sender = Some(DeadlineWatcher::spawn());
+    }
+    let _ = sender.as_ref().unwrap().send(Deadline {
+      at: Instant::now() + self.deadline,
+      reply: pending.clone(),
+    });
+    pending
+  }
+}
@@ -0,0 +1,580 @@
+extern crate libc;
+extern crate md5;
+
+use std;
+use std::collections::{HashMap, HashSet};
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync;
+use std::sync::atomic;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+// chunks are written under this name first, then renamed into place, so
+// that a chunk is never read half written.
+const PARTIAL_SUFFIX: &'static str = ".partial";
+// chunks of files in pinned folders are kept under their name followed by
+// this, so that they stay pinned across restarts.
+const PINNED_SUFFIX: &'static str = ".pinned";
+// each chunk file starts with this, followed by the md5 of the chunk.
+const MAGIC: &'static [u8] = b"gdc1";
+const HEADER_LEN: u64 = 20;
+// chunks whose checksum is verified on startup, the most recently written,
+// which are the likeliest to be damaged by an unclean shutdown.
+const VERIFIED_ON_STARTUP: usize = 32;
+// how often the chunks unused for longer than the max age are removed.
+const SWEEP_INTERVAL_SECS: u64 = 60;
+
+// numbers the chunks being written by this process.
+static NEXT_PARTIAL: atomic::AtomicUsize = atomic::ATOMIC_USIZE_INIT;
+
+// a chunk in the cache.
+struct Entry {
+  // size of the chunk file, header included.
+  size: u64,
+  // the tick the chunk was last used at.
+  tick: u64,
+  last_used: SystemTime,
+  pinned: bool,
+}
+
+// a folder of chunks of file contents, each in its own file.
+struct DiskCache {
+  dir: PathBuf,
+  max_bytes: u64,
+  max_age: Option<Duration>,
+  // paths within the mount, without leading or trailing /, whose files have
+  // their chunks pinned.
+  pinned_folders: Vec<String>,
+  // ids of the files opened from the pinned folders.
+  pinned_files: HashSet<String>,
+  // map of chunk name -> chunk.
+  entries: HashMap<String, Entry>,
+  // total size of |entries|.
+  total_bytes: u64,
+  // incremented on each use of a chunk, to tell the least recently used.
+  tick: u64,
+}
+
+lazy_static! {
+  static ref CACHE: sync::Mutex<Option<DiskCache>> = sync::Mutex::new(None);
+}
+
+/// Keeps the chunks downloaded by all read handles of the process in `dir`,
+/// up to `max_bytes`, evicting the least recently used chunks beyond it.
+/// Chunks unused for longer than `max_age`, if set, are removed as well.
+/// Chunks of the files in `pinned_folders`, paths within the mount, are
+/// exempt from both, unless only pinned chunks are left over `max_bytes`:
+/// it's never exceeded.
+///
+/// Chunks left in `dir` by previous runs are reused, oldest first evicted,
+/// after dropping those of the wrong size and those failing the checksum
+/// check of the most recently written ones, as left by an unclean shutdown
+/// or a full disk. Several processes may share `dir`, each keeping to its
+/// own limit.
+///
+/// `dir` is created readable by the user only, as are the chunks. If `owner`
+/// is set, `dir` and the chunks in it are handed to that uid and gid, for a
+/// process started as root that switches to them once mounted.
+pub fn configure(
+  dir: &str,
+  max_bytes: u64,
+  max_age: Option<Duration>,
+  pinned_folders: &[String],
+  owner: Option<(u32, u32)>,
+) -> io::Result<()> {
+  try!(fs::DirBuilder::new().recursive(true).mode(0o700).create(dir));
+  if let Some((uid, gid)) = owner {
+    try!(chown(Path::new(dir), uid, gid));
+  }
+  let pinned_folders = pinned_folders
+    .iter()
+    .map(|folder| folder.trim_matches('/').to_string())
+    .collect();
+  let cache = try!(DiskCache::load(
+    Path::new(dir),
+    max_bytes,
+    max_age,
+    pinned_folders,
+    owner
+  ));
+  info!(
+    "disk cache at {}: {} chunks, {} bytes",
+    dir,
+    cache.entries.len(),
+    cache.total_bytes
+  );
+  *CACHE.lock().unwrap() = Some(cache);
+  if max_age.is_some() {
+    try!(thread::Builder::new()
+      .name(String::from("disk_cache_sweep"))
+      .spawn(|| loop {
+        thread::sleep(Duration::from_secs(SWEEP_INTERVAL_SECS));
+        if let Some(ref mut cache) = *CACHE.lock().unwrap() {
+          cache.expire(SystemTime::now());
+        }
+      }));
+  }
+  Ok(())
+}
+
+// |path| as a C string.
+fn c_path(path: &Path) -> io::Result<CString> {
+  CString::new(path.as_os_str().as_bytes())
+    .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+}
+
+// changes the owner of |path| to |uid| and |gid|.
+fn chown(path: &Path, uid: u32, gid: u32) -> io::Result<()> {
+  let c_path = try!(c_path(path));
+  if unsafe { libc::chown(c_path.as_ptr(), uid, gid) } != 0 {
+    return Err(io::Error::last_os_error());
+  }
+  Ok(())
+}
+
+// sets the modification time of |path| to now, which tells when the chunk
+// was last used after a restart.
+fn touch(path: &Path) -> io::Result<()> {
+  let c_path = try!(c_path(path));
+  if unsafe { libc::utimes(c_path.as_ptr(), std::ptr::null()) } != 0 {
+    return Err(io::Error::last_os_error());
+  }
+  Ok(())
+}
+
+/// Whether configure was called.
+pub fn is_enabled() -> bool {
+  CACHE.lock().unwrap().is_some()
+}
+
+/// The name a chunk is cached under: the chunk of `chunk_size` bytes at
+/// `offset` of the content of `file_id` at `version`, e.g. its md5 or
+/// revision id.
+pub fn chunk_name(file_id: &str, version: &str, chunk_size: u64, offset: u64) -> String {
+  format!("{}.{}.{}.{}", file_id, version, chunk_size, offset).replace('/', "_")
+}
+
+// whether a chunk file of |size| bytes can hold the chunk |name|: its
+// header and up to the chunk size given in the name.
+fn valid_size(name: &str, size: u64) -> bool {
+  let chunk_size = name
+    .rsplit('.')
+    .nth(1)
+    .and_then(|chunk_size| chunk_size.parse::<u64>().ok());
+  match chunk_size {
+    Some(chunk_size) => size > HEADER_LEN && size - HEADER_LEN <= chunk_size,
+    None => false,
+  }
+}
+
+// whether the chunk file at |path| holds the chunk its checksum was taken of.
+fn checksum_matches(path: &Path) -> bool {
+  let mut contents = Vec::new();
+  if fs::File::open(path)
+    .and_then(|mut file| file.read_to_end(&mut contents))
+    .is_err()
+    || (contents.len() as u64) < HEADER_LEN
+  {
+    return false;
+  }
+  let (header, chunk) = contents.split_at(HEADER_LEN as usize);
+  &header[..MAGIC.len()] == MAGIC && md5::compute(chunk).0 == header[MAGIC.len()..]
+}
+
+/// Records that the file `file_id` was opened at `path` within the mount,
+/// which pins its chunks if `path` is in a pinned folder.
+pub fn file_opened(file_id: &str, path: &str) {
+  if let Some(ref mut cache) = *CACHE.lock().unwrap() {
+    let pinned = cache.pinned_folders.iter().any(|folder| {
+      folder.is_empty() || path == folder || path.starts_with(&format!("{}/", folder))
+    });
+    if pinned {
+      cache.pinned_files.insert(file_id.into());
+    } else {
+      cache.pinned_files.remove(file_id);
+    }
+  }
+}
+
+/// Appends the cached chunk `name` to `buf`, returning false if it isn't
+/// cached.
+pub fn get(name: &str, buf: &mut Vec<u8>) -> bool {
+  let path = match *CACHE.lock().unwrap() {
+    Some(ref mut cache) => match cache.use_entry(name) {
+      Some(path) => path,
+      None => return false,
+    },
+    None => return false,
+  };
+  let initial_len = buf.len();
+  let mut header = [0; HEADER_LEN as usize];
+  let read = fs::File::open(&path).and_then(|mut file| {
+    try!(file.read_exact(&mut header));
+    if &header[..MAGIC.len()] != MAGIC {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "not a chunk file",
+      ));
+    }
+    file.read_to_end(buf)
+  });
+  match read {
+    Ok(_) => {
+      if let Err(err) = touch(&path) {
+        debug!("disk cache: touching {} failed: {}", name, err);
+      }
+      true
+    }
+    Err(err) => {
+      // evicted by another process sharing the folder, or written before
+      // chunk files had a header.
+      debug!("disk cache: reading {} failed: {}", name, err);
+      if err.kind() == io::ErrorKind::InvalidData {
+        let _ = fs::remove_file(&path);
+      }
+      buf.truncate(initial_len);
+      forget(name);
+      false
+    }
+  }
+}
+
+/// Caches `data` as the chunk `name`, evicting older chunks to make room.
+pub fn put(name: &str, data: &[u8]) {
+  let size = HEADER_LEN + data.len() as u64;
+  let (dir, pinned) = match *CACHE.lock().unwrap() {
+    Some(ref cache) if size <= cache.max_bytes => (cache.dir.clone(), cache.is_pinned(name)),
+    _ => return,
+  };
+  let partial = dir.join(format!(
+    "{}.{}.{}{}",
+    name,
+    std::process::id(),
+    NEXT_PARTIAL.fetch_add(1, atomic::Ordering::SeqCst),
+    PARTIAL_SUFFIX
+  ));
+  let written = fs::OpenOptions::new()
+    .write(true)
+    .create_new(true)
+    .mode(0o600)
+    .open(&partial)
+    .and_then(|mut file| {
+      try!(file.write_all(MAGIC));
+      try!(file.write_all(&md5::compute(data).0));
+      file.write_all(data)
+    })
+    .and_then(|()| fs::rename(&partial, chunk_path(&dir, name, pinned)));
+  if let Err(err) = written {
+    warn!("disk cache: writing {} failed: {}", name, err);
+    let _ = fs::remove_file(&partial);
+    return;
+  }
+  if let Some(ref mut cache) = *CACHE.lock().unwrap() {
+    cache.add(name, size, SystemTime::now(), pinned);
+    cache.evict();
+  }
+}
+
+// drops the chunk |name| from the index, after it went missing.
+fn forget(name: &str) {
+  if let Some(ref mut cache) = *CACHE.lock().unwrap() {
+    if let Some(entry) = cache.entries.remove(name) {
+      cache.total_bytes -= entry.size;
+    }
+  }
+}
+
+// the path of the file of the chunk |name| in |dir|.
+fn chunk_path(dir: &Path, name: &str, pinned: bool) -> PathBuf {
+  if pinned {
+    dir.join(format!("{}{}", name, PINNED_SUFFIX))
+  } else {
+    dir.join(name)
+  }
+}
+
+impl DiskCache {
+  // indexes the chunks in |dir|, dropping the partial and corrupt ones,
+  // then the expired ones and those over |max_bytes|.
+  fn load(
+    dir: &Path,
+    max_bytes: u64,
+    max_age: Option<Duration>,
+    pinned_folders: Vec<String>,
+    owner: Option<(u32, u32)>,
+  ) -> io::Result<DiskCache> {
+    let mut found: Vec<(SystemTime, String, u64, bool)> = Vec::new();
+    let mut corrupt = 0;
+    for entry in try!(fs::read_dir(dir)) {
+      let entry = try!(entry);
+      let file_name = entry.file_name().to_string_lossy().into_owned();
+      let metadata = try!(entry.metadata());
+      if !metadata.is_file() {
+        continue;
+      }
+      // left behind by a process that died while writing it.
+      if file_name.ends_with(PARTIAL_SUFFIX) {
+        let _ = fs::remove_file(entry.path());
+        continue;
+      }
+      let pinned = file_name.ends_with(PINNED_SUFFIX);
+      let name = if pinned {
+        file_name[..file_name.len() - PINNED_SUFFIX.len()].to_string()
+      } else {
+        file_name
+      };
+      if !valid_size(&name, metadata.len()) {
+        let _ = fs::remove_file(entry.path());
+        corrupt += 1;
+        continue;
+      }
+      if let Some((uid, gid)) = owner {
+        try!(chown(&entry.path(), uid, gid));
+      }
+      let modified = metadata.modified().unwrap_or(std::time::UNIX_EPOCH);
+      found.push((modified, name, metadata.len(), pinned));
+    }
+    found.sort();
+    let mut cache = DiskCache {
+      dir: dir.to_path_buf(),
+      max_bytes: max_bytes,
+      max_age: max_age,
+      pinned_folders: pinned_folders,
+      pinned_files: HashSet::new(),
+      entries: HashMap::new(),
+      total_bytes: 0,
+      tick: 0,
+    };
+    let verified_from = found.len().saturating_sub(VERIFIED_ON_STARTUP);
+    for (i, (modified, name, size, pinned)) in found.into_iter().enumerate() {
+      let path = chunk_path(dir, &name, pinned);
+      if i >= verified_from && !checksum_matches(&path) {
+        let _ = fs::remove_file(&path);
+        corrupt += 1;
+        continue;
+      }
+      // without pinned folders, nothing stays pinned.
+      let pinned = if pinned && cache.pinned_folders.is_empty() {
+        fs::rename(&path, chunk_path(dir, &name, false)).is_err()
+      } else {
+        pinned
+      };
+      cache.add(&name, size, modified, pinned);
+    }
+    if corrupt > 0 {
+      warn!(
+        "disk cache: dropped {} corrupt chunks from {}",
+        corrupt,
+        dir.display()
+      );
+    }
+    cache.expire(SystemTime::now());
+    cache.evict();
+    Ok(cache)
+  }
+
+  // whether the chunk |name| is of a file opened from a pinned folder.
+  fn is_pinned(&self, name: &str) -> bool {
+    name
+      .split('.')
+      .next()
+      .map_or(false, |file_id| self.pinned_files.contains(file_id))
+  }
+
+  // indexes the chunk |name| of |size| bytes, last used at |last_used|.
+  fn add(&mut self, name: &str, size: u64, last_used: SystemTime, pinned: bool) {
+    self.tick += 1;
+    let entry = Entry {
+      size: size,
+      tick: self.tick,
+      last_used: last_used,
+      pinned: pinned,
+    };
+    if let Some(old) = self.entries.insert(name.into(), entry) {
+      self.total_bytes -= old.size;
+      // rewritten under the other name.
+      if old.pinned != pinned {
+        let _ = fs::remove_file(chunk_path(&self.dir, name, old.pinned));
+      }
+    }
+    self.total_bytes += size;
+  }
+
+  // marks the chunk |name| used, returning the path of its file, or None if
+  // it isn't cached. A chunk whose file was pinned or unpinned since it was
+  // cached is renamed accordingly.
+  fn use_entry(&mut self, name: &str) -> Option<PathBuf> {
+    let pinned = self.is_pinned(name);
+    self.tick += 1;
+    let entry = match self.entries.get_mut(name) {
+      Some(entry) => entry,
+      None => return None,
+    };
+    entry.tick = self.tick;
+    entry.last_used = SystemTime::now();
+    let path = chunk_path(&self.dir, name, entry.pinned);
+    if entry.pinned == pinned {
+      return Some(path);
+    }
+    let new_path = chunk_path(&self.dir, name, pinned);
+    match fs::rename(&path, &new_path) {
+      Ok(()) => {
+        entry.pinned = pinned;
+        Some(new_path)
+      }
+      Err(_) => Some(path),
+    }
+  }
+
+  // removes the chunk |name|.
+  fn remove(&mut self, name: &str) {
+    if let Some(entry) = self.entries.remove(name) {
+      self.total_bytes -= entry.size;
+      if let Err(err) = fs::remove_file(chunk_path(&self.dir, name, entry.pinned)) {
+        debug!("disk cache: removing {} failed: {}", name, err);
+      }
+    }
+  }
+
+  // removes the unpinned chunks unused for longer than max_age, if set.
+  fn expire(&mut self, now: SystemTime) {
+    let max_age = match self.max_age {
+      Some(max_age) => max_age,
+      None => return,
+    };
+    let expired: Vec<String> = self
+      .entries
+      .iter()
+      .filter(|&(_, entry)| {
+        !entry.pinned
+          && now
+            .duration_since(entry.last_used)
+            .map(|age| age > max_age)
+            .unwrap_or(false)
+      })
+      .map(|(name, _)| name.clone())
+      .collect();
+    for name in expired {
+      self.remove(&name);
+    }
+  }
+
+  // removes the least recently used chunks until the total fits in
+  // max_bytes, the pinned ones only once no other is left.
+  fn evict(&mut self) {
+    while self.total_bytes > self.max_bytes {
+      let oldest = match self
+        .entries
+        .iter()
+        .min_by_key(|&(_, entry)| (entry.pinned, entry.tick))
+      {
+        Some((name, _)) => name.clone(),
+        None => break,
+      };
+      self.remove(&oldest);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // an empty folder for the test |name|.
+  fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+      "gdrivefs-disk-cache-{}-{}",
+      name,
+      std::process::id()
+    ));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+  }
+
+  // the names of the chunks in |cache|, sorted.
+  fn names(cache: &DiskCache) -> Vec<String> {
+    let mut names: Vec<String> = cache.entries.keys().cloned().collect();
+    names.sort();
+    names
+  }
+
+  // writes the chunk |name| holding |data| to |dir|, with its header.
+  fn write_chunk(dir: &Path, name: &str, data: &[u8], pinned: bool) {
+    let mut file = fs::File::create(chunk_path(dir, name, pinned)).unwrap();
+    file.write_all(MAGIC).unwrap();
+    file.write_all(&md5::compute(data).0).unwrap();
+    file.write_all(data).unwrap();
+  }
+
+  #[test]
+  fn load_drops_corrupt_chunks() {
+    let dir = test_dir("corrupt");
+    write_chunk(&dir, &chunk_name("good", "v", 4, 0), b"abcd", false);
+    write_chunk(&dir, &chunk_name("long", "v", 4, 0), b"abcde", false);
+    write_chunk(&dir, &chunk_name("empty", "v", 4, 0), b"", false);
+    write_chunk(&dir, "unnamed", b"abcd", false);
+    let flipped = chunk_name("flipped", "v", 4, 0);
+    write_chunk(&dir, &flipped, b"abcd", false);
+    let mut contents = Vec::new();
+    fs::File::open(dir.join(&flipped))
+      .unwrap()
+      .read_to_end(&mut contents)
+      .unwrap();
+    contents[HEADER_LEN as usize] ^= 1;
+    fs::File::create(dir.join(&flipped))
+      .unwrap()
+      .write_all(&contents)
+      .unwrap();
+    fs::File::create(dir.join("good.v.4.4.1.2.partial")).unwrap();
+
+    let cache = DiskCache::load(&dir, 1 << 20, None, Vec::new(), None).unwrap();
+    assert_eq!(names(&cache), vec!["good.v.4.0"]);
+    assert_eq!(cache.total_bytes, HEADER_LEN + 4);
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn evicts_pinned_chunks_last() {
+    let dir = test_dir("evict");
+    let pinned_folders = vec![String::from("kept")];
+    let mut cache = DiskCache::load(&dir, 1 << 20, None, pinned_folders, None).unwrap();
+    cache.max_bytes = 3 * (HEADER_LEN + 4);
+    let now = SystemTime::now();
+    for (name, pinned) in vec![("a", true), ("b", false), ("c", false), ("d", true)] {
+      write_chunk(&dir, &chunk_name(name, "v", 4, 0), b"abcd", pinned);
+      cache.add(&chunk_name(name, "v", 4, 0), HEADER_LEN + 4, now, pinned);
+    }
+    cache.evict();
+    assert_eq!(names(&cache), vec!["a.v.4.0", "c.v.4.0", "d.v.4.0"]);
+
+    cache.max_bytes = HEADER_LEN + 4;
+    cache.evict();
+    assert_eq!(names(&cache), vec!["d.v.4.0"]);
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn expires_unpinned_chunks() {
+    let dir = test_dir("expire");
+    let max_age = Some(Duration::from_secs(3600));
+    let mut cache = DiskCache::load(&dir, 1 << 20, max_age, Vec::new(), None).unwrap();
+    let now = SystemTime::now();
+    let old = now - Duration::from_secs(7200);
+    cache.add("old.v.4.0", HEADER_LEN + 4, old, false);
+    cache.add("pinned.v.4.0", HEADER_LEN + 4, old, true);
+    cache.add("new.v.4.0", HEADER_LEN + 4, now, false);
+    cache.expire(now);
+    assert_eq!(names(&cache), vec!["new.v.4.0", "pinned.v.4.0"]);
+    fs::remove_dir_all(&dir).unwrap();
+  }
+}
@@ -0,0 +1,165 @@
+extern crate hyper;
+extern crate libc;
+extern crate serde_json;
+
+use self::serde_json::Value;
+use std::error::Error;
+use std::fmt;
+
+// reasons Drive gives for a 403 that goes away when requests slow down.
+const RATE_LIMIT_REASONS: &'static [&'static str] = &["rateLimitExceeded", "userRateLimitExceeded"];
+// reasons Drive gives for a request over a quota of the oauth client's
+// project, which a request from another project isn't held to.
+const CLIENT_QUOTA_REASONS: &'static [&'static str] = &[
+  "rateLimitExceeded",
+  "userRateLimitExceeded",
+  "dailyLimitExceeded",
+  "quotaExceeded",
+];
+const PERMISSION_REASONS: &'static [&'static str] = &[
+  "forbidden",
+  "insufficientPermissions",
+  "insufficientFilePermissions",
+  "appNotAuthorizedToFile",
+  "cannotDownloadAbusiveFile",
+  "domainPolicy",
+  "fileNotDownloadable",
+];
+// reason Drive gives when a file was downloaded too often, typically a
+// widely shared one. Other files still download.
+const DOWNLOAD_QUOTA_REASON: &'static str = "downloadQuotaExceeded";
+const STORAGE_QUOTA_REASON: &'static str = "storageQuotaExceeded";
+// reason Drive gives for refusing to export a native Google item over 10MB.
+const EXPORT_SIZE_REASON: &'static str = "exportSizeLimitExceeded";
+
+/// A request refused by Google Drive, with the reason it gave.
+#[derive(Debug, Clone)]
+pub struct DriveError {
+  /// HTTP status of the response.
+  pub status: u16,
+  /// Reason code, e.g. userRateLimitExceeded or notFound, or empty if the
+  /// response had none.
+  pub reason: String,
+  /// Domain of the reason, e.g. usageLimits or global.
+  pub domain: String,
+  /// Message for humans, or the raw body if it wasn't a JSON error.
+  pub message: String,
+}
+
+impl DriveError {
+  /// Parses the error `body` of a response with `status`, as Drive's
+  /// `{"error": {"errors": [{"reason", "domain", "message"}], ...}}` or the
+  /// OAuth endpoint's `{"error", "error_description"}`.
+  pub fn parse(status: hyper::status::StatusCode, body: &str) -> DriveError {
+    let mut err = DriveError {
+      status: status.to_u16(),
+      reason: String::new(),
+      domain: String::new(),
+      message: body.trim().into(),
+    };
+    let value: Value = match serde_json::from_str(body) {
+      Ok(value) => value,
+      Err(_) => return err,
+    };
+    let text = |value: &Value| value.as_str().map(String::from);
+    match value["error"] {
+      Value::String(ref reason) => {
+        err.reason = reason.clone();
+        if let Some(description) = text(&value["error_description"]) {
+          err.message = description;
+        }
+      }
+      ref error => {
+        let first = &error["errors"][0];
+        err.reason = text(&first["reason"]).unwrap_or_default();
+        err.domain = text(&first["domain"]).unwrap_or_default();
+        if let Some(message) = text(&error["message"]).or_else(|| text(&first["message"])) {
+          err.message = message;
+        }
+      }
+    }
+    err
+  }
+
+  /// Whether Drive refused the request for exceeding a rate limit.
+  pub fn is_rate_limit(&self) -> bool {
+    self.status == 429 || (self.status == 403 && RATE_LIMIT_REASONS.contains(&&*self.reason))
+  }
+
+  /// Whether Drive refused the request for exceeding a quota of the oauth
+  /// client that made it.
+  pub fn is_client_quota(&self) -> bool {
+    (self.status == 403 || self.status == 429) && CLIENT_QUOTA_REASONS.contains(&&*self.reason)
+  }
+
+  /// Whether Drive refused to download a file because it was downloaded too
+  /// often, which lifts within a day.
+  pub fn is_download_quota(&self) -> bool {
+    self.reason == DOWNLOAD_QUOTA_REASON
+  }
+
+  /// Whether retrying the request later may succeed: it hit a rate limit or
+  /// failed with a server error.
+  pub fn is_transient(&self) -> bool {
+    self.is_rate_limit() || self.status >= 500
+  }
+
+  /// The errno a filesystem operation failing with this error fails with.
+  /// A file over its download quota fails with EDQUOT, which no other error
+  /// maps to, so it isn't taken for a broken file.
+  pub fn errno(&self) -> libc::c_int {
+    if self.is_rate_limit() {
+      libc::EAGAIN
+    } else if self.status == 404 || self.reason == "notFound" {
+      libc::ENOENT
+    } else if self.is_download_quota() {
+      libc::EDQUOT
+    } else if self.reason == STORAGE_QUOTA_REASON {
+      libc::ENOSPC
+    } else if self.reason == EXPORT_SIZE_REASON {
+      libc::EFBIG
+    } else if self.is_client_quota() {
+      libc::EAGAIN
+    } else if self.status == 403 && PERMISSION_REASONS.contains(&&*self.reason) {
+      libc::EACCES
+    } else {
+      libc::EIO
+    }
+  }
+}
+
+impl fmt::Display for DriveError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match (self.reason.is_empty(), self.domain.is_empty()) {
+      (true, _) => write!(f, "{}: {}", self.status, self.message),
+      (false, true) => write!(f, "{} {}: {}", self.status, self.reason, self.message),
+      (false, false) => write!(
+        f,
+        "{} {} ({}): {}",
+        self.status, self.reason, self.domain, self.message
+      ),
+    }
+  }
+}
+
+impl Error for DriveError {
+  fn description(&self) -> &str {
+    &self.message
+  }
+}
+
+/// Whether `err` is the refusal of a changes.list page token, which expired
+/// or is otherwise invalid.
+pub fn is_invalid_page_token(err: &(Error + 'static)) -> bool {
+  err
+    .downcast_ref::<DriveError>()
+    .map_or(false, |err| err.status == 400 || err.status == 404 || err.status == 410)
+}
+
+/// The errno for a filesystem operation failing with `err`: the one matching
+/// the reason of a DriveError, and EIO for anything else.
+pub fn errno(err: &(Error + 'static)) -> libc::c_int {
+  err
+    .downcast_ref::<DriveError>()
+    .map_or(libc::EIO, |err| err.errno())
+}
@@ -0,0 +1,251 @@
+use retry;
+use std;
+use std::collections::{HashMap, VecDeque};
+use std::sync;
+use std::sync::atomic;
+use std::thread;
+use std::time::Duration;
+
+// counts are kept per minute of the window.
+const BUCKET_SECS: u64 = 60;
+const DEFAULT_WINDOW_MINS: usize = 5;
+
+// minutes over which rates are reported.
+static WINDOW_MINS: atomic::AtomicUsize = atomic::ATOMIC_USIZE_INIT;
+
+/// Why a request to Google Drive failed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorKind {
+  /// Drive refused the request for exceeding a rate limit.
+  RateLimit,
+  /// Drive failed with a server error.
+  Server,
+  /// The connection failed or broke off.
+  Network,
+  /// Drive refused to download a file that was downloaded too often.
+  DownloadQuota,
+  /// Any other refusal, e.g. a missing file or permission.
+  Other,
+}
+
+/// Counts of read operations and failed requests over a span of time.
+#[derive(Debug, Clone, Default)]
+pub struct ErrorRates {
+  /// Reads that succeeded.
+  pub succeeded: u64,
+  /// Retries of reads after a failed request.
+  pub retried: u64,
+  /// Reads that failed after their retries.
+  pub failed: u64,
+  /// Requests that failed with ErrorKind::RateLimit.
+  pub rate_limit_errors: u64,
+  /// Requests that failed with ErrorKind::Server.
+  pub server_errors: u64,
+  /// Requests that failed with ErrorKind::Network.
+  pub network_errors: u64,
+  /// Requests that failed with ErrorKind::DownloadQuota.
+  pub download_quota_errors: u64,
+  /// Requests that failed with ErrorKind::Other.
+  pub other_errors: u64,
+}
+
+impl ErrorRates {
+  fn add(&mut self, other: &ErrorRates) {
+    self.succeeded += other.succeeded;
+    self.retried += other.retried;
+    self.failed += other.failed;
+    self.rate_limit_errors += other.rate_limit_errors;
+    self.server_errors += other.server_errors;
+    self.network_errors += other.network_errors;
+    self.download_quota_errors += other.download_quota_errors;
+    self.other_errors += other.other_errors;
+  }
+
+  fn errors(&self) -> u64 {
+    self.rate_limit_errors
+      + self.server_errors
+      + self.network_errors
+      + self.download_quota_errors
+      + self.other_errors
+  }
+}
+
+lazy_static! {
+  // the counts of the last minutes, each with the minute it covers, oldest
+  // first.
+  static ref BUCKETS: sync::Mutex<VecDeque<(u64, ErrorRates)>> =
+    sync::Mutex::new(VecDeque::new());
+  // map of file id -> minute it last hit its download quota.
+  static ref DOWNLOAD_QUOTA_FILES: sync::Mutex<HashMap<String, u64>> =
+    sync::Mutex::new(HashMap::new());
+}
+
+fn window_mins() -> usize {
+  match WINDOW_MINS.load(atomic::Ordering::SeqCst) {
+    0 => DEFAULT_WINDOW_MINS,
+    mins => mins,
+  }
+}
+
+fn current_minute() -> u64 {
+  std::cmp::max(::time::get_time().sec, 0) as u64 / BUCKET_SECS
+}
+
+// applies |update| to the counts of the current minute, dropping the
+// minutes that fell out of the window. The minute before the window is
+// kept until reported.
+fn record<F: FnOnce(&mut ErrorRates)>(update: F) {
+  let minute = current_minute();
+  let oldest = minute.saturating_sub(window_mins() as u64);
+  let mut buckets = BUCKETS.lock().unwrap();
+  while buckets.front().map_or(false, |&(start, _)| start < oldest) {
+    buckets.pop_front();
+  }
+  if buckets.back().map_or(true, |&(start, _)| start != minute) {
+    buckets.push_back((minute, ErrorRates::default()));
+  }
+  update(&mut buckets.back_mut().unwrap().1);
+}
+
+/// Records a read that succeeded.
+pub fn record_success() {
+  record(|rates| rates.succeeded += 1);
+}
+
+/// Records a read retried after a failed request.
+pub fn record_retry() {
+  record(|rates| rates.retried += 1);
+}
+
+/// Records a read that failed for good.
+pub fn record_failed() {
+  record(|rates| rates.failed += 1);
+}
+
+/// Records a request that failed with `kind`.
+pub fn record_error(kind: ErrorKind) {
+  record(|rates| match kind {
+    ErrorKind::RateLimit => rates.rate_limit_errors += 1,
+    ErrorKind::Server => rates.server_errors += 1,
+    ErrorKind::Network => rates.network_errors += 1,
+    ErrorKind::DownloadQuota => rates.download_quota_errors += 1,
+    ErrorKind::Other => rates.other_errors += 1,
+  });
+}
+
+/// Records that Drive refused to download `file_id` for being over its
+/// download quota. Unlike other errors, this is logged as a warning right
+/// away, once per file and window, since it lasts for hours and only
+/// affects that file.
+pub fn record_download_quota(file_id: &str) {
+  record_error(ErrorKind::DownloadQuota);
+  let minute = current_minute();
+  let oldest = minute.saturating_sub(window_mins() as u64);
+  let mut files = DOWNLOAD_QUOTA_FILES.lock().unwrap();
+  let known = files.get(file_id).map_or(false, |&last| last >= oldest);
+  files.insert(file_id.into(), minute);
+  files.retain(|_, &mut last| last >= oldest);
+  if !known {
+    warn!(
+      "Drive refused to download {}: its download quota is exceeded, reads fail with EDQUOT \
+       until it resets",
+      file_id
+    );
+  }
+}
+
+// the number of files that hit their download quota from minute |first| on.
+fn download_quota_files_since(first: u64) -> usize {
+  DOWNLOAD_QUOTA_FILES
+    .lock()
+    .unwrap()
+    .values()
+    .filter(|&&last| last >= first)
+    .count()
+}
+
+// the counts of the minutes from |first| up to but excluding |end|.
+fn rates_between(first: u64, end: u64) -> ErrorRates {
+  let mut total = ErrorRates::default();
+  for &(start, ref rates) in BUCKETS.lock().unwrap().iter() {
+    if start >= first && start < end {
+      total.add(rates);
+    }
+  }
+  total
+}
+
+/// Describes the counts over the window on one line, as
+/// `window=<mins>m succeeded=<n> retried=<n> failed=<n> rate-limit=<n>
+/// server=<n> network=<n> download-quota=<n> other=<n>
+/// download-quota-files=<n>`.
+pub fn describe() -> String {
+  let mins = window_mins();
+  let current = current_minute();
+  let rates = rates_between(current + 1 - mins as u64, current + 1);
+  format!(
+    "window={}m succeeded={} retried={} failed={} rate-limit={} server={} network={} \
+     download-quota={} other={} download-quota-files={}",
+    mins,
+    rates.succeeded,
+    rates.retried,
+    rates.failed,
+    rates.rate_limit_errors,
+    rates.server_errors,
+    rates.network_errors,
+    rates.download_quota_errors,
+    rates.other_errors,
+    download_quota_files_since(current + 1 - mins as u64)
+  )
+}
+
+/// Starts a background thread that, every `window`, logs a single warning
+/// summing up the requests that failed during it, if any did. Failures are
+/// otherwise only logged at debug level. `window` is rounded to whole
+/// minutes and is also the span that describe reports on.
+pub fn start_reporter(window: Duration) {
+  let mins = std::cmp::max(window.as_secs() / BUCKET_SECS, 1) as usize;
+  WINDOW_MINS.store(mins, atomic::Ordering::SeqCst);
+  thread::Builder::new()
+    .name(String::from("error_reporter"))
+    .spawn(move || {
+      // the minutes before this one were reported.
+      let mut reported = current_minute();
+      loop {
+        thread::sleep(Duration::from_secs(mins as u64 * BUCKET_SECS));
+        // the minute in progress is left for the next report.
+        let current = current_minute();
+        let rates = rates_between(reported, current);
+        reported = current;
+        if rates.errors() == 0 {
+          continue;
+        }
+        let mut kinds = Vec::new();
+        for &(count, kind) in &[
+          (rates.rate_limit_errors, "rate-limit"),
+          (rates.server_errors, "server"),
+          (rates.network_errors, "network"),
+          (rates.download_quota_errors, "download-quota"),
+          (rates.other_errors, "other"),
+        ] {
+          if count > 0 {
+            kinds.push(format!("{} {} errors", count, kind));
+          }
+        }
+        warn!(
+          "Drive returned {} in the last {}m; {} reads retried, {} failed, {} succeeded{}",
+          kinds.join(", "),
+          mins,
+          rates.retried,
+          rates.failed,
+          rates.succeeded,
+          if retry::any_exhausted() {
+            "; throttling retries"
+          } else {
+            ""
+          }
+        );
+      }
+    })
+    .unwrap();
+}
@@ -0,0 +1,343 @@
+extern crate google_drive3;
+extern crate hyper;
+extern crate threadpool;
+extern crate time;
+
+use common;
+use drive_error;
+use listing;
+use oauth;
+use oauth::GetToken;
+use std::collections::HashMap;
+use std::error::Error;
+use std::io;
+use std::io::{Read, Write};
+use std::sync::mpsc;
+
+const FILE_GET_URL: &'static str = "https://www.googleapis.com/drive/v3/files";
+const FIELDS: &'static str = "id,name,mimeType,size,modifiedTime";
+const FOLDER_MIME_TYPE: &'static str = "application/vnd.google-apps.folder";
+const GOOGLE_APPS_MIME_PREFIX: &'static str = "application/vnd.google-apps.";
+const SYMLINK_MIME_TYPE: &'static str = "inode/symlink";
+const RFC3339_FMT: &'static str = "%Y-%m-%dT%H:%M:%S";
+
+// files up to this size are downloaded ahead into memory by the workers,
+// larger ones are streamed into the archive when their turn comes.
+const MAX_BUFFERED_FILE_SIZE: u64 = 16 * 1024 * 1024;
+const TAR_BLOCK_SIZE: usize = 512;
+// name of the GNU entry holding the full path of the next entry, for paths
+// longer than the 100 bytes a tar header has room for.
+const TAR_LONG_NAME: &'static str = "././@LongLink";
+
+/// What an export wrote to the archive.
+#[derive(Debug, Default)]
+pub struct ExportSummary {
+  /// Number of files written.
+  pub files: u64,
+  /// Number of folders written.
+  pub folders: u64,
+  /// Total size of the files written.
+  pub bytes: u64,
+  /// Number of Google Docs, shortcuts and symlinks left out, which have no
+  /// content to download.
+  pub skipped: u64,
+}
+
+// an item to write to the archive.
+struct Entry {
+  // path in the archive.
+  path: String,
+  file_id: String,
+  size: u64,
+  mtime: u64,
+  is_dir: bool,
+}
+
+/// Writes the folder at `drive_path`, relative to the root of the drive, as a
+/// tar archive to `out`, downloading the content of files straight from the
+/// drive rather than through a mount. Up to `jobs` small files are downloaded
+/// in parallel ahead of the one being written. Paths in the archive start
+/// with the name of the folder, as with tar -c.
+pub fn export_tar<W: Write>(
+  auth: &oauth::GoogleAuthenticator,
+  drive_path: &str,
+  out: W,
+  jobs: usize,
+) -> Result<ExportSummary, Box<Error>> {
+  let mut out = out;
+  let mut auth = auth.clone();
+  let root = try!(listing::find_by_path(&mut auth, drive_path, FIELDS));
+  let mut summary = ExportSummary::default();
+  let mut entries = Vec::new();
+  try!(collect_entries(
+    &mut auth,
+    root,
+    "",
+    &mut entries,
+    &mut summary
+  ));
+
+  let jobs = std::cmp::max(jobs, 1);
+  let pool = threadpool::ThreadPool::new(jobs);
+  // map of index in |entries| -> the download of that entry in progress.
+  let mut pending: HashMap<usize, mpsc::Receiver<Result<Vec<u8>, String>>> = HashMap::new();
+  let mut next_download = 0;
+  for (i, entry) in entries.iter().enumerate() {
+    while next_download < entries.len() && pending.len() < jobs {
+      let ahead = &entries[next_download];
+      if !ahead.is_dir && ahead.size <= MAX_BUFFERED_FILE_SIZE {
+        let (tx, rx) = mpsc::channel();
+        let mut auth = auth.clone();
+        let file_id = ahead.file_id.clone();
+        pool.execute(move || {
+          let result = download(&mut auth, &file_id)
+            .and_then(|mut resp| {
+              let mut content = Vec::new();
+              try!(resp.read_to_end(&mut content));
+              Ok(content)
+            })
+            .map_err(|err| err.to_string());
+          tx.send(result).ok();
+        });
+        pending.insert(next_download, rx);
+      }
+      next_download += 1;
+    }
+
+    if entry.is_dir {
+      try!(write_header(
+        &mut out,
+        &format!("{}/", entry.path),
+        0,
+        entry.mtime,
+        b'5'
+      ));
+      summary.folders += 1;
+      continue;
+    }
+    try!(write_header(
+      &mut out,
+      &entry.path,
+      entry.size,
+      entry.mtime,
+      b'0'
+    ));
+    let written = match pending.remove(&i) {
+      Some(download) => {
+        let content = match download.recv() {
+          Ok(Ok(content)) => content,
+          Ok(Err(err)) => return Err(From::from(format!("{}: {}", entry.path, err))),
+          Err(_) => return Err(From::from(format!("{}: download abandoned", entry.path))),
+        };
+        try!(out.write_all(&content[..std::cmp::min(content.len() as u64, entry.size) as usize]));
+        content.len() as u64
+      }
+      None => {
+        let resp = try!(download(&mut auth, &entry.file_id));
+        // one byte more than expected is read, to tell a file that grew.
+        try!(io::copy(
+          &mut resp.take(entry.size + 1),
+          &mut LimitedWriter {
+            inner: &mut out,
+            remaining: entry.size,
+          }
+        ))
+      }
+    };
+    if written != entry.size {
+      return Err(From::from(format!(
+        "{} changed during the export: expected {} bytes, got {}",
+        entry.path, entry.size, written
+      )));
+    }
+    try!(write_padding(&mut out, entry.size));
+    summary.files += 1;
+    summary.bytes += entry.size;
+  }
+  // a tar archive ends with two empty blocks.
+  try!(out.write_all(&[0; 2 * TAR_BLOCK_SIZE]));
+  try!(out.flush());
+  Ok(summary)
+}
+
+// appends |file| and, if it's a folder, everything under it to |entries|,
+// with paths under |prefix|. Children are sorted by name so that exports of
+// the same content are identical.
+fn collect_entries(
+  auth: &mut oauth::GoogleAuthenticator,
+  file: google_drive3::File,
+  prefix: &str,
+  entries: &mut Vec<Entry>,
+  summary: &mut ExportSummary,
+) -> Result<(), Box<Error>> {
+  let name = file.name.clone().unwrap_or_default().replace('/', "_");
+  let path = if prefix.is_empty() {
+    name
+  } else {
+    format!("{}/{}", prefix, name)
+  };
+  let mime_type = file.mime_type.clone().unwrap_or_default();
+  let mtime = file
+    .modified_time
+    .as_ref()
+    .and_then(|modified| time::strptime(modified, RFC3339_FMT).ok())
+    .map(|tm| std::cmp::max(tm.to_timespec().sec, 0) as u64)
+    .unwrap_or(0);
+  let file_id = file.id.clone().unwrap_or_default();
+  if mime_type == FOLDER_MIME_TYPE {
+    entries.push(Entry {
+      path: path.clone(),
+      file_id: file_id.clone(),
+      size: 0,
+      mtime: mtime,
+      is_dir: true,
+    });
+    let mut children = try!(listing::list_children(auth, &file_id, FIELDS));
+    children.sort_by(|a, b| a.name.cmp(&b.name));
+    for child in children {
+      try!(collect_entries(auth, child, &path, entries, summary));
+    }
+  } else if mime_type.starts_with(GOOGLE_APPS_MIME_PREFIX) || mime_type == SYMLINK_MIME_TYPE {
+    warn!(
+      "export: leaving out {}, which has no content to download",
+      path
+    );
+    summary.skipped += 1;
+  } else {
+    entries.push(Entry {
+      path: path,
+      file_id: file_id,
+      size: file
+        .size
+        .as_ref()
+        .and_then(|size| size.parse().ok())
+        .unwrap_or(0),
+      mtime: mtime,
+      is_dir: false,
+    });
+  }
+  Ok(())
+}
+
+// starts a download of the content of |file_id|.
+fn download(
+  auth: &mut oauth::GoogleAuthenticator,
+  file_id: &str,
+) -> Result<hyper::client::Response, Box<Error>> {
+  let url = format!(
+    "{}/{}?alt=media&supportsAllDrives=true",
+    FILE_GET_URL, file_id
+  );
+  let token = auth.api_key().unwrap();
+  let mut resp = try!(common::new_hyper_tls_client()
+    .get(&url)
+    .header(hyper::header::Authorization(hyper::header::Bearer {
+      token: token,
+    }))
+    .send());
+  if !resp.status.is_success() {
+    let mut body: String = String::new();
+    try!(resp.read_to_string(&mut body));
+    let err = drive_error::DriveError::parse(resp.status, &body);
+    warn!("Export download error: {}", err);
+    return Err(Box::new(err));
+  }
+  Ok(resp)
+}
+
+// a writer passing on at most |remaining| bytes and dropping the rest, while
+// reporting all of them as written.
+struct LimitedWriter<'a, W: 'a + Write> {
+  inner: &'a mut W,
+  remaining: u64,
+}
+
+impl<'a, W: Write> Write for LimitedWriter<'a, W> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    let passed = std::cmp::min(buf.len() as u64, self.remaining) as usize;
+    try!(self.inner.write_all(&buf[..passed]));
+    self.remaining -= passed as u64;
+    Ok(buf.len())
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.inner.flush()
+  }
+}
+
+// writes the tar header of an entry named |path|, preceded by a GNU long
+// name entry if |path| doesn't fit in the header.
+fn write_header<W: Write>(
+  out: &mut W,
+  path: &str,
+  size: u64,
+  mtime: u64,
+  entry_type: u8,
+) -> io::Result<()> {
+  if path.len() > 100 {
+    let mut name = path.as_bytes().to_vec();
+    name.push(0);
+    try!(out.write_all(&tar_header(
+      TAR_LONG_NAME.as_bytes(),
+      name.len() as u64,
+      0,
+      b'L'
+    )));
+    try!(out.write_all(&name));
+    try!(write_padding(out, name.len() as u64));
+  }
+  out.write_all(&tar_header(path.as_bytes(), size, mtime, entry_type))
+}
+
+fn tar_header(name: &[u8], size: u64, mtime: u64, entry_type: u8) -> [u8; TAR_BLOCK_SIZE] {
+  let mut header = [0; TAR_BLOCK_SIZE];
+  let name_len = std::cmp::min(name.len(), 100);
+  header[..name_len].copy_from_slice(&name[..name_len]);
+  let mode = if entry_type == b'5' { 0o755 } else { 0o644 };
+  put_number(&mut header[100..108], mode);
+  put_number(&mut header[108..116], 0);
+  put_number(&mut header[116..124], 0);
+  put_number(&mut header[124..136], size);
+  put_number(&mut header[136..148], mtime);
+  header[156] = entry_type;
+  // the GNU magic, since long names use a GNU extension.
+  header[257..265].copy_from_slice(b"ustar  \0");
+  // the checksum is computed with its own field filled with spaces.
+  for byte in &mut header[148..156] {
+    *byte = b' ';
+  }
+  let checksum: u64 = header.iter().map(|&byte| byte as u64).sum();
+  header[148..155].copy_from_slice(format!("{:06o}\0", checksum).as_bytes());
+  header
+}
+
+// writes |value| to the numeric header |field| as zero-padded octal, or as
+// big-endian base-256 with the high bit set if it doesn't fit, as GNU tar
+// does for files of 8GiB or more.
+fn put_number(field: &mut [u8], value: u64) {
+  let octal = format!("{:01$o}", value, field.len() - 1);
+  if octal.len() < field.len() {
+    field[..octal.len()].copy_from_slice(octal.as_bytes());
+    field[octal.len()] = 0;
+    return;
+  }
+  let len = field.len();
+  for (i, byte) in field.iter_mut().enumerate() {
+    let shift = 8 * (len - 1 - i);
+    *byte = if shift < 64 {
+      (value >> shift) as u8
+    } else {
+      0
+    };
+  }
+  field[0] |= 0x80;
+}
+
+// pads an entry of |size| bytes to a whole number of blocks.
+fn write_padding<W: Write>(out: &mut W, size: u64) -> io::Result<()> {
+  let remainder = (size % TAR_BLOCK_SIZE as u64) as usize;
+  if remainder == 0 {
+    return Ok(());
+  }
+  out.write_all(&[0; TAR_BLOCK_SIZE][remainder..])
+}
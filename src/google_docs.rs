@@ -0,0 +1,149 @@
+extern crate hyper;
+extern crate time;
+
+use common;
+use drive_error;
+use oauth;
+use oauth::GetToken;
+use resolver;
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::Read;
+use std::str::FromStr;
+use std::sync;
+
+const GOOGLE_APPS_MIME_PREFIX: &'static str = "application/vnd.google-apps.";
+
+// file extensions that native Google items can be exported as, and the mime
+// type each is requested with.
+const EXPORT_MIME_TYPES: &'static [(&'static str, &'static str)] = &[
+  (
+    "docx",
+    "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+  ),
+  (
+    "xlsx",
+    "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+  ),
+  (
+    "pptx",
+    "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+  ),
+  ("odt", "application/vnd.oasis.opendocument.text"),
+  ("ods", "application/x-vnd.oasis.opendocument.spreadsheet"),
+  ("odp", "application/vnd.oasis.opendocument.presentation"),
+  ("rtf", "application/rtf"),
+  ("pdf", "application/pdf"),
+  ("txt", "text/plain"),
+  ("html", "text/html"),
+  ("epub", "application/epub+zip"),
+  ("csv", "text/csv"),
+  ("tsv", "text/tab-separated-values"),
+  ("png", "image/png"),
+  ("jpg", "image/jpeg"),
+  ("svg", "image/svg+xml"),
+  ("json", "application/vnd.google-apps.script+json"),
+];
+
+lazy_static! {
+  // map of item id -> modification time of the item and size of its last
+  // export, since Drive doesn't tell the size of exports before they're made.
+  static ref EXPORT_SIZES: sync::Mutex<HashMap<String, (time::Timespec, u64)>> =
+    sync::Mutex::new(HashMap::new());
+}
+
+/// How a kind of native Google item, e.g. Docs, is exported when read.
+#[derive(Debug, Clone)]
+pub struct ExportFormat {
+  /// Mime type of the items exported, e.g.
+  /// application/vnd.google-apps.document.
+  pub google_mime_type: String,
+  /// Mime type the items are exported as.
+  pub mime_type: String,
+  /// Extension appended to the names of the items, without the dot.
+  pub extension: String,
+}
+
+impl FromStr for ExportFormat {
+  type Err = Box<Error>;
+
+  /// Parses a `<kind>=<extension>` format, where the kind is the last part
+  /// of the mime type of native Google items, such as `document`,
+  /// `spreadsheet`, `presentation` or `drawing`, and the extension one of
+  /// those Drive exports to, e.g. `document=docx` or `presentation=pdf`.
+  fn from_str(spec: &str) -> Result<ExportFormat, Box<Error>> {
+    let mut parts = spec.splitn(2, '=');
+    let (kind, extension) = match (parts.next(), parts.next()) {
+      (Some(kind), Some(extension)) if !kind.is_empty() => (kind, extension.to_lowercase()),
+      _ => {
+        return Err(From::from(format!(
+          "Invalid export format {}, expected <kind>=<extension>",
+          spec
+        )))
+      }
+    };
+    let mime_type = match EXPORT_MIME_TYPES
+      .iter()
+      .find(|&&(known, _)| known == extension)
+    {
+      Some(&(_, mime_type)) => mime_type,
+      None => {
+        return Err(From::from(format!(
+          "Unknown export extension {} in {}",
+          extension, spec
+        )))
+      }
+    };
+    Ok(ExportFormat {
+      google_mime_type: format!("{}{}", GOOGLE_APPS_MIME_PREFIX, kind),
+      mime_type: mime_type.into(),
+      extension: extension,
+    })
+  }
+}
+
+/// Exports the native Google item `file_id` as `mime_type`, from the url of
+/// its resolver::ContentSource::Export source.
+pub fn export(
+  auth: &mut oauth::GoogleAuthenticator,
+  file_id: &str,
+  mime_type: &str,
+) -> Result<Vec<u8>, Box<Error>> {
+  let url = resolver::ContentSource::Export(mime_type.into()).url(file_id);
+  let token = auth.api_key().unwrap();
+  let mut resp = try!(common::new_hyper_tls_client()
+    .get(&url)
+    .header(hyper::header::Authorization(hyper::header::Bearer {
+      token: token,
+    }))
+    .send());
+  if !resp.status.is_success() {
+    let mut body: String = String::new();
+    try!(resp.read_to_string(&mut body));
+    let err = drive_error::DriveError::parse(resp.status, &body);
+    warn!("Export error: {}", err);
+    auth.rotate_on_quota_error(&err);
+    return Err(Box::new(err));
+  }
+  let mut content = Vec::new();
+  try!(resp.read_to_end(&mut content));
+  Ok(content)
+}
+
+/// Remembers that the export of `file_id`, as modified at `modified_time`,
+/// is `size` bytes long.
+pub fn record_export_size(file_id: &str, modified_time: time::Timespec, size: u64) {
+  EXPORT_SIZES
+    .lock()
+    .unwrap()
+    .insert(file_id.into(), (modified_time, size));
+}
+
+/// The size of the last export of `file_id`, if it was exported since it was
+/// last modified at `modified_time`.
+pub fn export_size(file_id: &str, modified_time: time::Timespec) -> Option<u64> {
+  match EXPORT_SIZES.lock().unwrap().get(file_id) {
+    Some(&(exported_time, size)) if exported_time == modified_time => Some(size),
+    _ => None,
+  }
+}
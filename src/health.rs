@@ -0,0 +1,78 @@
+use oauth;
+use std;
+use std::sync::atomic;
+use std::thread;
+use std::time::Duration;
+
+// unix time of the last successful request to Google Drive.
+static LAST_SUCCESS: atomic::AtomicUsize = atomic::ATOMIC_USIZE_INIT;
+// failed requests since the last successful one.
+static FAILURES: atomic::AtomicUsize = atomic::ATOMIC_USIZE_INIT;
+// read worker threads found dead since the last successful request.
+static DEAD_WORKERS: atomic::AtomicUsize = atomic::ATOMIC_USIZE_INIT;
+
+fn now() -> usize {
+  ::time::get_time().sec as usize
+}
+
+/// Records a successful request to Google Drive.
+pub fn record_success() {
+  LAST_SUCCESS.store(now(), atomic::Ordering::SeqCst);
+  FAILURES.store(0, atomic::Ordering::SeqCst);
+  DEAD_WORKERS.store(0, atomic::Ordering::SeqCst);
+}
+
+/// Records a failed request to Google Drive.
+pub fn record_failure() {
+  FAILURES.fetch_add(1, atomic::Ordering::SeqCst);
+}
+
+/// Records a read worker thread that stopped taking requests.
+pub fn record_dead_worker() {
+  DEAD_WORKERS.fetch_add(1, atomic::Ordering::SeqCst);
+}
+
+/// Starts a background thread that watches for requests to Google Drive
+/// failing for longer than `stall`, and then tries to recover by refreshing
+/// the tokens of `auths` over new connections. Dead read workers are
+/// respawned as they're found, see GDriveFS::read.
+pub fn start_monitor(auths: Vec<oauth::GoogleAuthenticator>, stall: Duration) {
+  LAST_SUCCESS.store(now(), atomic::Ordering::SeqCst);
+  let check_interval = std::cmp::min(stall, Duration::from_secs(60));
+  thread::Builder::new()
+    .name(String::from("health_monitor"))
+    .spawn(move || {
+      // start of the current incident, if any.
+      let mut incident: Option<usize> = None;
+      loop {
+        thread::sleep(check_interval);
+        let failures = FAILURES.load(atomic::Ordering::SeqCst);
+        let dead_workers = DEAD_WORKERS.load(atomic::Ordering::SeqCst);
+        let since_success = now().saturating_sub(LAST_SUCCESS.load(atomic::Ordering::SeqCst));
+        let wedged = (failures > 0 || dead_workers > 0) && since_success >= stall.as_secs() as usize;
+        match (wedged, incident) {
+          (true, _) => {
+            if incident.is_none() {
+              incident = Some(now() - since_success);
+            }
+            warn!(
+              "health: no successful drive request in {}s ({} failed requests, {} dead read workers), refreshing tokens",
+              since_success, failures, dead_workers
+            );
+            for auth in &auths {
+              auth.reset();
+            }
+          }
+          (false, Some(start)) if failures == 0 => {
+            warn!(
+              "health: recovered, drive requests failed for {}s",
+              now().saturating_sub(start)
+            );
+            incident = None;
+          }
+          _ => {}
+        }
+      }
+    })
+    .unwrap();
+}
@@ -3,31 +3,95 @@ extern crate hyper;
 extern crate libc;
 extern crate poolcache;
 
+use autotune;
 use common;
 use constants;
+use container;
+use deadline;
+use disk_cache;
+use drive_error;
+use error_rates;
+use health;
 use oauth;
 use oauth::GetToken;
+use read_policy;
+use resolver;
+use scheduler;
 use std::cmp;
+use std::collections::BTreeMap;
 use std::collections::VecDeque;
 use std::convert::From;
 use std::error::Error;
+use std::fmt;
 use std::io::Read;
+use std::str::FromStr;
 use std::sync;
 use std::thread;
+use std::time::Duration;
 
-// RangeReader reads byte ranges from an http url
+// number of bytes read from a response between checks for cancellation.
+const READ_PIECE_SIZE: usize = 64 * 1024;
+// wait before the first retry of a failed download, doubled on each retry.
+const RETRY_BACKOFF_MILLIS: u64 = 250;
+// the backoff stops doubling after this many retries.
+const MAX_BACKOFF_SHIFT: u32 = 5;
+
+// a network error which retrying the request may fix: the connection
+// failed or broke off. Refusals by Drive are DriveErrors.
+#[derive(Debug)]
+struct TransientError(String);
+
+impl fmt::Display for TransientError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", self.0)
+  }
+}
+
+impl Error for TransientError {
+  fn description(&self) -> &str {
+    &self.0
+  }
+}
+
+fn transient<E: fmt::Display>(err: E) -> Box<Error> {
+  Box::new(TransientError(err.to_string()))
+}
+
+// whether retrying the request that failed with |err| may fix it.
+fn is_transient(err: &(Error + 'static)) -> bool {
+  err.is::<TransientError>()
+    || err
+      .downcast_ref::<drive_error::DriveError>()
+      .map_or(false, |err| err.is_transient())
+}
+
+// RangeReader reads byte ranges from the content of a file
 struct RangeReader {
   client: hyper::Client,
   authenticator: oauth::GoogleAuthenticator,
-  file_url: String,
+  file_id: String,
+  source: resolver::ContentSource,
+  // times a request failing with a TransientError is retried.
+  retries: u32,
+  // gives out the download slots requests are made in.
+  scheduler: sync::Arc<scheduler::DownloadScheduler>,
 }
 
 impl RangeReader {
-  fn new(file_url: &str, authenticator: oauth::GoogleAuthenticator) -> RangeReader {
+  fn new(
+    file_id: &str,
+    source: resolver::ContentSource,
+    authenticator: oauth::GoogleAuthenticator,
+    retries: u32,
+    scheduler: sync::Arc<scheduler::DownloadScheduler>,
+  ) -> RangeReader {
     RangeReader {
       client: common::new_hyper_tls_client(),
       authenticator: authenticator,
-      file_url: file_url.into(),
+      file_id: file_id.into(),
+      source: source,
+      retries: retries,
+      scheduler: scheduler,
     }
   }
 
@@ -35,29 +99,196 @@ impl RangeReader {
   // this uses the same semantics as http Range, notably:
   // - the range is inclusive, so 0-499 reads 500 bytes.
   // - |end| may be past EOF, in which case available data is returned.
-  fn read_range(&mut self, start: u64, end: u64, buf: &mut Vec<u8>) -> Result<(), Box<Error>> {
+  // The read is abandoned if |cancelled| gets set while it's in progress.
+  // |progress| is called with the data received so far each time more
+  // arrives.
+  fn read_range(
+    &mut self,
+    start: u64,
+    end: u64,
+    buf: &mut Vec<u8>,
+    cancelled: &sync::atomic::AtomicBool,
+    progress: &mut FnMut(&[u8]),
+  ) -> Result<(), Box<Error>> {
     let token = self.authenticator.api_key().unwrap();
+    let url = self.source.url(&self.file_id);
+    let started = std::time::Instant::now();
     let request = self
       .client
-      .get(&self.file_url)
+      .get(&url)
       .header(hyper::header::Range::bytes(start, end))
       .header(hyper::header::Authorization(hyper::header::Bearer {
         token: token,
       }));
-    let mut resp = try!(request.send());
+    let mut resp = match request.send() {
+      Ok(resp) => resp,
+      Err(err) => {
+        health::record_failure();
+        error_rates::record_error(error_rates::ErrorKind::Network);
+        return Err(transient(err));
+      }
+    };
+    // the range starts at or past the end of the file.
+    if resp.status == hyper::status::StatusCode::RangeNotSatisfiable {
+      health::record_success();
+      self.scheduler.retry_budget().record_success();
+      return Ok(());
+    }
     if !resp.status.is_success() {
-      let mut err: String = String::new();
-      try!(resp.read_to_string(&mut err));
-      warn!("Read error result: {}", err);
-      return Err(Box::new(hyper::error::Error::Status));
+      health::record_failure();
+      let mut body: String = String::new();
+      try!(resp.read_to_string(&mut body));
+      let err = drive_error::DriveError::parse(resp.status, &body);
+      // failures are summed up by error_rates rather than logged one by one.
+      debug!("Read error: {}", err);
+      if err.is_download_quota() {
+        error_rates::record_download_quota(&self.file_id);
+      } else {
+        error_rates::record_error(if err.is_rate_limit() {
+          error_rates::ErrorKind::RateLimit
+        } else if err.is_transient() {
+          error_rates::ErrorKind::Server
+        } else {
+          error_rates::ErrorKind::Other
+        });
+      }
+      // a request with the next client may go through.
+      if self.authenticator.rotate_on_quota_error(&err) {
+        return Err(transient(err));
+      }
+      return Err(Box::new(err));
+    }
+    health::record_success();
+    self.scheduler.retry_budget().record_success();
+    let latency = started.elapsed();
+    let initial_len = buf.len();
+    let mut piece = [0u8; READ_PIECE_SIZE];
+    loop {
+      if cancelled.load(sync::atomic::Ordering::SeqCst) {
+        return Err(From::from("read cancelled"));
+      }
+      let read = resp.read(&mut piece).map_err(|err| {
+        error_rates::record_error(error_rates::ErrorKind::Network);
+        transient(err)
+      });
+      match try!(read) {
+        0 => {
+          autotune::record_download(latency, started.elapsed(), (buf.len() - initial_len) as u64);
+          return Ok(());
+        }
+        n => {
+          buf.extend_from_slice(&piece[..n]);
+          progress(&buf[initial_len..]);
+        }
+      }
+    }
+  }
+
+  // As above, but using a start + size rather than a range, and retrying
+  // transient errors with backoff while the retry budget of the mount allows
+  // it, or if |block|, until the read succeeds or |abandoned| returns true.
+  // Each attempt is made in a download slot of |priority|, given back while
+  // waiting to retry. Reading 0 bytes makes no request.
+  fn read_bytes(
+    &mut self,
+    start: u64,
+    size: u64,
+    buf: &mut Vec<u8>,
+    priority: scheduler::Priority,
+    cancelled: &sync::atomic::AtomicBool,
+    block: bool,
+    abandoned: &Fn() -> bool,
+    progress: &mut FnMut(&[u8]),
+  ) -> Result<(), Box<Error>> {
+    let end = match range_end(start, size) {
+      Some(end) => end,
+      None => return Ok(()),
+    };
+    let initial_len = buf.len();
+    let mut attempt = 0;
+    loop {
+      let result = {
+        let scheduler = self.scheduler.clone();
+        let _slot = scheduler.acquire(priority);
+        // the file may have been closed while waiting for a slot.
+        if cancelled.load(sync::atomic::Ordering::SeqCst) {
+          return Err(From::from("read cancelled"));
+        }
+        self.read_range(start, end, buf, cancelled, progress)
+      };
+      let err = match result {
+        Ok(()) => {
+          error_rates::record_success();
+          return Ok(());
+        }
+        Err(err) => err,
+      };
+      if cancelled.load(sync::atomic::Ordering::SeqCst) {
+        return Err(err);
+      }
+      let give_up = if block {
+        abandoned()
+      } else {
+        attempt >= self.retries || !self.scheduler.retry_budget().acquire()
+      };
+      if !is_transient(&*err) || give_up {
+        error_rates::record_failed();
+        return Err(err);
+      }
+      error_rates::record_retry();
+      let backoff =
+        Duration::from_millis(RETRY_BACKOFF_MILLIS << cmp::min(attempt, MAX_BACKOFF_SHIFT));
+      debug!(
+        "Retrying read of {} in {}ms: {}",
+        self.file_id,
+        backoff.as_secs() * 1000 + backoff.subsec_millis() as u64,
+        err
+      );
+      thread::sleep(backoff);
+      buf.truncate(initial_len);
+      attempt = attempt.saturating_add(1);
     }
-    try!(resp.read_to_end(buf));
-    Ok(())
   }
+}
+
+/// What a read does when the data it needs isn't cached and can't be
+/// downloaded, because the network or Drive is down or, with
+/// `max_uncached_file_size`, the download is refused. Programs differ in
+/// which of these they cope with: media players tend to retry on EAGAIN,
+/// while backup tools handle EIO as a failed file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UncachedReadAction {
+  /// Fail the read with EIO.
+  Eio,
+  /// Fail the read with EAGAIN.
+  Eagain,
+  /// Keep retrying the download until it succeeds, so the read waits for
+  /// connectivity to return, or until the read misses its deadline or the
+  /// file is closed. Refused downloads fail with EIO.
+  Block,
+}
 
-  // As above, but using a start + size rather than a range.
-  fn read_bytes(&mut self, start: u64, size: u64, buf: &mut Vec<u8>) -> Result<(), Box<Error>> {
-    self.read_range(start, start + size - 1, buf)
+impl UncachedReadAction {
+  /// The error a read fails with, when it fails.
+  pub fn errno(&self) -> libc::c_int {
+    match *self {
+      UncachedReadAction::Eagain => libc::EAGAIN,
+      UncachedReadAction::Eio | UncachedReadAction::Block => libc::EIO,
+    }
+  }
+}
+
+impl FromStr for UncachedReadAction {
+  type Err = Box<Error>;
+
+  /// Parses one of `eio`, `eagain` or `block`.
+  fn from_str(spec: &str) -> Result<UncachedReadAction, Box<Error>> {
+    match spec {
+      "eio" => Ok(UncachedReadAction::Eio),
+      "eagain" => Ok(UncachedReadAction::Eagain),
+      "block" => Ok(UncachedReadAction::Block),
+      _ => Err(From::from("expected eio, eagain or block")),
+    }
   }
 }
 
@@ -78,25 +309,184 @@ pub struct FileReadOptions {
   /// request to Google Drive. For example, a value of 1024 here would
   /// cause files to be retrieved in 4MB chunks.
   pub read_block_multiplier: u32,
+
+  /// If set, reads of files larger than this many bytes fail unless the data
+  /// is already in the cache, to avoid large downloads on metered networks.
+  pub max_uncached_file_size: Option<u64>,
+
+  /// The maximum number of chunk downloads in progress at once, across all
+  /// files. A value of `0` allows any number of them.
+  pub max_concurrent_downloads: usize,
+
+  /// The number of most recently read files that readahead is done for. A
+  /// value of `0` allows readahead on any number of files.
+  pub max_readahead_files: usize,
+
+  /// The number of reads waiting for a download from which readahead and
+  /// metadata refreshes on open are dropped until the backlog shrinks. A
+  /// value of `0` never drops them.
+  pub overload_backlog: usize,
+
+  /// If set, reads not answered within this time fail with EIO, rather than
+  /// leaving the reading process blocked on a stalled download.
+  pub read_deadline: Option<std::time::Duration>,
+
+  /// The number of read handles (each with its own thread and cache) kept
+  /// across all files. Handles of closed files are kept idle within this
+  /// limit, so reopening a file reuses its cache, and are recycled least
+  /// recently used first. Open files always get a handle.
+  pub max_read_handles: usize,
+
+  /// If true, the start of mp4 and mkv files is parsed when downloaded, to
+  /// prefetch their index (the moov atom or cues) if it's further in.
+  pub probe_containers: bool,
+
+  /// Rules overriding the options above for matching files, the first
+  /// matching rule applying.
+  pub read_policies: Vec<read_policy::ReadPolicy>,
+
+  /// If true, the read block multiplier and readahead queue size of files
+  /// opened after the first few downloads are chosen from the latency and
+  /// throughput measured on those downloads. Read policies still apply.
+  pub auto_tune: bool,
+
+  /// If true, opening a file starts fetching its first chunk right away,
+  /// so that the first read is likely to find it cached.
+  pub prefetch_on_open: bool,
+
+  /// The number of times a chunk download failing with a network or server
+  /// error is retried. Retries across all files are limited by a shared
+  /// budget, so they stop during outages.
+  pub read_retries: u32,
+
+  /// What reads do when their data can't be downloaded after the retries,
+  /// or is refused by `max_uncached_file_size`.
+  pub uncached_read_action: UncachedReadAction,
+
+  /// If true, a read that misses the cache is answered as soon as the bytes
+  /// it asked for have arrived, while the rest of the chunk keeps
+  /// downloading into the cache, rather than once the whole chunk is in.
+  /// Reads of the same chunk made meanwhile are answered from the partly
+  /// received chunk too, instead of queuing behind the download. This cuts
+  /// the latency of seeks with large chunks.
+  pub early_reply: bool,
+}
+
+impl FileReadOptions {
+  /// The options for reading a file named `name` of type `mime_type`, with
+  /// any auto-tuning and the first matching read policy applied.
+  pub fn for_file(&self, name: &str, mime_type: Option<&str>) -> FileReadOptions {
+    let mut options = self.clone();
+    if self.auto_tune {
+      if let Some((read_block_multiplier, readahead_queue_size)) =
+        autotune::tuning(self.file_read_cache_blocks)
+      {
+        options.read_block_multiplier = read_block_multiplier;
+        options.readahead_queue_size = readahead_queue_size;
+      }
+    }
+    if let Some(policy) = self
+      .read_policies
+      .iter()
+      .find(|policy| policy.matches(name, mime_type))
+    {
+      if let Some(readahead_queue_size) = policy.readahead_queue_size {
+        options.readahead_queue_size = readahead_queue_size;
+      }
+      if let Some(read_block_multiplier) = policy.read_block_multiplier {
+        options.read_block_multiplier = read_block_multiplier;
+      }
+      if let Some(file_read_cache_blocks) = policy.file_read_cache_blocks {
+        options.file_read_cache_blocks = file_read_cache_blocks;
+      }
+    }
+    options
+  }
+}
+
+/// Counters of the reads of one file since its read handle was created, to
+/// tell why reading that file is slow.
+#[derive(Debug, Clone, Default)]
+pub struct ReadStats {
+  /// Reads answered with data.
+  pub reads: u64,
+  /// Reads answered from a chunk already in the cache.
+  pub cache_hits: u64,
+  /// Chunks read from the disk cache rather than downloaded.
+  pub disk_cache_hits: u64,
+  /// Chunks downloaded, for reads and readahead.
+  pub chunks_fetched: u64,
+  /// Bytes downloaded.
+  pub bytes_fetched: u64,
+  /// Chunks downloaded by readahead.
+  pub readahead_chunks: u64,
+  /// Bytes downloaded by readahead that were evicted from the cache without
+  /// being read.
+  pub readahead_wasted_bytes: u64,
+  /// Chunk downloads that failed.
+  pub fetch_errors: u64,
+  // time spent on the chunks downloaded, from request to last byte.
+  fetch_time: Duration,
+}
+
+impl ReadStats {
+  /// The average time a chunk download took, if any completed.
+  pub fn average_fetch_latency(&self) -> Option<Duration> {
+    if self.chunks_fetched == 0 {
+      return None;
+    }
+    let nanos = (self.fetch_time.as_secs() as u64 * 1_000_000_000
+      + self.fetch_time.subsec_nanos() as u64)
+      / self.chunks_fetched;
+    Some(Duration::new(
+      nanos / 1_000_000_000,
+      (nanos % 1_000_000_000) as u32,
+    ))
+  }
 }
 
 // A request to read data from a file, for async handling.
 struct FileReadRequest {
   offset: u64,
   size: u32,
-  reply: Option<fuse::ReplyData>,
+  reply: Option<deadline::PendingReply>,
+  // for a read spanning several chunks, handled one chunk at a time: the
+  // data read from the chunks before this one, and the number of bytes to
+  // read past the end of this one.
+  prefix: Vec<u8>,
+  rest: u64,
 }
 
 impl FileReadRequest {
   pub fn error(self, err: libc::c_int) {
-    if let Some(reply) = self.reply {
+    if let Some(reply) = self.reply.and_then(|reply| reply.take()) {
       reply.error(err);
     }
   }
 
   pub fn data(self, data: &[u8]) {
-    if let Some(reply) = self.reply {
-      reply.data(data);
+    if let Some(reply) = self.reply.and_then(|reply| reply.take()) {
+      if self.prefix.is_empty() {
+        reply.data(data);
+      } else {
+        let mut prefix = self.prefix;
+        prefix.extend_from_slice(data);
+        reply.data(&prefix);
+      }
+    }
+  }
+
+  // answers the read with |data| without consuming the request, so that it
+  // can still be handled as a read. Later answers are dropped.
+  pub fn answer(&self, data: &[u8]) {
+    if let Some(reply) = self.reply.as_ref().and_then(|reply| reply.take()) {
+      if self.prefix.is_empty() {
+        reply.data(data);
+      } else {
+        let mut prefix = self.prefix.clone();
+        prefix.extend_from_slice(data);
+        reply.data(&prefix);
+      }
     }
   }
 
@@ -105,66 +495,272 @@ impl FileReadRequest {
   }
 }
 
+// the inclusive http range end of the |size| bytes at |start|, or None if
+// there's nothing to read. Ranges stop at the largest offset.
+fn range_end(start: u64, size: u64) -> Option<u64> {
+  if size == 0 {
+    return None;
+  }
+  Some(start.saturating_add(size - 1))
+}
+
+// the offset of the chunk of |chunk_size| bytes holding |offset|.
+fn chunk_start(offset: u64, chunk_size: u64) -> u64 {
+  (offset / chunk_size) * chunk_size
+}
+
+// splits a read of |size| bytes at |offset| at the end of the chunk of
+// |chunk_size| bytes at |chunk_offset|, into the number of bytes read from
+// that chunk and the number read past its end.
+fn split_at_chunk_end(offset: u64, size: u32, chunk_offset: u64, chunk_size: u64) -> (u32, u64) {
+  let chunk_end = chunk_offset.saturating_add(chunk_size);
+  if offset.saturating_add(size as u64) > chunk_end {
+    let within = chunk_end - offset;
+    (within as u32, size as u64 - within)
+  } else {
+    (size, 0)
+  }
+}
+
+// the bounds within the |chunk_len| bytes held for the chunk at
+// |chunk_offset| of a read of |size| bytes at |offset|. The chunk is short
+// at the end of the file, and empty past it.
+fn chunk_slice(offset: u64, size: u32, chunk_offset: u64, chunk_len: usize) -> (usize, usize) {
+  let start = cmp::min(offset - chunk_offset, chunk_len as u64);
+  let end = cmp::min(start + size as u64, chunk_len as u64);
+  (start as usize, end as usize)
+}
+
+// the offsets of the |count| chunks of |chunk_size| bytes following the one
+// at |chunk_offset|, fewer if they'd go past the largest offset.
+fn readahead_offsets(chunk_offset: u64, chunk_size: u64, count: usize) -> Vec<u64> {
+  let mut offsets = Vec::with_capacity(count);
+  let mut offset = chunk_offset;
+  for _ in 0..count {
+    offset = match offset.checked_add(chunk_size) {
+      Some(offset) => offset,
+      None => break,
+    };
+    offsets.push(offset);
+  }
+  offsets
+}
+
+// answers |req| from |received|, the first bytes of the chunk at
+// |chunk_offset|, if they hold everything it asked for. A read continuing
+// past the chunk waits for the next one.
+fn answer_from_partial(req: &FileReadRequest, chunk_offset: u64, received: &[u8]) {
+  if req.is_readahead() || req.rest > 0 {
+    return;
+  }
+  if let Some((start, end)) = partial_slice(req.offset, req.size, chunk_offset, received.len()) {
+    req.answer(&received[start..end]);
+  }
+}
+
+// the bounds within the first |received_len| bytes of the chunk at
+// |chunk_offset| of a read of |size| bytes at |offset|, if they hold all of
+// it.
+fn partial_slice(
+  offset: u64,
+  size: u32,
+  chunk_offset: u64,
+  received_len: usize,
+) -> Option<(usize, usize)> {
+  if offset < chunk_offset {
+    return None;
+  }
+  let start = offset - chunk_offset;
+  let end = start.saturating_add(size as u64);
+  if end <= received_len as u64 {
+    Some((start as usize, end as usize))
+  } else {
+    None
+  }
+}
+
 /// A handle to a a thread performing reads for a file.
 /// |incref()| should be called once for each active reader of the file,
-/// with a matching call to |decref| when the file is closed.
+/// with a matching call to |decref| when the file is closed. A handle with
+/// no readers is idle: it keeps its cache, but stops any readahead.
 pub struct FileReadHandle {
   read_chan: sync::mpsc::Sender<FileReadRequest>,
   open_count: u32,
+  // map of chunk offset -> length of the chunks currently in the cache.
+  cached_chunks: sync::Arc<sync::Mutex<BTreeMap<u64, u64>>>,
+  stats: sync::Arc<sync::Mutex<ReadStats>>,
+  // set while the file isn't open, to abandon any readahead in progress.
+  idle: sync::Arc<sync::atomic::AtomicBool>,
+}
+
+impl Drop for FileReadHandle {
+  fn drop(&mut self) {
+    // queued readahead is dropped with the channel, but a download in
+    // progress has to be told to stop.
+    self.idle.store(true, sync::atomic::Ordering::SeqCst);
+  }
 }
 
 impl FileReadHandle {
   /// Asynchronously peform a read at |offset| of size |size|, returning
   /// the results of the read directly to |reply|
-  pub fn do_read(&self, offset: u64, size: u32, reply: fuse::ReplyData) -> Result<(), String> {
+  pub fn do_read(
+    &self,
+    offset: u64,
+    size: u32,
+    reply: deadline::PendingReply,
+  ) -> Result<(), String> {
     self
       .read_chan
       .send(FileReadRequest {
         offset: offset,
         size: size,
         reply: Some(reply),
+        prefix: Vec::new(),
+        rest: 0,
       })
       .map_err(|err| err.description().into())
   }
 
+  /// Asynchronously fetch the chunk holding `offset` into the cache, as
+  /// readahead.
+  pub fn prefetch(&self, offset: u64) -> Result<(), String> {
+    self
+      .read_chan
+      .send(FileReadRequest {
+        offset: offset,
+        size: 0,
+        reply: None,
+        prefix: Vec::new(),
+        rest: 0,
+      })
+      .map_err(|err| err.description().into())
+  }
+
+  /// Whether the `size` bytes at `offset` are all held in the cache.
+  pub fn is_cached(&self, offset: u64, size: u32) -> bool {
+    let end = offset.saturating_add(cmp::max(size, 1) as u64 - 1);
+    self
+      .cached_ranges()
+      .iter()
+      .any(|&(start, range_end)| start <= offset && end <= range_end)
+  }
+
+  /// Returns the byte ranges of the file currently held in the cache, as
+  /// (start, end) pairs where `end` is inclusive, as in an http Range.
+  pub fn cached_ranges(&self) -> Vec<(u64, u64)> {
+    let mut ranges: Vec<(u64, u64)> = Vec::new();
+    for (&offset, &len) in self.cached_chunks.lock().unwrap().iter() {
+      // merge chunks that are next to each other.
+      if let Some(range) = ranges.last_mut() {
+        if range.1 + 1 == offset {
+          range.1 = offset + len - 1;
+          continue;
+        }
+      }
+      ranges.push((offset, offset + len - 1));
+    }
+    ranges
+  }
+
+  /// Returns the read counters of the file.
+  pub fn stats(&self) -> ReadStats {
+    self.stats.lock().unwrap().clone()
+  }
+
+  /// replaces the read thread of the handle with a new one, keeping the
+  /// reference count. Used when the thread stopped taking requests.
+  pub fn respawn(
+    &mut self,
+    file_id: &str,
+    version: Option<&str>,
+    source: &resolver::ContentSource,
+    auth: &oauth::GoogleAuthenticator,
+    options: &FileReadOptions,
+    scheduler: &sync::Arc<scheduler::DownloadScheduler>,
+  ) {
+    let open_count = self.open_count;
+    *self = FileReadHandle::spawn(file_id, version, source, auth, options, scheduler);
+    self.open_count = open_count;
+  }
+
   /// increase the reference count of the handle.
   pub fn incref(&mut self) {
     self.open_count += 1;
+    self.idle.store(false, sync::atomic::Ordering::SeqCst);
     debug!("after increment, open_count = {}", self.open_count);
   }
 
-  /// decrease the reference count of the handle, returning the
-  /// handle if it's still active.
-  pub fn decref(mut self) -> Option<FileReadHandle> {
+  /// decrease the reference count of the handle, making it idle once it
+  /// reaches 0.
+  pub fn decref(&mut self) {
     self.open_count -= 1;
     debug!("after decrement, open_count = {}", self.open_count);
-    match self.open_count {
-      0 => None,
-      _ => Some(self),
+    if self.open_count == 0 {
+      self.idle.store(true, sync::atomic::Ordering::SeqCst);
     }
   }
 
-  /// creates a new FileReadHandle to read data from |url| in a background thread.
+  /// whether the file is open by any reader.
+  pub fn is_open(&self) -> bool {
+    self.open_count > 0
+  }
+
+  /// creates a new FileReadHandle to read the content of |file_id| from |source|
+  /// in a background thread.
+  /// Chunks of the content at |version| (its md5) are kept in the disk cache,
+  /// if configured. Revisions are versioned by their id.
+  /// Downloads are started through |scheduler|, which is shared by all files.
   /// The returned read handle has a refcount of '0', and should be `incref()`d before use.
   pub fn spawn(
-    url: &str,
+    file_id: &str,
+    version: Option<&str>,
+    source: &resolver::ContentSource,
     auth: &oauth::GoogleAuthenticator,
     options: &FileReadOptions,
+    scheduler: &sync::Arc<scheduler::DownloadScheduler>,
   ) -> FileReadHandle {
-    let url = String::from(url);
+    let file_id = String::from(file_id);
+    let version = match *source {
+      resolver::ContentSource::Media => version.map(String::from),
+      resolver::ContentSource::Revision(ref revision_id) => Some(revision_id.clone()),
+      // exports are made anew on each request, so they aren't kept on disk.
+      resolver::ContentSource::Export(_) => None,
+    };
+    let source = source.clone();
     let auth = auth.clone();
+    let scheduler = scheduler.clone();
     let cache_size = options.file_read_cache_blocks;
     let readahead_queue_size = options.readahead_queue_size;
     let read_block_multiplier = options.read_block_multiplier;
+    let probe_containers = options.probe_containers;
+    let read_retries = options.read_retries;
+    let uncached_read_action = options.uncached_read_action;
+    let early_reply = options.early_reply;
     let (tx, rx) = sync::mpsc::channel::<FileReadRequest>();
+    let cached_chunks = sync::Arc::new(sync::Mutex::new(BTreeMap::new()));
+    let thread_cached_chunks = cached_chunks.clone();
+    let stats = sync::Arc::new(sync::Mutex::new(ReadStats::default()));
+    let thread_stats = stats.clone();
+    let idle = sync::Arc::new(sync::atomic::AtomicBool::new(false));
+    let thread_idle = idle.clone();
+    // foreground reads are never cancelled, but stop retrying once nobody
+    // waits for them.
+    let never_cancelled = sync::atomic::AtomicBool::new(false);
     thread::Builder::new()
-      .name(url.clone())
+      .name(file_id.clone())
       .spawn(move || {
         // queue of offsets to read next.
         let mut readahead: VecDeque<u64> = VecDeque::with_capacity(readahead_queue_size);
+        // map of chunk offset -> length of the chunks fetched by readahead
+        // and not read yet.
+        let mut unread_readahead: BTreeMap<u64, u64> = BTreeMap::new();
+        // requests received while a download was in progress, to handle
+        // before any newer ones.
+        let mut deferred: VecDeque<FileReadRequest> = VecDeque::new();
 
-        // reads ranges from |url|
-        let mut reader = RangeReader::new(&url, auth);
+        // reads ranges from the content of |file_id|
+        let mut reader = RangeReader::new(&file_id, source, auth, read_retries, scheduler.clone());
 
         let chunk_size: u64 = constants::BLOCK_SIZE as u64 * read_block_multiplier as u64;
 
@@ -177,13 +773,18 @@ impl FileReadHandle {
         // loop until read channel is closed.
         loop {
           // get the next request.
-          let req = match rx.try_recv() {
+          let next = match deferred.pop_front() {
+            Some(req) => Ok(req),
+            None => rx.try_recv(),
+          };
+          let mut req = match next {
             // A new request was waiting
             Ok(req) => req,
 
             // channel was closed, we can exit.
             Err(sync::mpsc::TryRecvError::Disconnected) => {
               debug!("exiting read thread on disconnect");
+              scheduler.forget_reads(&file_id);
               return;
             }
 
@@ -193,8 +794,10 @@ impl FileReadHandle {
               match readahead.pop_front() {
                 Some(offset) => FileReadRequest {
                   offset: offset,
-                  size: chunk_size as u32,
+                  size: cmp::min(chunk_size, u32::max_value() as u64) as u32,
                   reply: None,
+                  prefix: Vec::new(),
+                  rest: 0,
                 },
                 None => {
                   // no readahead, just block for the next request.
@@ -202,6 +805,7 @@ impl FileReadHandle {
                     Ok(req) => req,
                     Err(_) => {
                       debug!("exiting read thread on disconnect");
+                      scheduler.forget_reads(&file_id);
                       return;
                     }
                   }
@@ -211,33 +815,168 @@ impl FileReadHandle {
           };
 
           // handle the new request.
-          // calculate the offset of the chunk for this read.
-          let chunk_offset = (req.offset / chunk_size) * chunk_size;
-          if (req.offset + req.size as u64) > (chunk_offset + chunk_size) {
-            error!("cross chunk read not supported");
-            req.error(libc::ENOSYS);
+          if !req.is_readahead() {
+            scheduler.record_read(&file_id);
+          } else if !scheduler.may_readahead(&file_id) {
+            debug!(
+              "file: {}, other files read more recently, dropping readahead",
+              file_id
+            );
+            readahead.clear();
+            continue;
+          } else if scheduler.is_overloaded() {
+            debug!(
+              "file: {}, downloads backlogged, dropping readahead",
+              file_id
+            );
+            readahead.clear();
             continue;
           }
 
+          // calculate the offset of the chunk for this read.
+          let chunk_offset = chunk_start(req.offset, chunk_size);
+          // a read spanning several chunks is handled up to the end of this
+          // one, then continues with the next.
+          let chunk_end = chunk_offset.saturating_add(chunk_size);
+          let (within, rest) = split_at_chunk_end(req.offset, req.size, chunk_offset, chunk_size);
+          req.size = within;
+          req.rest = rest;
+
+          if !req.is_readahead() && buf_cache.contains_key(&chunk_offset) {
+            thread_stats.lock().unwrap().cache_hits += 1;
+          }
           if !buf_cache.contains_key(&chunk_offset) {
             // cache miss. If we're responding to a user request, then
             // the readahead queue isn't keeping up, or we're seeking
             // within the file. Either way, we should clear the
             // readahead queue.
             if !req.is_readahead() {
-              debug!("file: {}, cache miss, clearing readahead", url);
+              debug!("file: {}, cache miss, clearing readahead", file_id);
               readahead.clear();
             }
+            let (priority, cancelled) = if req.is_readahead() {
+              (scheduler::Priority::Background, &*thread_idle)
+            } else {
+              (scheduler::Priority::Foreground, &never_cancelled)
+            };
             let mut buf = buf_cache.take().unwrap();
             buf.clear();
-            match reader.read_bytes(chunk_offset, chunk_size, &mut buf) {
+            let disk_name = version
+              .as_ref()
+              .filter(|_| disk_cache::is_enabled())
+              .map(|version| disk_cache::chunk_name(&file_id, version, chunk_size, chunk_offset));
+            let from_disk = disk_name
+              .as_ref()
+              .map_or(false, |name| disk_cache::get(name, &mut buf));
+            let fetch_started = std::time::Instant::now();
+            // readahead isn't worth waiting for, and would hold up reads.
+            let block = uncached_read_action == UncachedReadAction::Block && !req.is_readahead();
+            let result = if from_disk {
+              Ok(())
+            } else {
+              // reads arriving meanwhile are still handled in order once the
+              // download is over, but find their answer already sent.
+              let mut answer_early = |received: &[u8]| {
+                if !early_reply {
+                  return;
+                }
+                answer_from_partial(&req, chunk_offset, received);
+                while let Ok(new_req) = rx.try_recv() {
+                  deferred.push_back(new_req);
+                }
+                for waiting in &deferred {
+                  answer_from_partial(waiting, chunk_offset, received);
+                }
+              };
+              // the read missed its deadline, or the handle was dropped.
+              let abandoned = || {
+                thread_idle.load(sync::atomic::Ordering::SeqCst)
+                  || req.reply.as_ref().map_or(true, |reply| reply.is_sent())
+              };
+              reader.read_bytes(
+                chunk_offset,
+                chunk_size,
+                &mut buf,
+                priority,
+                cancelled,
+                block,
+                &abandoned,
+                &mut answer_early,
+              )
+            };
+            match result {
               Ok(()) => {
+                let len = buf.len() as u64;
+                if from_disk {
+                  thread_stats.lock().unwrap().disk_cache_hits += 1;
+                } else {
+                  let mut stats = thread_stats.lock().unwrap();
+                  stats.chunks_fetched += 1;
+                  stats.bytes_fetched += len;
+                  stats.fetch_time += fetch_started.elapsed();
+                  if req.is_readahead() {
+                    stats.readahead_chunks += 1;
+                  }
+                }
+                if !from_disk && len > 0 {
+                  if let Some(ref name) = disk_name {
+                    disk_cache::put(name, &buf);
+                  }
+                }
+                if req.is_readahead() {
+                  unread_readahead.insert(chunk_offset, len);
+                }
+                // fetch the index of videos stored at the end early, since
+                // players read it right after the header.
+                let index_offset = if probe_containers && chunk_offset == 0 {
+                  container::index_offset(&buf)
+                } else {
+                  None
+                };
+                if let Some(index_offset) = index_offset {
+                  let index_chunk = chunk_start(index_offset, chunk_size);
+                  if !buf_cache.contains_key(&index_chunk) {
+                    debug!(
+                      "file: {}, prefetching video index at {}",
+                      file_id, index_offset
+                    );
+                    readahead.push_front(index_chunk);
+                  }
+                }
                 buf_cache.insert(chunk_offset, buf);
+                // the cache doesn't report evictions, so drop any chunk it
+                // no longer holds.
+                let mut cached = thread_cached_chunks.lock().unwrap();
+                let evicted: Vec<u64> = cached
+                  .keys()
+                  .cloned()
+                  .filter(|offset| !buf_cache.contains_key(offset))
+                  .collect();
+                for offset in evicted {
+                  cached.remove(&offset);
+                  if let Some(wasted) = unread_readahead.remove(&offset) {
+                    thread_stats.lock().unwrap().readahead_wasted_bytes += wasted;
+                  }
+                }
+                if len > 0 {
+                  cached.insert(chunk_offset, len);
+                }
+              }
+              Err(_) if cancelled.load(sync::atomic::Ordering::SeqCst) => {
+                debug!("file: {}, closed, abandoned readahead", file_id);
+                buf_cache.put(buf);
+                readahead.clear();
+                continue;
               }
               Err(err) => {
-                error!("Read error for url: {} : {:?}", url, err);
+                error!("Read error for file: {} : {:?}", file_id, err);
+                thread_stats.lock().unwrap().fetch_errors += 1;
                 buf_cache.put(buf);
-                req.error(libc::EIO);
+                if is_transient(&*err) {
+                  req.error(uncached_read_action.errno());
+                } else {
+                  req.error(drive_error::errno(&*err));
+                }
                 continue;
               }
             }
@@ -250,19 +989,35 @@ impl FileReadHandle {
           {
             // scope for block cache borrow.
             let chunk_data: &Vec<u8> = buf_cache.get(&chunk_offset).unwrap();
-            let start: usize = (req.offset - chunk_offset) as usize;
-            let end: usize = cmp::min(start + req.size as usize, chunk_data.len() - 1);
+            let (start, end) = chunk_slice(req.offset, req.size, chunk_offset, chunk_data.len());
             let slice = &chunk_data[start..end];
-            req.data(slice);
+            if req.rest > 0 && slice.len() == req.size as usize {
+              // the rest is read next, before any other request.
+              let mut prefix = req.prefix;
+              prefix.extend_from_slice(slice);
+              deferred.push_front(FileReadRequest {
+                offset: chunk_end,
+                size: req.rest as u32,
+                reply: req.reply,
+                prefix: prefix,
+                rest: 0,
+              });
+            } else {
+              req.data(slice);
+              thread_stats.lock().unwrap().reads += 1;
+            }
           }
+          unread_readahead.remove(&chunk_offset);
 
           // schedule readahead.
-          let mut readahead_offset = chunk_offset + chunk_size;
-          for _ in 0..readahead_queue_size {
+          if !scheduler.may_readahead(&file_id) || scheduler.is_overloaded() {
+            continue;
+          }
+          for readahead_offset in readahead_offsets(chunk_offset, chunk_size, readahead_queue_size)
+          {
             if !buf_cache.contains_key(&readahead_offset) {
               readahead.push_back(readahead_offset);
             }
-            readahead_offset += chunk_size;
           }
         } // loop
       })
@@ -271,6 +1026,218 @@ impl FileReadHandle {
     FileReadHandle {
       read_chan: tx,
       open_count: 0,
+      cached_chunks: cached_chunks,
+      stats: stats,
+      idle: idle,
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const CHUNK_SIZE: u64 = 4 * 1024 * 1024;
+  // offsets past 4GB, which a 32-bit offset can't hold, and past 1TB.
+  const FOUR_GB: u64 = 1 << 32;
+  const ONE_TB: u64 = 1 << 40;
+
+  #[test]
+  fn range_end_past_4gb() {
+    assert_eq!(range_end(FOUR_GB - 1, 2), Some(FOUR_GB));
+    assert_eq!(
+      range_end(FOUR_GB + 1, CHUNK_SIZE),
+      Some(FOUR_GB + CHUNK_SIZE)
+    );
+    assert_eq!(range_end(ONE_TB, CHUNK_SIZE), Some(ONE_TB + CHUNK_SIZE - 1));
+  }
+
+  #[test]
+  fn chunk_start_past_4gb() {
+    assert_eq!(chunk_start(FOUR_GB - 1, CHUNK_SIZE), FOUR_GB - CHUNK_SIZE);
+    assert_eq!(chunk_start(FOUR_GB, CHUNK_SIZE), FOUR_GB);
+    assert_eq!(chunk_start(FOUR_GB + 1, CHUNK_SIZE), FOUR_GB);
+    assert_eq!(chunk_start(ONE_TB + CHUNK_SIZE - 1, CHUNK_SIZE), ONE_TB);
+  }
+
+  #[test]
+  fn split_read_across_4gb() {
+    // a read of 2 bytes straddling the 4GB boundary, which is also a chunk
+    // boundary.
+    let chunk_offset = chunk_start(FOUR_GB - 1, CHUNK_SIZE);
+    assert_eq!(
+      split_at_chunk_end(FOUR_GB - 1, 2, chunk_offset, CHUNK_SIZE),
+      (1, 1)
+    );
+    assert_eq!(
+      split_at_chunk_end(FOUR_GB + 1, 2, FOUR_GB, CHUNK_SIZE),
+      (2, 0)
+    );
+    assert_eq!(
+      split_at_chunk_end(ONE_TB - 1, 131072, ONE_TB - CHUNK_SIZE, CHUNK_SIZE),
+      (1, 131071)
+    );
+  }
+
+  #[test]
+  fn chunk_slice_past_4gb() {
+    let len = CHUNK_SIZE as usize;
+    assert_eq!(chunk_slice(FOUR_GB + 1, 4096, FOUR_GB, len), (1, 4097));
+    assert_eq!(
+      chunk_slice(FOUR_GB - 1, 1, FOUR_GB - CHUNK_SIZE, len),
+      (len - 1, len)
+    );
+    assert_eq!(chunk_slice(ONE_TB + 4096, 4096, ONE_TB, len), (4096, 8192));
+  }
+
+  #[test]
+  fn readahead_past_4gb() {
+    assert_eq!(
+      readahead_offsets(FOUR_GB - CHUNK_SIZE, CHUNK_SIZE, 2),
+      vec![FOUR_GB, FOUR_GB + CHUNK_SIZE]
+    );
+    assert_eq!(
+      readahead_offsets(ONE_TB, CHUNK_SIZE, 3),
+      vec![
+        ONE_TB + CHUNK_SIZE,
+        ONE_TB + 2 * CHUNK_SIZE,
+        ONE_TB + 3 * CHUNK_SIZE
+      ]
+    );
+  }
+
+  // reads |size| bytes at |offset| of |file| the way the read thread does,
+  // one chunk of |chunk_size| bytes at a time, carrying the bytes read so far
+  // over to the next chunk as the prefix of the rest.
+  fn read_through_chunks(file: &[u8], offset: u64, size: u32, chunk_size: u64) -> Vec<u8> {
+    let mut prefix = Vec::new();
+    let (mut offset, mut size) = (offset, size);
+    loop {
+      let chunk_offset = chunk_start(offset, chunk_size);
+      let (within, rest) = split_at_chunk_end(offset, size, chunk_offset, chunk_size);
+      let chunk_data = if chunk_offset < file.len() as u64 {
+        let chunk_end = cmp::min(chunk_offset + chunk_size, file.len() as u64);
+        &file[chunk_offset as usize..chunk_end as usize]
+      } else {
+        &file[..0]
+      };
+      let (start, end) = chunk_slice(offset, within, chunk_offset, chunk_data.len());
+      let slice = &chunk_data[start..end];
+      prefix.extend_from_slice(slice);
+      if rest == 0 || slice.len() != within as usize {
+        return prefix;
+      }
+      offset = chunk_offset + chunk_size;
+      size = rest as u32;
+    }
+  }
+
+  #[test]
+  fn empty_reads_make_no_request() {
+    assert_eq!(range_end(0, 0), None);
+    assert_eq!(range_end(u64::max_value(), 0), None);
+  }
+
+  #[test]
+  fn ranges_stop_at_largest_offset() {
+    let max = u64::max_value();
+    assert_eq!(range_end(max, 1), Some(max));
+    assert_eq!(range_end(max - 1, CHUNK_SIZE), Some(max));
+    assert_eq!(range_end(0, max), Some(max - 1));
+  }
+
+  #[test]
+  fn reads_near_largest_offset() {
+    let max = u64::max_value();
+    let chunk_offset = chunk_start(max, CHUNK_SIZE);
+    assert_eq!(chunk_offset, max - max % CHUNK_SIZE);
+    assert_eq!(
+      split_at_chunk_end(max - 1, 4096, chunk_offset, CHUNK_SIZE),
+      (4096, 0)
+    );
+    assert_eq!(chunk_slice(max - 1, 4096, chunk_offset, 0), (0, 0));
+    assert!(readahead_offsets(chunk_offset, CHUNK_SIZE, 4).is_empty());
+    assert_eq!(
+      readahead_offsets(chunk_offset - CHUNK_SIZE, CHUNK_SIZE, 4),
+      vec![chunk_offset]
+    );
+    assert_eq!(partial_slice(max - 1, 4096, 0, 1024), None);
+  }
+
+  #[test]
+  fn slices_keep_last_byte() {
+    // the slice used to end at len() - 1, dropping the last byte of files.
+    assert_eq!(chunk_slice(9, 1, 0, 10), (9, 10));
+    assert_eq!(chunk_slice(0, 4096, 0, 10), (0, 10));
+    assert_eq!(chunk_slice(0, 4096, 0, 0), (0, 0));
+    let file: Vec<u8> = (0..10).collect();
+    assert_eq!(read_through_chunks(&file, 0, 4096, 8), file);
+    assert_eq!(read_through_chunks(&file, 9, 1, 8), vec![9]);
+  }
+
+  #[test]
+  fn reads_ending_at_boundaries() {
+    let file: Vec<u8> = (0..24).collect();
+    // at the end of a chunk, which is read alone.
+    assert_eq!(split_at_chunk_end(0, 8, 0, 8), (8, 0));
+    assert_eq!(read_through_chunks(&file, 4, 4, 8), vec![4, 5, 6, 7]);
+    // at the end of the file.
+    assert_eq!(read_through_chunks(&file, 20, 4, 8), vec![20, 21, 22, 23]);
+    // from the end of the file.
+    assert!(read_through_chunks(&file, 24, 4, 8).is_empty());
+  }
+
+  #[test]
+  fn reads_across_chunks() {
+    // 4 bytes from the end of one chunk, then the rest from the next one
+    // with those 4 as the prefix.
+    assert_eq!(split_at_chunk_end(4, 12, 0, 8), (4, 8));
+    assert_eq!(split_at_chunk_end(8, 8, 8, 8), (8, 0));
+    let file: Vec<u8> = (0..24).collect();
+    assert_eq!(
+      read_through_chunks(&file, 4, 12, 8),
+      (4..16).collect::<Vec<u8>>()
+    );
+    assert_eq!(
+      read_through_chunks(&file, 4, 100, 8),
+      (4..24).collect::<Vec<u8>>()
+    );
+    // the second chunk is short.
+    let file: Vec<u8> = (0..10).collect();
+    assert_eq!(
+      read_through_chunks(&file, 6, 8, 8),
+      (6..10).collect::<Vec<u8>>()
+    );
+  }
+
+  #[test]
+  fn chunked_reads_match_file() {
+    let chunk_size = 8;
+    for &len in &[0, 1, 7, 8, 9, 16, 17, 23, 24] {
+      let file: Vec<u8> = (0..len as u8).collect();
+      for offset in 0..len + 10 {
+        for size in 0..(3 * chunk_size as u32 + 2) {
+          let start = cmp::min(offset, len) as usize;
+          let end = cmp::min(offset + size as u64, len) as usize;
+          assert_eq!(
+            read_through_chunks(&file, offset, size, chunk_size),
+            &file[start..end],
+            "len {} offset {} size {}",
+            len,
+            offset,
+            size
+          );
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn partial_answers_need_whole_read() {
+    assert_eq!(partial_slice(4, 4, 0, 8), Some((4, 8)));
+    assert_eq!(partial_slice(4, 4, 0, 7), None);
+    assert_eq!(partial_slice(8, 0, 8, 0), Some((0, 0)));
+    // a read of an earlier chunk.
+    assert_eq!(partial_slice(4, 4, 8, 8), None);
+  }
+}
@@ -0,0 +1,268 @@
+extern crate hyper;
+
+use common;
+use drive_error;
+use oauth;
+use oauth::GetToken;
+use std::error::Error;
+use std::io::Read;
+
+const FILE_GET_URL: &'static str = "https://www.googleapis.com/drive/v3/files";
+
+/// Name of the file at the root of the drive holding the ignore rules.
+pub const IGNORE_FILE_NAME: &'static str = ".gdriveignore";
+
+/// Rules in gitignore syntax deciding which paths are hidden from the mount.
+#[derive(Debug, Clone, Default)]
+pub struct IgnoreRules {
+  rules: Vec<Rule>,
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+  pattern: String,
+  // a rule starting with ! makes matching paths visible again.
+  negated: bool,
+  // a rule ending with / only matches folders.
+  dir_only: bool,
+  // a rule containing a / (other than at the end) matches the whole path
+  // from the root, and any other rule the name alone.
+  anchored: bool,
+}
+
+impl IgnoreRules {
+  /// Parses rules in gitignore syntax, one per line.
+  pub fn parse(text: &str) -> IgnoreRules {
+    let mut rules = Vec::new();
+    for line in text.lines() {
+      let line = line.trim_right();
+      if line.is_empty() || line.starts_with('#') {
+        continue;
+      }
+      // a leading \ escapes a # or !.
+      let (negated, line) = if line.starts_with('!') {
+        (true, &line[1..])
+      } else if line.starts_with("\\#") || line.starts_with("\\!") {
+        (false, &line[1..])
+      } else {
+        (false, line)
+      };
+      let (dir_only, line) = if line.ends_with('/') {
+        (true, line.trim_right_matches('/'))
+      } else {
+        (false, line)
+      };
+      if line.is_empty() {
+        continue;
+      }
+      rules.push(Rule {
+        pattern: line.trim_left_matches('/').into(),
+        negated: negated,
+        dir_only: dir_only,
+        anchored: line.contains('/'),
+      });
+    }
+    IgnoreRules { rules: rules }
+  }
+
+  /// Whether the item at `path`, relative to the root of the mount and
+  /// without a leading /, is hidden. As in gitignore, the last matching rule
+  /// decides.
+  pub fn is_ignored(&self, path: &str, is_dir: bool) -> bool {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    let mut ignored = false;
+    for rule in &self.rules {
+      if rule.dir_only && !is_dir {
+        continue;
+      }
+      let subject = if rule.anchored { path } else { name };
+      if glob_match(rule.pattern.as_bytes(), subject.as_bytes()) {
+        ignored = !rule.negated;
+      }
+    }
+    ignored
+  }
+}
+
+// matches |text| against the glob |pattern|, where * and ? don't match a /
+// but ** matches across folders.
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+  match pattern.first() {
+    None => text.is_empty(),
+    Some(&b'*') if pattern.get(1) == Some(&b'*') => {
+      let rest = &pattern[2..];
+      // "**/" also matches no folder at all.
+      if rest.first() == Some(&b'/') && glob_match(&rest[1..], text) {
+        return true;
+      }
+      (0..text.len() + 1).any(|i| glob_match(rest, &text[i..]))
+    }
+    Some(&b'*') => {
+      for i in 0..text.len() + 1 {
+        if glob_match(&pattern[1..], &text[i..]) {
+          return true;
+        }
+        if i < text.len() && text[i] == b'/' {
+          break;
+        }
+      }
+      false
+    }
+    Some(&b'?') => match text.first() {
+      Some(&c) if c != b'/' => glob_match(&pattern[1..], &text[1..]),
+      _ => false,
+    },
+    Some(&b'[') => match (
+      pattern.iter().skip(2).position(|&c| c == b']'),
+      text.first(),
+    ) {
+      (Some(end), Some(&c)) => {
+        let class = &pattern[1..end + 2];
+        class_match(class, c) && glob_match(&pattern[end + 3..], &text[1..])
+      }
+      // an unclosed [ is a plain character.
+      (None, Some(&c)) => c == b'[' && glob_match(&pattern[1..], &text[1..]),
+      (_, None) => false,
+    },
+    Some(&b'\\') if pattern.len() > 1 => {
+      text.first() == Some(&pattern[1]) && glob_match(&pattern[2..], &text[1..])
+    }
+    Some(&c) => text.first() == Some(&c) && glob_match(&pattern[1..], &text[1..]),
+  }
+}
+
+// whether |c| is in the character class |class|, the inside of a [...].
+fn class_match(class: &[u8], c: u8) -> bool {
+  let (negated, class) = match class.first() {
+    Some(&b'!') | Some(&b'^') => (true, &class[1..]),
+    _ => (false, class),
+  };
+  let mut found = false;
+  let mut i = 0;
+  while i < class.len() {
+    if i + 2 < class.len() && class[i + 1] == b'-' {
+      found |= class[i] <= c && c <= class[i + 2];
+      i += 3;
+    } else {
+      found |= class[i] == c;
+      i += 1;
+    }
+  }
+  found != negated
+}
+
+/// Downloads and parses the ignore rules stored in `file_id`.
+pub fn fetch_rules(
+  auth: &mut oauth::GoogleAuthenticator,
+  file_id: &str,
+) -> Result<IgnoreRules, Box<Error>> {
+  let url = format!("{}/{}?alt=media", FILE_GET_URL, file_id);
+  let token = auth.api_key().unwrap();
+  let mut resp = try!(common::new_hyper_tls_client()
+    .get(&url)
+    .header(hyper::header::Authorization(hyper::header::Bearer {
+      token: token,
+    }))
+    .send());
+  let mut text = String::new();
+  try!(resp.read_to_string(&mut text));
+  if !resp.status.is_success() {
+    let err = drive_error::DriveError::parse(resp.status, &text);
+    warn!("Ignore file get error: {}", err);
+    return Err(Box::new(err));
+  }
+  Ok(IgnoreRules::parse(&text))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn glob(pattern: &str, text: &str) -> bool {
+    glob_match(pattern.as_bytes(), text.as_bytes())
+  }
+
+  #[test]
+  fn glob_stars_stay_within_folders() {
+    assert!(glob("*.txt", "a.txt"));
+    assert!(glob("*", ""));
+    assert!(!glob("*.txt", "a/b.txt"));
+    assert!(glob("a?c", "abc"));
+    assert!(!glob("a?c", "a/c"));
+    assert!(!glob("a?c", "ac"));
+    assert!(glob("\\*", "*"));
+    assert!(!glob("\\*", "a"));
+  }
+
+  #[test]
+  fn glob_double_star_crosses_folders() {
+    assert!(glob("**/foo", "foo"));
+    assert!(glob("**/foo", "a/b/foo"));
+    assert!(!glob("**/foo", "a/bfoo"));
+    assert!(glob("a/**/b", "a/b"));
+    assert!(glob("a/**/b", "a/x/y/b"));
+    assert!(glob("a/**", "a/x/y"));
+    assert!(!glob("a/**", "b/x"));
+  }
+
+  #[test]
+  fn glob_matches_character_classes() {
+    assert!(glob("[a-c]x", "bx"));
+    assert!(!glob("[a-c]x", "dx"));
+    assert!(glob("[!a-c]x", "dx"));
+    assert!(glob("[]]", "]"));
+    assert!(!glob("[ab]", "/"));
+    // an unclosed [ is a plain character.
+    assert!(glob("[ab", "[ab"));
+    assert!(!glob("[ab", "a"));
+  }
+
+  #[test]
+  fn class_match_handles_ranges_and_negation() {
+    assert!(class_match(b"a-c", b'b'));
+    assert!(!class_match(b"a-c", b'd'));
+    assert!(class_match(b"!a-c", b'd'));
+    assert!(!class_match(b"^x", b'x'));
+    assert!(class_match(b"xyz", b'y'));
+    // a trailing - is a plain character.
+    assert!(class_match(b"a-", b'-'));
+    assert!(!class_match(b"a-", b'b'));
+  }
+
+  #[test]
+  fn unanchored_rules_match_the_name_anywhere() {
+    let rules = IgnoreRules::parse("# comment\n*.log\n");
+    assert!(rules.is_ignored("a.log", false));
+    assert!(rules.is_ignored("a/b/c.log", false));
+    assert!(!rules.is_ignored("a.log.txt", false));
+    assert!(!rules.is_ignored("# comment", false));
+  }
+
+  #[test]
+  fn anchored_rules_match_from_the_root() {
+    let rules = IgnoreRules::parse("/tmp\ndoc/*.txt\n");
+    assert!(rules.is_ignored("tmp", true));
+    assert!(!rules.is_ignored("a/tmp", true));
+    assert!(rules.is_ignored("doc/a.txt", false));
+    assert!(!rules.is_ignored("x/doc/a.txt", false));
+    assert!(!rules.is_ignored("doc/sub/a.txt", false));
+  }
+
+  #[test]
+  fn dir_only_rules_skip_files() {
+    let rules = IgnoreRules::parse("build/\n");
+    assert!(rules.is_ignored("build", true));
+    assert!(rules.is_ignored("a/build", true));
+    assert!(!rules.is_ignored("build", false));
+  }
+
+  #[test]
+  fn last_matching_rule_decides() {
+    let rules = IgnoreRules::parse("*.log\n!keep*.log\nkeep-not.log\n\\!bang\n");
+    assert!(rules.is_ignored("other.log", false));
+    assert!(!rules.is_ignored("keep.log", false));
+    assert!(rules.is_ignored("keep-not.log", false));
+    assert!(rules.is_ignored("!bang", false));
+    assert!(!rules.is_ignored("bang", false));
+  }
+}
@@ -0,0 +1,28 @@
+use std::collections::HashSet;
+use std::sync;
+
+/// A string shared by all the items that have it, such as a mime type.
+pub type Interned = sync::Arc<str>;
+
+lazy_static! {
+  // strings interned so far. They're never dropped, since the strings
+  // interned, e.g. mime types and shared drive ids, are few and reused.
+  static ref STRINGS: sync::Mutex<HashSet<Interned>> = sync::Mutex::new(HashSet::new());
+}
+
+/// The shared copy of `s`.
+pub fn intern(s: &str) -> Interned {
+  let mut strings = STRINGS.lock().unwrap();
+  if let Some(interned) = strings.get(s) {
+    return interned.clone();
+  }
+  let interned: Interned = sync::Arc::from(s);
+  strings.insert(interned.clone());
+  interned
+}
+
+/// The number of strings interned, and their total size.
+pub fn stats() -> (usize, usize) {
+  let strings = STRINGS.lock().unwrap();
+  (strings.len(), strings.iter().map(|s| s.len()).sum())
+}
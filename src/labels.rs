@@ -0,0 +1,119 @@
+extern crate hyper;
+extern crate serde_json;
+
+use self::serde_json::Value;
+use common;
+use drive_error;
+use oauth;
+use oauth::GetToken;
+use recent::RecentCache;
+use std::error::Error;
+use std::io::Read;
+use std::sync;
+use std::time::Duration;
+
+const FILE_GET_URL: &'static str = "https://www.googleapis.com/drive/v3/files";
+
+// how long the labels of a file are reused: listing the xattrs of a file and
+// reading them takes several calls in a row.
+const LABELS_TTL_SECS: u64 = 60;
+
+lazy_static! {
+  // map of file id -> labels, see list_labels.
+  static ref LABELS: sync::Mutex<RecentCache<Vec<(String, String)>>> =
+    sync::Mutex::new(RecentCache::new(Duration::from_secs(LABELS_TTL_SECS)));
+}
+
+/// Fetches the Drive Labels applied to `file_id`, as (label id, fields)
+/// pairs. The fields of each label are formatted as one "field_id=values"
+/// line per field, with multiple values separated by commas. The labels are
+/// reused for LABELS_TTL_SECS.
+pub fn list_labels(
+  auth: &mut oauth::GoogleAuthenticator,
+  file_id: &str,
+) -> Result<Vec<(String, String)>, Box<Error>> {
+  if let Some(labels) = LABELS.lock().unwrap().get(file_id) {
+    return Ok(labels);
+  }
+  let labels = try!(fetch_labels(auth, file_id));
+  LABELS.lock().unwrap().insert(file_id, labels.clone());
+  Ok(labels)
+}
+
+// fetches the labels of |file_id|, see list_labels.
+fn fetch_labels(
+  auth: &mut oauth::GoogleAuthenticator,
+  file_id: &str,
+) -> Result<Vec<(String, String)>, Box<Error>> {
+  let client = common::new_hyper_tls_client();
+  let mut labels: Vec<(String, String)> = Vec::new();
+  let mut page_token: Option<String> = None;
+  loop {
+    let url = {
+      let mut params = vec![("maxResults", "100")];
+      if let Some(ref token) = page_token {
+        params.push(("pageToken", token));
+      }
+      try!(hyper::Url::parse_with_params(
+        &format!("{}/{}/listLabels", FILE_GET_URL, file_id),
+        &params
+      ))
+    };
+
+    let token = auth.api_key().unwrap();
+    let mut resp = try!(client
+      .get(url)
+      .header(hyper::header::Authorization(hyper::header::Bearer {
+        token: token,
+      }))
+      .send());
+    if !resp.status.is_success() {
+      let mut body: String = String::new();
+      try!(resp.read_to_string(&mut body));
+      let err = drive_error::DriveError::parse(resp.status, &body);
+      warn!("Label list error: {}", err);
+      return Err(Box::new(err));
+    }
+    let result: Value = try!(serde_json::from_reader(&mut resp));
+
+    if let Some(results) = result["labels"].as_array() {
+      for label in results {
+        if let Some(id) = label["id"].as_str() {
+          labels.push((id.into(), format_fields(label)));
+        }
+      }
+    }
+
+    page_token = result["nextPageToken"].as_str().map(String::from);
+    if page_token.is_none() {
+      break;
+    }
+  }
+  Ok(labels)
+}
+
+// formats the fields of a Label as "field_id=values" lines.
+fn format_fields(label: &Value) -> String {
+  let mut lines = String::new();
+  if let Some(fields) = label["fields"].as_object() {
+    for (field_id, field) in fields {
+      // the values are held under a key named after the value type.
+      let value_type = field["valueType"].as_str().unwrap_or_default();
+      let values: Vec<String> = field[value_type]
+        .as_array()
+        .map(|values| values.iter().map(format_value).collect())
+        .unwrap_or_default();
+      lines.push_str(&format!("{}={}\n", field_id, values.join(",")));
+    }
+  }
+  lines
+}
+
+// formats a single field value. Users are shown by their email address.
+fn format_value(value: &Value) -> String {
+  match *value {
+    Value::String(ref s) => s.clone(),
+    Value::Object(_) => value["emailAddress"].as_str().unwrap_or_default().into(),
+    ref other => other.to_string(),
+  }
+}
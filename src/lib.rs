@@ -1,23 +1,72 @@
 extern crate fuse;
 extern crate google_drive3;
 extern crate hyper;
+#[macro_use]
+extern crate lazy_static;
 extern crate libc;
 #[macro_use]
 extern crate log;
+extern crate serde_json;
 extern crate threadpool;
 extern crate time;
 
+mod activity;
+mod app_properties;
+mod autotune;
+mod checksums;
 pub mod common;
 mod constants;
+mod container;
+mod control;
+mod deadline;
+mod disk_cache;
+mod drive_error;
+mod error_rates;
+mod export;
+mod google_docs;
+mod health;
 mod http;
+mod ignore;
+mod intern;
+mod labels;
+mod listing;
 pub mod oauth;
+mod pool;
+pub mod rclone;
+mod read_policy;
+mod recent;
+mod resolver;
+mod retry;
+mod revisions;
+pub mod sandbox;
+mod scheduler;
+pub mod startup;
+mod thumbnails;
+mod upload;
+mod xattr;
 
+pub use checksums::write_md5sums;
 pub use common::get_contents;
 pub use common::set_contents;
+pub use control::bind as bind_control_socket;
+pub use control::parse_status;
+pub use control::send_command;
+pub use control::tune as tune_read_options;
+pub use disk_cache::configure as configure_disk_cache;
+pub use error_rates::start_reporter as start_error_reporter;
+pub use export::{export_tar, ExportSummary};
+pub use google_docs::ExportFormat;
 pub use http::FileReadOptions;
+pub use http::UncachedReadAction;
+pub use read_policy::ReadPolicy;
+pub use upload::check_staging_dir;
+pub use upload::parse_mime_types as parse_upload_mime_types;
 
+use std::cmp;
 use std::collections::vec_deque::VecDeque;
 use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::HashSet;
 use std::convert::From;
 use std::convert::Into;
 use std::error::Error;
@@ -28,6 +77,8 @@ use std::sync;
 use std::thread;
 
 const TTL: time::Timespec = time::Timespec { sec: 5, nsec: 0 };
+// time the kernel may keep looked up attributes with --shallow-stat.
+const SHALLOW_STAT_TTL: time::Timespec = time::Timespec { sec: 600, nsec: 0 };
 
 // Default time used for the root, and for files for which no time is returned
 // or time parsing fails.
@@ -42,9 +93,76 @@ const RFC3339_FMT: &'static str = "%Y-%m-%dT%H:%M:%S";
 // gdrive id of the root node.
 const ROOT_ID: &'static str = "root";
 
+// id and name of the virtual folder holding the shared drives.
+const SHARED_DRIVES_ID: &'static str = "gdrivefs:shared-drives";
+const SHARED_DRIVES_NAME: &'static str = "Shared Drives";
+
+// id and name of the virtual folder holding the files that have no parent.
+const ORPHANS_ID: &'static str = "gdrivefs:orphans";
+const ORPHANS_NAME: &'static str = "Orphans";
+
+// id prefix and name of the virtual folders holding the snapshots of a
+// folder. The id is followed by the folder id.
+const SNAPSHOTS_ID_PREFIX: &'static str = "gdrivefs:snapshots:";
+const SNAPSHOTS_NAME: &'static str = ".snapshots";
+
+// id prefix of the snapshot of a folder at a date, followed by
+// "<date>:<folder id>".
+const SNAPSHOT_ID_PREFIX: &'static str = "gdrivefs:at:";
+
+// format of the dates naming snapshots.
+const SNAPSHOT_DATE_FMT: &'static str = "%Y-%m-%d";
+
+// id prefix of the content of a file at a revision, followed by
+// "<file id>:<revision id>".
+const REVISION_ID_PREFIX: &'static str = "gdrivefs:revision:";
+
+// id and name of the virtual folder holding gdrivefs' own files.
+const CONTROL_DIR_ID: &'static str = "gdrivefs:control";
+const CONTROL_DIR_NAME: &'static str = ".gdrivefs";
+
+// id and name of the virtual file listing recent drive activity.
+const ACTIVITY_LOG_ID: &'static str = "gdrivefs:activity-log";
+const ACTIVITY_LOG_NAME: &'static str = "activity.log";
+
+// number of events shown in the activity log.
+const ACTIVITY_LOG_EVENTS: usize = 500;
+
+// id prefix and name suffix of the virtual files holding a file's comments.
+const COMMENTS_ID_PREFIX: &'static str = "gdrivefs:comments:";
+const COMMENTS_NAME_SUFFIX: &'static str = ".comments.json";
+
+// comment fields shown in the comments files.
+const COMMENT_FIELDS: &'static str = "id,author/displayName,author/emailAddress,content,quotedFileContent/value,createdTime,modifiedTime,resolved,replies(author/displayName,author/emailAddress,content,action,createdTime,modifiedTime)";
+
+// prefix of the ids of everything that only exists within gdrivefs.
+const VIRTUAL_ID_PREFIX: &'static str = "gdrivefs:";
+
+// mime type of the files standing in for symlinks.
+const SYMLINK_MIME_TYPE: &'static str = "inode/symlink";
+
 const ROOT_INODE: u64 = 1;
+// the permission bits kept by chmod with FileSystemOptions::store_full_mode.
+// The setuid and setgid bits are left out, since anyone who can edit a file
+// on Drive could otherwise set them.
+const STORED_MODE_BITS: u16 = 0o1777;
 
-const FILE_GET_URL: &'static str = "https://www.googleapis.com/drive/v3/files";
+// differential refreshes fetch the items modified since this long before
+// the previous refresh started, in case the clocks of drive and this host
+// disagree.
+const REFRESH_CLOCK_MARGIN_SECS: i64 = 300;
+// folders with at least this many children get an index of their children
+// by name, so lookups don't scan all of them.
+const INDEXED_DIR_MIN_CHILDREN: usize = 256;
+// times creating a shared drive is retried after a network or server error.
+const SHARED_DRIVE_CREATE_RETRIES: u32 = 2;
+// longest wait before following the changes again from a fresh page token,
+// after the previous ones were refused in a row.
+const MAX_CHANGES_RESET_BACKOFF_SECS: u64 = 3600;
+
+// metadata fields requested for each file.
+const FILE_FIELDS: &'static str =
+  "id,mimeType,name,size,md5Checksum,appProperties,createdTime,modifiedTime,ownedByMe,teamDriveId,capabilities/canDownload";
 
 // attributes of the root node.
 const ROOT_ATTR: fuse::FileAttr = fuse::FileAttr {
@@ -66,6 +184,279 @@ const ROOT_ATTR: fuse::FileAttr = fuse::FileAttr {
 
 // mime type of a directory in google drive.
 const FOLDER_MIME_TYPE: &'static str = "application/vnd.google-apps.folder";
+// native Google items, whose content can only be exported.
+const GOOGLE_APPS_MIME_PREFIX: &'static str = "application/vnd.google-apps.";
+
+// mime types of google drive items that can neither be downloaded nor
+// exported.
+const UNEXPORTABLE_MIME_TYPES: &'static [&'static str] = &[
+  "application/vnd.google-apps.form",
+  "application/vnd.google-apps.site",
+  "application/vnd.google-apps.map",
+  "application/vnd.google-apps.fusiontable",
+];
+
+/// The order in which readdir returns the entries of a folder.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DirOrder {
+  /// The order the entries were listed in, which can change as the folder is
+  /// refreshed.
+  Listed,
+  /// By name, in byte order.
+  Name,
+  /// By modification time, oldest first.
+  Modified,
+  /// By modification time, newest first, e.g. for camera uploads.
+  Newest,
+}
+
+impl FromStr for DirOrder {
+  type Err = Box<Error>;
+
+  /// Parses one of `none`, `name`, `mtime` or `newest`.
+  fn from_str(spec: &str) -> Result<DirOrder, Box<Error>> {
+    match spec {
+      "none" => Ok(DirOrder::Listed),
+      "name" => Ok(DirOrder::Name),
+      "mtime" => Ok(DirOrder::Modified),
+      "newest" => Ok(DirOrder::Newest),
+      _ => Err(From::from("expected none, name, mtime or newest")),
+    }
+  }
+}
+
+/// Options that control how Google Drive items are presented by the
+/// filesystem.
+#[derive(Debug, Clone)]
+pub struct FileSystemOptions {
+  /// Show items that are in the trash alongside regular items.
+  pub show_trashed: bool,
+
+  /// Hide native Google items that can't be downloaded or exported
+  /// (Forms, Sites, ...), which would otherwise show up as empty files that
+  /// fail to open.
+  pub hide_unexportable: bool,
+
+  /// The formats native Google items of each kind are exported in when
+  /// read. Their names get the extension of their format, and their size
+  /// is only known once they were read, showing as 0 until then. The size
+  /// of the last export is shown from then on, until the item is modified.
+  /// Kinds without a format stay unreadable.
+  pub export_formats: Vec<ExportFormat>,
+
+  /// Hide items that the owner doesn't allow the current user to download.
+  pub hide_undownloadable: bool,
+
+  /// Refuse to open drive files with EACCES, so that the tree can be
+  /// inventoried with its sizes and times without any risk of downloading
+  /// content. Generated files such as sidecars still open.
+  pub no_content: bool,
+
+  /// If set, opening a drive file larger than this many bytes fails with
+  /// EFBIG, so an accidental copy of a huge folder can't start downloading
+  /// its largest files.
+  pub max_read_file_size: Option<u64>,
+
+  /// If set, writes that would make a drive file larger than this many bytes
+  /// fail with EFBIG, as does opening a larger file for writing without
+  /// truncating it, since its whole content would be staged first.
+  pub max_write_file_size: Option<u64>,
+
+  /// Let the kernel keep the attributes of looked up items for minutes
+  /// rather than seconds, and refresh the metadata of a file when it's
+  /// opened instead. Listing a huge folder with ls -l then doesn't go back to
+  /// the filesystem for every entry each time, at the cost of sizes and
+  /// times that may be stale. Opening a file can't update the attributes
+  /// the kernel already holds, so they stay stale until those expire, up to
+  /// 10 minutes; from then on the attributes of opened files are only kept
+  /// for a second.
+  pub shallow_stat: bool,
+
+  /// If set, the periodic refresh of a folder only fetches the items
+  /// modified since its previous refresh, and lists it in full every this
+  /// many refreshes, to drop the items deleted or moved away meanwhile. The
+  /// root, merged folders and folders with comment sidecars are always
+  /// listed in full.
+  pub differential_refresh: Option<u32>,
+
+  /// If true, the periodic refresh follows the changes made to the drive
+  /// since the previous refresh through the changes API, updating the items
+  /// they touch in place, instead of listing every folder again. Folders
+  /// whose listing can't be updated in place, such as the root, are listed
+  /// again. Only supported with a single account.
+  pub changes_sync: bool,
+
+  /// The order readdir returns entries in. Ties are broken by name, so that
+  /// listings of the same content come out the same.
+  pub dir_order: DirOrder,
+
+  /// If set, the path of the folder shown at the root of the mount instead
+  /// of the whole drive, relative to the root of the drive. With several
+  /// accounts, the folders at this path in each of them are merged, and
+  /// accounts without one are left out.
+  pub root_folder: Option<String>,
+
+  /// The uid and gid reported as the owner of items owned by the current
+  /// user.
+  pub owner_uid: u32,
+  pub owner_gid: u32,
+
+  /// The uid and gid reported as the owner of items shared with the current
+  /// user by someone else.
+  pub shared_uid: u32,
+  pub shared_gid: u32,
+
+  /// Expose the sharing permissions of each item through the
+  /// `user.gdrive.permissions` xattr.
+  pub permissions_xattr: bool,
+
+  /// Expose the Drive Labels applied to each file as `user.gdrive.label.*`
+  /// xattrs, one per label. Listing the xattrs of a file takes a Drive
+  /// request, whose answer is kept for a minute for the reads that follow.
+  pub labels_xattr: bool,
+
+  /// Expose the content checksums of binary files as the `user.gdrive.md5`,
+  /// `user.gdrive.sha1` and `user.gdrive.sha256` xattrs.
+  pub checksums_xattr: bool,
+
+  /// Expose the byte ranges of a file that can be read without going to the
+  /// network as the `user.gdrive.cached_ranges` xattr, one "start-end" line
+  /// per range with `end` inclusive.
+  pub cached_ranges_xattr: bool,
+
+  /// Expose the read counters of a file as the `user.gdrive.readstats`
+  /// xattr, one "<counter> <value>" line per counter, to tell why reads of
+  /// one file are slow.
+  pub readstats_xattr: bool,
+
+  /// Expose the thumbnail Drive generated for a file (e.g. an image or video)
+  /// as the `user.gdrive.thumbnail` xattr, holding the image itself. It is
+  /// fetched on demand, since the links to thumbnails are short-lived.
+  pub thumbnails_xattr: bool,
+
+  /// Expose the total size of the files under each folder as the
+  /// `user.gdrive.treesize` xattr, computed from the listings already cached
+  /// so that nothing is fetched. Its value is "<bytes> <files> <unlisted>",
+  /// where `unlisted` counts the folders not yet listed, whose contents are
+  /// missing from the total.
+  pub treesize_xattr: bool,
+
+  /// If set, only requests from this uid may access the filesystem. Used to
+  /// isolate tenants when serving several accounts from one process.
+  pub restrict_to_uid: Option<u32>,
+
+  /// Show the shared drives the user is a member of under a virtual
+  /// `Shared Drives` folder at the root.
+  pub shared_drives: bool,
+
+  /// Allow `mkdir` in the `Shared Drives` folder to create new shared drives.
+  pub allow_shared_drive_create: bool,
+
+  /// Allow renaming and moving folders. Moves between My Drive and a shared
+  /// drive, or between two shared drives, fail with EXDEV, since Drive can
+  /// only copy folders across drives.
+  pub allow_dir_move: bool,
+
+  /// Show the files owned by the user that are in no folder, e.g. because
+  /// the folder they were in was deleted by its owner, under a virtual
+  /// `Orphans` folder at the root.
+  pub orphans_folder: bool,
+
+  /// Hide the paths matching the rules in a `.gdriveignore` file at the root
+  /// of the drive, written in gitignore syntax. Hidden folders are neither
+  /// listed nor refreshed. The rules are reloaded whenever the root is.
+  pub ignore_file: bool,
+
+  /// Add a `.snapshots` folder to every folder, in which `<YYYY-MM-DD>/`
+  /// shows the folder as it was at the end of that day (UTC), built from the
+  /// revisions of its files. Deleted files can't be brought back, and files
+  /// whose revisions from that time were pruned by Drive are missing.
+  pub snapshots: bool,
+
+  /// Expose recent Drive Activity events as `.gdrivefs/activity.log`. Needs
+  /// a token with the drive.activity.readonly scope, see init_token
+  /// --activity-log.
+  pub activity_log: bool,
+
+  /// Show the comments on each file as a read-only `<name>.comments.json`
+  /// file next to it.
+  pub comments_sidecars: bool,
+
+  /// Let chmod and symlink store permission bits and symlink targets in the
+  /// Drive `appProperties` of files, so they're seen by every mount.
+  pub store_local_metadata: bool,
+
+  /// Have chmod store the full mode, including for folders, rather than only
+  /// whether a file is executable. Needs `store_local_metadata`. The setuid
+  /// and setgid bits are never kept.
+  pub store_full_mode: bool,
+
+  /// Allow creating, writing and truncating files. A file opened for writing
+  /// is staged whole in `staging_dir` and uploaded as a new version when it's
+  /// flushed or closed. Google Docs and generated files can't be written.
+  /// The mount turns read-only if uploads keep failing, see
+  /// upload::FailureTracker.
+  pub writable: bool,
+
+  /// The folder files being written are staged in.
+  pub staging_dir: String,
+
+  /// Keep the revision a file had before it was opened for writing or
+  /// truncated forever in its Drive history, before the first upload
+  /// replaces it, so applications that wrongly truncate files can't lose
+  /// their content.
+  pub keep_overwritten_revisions: bool,
+
+  /// Lowercase extensions and the mime types given to new files with them,
+  /// over the built-in ones, see upload::infer_mime_type.
+  pub upload_mime_types: Vec<(String, String)>,
+}
+
+impl FileSystemOptions {
+  // whether |api_file| should appear in directory listings.
+  fn is_visible(&self, api_file: &google_drive3::File) -> bool {
+    if self.hide_unexportable {
+      if let Some(ref mime_type) = api_file.mime_type {
+        if UNEXPORTABLE_MIME_TYPES.contains(&mime_type.as_str()) {
+          return false;
+        }
+      }
+    }
+    if self.hide_undownloadable {
+      let can_download = api_file
+        .capabilities
+        .as_ref()
+        .and_then(|c| c.can_download)
+        .unwrap_or(true);
+      if !can_download {
+        return false;
+      }
+    }
+    true
+  }
+
+  // the format |gfile| is exported in, if it's a native Google item that
+  // is exported.
+  fn export_format(&self, gfile: &GoogleFile) -> Option<&ExportFormat> {
+    let mime_type = match gfile.mime_type {
+      Some(ref mime_type) if !gfile.is_dir() => mime_type,
+      _ => return None,
+    };
+    self
+      .export_formats
+      .iter()
+      .find(|format| *format.google_mime_type == **mime_type)
+  }
+
+  // the uid and gid to report as the owner of |api_file|.
+  fn ownership(&self, api_file: &google_drive3::File) -> (u32, u32) {
+    if api_file.owned_by_me.unwrap_or(true) {
+      (self.owner_uid, self.owner_gid)
+    } else {
+      (self.shared_uid, self.shared_gid)
+    }
+  }
+}
 
 fn parse_rfc3339(s: &str) -> time::Timespec {
   match time::strptime(s, RFC3339_FMT) {
@@ -74,20 +465,131 @@ fn parse_rfc3339(s: &str) -> time::Timespec {
   }
 }
 
+// the 16 bytes of the md5 written in hex in |hex|, if it's valid.
+fn parse_md5(hex: &str) -> Option<[u8; 16]> {
+  if hex.len() != 32 || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+    return None;
+  }
+  let mut md5 = [0u8; 16];
+  for (i, byte) in md5.iter_mut().enumerate() {
+    *byte = match u8::from_str_radix(&hex[2 * i..2 * i + 2], 16) {
+      Ok(byte) => byte,
+      Err(_) => return None,
+    };
+  }
+  Some(md5)
+}
+
+// the inode of the item with id |file_id|.
+fn inode_of(file_id: &str) -> u64 {
+  let mut hasher = std::hash::SipHasher::new();
+  file_id.hash(&mut hasher);
+  hasher.finish()
+}
+
+// a file open for writing, see FileSystemOptions::writable.
+struct WriteHandle {
+  ino: u64,
+  file_id: String,
+  account: usize,
+  // only held briefly, so that the fuse thread never waits on an upload.
+  staged: sync::Mutex<upload::StagedFile>,
+  // held for the duration of an upload, so that uploads of the handle
+  // happen one at a time, in order.
+  uploading: sync::Mutex<()>,
+  // whether the revision the file had before it was opened was kept, see
+  // FileSystemOptions::keep_overwritten_revisions.
+  previous_kept: sync::atomic::AtomicBool,
+}
+
 // A GoogleFile merges fuse file attributes with google drive metadata.
 #[derive(Debug, Clone)]
 struct GoogleFile {
   file_id: String,
-  file_name: String,
-  //file_download_url: String,
+  // names aren't edited in place, so are kept without spare capacity.
+  file_name: Box<str>,
   file_attr: fuse::FileAttr,
+  // interned, as most files share a handful of types.
+  mime_type: Option<intern::Interned>,
+  // id of the shared drive containing the file, if any. Interned, as all
+  // the files of a shared drive share it.
+  team_drive_id: Option<intern::Interned>,
+  // md5 of the content, only known for binary files. Kept as bytes, which
+  // take half the space of the hex drive gives.
+  md5_checksum: Option<[u8; 16]>,
+  // target of the link, for files standing in for symlinks. Empty if the
+  // target is the file content, which is only fetched on readlink. Boxed,
+  // like the other fields few files have, to keep GoogleFile small.
+  symlink_target: Option<Box<String>>,
+  // index of the account the file belongs to, see GDriveFS::new_union.
+  account: usize,
+  // folders from lower priority accounts merged into this one.
+  merged_dirs: Option<Box<Vec<GoogleFile>>>,
 }
 
 impl GoogleFile {
-  fn name(&self) -> &String {
+  // converts |api_file|, applying the ownership mapping and export
+  // formats in |options|.
+  fn from_api_file(api_file: google_drive3::File, options: &FileSystemOptions) -> GoogleFile {
+    let (uid, gid) = options.ownership(&api_file);
+    let mut gfile = GoogleFile::from(api_file);
+    gfile.file_attr.uid = uid;
+    gfile.file_attr.gid = gid;
+    let extension = options
+      .export_format(&gfile)
+      .map(|format| format.extension.clone());
+    if let Some(extension) = extension {
+      gfile.file_name = format!("{}.{}", gfile.file_name, extension).into_boxed_str();
+      // exports have no size until made, so show that of the last one.
+      if let Some(size) = google_docs::export_size(&gfile.file_id, gfile.file_attr.mtime) {
+        gfile.file_attr.size = size;
+        gfile.file_attr.blocks = size / constants::BLOCK_SIZE as u64;
+      }
+    }
+    gfile
+  }
+
+  fn name(&self) -> &str {
     &self.file_name
   }
 
+  // the memory used by the file, leaving out interned strings, in bytes.
+  fn memory_usage(&self) -> usize {
+    std::mem::size_of::<GoogleFile>()
+      + self.file_id.capacity()
+      + self.file_name.len()
+      + self.symlink_target.as_ref().map_or(0, |target| {
+        std::mem::size_of::<String>() + target.capacity()
+      })
+      + self
+        .merged_dirs()
+        .iter()
+        .map(|dir| dir.memory_usage())
+        .sum::<usize>()
+  }
+
+  // the md5 of the content in hex, as drive gives it.
+  fn md5_hex(&self) -> Option<String> {
+    self.md5_checksum.map(|md5| {
+      md5
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+    })
+  }
+
+  // the folders merged into this one.
+  fn merged_dirs(&self) -> &[GoogleFile] {
+    self.merged_dirs.as_ref().map_or(&[], |dirs| &dirs[..])
+  }
+
+  fn merge_dir(&mut self, dir: GoogleFile) {
+    self
+      .merged_dirs
+      .get_or_insert_with(|| Box::new(Vec::new()))
+      .push(dir);
+  }
+
   fn inode(&self) -> u64 {
     self.file_attr.ino
   }
@@ -96,40 +598,100 @@ impl GoogleFile {
     self.file_attr.kind == fuse::FileType::Directory
   }
 
+  // whether this is a file generated by gdrivefs rather than a drive item.
+  // Old revisions of drive files are read like the files themselves.
+  fn is_virtual_file(&self) -> bool {
+    !self.is_dir()
+      && self.file_id.starts_with(VIRTUAL_ID_PREFIX)
+      && !self.file_id.starts_with(REVISION_ID_PREFIX)
+  }
+
+  // a copy of the file under the id |file_id|, which determines its inode.
+  fn with_id(&self, file_id: String) -> GoogleFile {
+    let mut gfile = self.clone();
+    gfile.file_attr.ino = inode_of(&file_id);
+    gfile.file_id = file_id;
+    gfile
+  }
+
   fn kind(&self) -> fuse::FileType {
     self.file_attr.kind
   }
 
-  fn download_url(&self) -> Option<String> {
+  // the id of the drive file holding the content of the file, and where the
+  // content is read from, if it has any.
+  fn content_source(&self) -> Option<(String, resolver::ContentSource)> {
     if self.is_dir() {
       None
+    } else if self.file_id.starts_with(REVISION_ID_PREFIX) {
+      let mut parts = self.file_id[REVISION_ID_PREFIX.len()..].splitn(2, ':');
+      match (parts.next(), parts.next()) {
+        (Some(file_id), Some(revision_id)) => Some((
+          file_id.into(),
+          resolver::ContentSource::Revision(revision_id.into()),
+        )),
+        _ => None,
+      }
     } else {
-      // Some(self.file_download_url.clone())
-      Some(format!("{}/{}?alt=media", FILE_GET_URL, self.file_id))
+      Some((self.file_id.clone(), resolver::ContentSource::Media))
     }
   }
+
+  // the snapshot at |date| of the folder whose .snapshots folder is this
+  // one, or None if |date| isn't a YYYY-MM-DD date.
+  fn snapshot_dir(&self, date: &str) -> Option<GoogleFile> {
+    if !self.file_id.starts_with(SNAPSHOTS_ID_PREFIX) {
+      return None;
+    }
+    // the date is compared as text, so it must be in the canonical format.
+    let canonical = time::strptime(date, SNAPSHOT_DATE_FMT)
+      .and_then(|tm| time::strftime(SNAPSHOT_DATE_FMT, &tm))
+      .ok();
+    if canonical.as_ref().map(|canonical| canonical.as_str()) != Some(date) {
+      return None;
+    }
+    let folder_id = &self.file_id[SNAPSHOTS_ID_PREFIX.len()..];
+    let mut dir = self.with_id(format!("{}{}:{}", SNAPSHOT_ID_PREFIX, date, folder_id));
+    dir.file_name = date.into();
+    dir.merged_dirs = None;
+    Some(dir)
+  }
 }
 
 impl std::convert::From<google_drive3::File> for GoogleFile {
   fn from(api_file: google_drive3::File) -> GoogleFile {
     let file_id = api_file.id.expect("file id is missing");
-    let mut hasher = std::hash::SipHasher::new();
     let file_size = u64::from_str(api_file.size.as_ref().unwrap_or(&"0".into())).unwrap();
-    file_id.hash(&mut hasher);
+    // metadata stored by gdrivefs itself, see FileSystemOptions.
+    let app_properties = api_file.app_properties.unwrap_or_default();
+    let symlink_target = app_properties.get(app_properties::SYMLINK_KEY).cloned();
+    let stored_mode = app_properties
+      .get(app_properties::MODE_KEY)
+      .and_then(|mode| u16::from_str_radix(mode, 8).ok());
     let kind = match api_file.mime_type.as_ref() {
+      _ if symlink_target.is_some() => fuse::FileType::Symlink,
       Some(mime_type) if mime_type == FOLDER_MIME_TYPE => fuse::FileType::Directory,
       Some(_) | None => fuse::FileType::RegularFile,
     };
-    let perms = if kind == fuse::FileType::Directory {
-      0o755
-    } else {
-      0o644
+    let perms = match kind {
+      fuse::FileType::Directory => stored_mode
+        .map(|mode| mode & STORED_MODE_BITS)
+        .unwrap_or(0o755),
+      fuse::FileType::Symlink => 0o777,
+      _ => stored_mode
+        .map(|mode| mode & STORED_MODE_BITS)
+        .unwrap_or(0o644),
+    };
+    // long symlink targets are stored as the content, so have the right size.
+    let file_size = match symlink_target {
+      Some(ref target) if !target.is_empty() => target.len() as u64,
+      _ => file_size,
     };
     let created_time = parse_rfc3339(api_file.created_time.as_ref().unwrap_or(&"".into()));
     let modified_time = parse_rfc3339(api_file.modified_time.as_ref().unwrap_or(&"".into()));
 
     let attr = fuse::FileAttr {
-      ino: hasher.finish(),
+      ino: inode_of(&file_id),
       size: file_size,
       blocks: file_size / constants::BLOCK_SIZE as u64,
       atime: modified_time,
@@ -146,9 +708,17 @@ impl std::convert::From<google_drive3::File> for GoogleFile {
     };
     GoogleFile {
       file_id: file_id,
-      file_name: api_file.name.unwrap_or("__UNKNOWN_FILE_NAME__".into()),
-      //file_download_url: api_file.download_url.unwrap_or("".into()),
+      file_name: api_file
+        .name
+        .unwrap_or("__UNKNOWN_FILE_NAME__".into())
+        .into_boxed_str(),
       file_attr: attr,
+      mime_type: api_file.mime_type.map(|mime_type| intern::intern(&mime_type)),
+      team_drive_id: api_file.team_drive_id.map(|id| intern::intern(&id)),
+      md5_checksum: api_file.md5_checksum.as_ref().and_then(|md5| parse_md5(md5)),
+      symlink_target: symlink_target.map(Box::new),
+      account: 0,
+      merged_dirs: None,
     }
   }
 }
@@ -158,20 +728,48 @@ struct GoogleFileTree {
   file_tree: BTreeMap<u64, Vec<u64>>,
   // map of inode -> GoogleFile
   file_attrs: BTreeMap<u64, GoogleFile>,
+  // map of inode -> inode of the folder it was last listed in.
+  parents: BTreeMap<u64, u64>,
+  // map of inode of a large folder -> name -> inodes of the children with
+  // that name, in listing order.
+  name_index: BTreeMap<u64, HashMap<String, Vec<u64>>>,
+  // paths hidden from listings, see FileSystemOptions::ignore_file.
+  ignore_rules: ignore::IgnoreRules,
+  // inodes of the files the drive changes removed, whose open handles fail
+  // with EACCES, see apply_change.
+  lost_access: HashSet<u64>,
 }
 
 impl GoogleFileTree {
-  fn new() -> GoogleFileTree {
+  // creates a tree whose root merges the folders in |roots|, each an account
+  // and the id of its folder.
+  fn new(root_attr: fuse::FileAttr, roots: &[(usize, String)]) -> GoogleFileTree {
     let mut tree = GoogleFileTree {
       file_tree: BTreeMap::new(),
       file_attrs: BTreeMap::new(),
+      parents: BTreeMap::new(),
+      name_index: BTreeMap::new(),
+      ignore_rules: ignore::IgnoreRules::default(),
+      lost_access: HashSet::new(),
     };
-    let root_gfile = GoogleFile {
-      file_id: ROOT_ID.into(),
+    let (account, ref root_id) = roots[0];
+    let mut root_gfile = GoogleFile {
+      file_id: root_id.clone(),
       file_name: ROOT_ID.into(),
-      //file_download_url: "".into(),
-      file_attr: ROOT_ATTR,
+      file_attr: root_attr,
+      mime_type: Some(intern::intern(FOLDER_MIME_TYPE)),
+      team_drive_id: None,
+      md5_checksum: None,
+      symlink_target: None,
+      account: account,
+      merged_dirs: None,
     };
+    for &(account, ref root_id) in &roots[1..] {
+      let mut merged_root = root_gfile.clone();
+      merged_root.file_id = root_id.clone();
+      merged_root.account = account;
+      root_gfile.merge_dir(merged_root);
+    }
     tree.insert_node(None, root_gfile);
     tree
   }
@@ -192,61 +790,554 @@ impl GoogleFileTree {
     self.file_tree.get(inode).is_some()
   }
 
+  // an estimate of the memory used by the tree, in bytes, leaving out
+  // interned strings and the overhead of the maps.
+  fn memory_usage(&self) -> usize {
+    let inode_size = std::mem::size_of::<u64>();
+    let files: usize = self
+      .file_attrs
+      .values()
+      .map(|gfile| inode_size + gfile.memory_usage())
+      .sum();
+    let children: usize = self
+      .file_tree
+      .values()
+      .map(|children| {
+        inode_size + std::mem::size_of::<Vec<u64>>() + children.capacity() * inode_size
+      })
+      .sum();
+    let parents = self.parents.len() * 2 * inode_size;
+    let index: usize = self
+      .name_index
+      .values()
+      .flat_map(|index| index.iter())
+      .map(|(name, inodes)| {
+        std::mem::size_of::<String>()
+          + name.capacity()
+          + std::mem::size_of::<Vec<u64>>()
+          + inodes.capacity() * inode_size
+      })
+      .sum();
+    files + children + parents + index
+  }
+
+  // the inodes of the folders whose children are known.
+  fn listed_dirs(&self) -> Vec<u64> {
+    self.file_tree.keys().cloned().collect()
+  }
+
+  // the first child of |parent_inode| named |name|, if it was listed.
+  fn find_child(&self, parent_inode: &u64, name: &str) -> Option<u64> {
+    if let Some(index) = self.name_index.get(parent_inode) {
+      return index.get(name).and_then(|inodes| inodes.first()).cloned();
+    }
+    self.get_children(parent_inode).and_then(|children| {
+      children
+        .iter()
+        .find(|child| {
+          self
+            .get_file(child)
+            .map_or(false, |file| file.name() == name)
+        })
+        .cloned()
+    })
+  }
+
   fn insert_node(&mut self, parent_inode: Option<u64>, new_node: GoogleFile) {
-    if let Some(inode) = parent_inode {
-      self
-        .file_tree
-        .entry(inode)
-        .or_insert(Vec::new())
-        .push(new_node.inode());
+    let inode = new_node.inode();
+    // a file listed again may have been renamed.
+    let renamed_in = match (self.file_attrs.get(&inode), self.parents.get(&inode)) {
+      (Some(old_node), Some(&old_parent)) if old_node.name() != new_node.name() => {
+        Some((old_parent, old_node.name().to_string()))
+      }
+      _ => None,
+    };
+    if let Some((old_parent, ref old_name)) = renamed_in {
+      self.unindex_child(old_parent, inode, old_name);
+    }
+    self.file_attrs.insert(inode, new_node);
+    match (parent_inode, renamed_in) {
+      (Some(parent), _) => {
+        self.file_tree.entry(parent).or_insert(Vec::new()).push(inode);
+        self.parents.insert(inode, parent);
+        self.index_child(parent, inode);
+      }
+      (None, Some((old_parent, _))) => self.index_child(old_parent, inode),
+      (None, None) => {}
+    }
+  }
+
+  // adds the child |inode| of |parent_inode| to the name index of
+  // |parent_inode|, first building the index if the folder has grown large
+  // enough to need one.
+  fn index_child(&mut self, parent_inode: u64, inode: u64) {
+    let name = match self.file_attrs.get(&inode) {
+      Some(gfile) => gfile.name().to_string(),
+      None => return,
+    };
+    if let Some(index) = self.name_index.get_mut(&parent_inode) {
+      index.entry(name).or_insert_with(Vec::new).push(inode);
+      return;
+    }
+    let index = match self.get_children(&parent_inode) {
+      Some(children) if children.len() >= INDEXED_DIR_MIN_CHILDREN => {
+        let mut index: HashMap<String, Vec<u64>> = HashMap::new();
+        for child in children {
+          if let Some(gfile) = self.get_file(child) {
+            index
+              .entry(gfile.name().to_string())
+              .or_insert_with(Vec::new)
+              .push(*child);
+          }
+        }
+        index
+      }
+      _ => return,
+    };
+    self.name_index.insert(parent_inode, index);
+  }
+
+  // removes the child |inode| named |name| from the name index of
+  // |parent_inode|, if it has one.
+  fn unindex_child(&mut self, parent_inode: u64, inode: u64, name: &str) {
+    if let Some(index) = self.name_index.get_mut(&parent_inode) {
+      let now_unused = match index.get_mut(name) {
+        Some(inodes) => {
+          inodes.retain(|&child| child != inode);
+          inodes.is_empty()
+        }
+        None => false,
+      };
+      if now_unused {
+        index.remove(name);
+      }
+    }
+  }
+
+  // the path of |inode| from the root of the mount, without a leading /.
+  fn path_of(&self, inode: &u64) -> String {
+    let mut names: Vec<&str> = Vec::new();
+    let mut inode = *inode;
+    while inode != ROOT_INODE {
+      match (self.get_file(&inode), self.parents.get(&inode)) {
+        (Some(gfile), Some(&parent)) => {
+          names.push(gfile.name());
+          inode = parent;
+        }
+        _ => break,
+      }
+    }
+    names.reverse();
+    names.join("/")
+  }
+
+  // replaces the children of |parent_inode| with |files|, leaving out those
+  // hidden by the ignore rules. Returns the inodes of the folders inserted.
+  fn set_children(&mut self, parent_inode: &u64, files: Vec<GoogleFile>) -> Vec<u64> {
+    self.clear_children(parent_inode);
+    let parent_path = self.path_of(parent_inode);
+    let mut dirs = Vec::new();
+    for file in files {
+      let path = if parent_path.is_empty() {
+        file.name().to_string()
+      } else {
+        format!("{}/{}", parent_path, file.name())
+      };
+      if self.ignore_rules.is_ignored(&path, file.is_dir()) {
+        debug!("{} is hidden by {}", path, ignore::IGNORE_FILE_NAME);
+        continue;
+      }
+      if file.is_dir() {
+        dirs.push(file.inode());
+      }
+      self.insert_node(Some(*parent_inode), file);
+    }
+    dirs
+  }
+
+  // renames |inode| to |name| and moves it from |old_parent| to
+  // |new_parent|, linking it into the listing of |new_parent| only if that
+  // was already fetched.
+  fn move_node(&mut self, inode: u64, old_parent: u64, new_parent: u64, name: String) {
+    if let Some(old_name) = self.get_file(&inode).map(|gfile| gfile.name().to_string()) {
+      self.unindex_child(old_parent, inode, &old_name);
+    }
+    if let Some(children) = self.file_tree.get_mut(&old_parent) {
+      children.retain(|&child| child != inode);
     }
-    self.file_attrs.insert(new_node.inode(), new_node);
+    if let Some(gfile) = self.file_attrs.get_mut(&inode) {
+      gfile.file_name = name.into_boxed_str();
+    }
+    let listed = match self.file_tree.get_mut(&new_parent) {
+      Some(children) => {
+        children.push(inode);
+        true
+      }
+      None => false,
+    };
+    if listed {
+      self.index_child(new_parent, inode);
+    }
+    self.parents.insert(inode, new_parent);
+  }
+
+  // removes |inode| from the listing of |parent_inode|, after it was
+  // deleted or moved elsewhere.
+  fn unlink_child(&mut self, parent_inode: u64, inode: u64) {
+    if let Some(name) = self.get_file(&inode).map(|gfile| gfile.name().to_string()) {
+      self.unindex_child(parent_inode, inode, &name);
+    }
+    if let Some(children) = self.file_tree.get_mut(&parent_inode) {
+      children.retain(|&child| child != inode);
+    }
+    if self.parents.get(&inode) == Some(&parent_inode) {
+      self.parents.remove(&inode);
+    }
+  }
+
+  // whether |inode| is |ancestor| or under it, as far as the listings
+  // fetched so far tell.
+  fn is_under(&self, inode: u64, ancestor: u64) -> bool {
+    let mut inode = inode;
+    loop {
+      if inode == ancestor {
+        return true;
+      }
+      match self.parents.get(&inode) {
+        Some(&parent) if parent != inode => inode = parent,
+        _ => return false,
+      }
+    }
+  }
+
+  // updates the children of |parent_inode| that are in |files|, and adds
+  // those that are new, leaving the others in place. Returns the inodes of
+  // all the folders in it.
+  fn update_children(&mut self, parent_inode: &u64, files: Vec<GoogleFile>) -> Vec<u64> {
+    let parent_path = self.path_of(parent_inode);
+    for file in files {
+      let path = if parent_path.is_empty() {
+        file.name().to_string()
+      } else {
+        format!("{}/{}", parent_path, file.name())
+      };
+      if self.ignore_rules.is_ignored(&path, file.is_dir()) {
+        debug!("{} is hidden by {}", path, ignore::IGNORE_FILE_NAME);
+        continue;
+      }
+      let known = self
+        .get_children(parent_inode)
+        .map_or(false, |children| children.contains(&file.inode()));
+      if known {
+        self.insert_node(None, file);
+      } else {
+        self.insert_node(Some(*parent_inode), file);
+      }
+    }
+    let children = self.get_children(parent_inode).unwrap_or(&[]);
+    children
+      .iter()
+      .cloned()
+      .filter(|child| self.get_file(child).map_or(false, |gfile| gfile.is_dir()))
+      .collect()
   }
 
+  // forgets the children of |parent_inode|, keeping it listed, since its
+  // new children are inserted next and it may have none.
   fn clear_children(&mut self, parent_inode: &u64) {
-    self.file_tree.remove(parent_inode);
+    self.file_tree.insert(*parent_inode, Vec::new());
+    self.name_index.remove(parent_inode);
+  }
+
+  // the total size and number of the drive files under the folder |inode|,
+  // and the number of folders under it whose children aren't known. Virtual
+  // items are left out, and items reachable through several paths counted
+  // once.
+  fn tree_size(&self, inode: &u64) -> (u64, u64, u64) {
+    let (mut bytes, mut files, mut unlisted) = (0, 0, 0);
+    let mut seen: HashSet<u64> = HashSet::new();
+    let mut pending = vec![*inode];
+    while let Some(dir) = pending.pop() {
+      let children = match self.get_children(&dir) {
+        Some(children) => children,
+        None => {
+          unlisted += 1;
+          continue;
+        }
+      };
+      for child in children {
+        let gfile = match self.get_file(child) {
+          Some(gfile) if !gfile.file_id.starts_with(VIRTUAL_ID_PREFIX) => gfile,
+          _ => continue,
+        };
+        if !seen.insert(*child) {
+          continue;
+        }
+        if gfile.is_dir() {
+          pending.push(*child);
+        } else {
+          bytes += gfile.file_attr.size;
+          files += 1;
+        }
+      }
+    }
+    (bytes, files, unlisted)
   }
 }
 
 type DriveHub = google_drive3::Drive<hyper::client::Client, oauth::GoogleAuthenticator>;
 
-fn list_gdrive_dir(gfile_id: &str, hub: &mut DriveHub) -> Result<Vec<GoogleFile>, Box<Error>> {
-  debug!("In list_gdrive_dir({}, …)", gfile_id);
+// creates a drive hub for each of |auths|.
+fn new_hubs(auths: &[oauth::GoogleAuthenticator]) -> Vec<DriveHub> {
+  auths
+    .iter()
+    .map(|auth| google_drive3::Drive::new(common::new_hyper_tls_client(), auth.clone()))
+    .collect()
+}
+
+// lists the children of |gfile| and of any folders merged into it, using the
+// hub of each folder's account from |hubs|. On name clashes, folders are
+// merged and otherwise the entry from the higher priority account wins.
+fn list_dir(
+  gfile: &GoogleFile,
+  hubs: &mut [DriveHub],
+  auths: &[oauth::GoogleAuthenticator],
+  options: &FileSystemOptions,
+) -> Result<Vec<GoogleFile>, Box<Error>> {
+  let mut files = try!(list_account_dir(
+    gfile,
+    &mut hubs[gfile.account],
+    &auths[gfile.account],
+    options
+  ));
+  if gfile.merged_dirs().is_empty() {
+    return Ok(files);
+  }
+  let mut names: HashMap<String, usize> = files
+    .iter()
+    .enumerate()
+    .map(|(i, file)| (file.name().to_string(), i))
+    .collect();
+  for merged_dir in gfile.merged_dirs() {
+    let merged_files = try!(list_account_dir(
+      merged_dir,
+      &mut hubs[merged_dir.account],
+      &auths[merged_dir.account],
+      options
+    ));
+    for file in merged_files {
+      match names.get(file.name()).cloned() {
+        Some(i) => {
+          // the same folder may be visible to several accounts, but the
+          // virtual folders are distinct per account.
+          let same_folder =
+            files[i].file_id == file.file_id && !file.file_id.starts_with(VIRTUAL_ID_PREFIX);
+          if files[i].is_dir() && file.is_dir() && !same_folder {
+            files[i].merge_dir(file);
+          } else {
+            debug!("{} is shadowed by a higher priority account", file.name());
+          }
+        }
+        None => {
+          names.insert(file.name().to_string(), files.len());
+          files.push(file);
+        }
+      }
+    }
+  }
+  Ok(files)
+}
+
+// lists the children of |gfile| in its own account only. |gfile| is either a
+// drive folder or one of the virtual folders maintained by gdrivefs.
+fn list_account_dir(
+  gfile: &GoogleFile,
+  hub: &mut DriveHub,
+  auth: &oauth::GoogleAuthenticator,
+  options: &FileSystemOptions,
+) -> Result<Vec<GoogleFile>, Box<Error>> {
+  let mut files = if gfile.file_id == SHARED_DRIVES_ID {
+    try!(list_gdrive_shared_drives(hub, options))
+  } else if gfile.file_id == ORPHANS_ID {
+    try!(list_gdrive_orphans(&mut auth.clone(), options))
+  } else if gfile.file_id.starts_with(SNAPSHOTS_ID_PREFIX) {
+    // snapshots are looked up by date rather than listed.
+    Vec::new()
+  } else if gfile.file_id.starts_with(SNAPSHOT_ID_PREFIX) {
+    try!(list_gdrive_snapshot(gfile, hub, &mut auth.clone(), options))
+  } else if gfile.file_id == CONTROL_DIR_ID {
+    vec![virtual_file(
+      ACTIVITY_LOG_ID,
+      ACTIVITY_LOG_NAME,
+      "text/plain",
+      options,
+    )]
+  } else {
+    let team_drive_id = gfile.team_drive_id.as_ref().map(|id| &**id);
+    let mut files = try!(list_gdrive_dir(
+      &gfile.file_id,
+      team_drive_id,
+      None,
+      &mut auth.clone(),
+      options
+    ));
+    if options.comments_sidecars {
+      let sidecars: Vec<GoogleFile> = {
+        // a real file already named like the sidecar keeps the name.
+        let names: HashSet<&str> = files.iter().map(|file| file.name()).collect();
+        files
+          .iter()
+          .filter(|file| !file.is_dir())
+          .map(|file| (file, format!("{}{}", file.name(), COMMENTS_NAME_SUFFIX)))
+          .filter(|&(_, ref name)| !names.contains(name.as_str()))
+          .map(|(file, name)| {
+            virtual_file(
+              &format!("{}{}", COMMENTS_ID_PREFIX, file.file_id),
+              &name,
+              "application/json",
+              options,
+            )
+          })
+          .collect()
+      };
+      files.extend(sidecars);
+    }
+    if options.snapshots {
+      let mut snapshots = virtual_file(
+        &format!("{}{}", SNAPSHOTS_ID_PREFIX, gfile.file_id),
+        SNAPSHOTS_NAME,
+        FOLDER_MIME_TYPE,
+        options,
+      );
+      snapshots.team_drive_id = gfile.team_drive_id.clone();
+      files.push(snapshots);
+    }
+    files
+  };
+  if gfile.inode() == ROOT_INODE {
+    if options.shared_drives {
+      files.push(virtual_file(
+        SHARED_DRIVES_ID,
+        SHARED_DRIVES_NAME,
+        FOLDER_MIME_TYPE,
+        options,
+      ));
+    }
+    if options.orphans_folder {
+      files.push(virtual_file(
+        ORPHANS_ID,
+        ORPHANS_NAME,
+        FOLDER_MIME_TYPE,
+        options,
+      ));
+    }
+    if options.activity_log {
+      files.push(virtual_file(
+        CONTROL_DIR_ID,
+        CONTROL_DIR_NAME,
+        FOLDER_MIME_TYPE,
+        options,
+      ));
+    }
+  }
+  for file in &mut files {
+    file.account = gfile.account;
+  }
+  Ok(files)
+}
+
+// creates a file or folder that only exists within gdrivefs.
+fn virtual_file(id: &str, name: &str, mime_type: &str, options: &FileSystemOptions) -> GoogleFile {
+  GoogleFile::from_api_file(
+    google_drive3::File {
+      id: Some(id.into()),
+      name: Some(name.into()),
+      mime_type: Some(mime_type.into()),
+      ..Default::default()
+    },
+    options,
+  )
+}
+
+// generates the contents of the virtual file |gfile|.
+fn read_virtual_file(
+  gfile: &GoogleFile,
+  auth: oauth::GoogleAuthenticator,
+) -> Result<Vec<u8>, Box<Error>> {
+  if gfile.file_id == ACTIVITY_LOG_ID {
+    read_activity_log(auth)
+  } else if gfile.file_id.starts_with(COMMENTS_ID_PREFIX) {
+    let mut hub = google_drive3::Drive::new(common::new_hyper_tls_client(), auth);
+    get_gdrive_comments(&gfile.file_id[COMMENTS_ID_PREFIX.len()..], &mut hub)
+  } else {
+    Err(From::from(format!(
+      "unknown virtual file {}",
+      gfile.file_id
+    )))
+  }
+}
+
+// fetches the recent activity under the root folder of |auth|'s drive.
+fn read_activity_log(mut auth: oauth::GoogleAuthenticator) -> Result<Vec<u8>, Box<Error>> {
+  // the activity API doesn't understand the "root" alias.
+  let hub = google_drive3::Drive::new(common::new_hyper_tls_client(), auth.clone());
+  let root_id = match hub.files().get(ROOT_ID).param("fields", "id").doit() {
+    Ok((_, file)) => file.id.unwrap_or(ROOT_ID.into()),
+    Err(e) => {
+      warn!("Error while getting the root folder id: {}", e);
+      return Err(Box::new(e));
+    }
+  };
+  let log = try!(activity::query_activity_log(
+    &mut auth,
+    &root_id,
+    ACTIVITY_LOG_EVENTS
+  ));
+  Ok(log.into_bytes())
+}
+
+// lists the shared drives the user is a member of, as folders.
+fn list_gdrive_shared_drives(
+  hub: &mut DriveHub,
+  options: &FileSystemOptions,
+) -> Result<Vec<GoogleFile>, Box<Error>> {
+  debug!("In list_gdrive_shared_drives(…)");
   let mut file_vec: Vec<GoogleFile> = Vec::new();
   let mut page_token: Option<String> = None;
   loop {
     let mut list_op = hub
-      .files()
+      .teamdrives()
       .list()
-      .param(
-        "fields",
-        "nextPageToken,files(id,mimeType,name,size,createdTime,modifiedTime)",
-      )
-      .q(&format!("'{}' in parents and trashed = false", gfile_id))
-      .order_by("name")
-      .page_size(500);
+      .param("fields", "nextPageToken,teamDrives(id,name,createdTime)")
+      .page_size(100);
 
     if let Some(ref token) = page_token {
       list_op = list_op.page_token(token);
     }
 
-    let file_list = match list_op.doit() {
+    let drive_list = match list_op.doit() {
       Ok((_, l)) => l,
       Err(e) => {
-        warn!(
-          "Error while evaluating list_gdrive_dir({}, …): {}",
-          gfile_id, e
-        );
+        warn!("Error while evaluating list_gdrive_shared_drives(…): {}", e);
         return Err(Box::new(e));
       }
     };
 
-    page_token = file_list.next_page_token;
+    page_token = drive_list.next_page_token;
 
-    if let Some(files) = file_list.files {
-      for file in files {
-        file_vec.push(GoogleFile::from(file));
-      }
+    for drive in drive_list.team_drives.unwrap_or_default() {
+      file_vec.push(GoogleFile::from_api_file(
+        google_drive3::File {
+          id: drive.id.clone(),
+          team_drive_id: drive.id,
+          name: drive.name,
+          mime_type: Some(FOLDER_MIME_TYPE.into()),
+          created_time: drive.created_time.clone(),
+          modified_time: drive.created_time,
+          ..Default::default()
+        },
+        options,
+      ));
     }
 
     if page_token.is_none() {
@@ -256,62 +1347,1476 @@ fn list_gdrive_dir(gfile_id: &str, hub: &mut DriveHub) -> Result<Vec<GoogleFile>
   Ok(file_vec)
 }
 
-/// GDriveFS is a fuse filesytem backed by Google drive.
-pub struct GDriveFS {
-  authenticator: oauth::GoogleAuthenticator,
-  file_tree: sync::Arc<sync::RwLock<GoogleFileTree>>,
-  // map of inode -> file read handle
-  read_handles: sync::Mutex<BTreeMap<u64, http::FileReadHandle>>,
-  list_dir_pool: threadpool::ThreadPool,
-  options: FileReadOptions,
+// whether the request failing with |err| may succeed if retried: the
+// connection failed or the server did.
+fn is_transient_api_error(err: &google_drive3::Error) -> bool {
+  match *err {
+    google_drive3::Error::HttpError(_) => true,
+    google_drive3::Error::Failure(ref resp) => resp.status.is_server_error(),
+    _ => false,
+  }
 }
 
-impl GDriveFS {
-  /// Create a new GDriveFS using `auth` to provide authentication, and `options`
-  /// to control the properties of file reads.
-  pub fn new(auth: oauth::GoogleAuthenticator, options: FileReadOptions) -> GDriveFS {
-    GDriveFS {
-      authenticator: auth,
-      file_tree: sync::Arc::new(sync::RwLock::new(GoogleFileTree::new())),
-      read_handles: sync::Mutex::new(BTreeMap::new()),
-      list_dir_pool: threadpool::ThreadPool::new(4),
-      options: options,
+// whether the request failing with |err| was refused with 409 Conflict.
+fn is_conflict(err: &google_drive3::Error) -> bool {
+  match *err {
+    google_drive3::Error::Failure(ref resp) => resp.status == hyper::status::StatusCode::Conflict,
+    google_drive3::Error::BadRequest(ref resp) => serde_json::to_value(resp)
+      .ok()
+      .and_then(|resp| resp["error"]["code"].as_u64())
+      == Some(409),
+    _ => false,
+  }
+}
+
+// creates a new shared drive called |name|.
+fn create_gdrive_shared_drive(
+  name: &str,
+  hub: &mut DriveHub,
+  options: &FileSystemOptions,
+) -> Result<GoogleFile, Box<Error>> {
+  debug!("In create_gdrive_shared_drive({}, …)", name);
+  // the request id is kept across retries, so that Drive doesn't create a
+  // second drive when the first attempt reached it but its reply was lost.
+  let now = time::get_time();
+  let request_id = format!("gdrivefs-{}-{}", now.sec, now.nsec);
+  let request = google_drive3::TeamDrive {
+    name: Some(name.into()),
+    ..Default::default()
+  };
+  let mut attempt = 0;
+  let drive = loop {
+    let created = hub
+      .teamdrives()
+      .create(request.clone(), &request_id)
+      .param("fields", "id,name,createdTime")
+      .doit();
+    match created {
+      Ok((_, drive)) => break drive,
+      Err(ref e) if attempt < SHARED_DRIVE_CREATE_RETRIES && is_transient_api_error(e) => {
+        attempt += 1;
+        warn!("creating shared drive {} failed, retrying: {}", name, e);
+        thread::sleep(std::time::Duration::from_secs(1 << attempt));
+      }
+      // the drive an earlier attempt created is already there.
+      Err(ref e) if attempt > 0 && is_conflict(e) => {
+        return Err(Box::new(std::io::Error::from_raw_os_error(libc::EEXIST)));
+      }
+      Err(e) => {
+        warn!(
+          "Error while evaluating create_gdrive_shared_drive({}, …): {}",
+          name, e
+        );
+        return Err(Box::new(e));
+      }
     }
+  };
+  Ok(GoogleFile::from_api_file(
+    google_drive3::File {
+      id: drive.id.clone(),
+      team_drive_id: drive.id,
+      name: drive.name,
+      mime_type: Some(FOLDER_MIME_TYPE.into()),
+      created_time: drive.created_time.clone(),
+      modified_time: drive.created_time,
+      ..Default::default()
+    },
+    options,
+  ))
+}
+
+// lists the children of the folder |gfile_id|, only those modified after
+// |modified_since| if set.
+fn list_gdrive_dir(
+  gfile_id: &str,
+  team_drive_id: Option<&str>,
+  modified_since: Option<time::Timespec>,
+  auth: &mut oauth::GoogleAuthenticator,
+  options: &FileSystemOptions,
+) -> Result<Vec<GoogleFile>, Box<Error>> {
+  debug!("In list_gdrive_dir({}, …)", gfile_id);
+  let mut file_vec: Vec<GoogleFile> = Vec::new();
+  let mut page_token: Option<String> = None;
+  let mut query = if options.show_trashed {
+    format!("'{}' in parents", gfile_id)
+  } else {
+    format!("'{}' in parents and trashed = false", gfile_id)
+  };
+  if let Some(since) = modified_since {
+    // times in queries are UTC.
+    query.push_str(&format!(
+      " and modifiedTime > '{}'",
+      time::at_utc(since).strftime(RFC3339_FMT).unwrap()
+    ));
   }
+  let fields = format!("nextPageToken,files({})", FILE_FIELDS);
+  loop {
+    let token = page_token.take();
+    let mut params = vec![
+      ("fields", fields.as_str()),
+      ("q", query.as_str()),
+      ("orderBy", "name"),
+      ("pageSize", "500"),
+    ];
 
-  /// Starts a background thread that will periodically refresh filesystem
-  /// metadata at |interval|.
-  pub fn start_auto_refresh(&self, interval: std::time::Duration) {
+    if let Some(team_drive_id) = team_drive_id {
+      params.push(("corpora", "teamDrive"));
+      params.push(("teamDriveId", team_drive_id));
+      params.push(("includeTeamDriveItems", "true"));
+      params.push(("supportsTeamDrives", "true"));
+    }
+
+    if let Some(ref token) = token {
+      params.push(("pageToken", token));
+    }
+
+    // listed directly rather than through the hub, for gzip compression.
+    let file_list = match listing::list_files(auth, &params) {
+      Ok(l) => l,
+      Err(e) => {
+        warn!(
+          "Error while evaluating list_gdrive_dir({}, …): {}",
+          gfile_id, e
+        );
+        health::record_failure();
+        return Err(e);
+      }
+    };
+    health::record_success();
+
+    page_token = file_list.next_page_token;
+
+    if let Some(files) = file_list.files {
+      for file in files {
+        if options.is_visible(&file) {
+          file_vec.push(GoogleFile::from_api_file(file, options));
+        }
+      }
+    }
+
+    if page_token.is_none() {
+      break;
+    }
+  }
+  Ok(file_vec)
+}
+
+// lists the folder of the snapshot folder |gfile| as it was at the end of the
+// snapshot's date: files created later are left out, subfolders are shown as
+// of the same date, and files are shown at their last revision from then.
+// Files without downloadable revisions, such as Google Docs, are left out.
+fn list_gdrive_snapshot(
+  gfile: &GoogleFile,
+  hub: &mut DriveHub,
+  auth: &mut oauth::GoogleAuthenticator,
+  options: &FileSystemOptions,
+) -> Result<Vec<GoogleFile>, Box<Error>> {
+  let mut parts = gfile.file_id[SNAPSHOT_ID_PREFIX.len()..].splitn(2, ':');
+  let (date, folder_id) = match (parts.next(), parts.next()) {
+    (Some(date), Some(folder_id)) => (date, folder_id),
+    _ => return Err(From::from(format!("invalid snapshot {}", gfile.file_id))),
+  };
+  let end_of_day = try!(time::strptime(date, SNAPSHOT_DATE_FMT))
+    .to_timespec()
+    .sec
+    + 24 * 3600;
+  // revision times are RFC 3339 in UTC, which compare correctly as text.
+  let cutoff = format!("{}T23:59:59.999Z", date);
+  let team_drive_id = gfile.team_drive_id.as_ref().map(|id| &**id);
+  let mut files = Vec::new();
+  for file in try!(list_gdrive_dir(folder_id, team_drive_id, None, auth, options)) {
+    if file.file_attr.crtime.sec >= end_of_day {
+      continue;
+    }
+    if file.is_dir() {
+      files.push(file.with_id(format!("{}{}:{}", SNAPSHOT_ID_PREFIX, date, file.file_id)));
+      continue;
+    }
+    if file.kind() != fuse::FileType::RegularFile || file.md5_checksum.is_none() {
+      continue;
+    }
+    let revision = match try!(get_gdrive_revision_at(&file.file_id, &cutoff, hub)) {
+      Some(revision) => revision,
+      None => {
+        debug!("no revision of {} left from {}", file.name(), date);
+        continue;
+      }
+    };
+    let mut revision_file = file.with_id(format!(
+      "{}{}:{}",
+      REVISION_ID_PREFIX,
+      file.file_id,
+      revision.id.unwrap_or_default()
+    ));
+    let size = revision
+      .size
+      .and_then(|size| u64::from_str(&size).ok())
+      .unwrap_or(0);
+    let modified_time = parse_rfc3339(&revision.modified_time.unwrap_or_default());
+    revision_file.file_attr.size = size;
+    revision_file.file_attr.blocks = size / constants::BLOCK_SIZE as u64;
+    revision_file.file_attr.atime = modified_time;
+    revision_file.file_attr.mtime = modified_time;
+    revision_file.file_attr.ctime = modified_time;
+    // checksums are looked up by file id, which would give the current ones.
+    revision_file.md5_checksum = None;
+    files.push(revision_file);
+  }
+  Ok(files)
+}
+
+// finds the last revision of |gfile_id| modified at or before |cutoff|, an
+// RFC 3339 time in UTC.
+fn get_gdrive_revision_at(
+  gfile_id: &str,
+  cutoff: &str,
+  hub: &mut DriveHub,
+) -> Result<Option<google_drive3::Revision>, Box<Error>> {
+  debug!("In get_gdrive_revision_at({}, {}, …)", gfile_id, cutoff);
+  let mut found = None;
+  let mut page_token: Option<String> = None;
+  loop {
+    let mut list_op = hub
+      .revisions()
+      .list(gfile_id)
+      .page_size(1000)
+      .param("fields", "nextPageToken,revisions(id,modifiedTime,size)");
+
+    if let Some(ref token) = page_token {
+      list_op = list_op.page_token(token);
+    }
+
+    let revision_list = match list_op.doit() {
+      Ok((_, l)) => l,
+      Err(e) => {
+        warn!(
+          "Error while evaluating get_gdrive_revision_at({}, …): {}",
+          gfile_id, e
+        );
+        health::record_failure();
+        return Err(Box::new(e));
+      }
+    };
+    health::record_success();
+
+    page_token = revision_list.next_page_token;
+
+    // revisions are listed oldest first.
+    for revision in revision_list.revisions.unwrap_or_default() {
+      let before_cutoff = match revision.modified_time {
+        Some(ref modified_time) => modified_time.as_str() <= cutoff,
+        None => false,
+      };
+      if before_cutoff {
+        found = Some(revision);
+      }
+    }
+
+    if page_token.is_none() {
+      break;
+    }
+  }
+  Ok(found)
+}
+
+// lists the files owned by the user of |auth| that have no parent folder.
+// Drive can't query for those, so all owned files are scanned.
+fn list_gdrive_orphans(
+  auth: &mut oauth::GoogleAuthenticator,
+  options: &FileSystemOptions,
+) -> Result<Vec<GoogleFile>, Box<Error>> {
+  debug!("In list_gdrive_orphans(…)");
+  let mut file_vec: Vec<GoogleFile> = Vec::new();
+  let mut page_token: Option<String> = None;
+  let query = if options.show_trashed {
+    "'me' in owners"
+  } else {
+    "'me' in owners and trashed = false"
+  };
+  let fields = format!("nextPageToken,files({},parents)", FILE_FIELDS);
+  loop {
+    let token = page_token.take();
+    let mut params = vec![
+      ("fields", fields.as_str()),
+      ("q", query),
+      ("orderBy", "name"),
+      ("pageSize", "1000"),
+    ];
+    if let Some(ref token) = token {
+      params.push(("pageToken", token));
+    }
+
+    let file_list = match listing::list_files(auth, &params) {
+      Ok(l) => l,
+      Err(e) => {
+        warn!("Error while evaluating list_gdrive_orphans(…): {}", e);
+        health::record_failure();
+        return Err(e);
+      }
+    };
+    health::record_success();
+
+    page_token = file_list.next_page_token;
+
+    if let Some(files) = file_list.files {
+      for file in files {
+        let orphan = file
+          .parents
+          .as_ref()
+          .map(|parents| parents.is_empty())
+          .unwrap_or(true);
+        if orphan && options.is_visible(&file) {
+          file_vec.push(GoogleFile::from_api_file(file, options));
+        }
+      }
+    }
+
+    if page_token.is_none() {
+      break;
+    }
+  }
+  Ok(file_vec)
+}
+
+// fetches the sharing permissions of |gfile_id|, formatted as one
+// "type:who:role" line per permission.
+fn get_gdrive_permissions(gfile_id: &str, hub: &mut DriveHub) -> Result<String, Box<Error>> {
+  debug!("In get_gdrive_permissions({}, …)", gfile_id);
+  let mut lines = String::new();
+  let mut page_token: Option<String> = None;
+  loop {
+    let mut list_op = hub
+      .permissions()
+      .list(gfile_id)
+      .supports_team_drives(true)
+      .param(
+        "fields",
+        "nextPageToken,permissions(type,role,emailAddress,domain)",
+      );
+
+    if let Some(ref token) = page_token {
+      list_op = list_op.page_token(token);
+    }
+
+    let perm_list = match list_op.doit() {
+      Ok((_, l)) => l,
+      Err(e) => {
+        warn!(
+          "Error while evaluating get_gdrive_permissions({}, …): {}",
+          gfile_id, e
+        );
+        return Err(Box::new(e));
+      }
+    };
+
+    page_token = perm_list.next_page_token;
+
+    for perm in perm_list.permissions.unwrap_or_default() {
+      let who = perm.email_address.or(perm.domain).unwrap_or_default();
+      lines.push_str(&format!(
+        "{}:{}:{}\n",
+        perm.type_.unwrap_or_default(),
+        who,
+        perm.role.unwrap_or_default()
+      ));
+    }
+
+    if page_token.is_none() {
+      break;
+    }
+  }
+  Ok(lines)
+}
+
+// fetches the comments on |gfile_id|, formatted as a JSON array.
+fn get_gdrive_comments(gfile_id: &str, hub: &mut DriveHub) -> Result<Vec<u8>, Box<Error>> {
+  debug!("In get_gdrive_comments({}, …)", gfile_id);
+  let mut comments: Vec<google_drive3::Comment> = Vec::new();
+  let mut page_token: Option<String> = None;
+  loop {
+    let mut list_op = hub.comments().list(gfile_id).page_size(100).param(
+      "fields",
+      &format!("nextPageToken,comments({})", COMMENT_FIELDS),
+    );
+
+    if let Some(ref token) = page_token {
+      list_op = list_op.page_token(token);
+    }
+
+    let comment_list = match list_op.doit() {
+      Ok((_, l)) => l,
+      Err(e) => {
+        warn!(
+          "Error while evaluating get_gdrive_comments({}, …): {}",
+          gfile_id, e
+        );
+        return Err(Box::new(e));
+      }
+    };
+
+    page_token = comment_list.next_page_token;
+    comments.extend(comment_list.comments.unwrap_or_default());
+
+    if page_token.is_none() {
+      break;
+    }
+  }
+  Ok(try!(serde_json::to_vec_pretty(&comments)))
+}
+
+// whether the listing of |gfile| can be refreshed by fetching only the
+// children modified since, see FileSystemOptions::differential_refresh.
+fn refreshable_by_changes(gfile: &GoogleFile, options: &FileSystemOptions) -> bool {
+  gfile.inode() != ROOT_INODE
+    && gfile.merged_dirs().is_empty()
+    && !gfile.file_id.starts_with(VIRTUAL_ID_PREFIX)
+    && !options.comments_sidecars
+}
+
+// lists again those of |dirs| that were listed, e.g. after changes to them
+// that can't be applied in place.
+fn relist_dirs<I: IntoIterator<Item = u64>>(
+  file_tree: &sync::RwLock<GoogleFileTree>,
+  auths: &[oauth::GoogleAuthenticator],
+  options: &FileSystemOptions,
+  dirs: I,
+) {
+  let mut hubs = new_hubs(auths);
+  for inode in dirs {
+    let gfile = {
+      let tree = file_tree.read().unwrap();
+      match tree.get_file(&inode) {
+        Some(gfile) if tree.has_children(&inode) => gfile.clone(),
+        _ => continue,
+      }
+    };
+    debug!("listing again dir id {}", gfile.file_id);
+    match list_dir(&gfile, &mut hubs, auths, options) {
+      Ok(files) => {
+        let ignore_rules = if inode == ROOT_INODE && options.ignore_file {
+          load_ignore_rules(&files, auths)
+        } else {
+          None
+        };
+        let mut tree = file_tree.write().unwrap();
+        if let Some(ignore_rules) = ignore_rules {
+          tree.ignore_rules = ignore_rules;
+        }
+        tree.set_children(&inode, files);
+      }
+      Err(err) => {
+        warn!("list_drive_dir: {:?}", err);
+      }
+    }
+  }
+}
+
+// applies the changes made to the drive of the first of |auths| since
+// |page_token| to |file_tree|, and returns the token to fetch the next
+// changes from. |root_id| is the id of the folder at the root of the mount.
+fn sync_changes(
+  file_tree: &sync::RwLock<GoogleFileTree>,
+  auths: &[oauth::GoogleAuthenticator],
+  options: &FileSystemOptions,
+  root_id: &str,
+  page_token: &str,
+) -> Result<String, Box<Error>> {
+  let mut auth = auths[0].clone();
+  let fields = format!(
+    "nextPageToken,newStartPageToken,changes(fileId,removed,file({},parents,trashed))",
+    FILE_FIELDS
+  );
+  // folders whose listing couldn't be updated in place.
+  let mut relist: HashSet<u64> = HashSet::new();
+  let mut page_token = String::from(page_token);
+  loop {
+    let page = try!(listing::list_changes(&mut auth, &page_token, &fields));
+    let changes = page.changes.unwrap_or(Vec::new());
+    if !changes.is_empty() {
+      debug!("applying {} drive changes", changes.len());
+      let mut tree = file_tree.write().unwrap();
+      for change in changes {
+        apply_change(&mut tree, change, root_id, options, &mut relist);
+      }
+    }
+    match (page.next_page_token, page.new_start_page_token) {
+      (Some(next), _) => page_token = next,
+      (None, Some(start)) => {
+        page_token = start;
+        break;
+      }
+      (None, None) => return Err(From::from("changes.list returned no page token")),
+    }
+  }
+  relist_dirs(file_tree, auths, options, relist);
+  Ok(page_token)
+}
+
+// updates |tree| with |change|, adding the folders whose listing can't be
+// updated in place to |relist|.
+fn apply_change(
+  tree: &mut GoogleFileTree,
+  change: google_drive3::Change,
+  root_id: &str,
+  options: &FileSystemOptions,
+  relist: &mut HashSet<u64>,
+) {
+  // changes to shared drives themselves concern no file.
+  let file_id = match change.file_id {
+    Some(ref file_id) if file_id != root_id => file_id.clone(),
+    _ => return,
+  };
+  let inode = inode_of(&file_id);
+  let removed = change.removed.unwrap_or(false);
+  // a file removed rather than trashed was deleted for good or, far more
+  // often, is no longer shared with the user. Either way its content can't
+  // be read anymore, which is access denied rather than an I/O error.
+  if !removed {
+    tree.lost_access.remove(&inode);
+  } else if tree.get_file(&inode).is_some() && tree.lost_access.insert(inode) {
+    warn!(
+      "lost access to {} ({}), or it was deleted for good: reads from open handles fail with EACCES",
+      tree.path_of(&inode),
+      file_id
+    );
+  }
+  let file = change
+    .file
+    .filter(|file| !removed && !file.trashed.unwrap_or(false));
+  let new_parent = file
+    .as_ref()
+    .and_then(|file| file.parents.as_ref())
+    .and_then(|parents| parents.first())
+    .map(|parent_id| {
+      if parent_id == root_id {
+        ROOT_INODE
+      } else {
+        inode_of(parent_id)
+      }
+    });
+  let in_place = |tree: &GoogleFileTree, dir: u64| {
+    tree
+      .get_file(&dir)
+      .map_or(false, |gfile| refreshable_by_changes(gfile, options))
+  };
+  if let Some(old_parent) = tree.parents.get(&inode).cloned() {
+    if new_parent != Some(old_parent) {
+      if in_place(tree, old_parent) {
+        tree.unlink_child(old_parent, inode);
+      } else {
+        relist.insert(old_parent);
+      }
+    }
+  }
+  let file = match file {
+    Some(file) => file,
+    None => return,
+  };
+  let gfile = GoogleFile::from_api_file(file, options);
+  match new_parent {
+    Some(parent) if tree.has_children(&parent) => {
+      if in_place(tree, parent) {
+        tree.update_children(&parent, vec![gfile]);
+      } else {
+        relist.insert(parent);
+      }
+    }
+    // the new parent wasn't listed yet, but the file may be open.
+    _ => {
+      if tree.get_file(&inode).is_some() {
+        tree.insert_node(None, gfile);
+      }
+    }
+  }
+}
+
+// lists the directory |ino| from google drive, unless its children are
+// already known. Returns an errno suitable for a fuse reply on failure.
+fn ensure_dir_listed(
+  file_tree: &sync::RwLock<GoogleFileTree>,
+  auths: &[oauth::GoogleAuthenticator],
+  options: &FileSystemOptions,
+  ino: u64,
+) -> Result<(), libc::c_int> {
+  let gfile = {
+    let tree = file_tree.read().unwrap();
+    match tree.get_file(&ino) {
+      Some(attr) if !attr.is_dir() => return Err(libc::ENOTDIR),
+      Some(_) if tree.has_children(&ino) => return Ok(()),
+      Some(attr) => attr.clone(),
+      None => return Err(libc::ENOENT),
+    }
+  };
+  // need to list the directory
+  let files = match list_dir(&gfile, &mut new_hubs(auths), auths, options) {
+    Ok(files) => files,
+    Err(err) => return Err(drive_error::errno(&*err)),
+  };
+  let ignore_rules = if ino == ROOT_INODE && options.ignore_file {
+    load_ignore_rules(&files, auths)
+  } else {
+    None
+  };
+  let mut tree = file_tree.write().unwrap();
+  if let Some(ignore_rules) = ignore_rules {
+    tree.ignore_rules = ignore_rules;
+  }
+  tree.set_children(&ino, files);
+  Ok(())
+}
+
+// finds the folder at |path| in the drive of each of |auths|, returning the
+// accounts that have one with the id of their folder. Panics if none has
+// one, since the mount would be empty.
+fn root_folders(auths: &[oauth::GoogleAuthenticator], path: &str) -> Vec<(usize, String)> {
+  let mut roots = Vec::new();
+  for (account, auth) in auths.iter().enumerate() {
+    match listing::find_by_path(&mut auth.clone(), path, "id,mimeType") {
+      Ok(ref folder) if folder.mime_type.as_ref().map(|t| t.as_str()) == Some(FOLDER_MIME_TYPE) => {
+        roots.push((account, folder.id.clone().unwrap_or_default()))
+      }
+      Ok(_) => warn!("{} is not a folder in account {}, leaving it out", path, account),
+      Err(err) => warn!("no folder {} in account {}, leaving it out: {}", path, account, err),
+    }
+  }
+  if roots.is_empty() {
+    panic!("Could not find the root folder {} in any account", path);
+  }
+  roots
+}
+
+// loads the ignore rules from the ignore file among |files|, the root
+// listing. Returns None if the rules couldn't be fetched, to keep the
+// current ones.
+fn load_ignore_rules(
+  files: &[GoogleFile],
+  auths: &[oauth::GoogleAuthenticator],
+) -> Option<ignore::IgnoreRules> {
+  let ignore_file = files.iter().find(|file| {
+    file.name() == ignore::IGNORE_FILE_NAME
+      && file.kind() == fuse::FileType::RegularFile
+      && !file.is_virtual_file()
+  });
+  let ignore_file = match ignore_file {
+    Some(ignore_file) => ignore_file,
+    None => return Some(ignore::IgnoreRules::default()),
+  };
+  let mut auth = auths[ignore_file.account].clone();
+  match ignore::fetch_rules(&mut auth, &ignore_file.file_id) {
+    Ok(rules) => Some(rules),
+    Err(err) => {
+      warn!("could not load {}: {}", ignore::IGNORE_FILE_NAME, err);
+      None
+    }
+  }
+}
+
+// fetches the metadata of |file_id| from drive and updates the file at |ino|
+// with it.
+fn refresh_file(
+  file_tree: &sync::RwLock<GoogleFileTree>,
+  auth: &mut oauth::GoogleAuthenticator,
+  options: &FileSystemOptions,
+  ino: u64,
+  file_id: &str,
+) -> Result<(), Box<Error>> {
+  let api_file = match listing::get_file(auth, file_id, FILE_FIELDS) {
+    Ok(api_file) => api_file,
+    Err(err) => {
+      health::record_failure();
+      return Err(err);
+    }
+  };
+  health::record_success();
+  let fresh = GoogleFile::from_api_file(api_file, options);
+  let mut tree = file_tree.write().unwrap();
+  if let Some(gfile) = tree.file_attrs.get_mut(&ino) {
+    if gfile.file_attr.size != fresh.file_attr.size || gfile.md5_checksum != fresh.md5_checksum {
+      debug!("inode {} changed since it was listed", ino);
+    }
+    // the name and account depend on how the file was reached, so are kept.
+    gfile.file_attr = fresh.file_attr;
+    gfile.mime_type = fresh.mime_type;
+    gfile.md5_checksum = fresh.md5_checksum;
+    gfile.symlink_target = fresh.symlink_target;
+  }
+  Ok(())
+}
+
+// finds the item at |path|, relative to the root of the mount, listing the
+// folders on the way as needed. Returns an errno on failure.
+fn find_path(
+  file_tree: &sync::RwLock<GoogleFileTree>,
+  auths: &[oauth::GoogleAuthenticator],
+  options: &FileSystemOptions,
+  path: &str,
+) -> Result<GoogleFile, libc::c_int> {
+  let mut ino = ROOT_INODE;
+  for name in path.split('/').filter(|name| !name.is_empty()) {
+    try!(ensure_dir_listed(file_tree, auths, options, ino));
+    let child = file_tree.read().unwrap().find_child(&ino, name);
+    ino = match child {
+      Some(child) => child,
+      None => return Err(libc::ENOENT),
+    };
+  }
+  file_tree
+    .read()
+    .unwrap()
+    .get_file(&ino)
+    .cloned()
+    .ok_or(libc::ENOENT)
+}
+
+// A single entry of a directory listing, as captured by opendir.
+struct DirEntry {
+  inode: u64,
+  kind: fuse::FileType,
+  name: String,
+}
+
+/// GDriveFS is a fuse filesytem backed by Google drive.
+pub struct GDriveFS {
+  // one authenticator per account, in priority order.
+  authenticators: Vec<oauth::GoogleAuthenticator>,
+  file_tree: sync::Arc<sync::RwLock<GoogleFileTree>>,
+  // map of inode -> file read handle
+  read_handles: sync::Arc<sync::Mutex<BTreeMap<u64, http::FileReadHandle>>>,
+  // inodes of the idle read handles, least recently used first.
+  idle_read_handles: sync::Arc<sync::Mutex<VecDeque<u64>>>,
+  // orders chunk downloads across all files.
+  download_scheduler: sync::Arc<scheduler::DownloadScheduler>,
+  // fails reads missing options.read_deadline, if set.
+  read_deadlines: Option<sync::Arc<deadline::DeadlineWatcher>>,
+  // map of directory handle -> snapshot of the listing taken at opendir.
+  dir_handles: sync::Arc<sync::Mutex<BTreeMap<u64, Vec<DirEntry>>>>,
+  // map of file handle -> contents of an open virtual file.
+  virtual_files: sync::Arc<sync::Mutex<BTreeMap<u64, Vec<u8>>>>,
+  // map of file handle -> file open for writing.
+  write_handles: sync::Arc<sync::Mutex<BTreeMap<u64, sync::Arc<WriteHandle>>>>,
+  // next directory, virtual file or write handle.
+  next_handle: sync::atomic::AtomicUsize,
+  list_dir_pool: pool::LazyPool,
+  // inodes of the files refreshed on open with shallow_stat, whose
+  // attributes the kernel may only keep for TTL.
+  revalidated: sync::Mutex<HashSet<u64>>,
+  // runs uploads and the downloads staging files for writing, which can
+  // take long enough to hold up listings.
+  upload_pool: pool::LazyPool,
+  // consecutive upload failures, which turn the mount read-only.
+  upload_failures: sync::Arc<sync::Mutex<upload::FailureTracker>>,
+  // times the changes sync started over from a fresh page token, and why
+  // it last did.
+  changes_resets: sync::Arc<sync::Mutex<(u32, Option<String>)>>,
+  // adjustable at runtime through the control socket.
+  options: sync::Arc<sync::RwLock<FileReadOptions>>,
+  fs_options: FileSystemOptions,
+}
+
+impl GDriveFS {
+  /// Create a new GDriveFS using `auth` to provide authentication, `options`
+  /// to control the properties of file reads, and `fs_options` to control how
+  /// drive items are presented.
+  pub fn new(
+    auth: oauth::GoogleAuthenticator,
+    options: FileReadOptions,
+    fs_options: FileSystemOptions,
+  ) -> GDriveFS {
+    GDriveFS::new_union(vec![auth], options, fs_options)
+  }
+
+  /// Create a new GDriveFS merging the drives of the accounts in `auths` into
+  /// a single namespace. Accounts earlier in `auths` take priority: folders
+  /// with the same path are merged, any other name clash shows the entry from
+  /// the higher priority account, and new shared drives are created in the
+  /// highest priority account containing the parent folder.
+  pub fn new_union(
+    auths: Vec<oauth::GoogleAuthenticator>,
+    options: FileReadOptions,
+    fs_options: FileSystemOptions,
+  ) -> GDriveFS {
+    let mut root_attr = ROOT_ATTR;
+    root_attr.uid = fs_options.owner_uid;
+    root_attr.gid = fs_options.owner_gid;
+    let roots = match fs_options.root_folder {
+      Some(ref path) => root_folders(&auths, path),
+      None => (0..auths.len()).map(|account| (account, ROOT_ID.into())).collect(),
+    };
+    let tree = GoogleFileTree::new(root_attr, &roots);
+    GDriveFS {
+      authenticators: auths,
+      file_tree: sync::Arc::new(sync::RwLock::new(tree)),
+      read_handles: sync::Arc::new(sync::Mutex::new(BTreeMap::new())),
+      idle_read_handles: sync::Arc::new(sync::Mutex::new(VecDeque::new())),
+      download_scheduler: sync::Arc::new(scheduler::DownloadScheduler::new(
+        options.max_concurrent_downloads,
+        options.max_readahead_files,
+        options.overload_backlog,
+      )),
+      read_deadlines: options
+        .read_deadline
+        .map(|read_deadline| sync::Arc::new(deadline::DeadlineWatcher::new(read_deadline))),
+      dir_handles: sync::Arc::new(sync::Mutex::new(BTreeMap::new())),
+      virtual_files: sync::Arc::new(sync::Mutex::new(BTreeMap::new())),
+      write_handles: sync::Arc::new(sync::Mutex::new(BTreeMap::new())),
+      next_handle: sync::atomic::AtomicUsize::new(1),
+      list_dir_pool: pool::LazyPool::new("list_dir", 4),
+      revalidated: sync::Mutex::new(HashSet::new()),
+      upload_pool: pool::LazyPool::new("upload", 4),
+      upload_failures: sync::Arc::new(sync::Mutex::new(upload::FailureTracker::default())),
+      changes_resets: sync::Arc::new(sync::Mutex::new((0, None))),
+      options: sync::Arc::new(sync::RwLock::new(options)),
+      fs_options: fs_options,
+    }
+  }
+
+  /// Returns a GDriveFS sharing the metadata cache, workers and
+  /// authenticators of this one, to serve a new fuse session after the
+  /// previous one died. Handles from the previous session are dropped,
+  /// since the kernel forgot them, but the read handle maps stay shared so
+  /// that the control socket sees the handles of the current session.
+  pub fn new_session(&self) -> GDriveFS {
+    self.read_handles.lock().unwrap().clear();
+    self.idle_read_handles.lock().unwrap().clear();
+    GDriveFS {
+      authenticators: self.authenticators.clone(),
+      file_tree: self.file_tree.clone(),
+      read_handles: self.read_handles.clone(),
+      idle_read_handles: self.idle_read_handles.clone(),
+      download_scheduler: self.download_scheduler.clone(),
+      read_deadlines: self.read_deadlines.clone(),
+      dir_handles: sync::Arc::new(sync::Mutex::new(BTreeMap::new())),
+      virtual_files: sync::Arc::new(sync::Mutex::new(BTreeMap::new())),
+      write_handles: sync::Arc::new(sync::Mutex::new(BTreeMap::new())),
+      next_handle: sync::atomic::AtomicUsize::new(1),
+      list_dir_pool: self.list_dir_pool.clone(),
+      revalidated: sync::Mutex::new(HashSet::new()),
+      upload_pool: self.upload_pool.clone(),
+      upload_failures: self.upload_failures.clone(),
+      changes_resets: self.changes_resets.clone(),
+      options: self.options.clone(),
+      fs_options: self.fs_options.clone(),
+    }
+  }
+
+  // drops the least recently used idle read handles in |handles| until
+  // there are no more than max_read_handles.
+  fn recycle_read_handles(&self, handles: &mut BTreeMap<u64, http::FileReadHandle>) {
+    let mut idle = self.idle_read_handles.lock().unwrap();
+    let max_read_handles = self.options.read().unwrap().max_read_handles;
+    while handles.len() > max_read_handles {
+      match idle.pop_front() {
+        Some(ino) => {
+          debug!("recycling idle read handle for inode {}", ino);
+          handles.remove(&ino);
+        }
+        None => break,
+      }
+    }
+  }
+
+  // allocates a handle for a directory, virtual file or file open for
+  // writing.
+  fn new_handle(&self) -> u64 {
+    self
+      .next_handle
+      .fetch_add(1, sync::atomic::Ordering::SeqCst) as u64
+  }
+
+  // opens the native Google item |gfile|, exported as |format|. Like
+  // virtual files, it's exported whole on open and served with direct io,
+  // and its size is updated once known.
+  fn open_export(&self, gfile: GoogleFile, format: ExportFormat, reply: fuse::ReplyOpen) {
+    let fh = self.new_handle();
+    let mut auth = self.authenticators[gfile.account].clone();
+    let virtual_files = self.virtual_files.clone();
+    let file_tree = self.file_tree.clone();
+    self.list_dir_pool.execute(move || {
+      match google_docs::export(&mut auth, &gfile.file_id, &format.mime_type) {
+        Ok(contents) => {
+          let size = contents.len() as u64;
+          google_docs::record_export_size(&gfile.file_id, gfile.file_attr.mtime, size);
+          if let Some(gfile) = file_tree
+            .write()
+            .unwrap()
+            .file_attrs
+            .get_mut(&gfile.inode())
+          {
+            gfile.file_attr.size = size;
+            gfile.file_attr.blocks = size / constants::BLOCK_SIZE as u64;
+          }
+          virtual_files.lock().unwrap().insert(fh, contents);
+          reply.opened(fh, fuse::consts::FOPEN_DIRECT_IO);
+        }
+        Err(err) => reply.error(drive_error::errno(&*err)),
+      }
+    });
+  }
+
+  // opens a snapshot of the virtual file |gfile|. The contents are generated
+  // on open, and served with direct io since their size isn't known up front.
+  fn open_virtual_file(&self, gfile: GoogleFile, reply: fuse::ReplyOpen) {
+    let fh = self.new_handle();
+    let auth = self.authenticators[gfile.account].clone();
+    let virtual_files = self.virtual_files.clone();
+    self
+      .list_dir_pool
+      .execute(move || match read_virtual_file(&gfile, auth) {
+        Ok(contents) => {
+          virtual_files.lock().unwrap().insert(fh, contents);
+          reply.opened(fh, fuse::consts::FOPEN_DIRECT_IO);
+        }
+        Err(err) => reply.error(drive_error::errno(&*err)),
+      });
+  }
+
+  // whether the mount accepts writes: it was mounted writable and uploads
+  // haven't been failing, see upload::FailureTracker.
+  fn writable(&self) -> bool {
+    self.fs_options.writable
+      && self
+        .upload_failures
+        .lock()
+        .unwrap()
+        .read_only_reason()
+        .is_none()
+  }
+
+  // the errno writing to |gfile| fails with, or None if it can be written.
+  fn write_error(&self, gfile: &GoogleFile) -> Option<libc::c_int> {
+    let google_doc = gfile
+      .mime_type
+      .as_ref()
+      .map_or(false, |mime_type| mime_type.starts_with(GOOGLE_APPS_MIME_PREFIX));
+    if !self.writable() {
+      Some(libc::EROFS)
+    } else if gfile.is_dir() {
+      Some(libc::EISDIR)
+    } else if gfile.kind() != fuse::FileType::RegularFile
+      || gfile.file_id.starts_with(VIRTUAL_ID_PREFIX)
+      || google_doc
+    {
+      Some(libc::EPERM)
+    } else {
+      None
+    }
+  }
+
+  // drops the read handle of |ino| if it's idle, so that the content it
+  // cached isn't served once the file is rewritten.
+  fn drop_idle_read_handle(&self, ino: u64) {
+    let mut handles = self.read_handles.lock().unwrap();
+    if handles.get(&ino).map_or(false, |handle| !handle.is_open()) {
+      handles.remove(&ino);
+      self
+        .idle_read_handles
+        .lock()
+        .unwrap()
+        .retain(|&idle| idle != ino);
+    }
+  }
+
+  // whether a drive file of |size| bytes is over --max-write-file-size.
+  fn over_max_write_size(&self, size: u64) -> bool {
+    match self.fs_options.max_write_file_size {
+      Some(max_size) if size > max_size => {
+        warn!("refusing to write {} bytes, over --max-write-file-size", size);
+        true
+      }
+      _ => false,
+    }
+  }
+
+  // opens |gfile| for writing, staging its current content unless
+  // |truncate| is set.
+  fn open_for_write(&self, gfile: GoogleFile, truncate: bool, reply: fuse::ReplyOpen) {
+    if let Some(errno) = self.write_error(&gfile) {
+      reply.error(errno);
+      return;
+    }
+    if !truncate && self.over_max_write_size(gfile.file_attr.size) {
+      reply.error(libc::EFBIG);
+      return;
+    }
+    self.drop_idle_read_handle(gfile.inode());
+    let fh = self.new_handle();
+    let mut auth = self.authenticators[gfile.account].clone();
+    let staging_dir = self.fs_options.staging_dir.clone();
+    let write_handles = self.write_handles.clone();
+    self.upload_pool.execute(move || {
+      let staged = upload::StagedFile::create(&staging_dir)
+        .map_err(|err| -> Box<Error> { Box::new(err) })
+        .and_then(|mut staged| {
+          if !truncate {
+            try!(staged.fill_from(&mut auth, &gfile.file_id));
+          }
+          Ok(staged)
+        });
+      match staged {
+        Ok(staged) => {
+          write_handles.lock().unwrap().insert(
+            fh,
+            sync::Arc::new(WriteHandle {
+              ino: gfile.inode(),
+              file_id: gfile.file_id.clone(),
+              account: gfile.account,
+              staged: sync::Mutex::new(staged),
+              uploading: sync::Mutex::new(()),
+              previous_kept: sync::atomic::AtomicBool::new(false),
+            }),
+          );
+          // the kernel mustn't serve reads from the pages of the old content.
+          reply.opened(fh, fuse::consts::FOPEN_DIRECT_IO);
+        }
+        Err(err) => {
+          warn!("staging {} for writing failed: {}", gfile.file_id, err);
+          reply.error(drive_error::errno(&*err))
+        }
+      }
+    });
+  }
+
+  // uploads the content of |handle| in the background if it changed since
+  // it was last uploaded, then replies to |reply|.
+  fn upload_staged(&self, handle: sync::Arc<WriteHandle>, reply: fuse::ReplyEmpty) {
+    self.drop_idle_read_handle(handle.ino);
+    let file_tree = self.file_tree.clone();
+    let mut auth = self.authenticators[handle.account].clone();
+    let fs_options = self.fs_options.clone();
+    let upload_failures = self.upload_failures.clone();
+    self.upload_pool.execute(move || {
+      let _uploading = handle.uploading.lock().unwrap();
+      let snapshot = {
+        let mut staged = handle.staged.lock().unwrap();
+        if !staged.is_dirty() {
+          reply.ok();
+          return;
+        }
+        match staged.snapshot() {
+          Ok(snapshot) => snapshot,
+          Err(err) => {
+            reply.error(err.raw_os_error().unwrap_or(libc::EIO));
+            return;
+          }
+        }
+      };
+      // content Drive already has, as rewritten by an rsync-style copy, isn't
+      // uploaded again. Only its modification time is updated, if set.
+      let remote_md5 = file_tree
+        .read()
+        .unwrap()
+        .get_file(&handle.ino)
+        .and_then(|gfile| gfile.md5_checksum);
+      let unchanged =
+        remote_md5.map_or(false, |remote_md5| snapshot.md5().ok() == Some(remote_md5));
+      let uploaded = if unchanged {
+        debug!("{} is unchanged, skipping its upload", handle.file_id);
+        match snapshot.modified_time() {
+          Some(mtime) => {
+            upload::set_modified_time(&mut auth, &handle.file_id, mtime, FILE_FIELDS).map(Some)
+          }
+          None => Ok(None),
+        }
+      } else {
+        // the content from before the file was opened is kept once, before
+        // the first upload replaces it.
+        let kept = if fs_options.keep_overwritten_revisions
+          && !handle.previous_kept.load(sync::atomic::Ordering::SeqCst)
+        {
+          revisions::keep_head_revision(&mut auth, &handle.file_id).map(|()| {
+            handle
+              .previous_kept
+              .store(true, sync::atomic::Ordering::SeqCst)
+          })
+        } else {
+          Ok(())
+        };
+        kept
+          .and_then(|()| snapshot.upload(&mut auth, &handle.file_id, FILE_FIELDS))
+          .map(Some)
+      };
+      match uploaded {
+        Ok(Some(api_file)) => {
+          upload_failures.lock().unwrap().succeeded();
+          let mut gfile = GoogleFile::from_api_file(api_file, &fs_options);
+          gfile.account = handle.account;
+          file_tree.write().unwrap().insert_node(None, gfile);
+          reply.ok();
+        }
+        Ok(None) => reply.ok(),
+        Err(err) => {
+          warn!("uploading {} failed: {}", handle.file_id, err);
+          upload_failures
+            .lock()
+            .unwrap()
+            .failed(&handle.file_id, &*err);
+          handle.staged.lock().unwrap().upload_failed(&snapshot);
+          reply.error(drive_error::errno(&*err));
+        }
+      }
+    });
+  }
+
+  // sets the size of |gfile| to |size|, in the staged content of the write
+  // handle |fh| if there's one, or else by uploading the truncated content.
+  fn truncate(&self, mut gfile: GoogleFile, fh: Option<u64>, size: u64, reply: fuse::ReplyAttr) {
+    if let Some(errno) = self.write_error(&gfile) {
+      reply.error(errno);
+      return;
+    }
+    if self.over_max_write_size(size) {
+      reply.error(libc::EFBIG);
+      return;
+    }
+    let handle = fh.and_then(|fh| self.write_handles.lock().unwrap().get(&fh).cloned());
+    if let Some(handle) = handle {
+      if let Err(err) = handle.staged.lock().unwrap().set_len(size) {
+        reply.error(err.raw_os_error().unwrap_or(libc::EIO));
+        return;
+      }
+      gfile.file_attr.size = size;
+      reply.attr(&TTL, &gfile.file_attr);
+      self.file_tree.write().unwrap().insert_node(None, gfile);
+      return;
+    }
+    self.drop_idle_read_handle(gfile.inode());
+    let file_tree = self.file_tree.clone();
+    let mut auth = self.authenticators[gfile.account].clone();
+    let fs_options = self.fs_options.clone();
+    let staging_dir = self.fs_options.staging_dir.clone();
+    let upload_failures = self.upload_failures.clone();
+    self.upload_pool.execute(move || {
+      let uploaded = upload::StagedFile::create(&staging_dir)
+        .map_err(|err| -> Box<Error> { Box::new(err) })
+        .and_then(|mut staged| {
+          // the common truncation to nothing needs no download.
+          if size > 0 {
+            try!(staged.fill_from(&mut auth, &gfile.file_id));
+          }
+          try!(staged.set_len(size));
+          if fs_options.keep_overwritten_revisions {
+            try!(revisions::keep_head_revision(&mut auth, &gfile.file_id));
+          }
+          try!(staged.snapshot()).upload(&mut auth, &gfile.file_id, FILE_FIELDS)
+        });
+      match uploaded {
+        Ok(api_file) => {
+          upload_failures.lock().unwrap().succeeded();
+          let mut new_gfile = GoogleFile::from_api_file(api_file, &fs_options);
+          new_gfile.account = gfile.account;
+          reply.attr(&TTL, &new_gfile.file_attr);
+          file_tree.write().unwrap().insert_node(None, new_gfile);
+        }
+        Err(err) => {
+          warn!("truncating {} failed: {}", gfile.file_id, err);
+          upload_failures
+            .lock()
+            .unwrap()
+            .failed(&gfile.file_id, &*err);
+          reply.error(drive_error::errno(&*err));
+        }
+      }
+    });
+  }
+
+  // sets the modification time of |gfile| to |mtime|, along with the next
+  // upload of the write handle |fh| if it has changes to upload.
+  fn set_mtime(
+    &self,
+    mut gfile: GoogleFile,
+    fh: Option<u64>,
+    mtime: time::Timespec,
+    reply: fuse::ReplyAttr,
+  ) {
+    if let Some(errno) = self.write_error(&gfile) {
+      reply.error(errno);
+      return;
+    }
+    let handle = fh.and_then(|fh| self.write_handles.lock().unwrap().get(&fh).cloned());
+    if let Some(handle) = handle {
+      let mut staged = handle.staged.lock().unwrap();
+      if staged.is_dirty() {
+        staged.set_modified_time(mtime);
+        gfile.file_attr.mtime = mtime;
+        reply.attr(&TTL, &gfile.file_attr);
+        self.file_tree.write().unwrap().insert_node(None, gfile);
+        return;
+      }
+    }
+    let file_tree = self.file_tree.clone();
+    let mut auth = self.authenticators[gfile.account].clone();
+    let fs_options = self.fs_options.clone();
+    self.list_dir_pool.execute(move || {
+      match upload::set_modified_time(&mut auth, &gfile.file_id, mtime, FILE_FIELDS) {
+        Ok(api_file) => {
+          let mut new_gfile = GoogleFile::from_api_file(api_file, &fs_options);
+          new_gfile.account = gfile.account;
+          reply.attr(&TTL, &new_gfile.file_attr);
+          file_tree.write().unwrap().insert_node(None, new_gfile);
+        }
+        Err(err) => reply.error(drive_error::errno(&*err)),
+      }
+    });
+  }
+
+  // refreshes the metadata of the file at |ino| from drive in the
+  // background, in case it changed since its directory was listed.
+  fn revalidate(&self, ino: u64, file_id: &str, account: usize) {
+    if self.fs_options.shallow_stat {
+      self.revalidated.lock().unwrap().insert(ino);
+    }
+    let file_tree = self.file_tree.clone();
+    let mut auth = self.authenticators[account].clone();
+    let fs_options = self.fs_options.clone();
+    let file_id = String::from(file_id);
+    self.list_dir_pool.execute(move || {
+      if let Err(err) = refresh_file(&file_tree, &mut auth, &fs_options, ino, &file_id) {
+        warn!("revalidating {} failed: {}", file_id, err);
+      }
+    });
+  }
+
+  // whether a read of |size| bytes at |offset| of |ino| must be refused, as
+  // it would download part of a file over max_uncached_file_size.
+  fn refuse_uncached_read(
+    &self,
+    ino: u64,
+    handle: &http::FileReadHandle,
+    offset: u64,
+    size: u32,
+  ) -> bool {
+    let file_size = match self.file_tree.read().unwrap().get_file(&ino) {
+      Some(attr) => attr.file_attr.size,
+      None => return false,
+    };
+    match self.options.read().unwrap().max_uncached_file_size {
+      Some(max_size) if file_size > max_size && offset < file_size => {
+        // reads may extend past the end of the file.
+        let size = cmp::min(size as u64, file_size - offset) as u32;
+        !handle.is_cached(offset, size)
+      }
+      _ => false,
+    }
+  }
+
+  // the time the kernel may cache the attributes of |ino|. Files refreshed
+  // on open are in use, so get the short TTL for their refreshed attributes
+  // to show.
+  fn attr_ttl(&self, ino: u64) -> time::Timespec {
+    if self.fs_options.shallow_stat && !self.revalidated.lock().unwrap().contains(&ino) {
+      SHALLOW_STAT_TTL
+    } else {
+      TTL
+    }
+  }
+
+  // the id of the folder of |account| that the folder |dir| shows, which is
+  // one of the folders merged into it if it belongs to another account.
+  fn folder_id_in(dir: &GoogleFile, account: usize) -> Option<String> {
+    if dir.account == account {
+      return Some(dir.file_id.clone());
+    }
+    dir
+      .merged_dirs()
+      .iter()
+      .find(|merged| merged.account == account)
+      .map(|merged| merged.file_id.clone())
+  }
+
+  // whether the sender of |req| may access the filesystem.
+  fn check_access(&self, req: &fuse::Request) -> bool {
+    match self.fs_options.restrict_to_uid {
+      Some(uid) if req.uid() != uid => {
+        debug!("denying access to uid {}", req.uid());
+        false
+      }
+      _ => true,
+    }
+  }
+
+  /// Serves commands adjusting the read options of files opened afterwards
+  /// on `listener`, bound by bind_control_socket, see control::start_server.
+  pub fn start_control_socket(
+    &self,
+    listener: std::os::unix::net::UnixListener,
+  ) -> std::io::Result<()> {
+    let file_tree = self.file_tree.clone();
+    let auths = self.authenticators.clone();
+    let fs_options = self.fs_options.clone();
+    let read_handles = self.read_handles.clone();
+    let idle_read_handles = self.idle_read_handles.clone();
+    let read_options = self.options.clone();
+    let download_scheduler = self.download_scheduler.clone();
+    let restore = move |path: &str, revision_id: &str| -> Result<(), Box<Error>> {
+      let gfile =
+        try!(find_path(&file_tree, &auths, &fs_options, path)
+          .map_err(std::io::Error::from_raw_os_error));
+      let file_id = match gfile.content_source() {
+        Some((ref file_id, resolver::ContentSource::Media)) if !gfile.is_virtual_file() => {
+          file_id.clone()
+        }
+        _ => return Err(From::from(format!("{} is not a drive file", path))),
+      };
+      let mut auth = auths[gfile.account].clone();
+      try!(revisions::restore_revision(
+        &mut auth,
+        &file_id,
+        revision_id
+      ));
+      info!("restored {} to revision {}", path, revision_id);
+      let ino = gfile.inode();
+      try!(refresh_file(&file_tree, &mut auth, &fs_options, ino, &file_id));
+      // the content cached by the read handle of the file, and on disk under
+      // its old md5, is that of the previous revision.
+      let mut handles = read_handles.lock().unwrap();
+      match handles.get(&ino).map(|handle| handle.is_open()) {
+        Some(true) => {
+          let fresh = file_tree.read().unwrap().get_file(&ino).cloned();
+          if let (Some(fresh), Some(handle)) = (fresh, handles.get_mut(&ino)) {
+            let options = read_options.read().unwrap().for_file(
+              fresh.name(),
+              fresh.mime_type.as_ref().map(|mime_type| &**mime_type),
+            );
+            let md5_checksum = fresh.md5_hex();
+            handle.respawn(
+              &file_id,
+              md5_checksum.as_ref().map(|md5| md5.as_str()),
+              &resolver::ContentSource::Media,
+              &auth,
+              &options,
+              &download_scheduler,
+            );
+          }
+        }
+        Some(false) => {
+          handles.remove(&ino);
+          idle_read_handles
+            .lock()
+            .unwrap()
+            .retain(|&idle| idle != ino);
+        }
+        None => (),
+      }
+      Ok(())
+    };
+    let file_tree = self.file_tree.clone();
+    let upload_failures = self.upload_failures.clone();
+    let changes_resets = self.changes_resets.clone();
+    let metadata_status = move || {
+      let (items, bytes) = {
+        let tree = file_tree.read().unwrap();
+        (tree.file_count(), tree.memory_usage())
+      };
+      let (interned, interned_bytes) = intern::stats();
+      let mut status = format!(
+        "metadata-items={} metadata-bytes={} interned-strings={} interned-bytes={}",
+        items, bytes, interned, interned_bytes
+      );
+      if let Some(reason) = upload_failures.lock().unwrap().read_only_reason() {
+        status.push_str(&format!(" read-only={}", control::quote(reason)));
+      }
+      if let (resets, Some(ref reason)) = *changes_resets.lock().unwrap() {
+        status.push_str(&format!(
+          " changes-resets={} last-changes-reset={}",
+          resets,
+          control::quote(reason)
+        ));
+      }
+      status
+    };
+    control::start_server(
+      listener,
+      self.options.clone(),
+      Box::new(restore),
+      Box::new(metadata_status),
+    )
+  }
+
+  /// Starts a background thread that tries to recover when requests to Google
+  /// Drive have been failing for longer than `stall`.
+  pub fn start_health_monitor(&self, stall: std::time::Duration) {
+    health::start_monitor(self.authenticators.clone(), stall);
+  }
+
+  /// Starts a background thread that will periodically refresh filesystem
+  /// metadata at |interval|.
+  pub fn start_auto_refresh(&self, interval: std::time::Duration) {
     debug!("In start_auto_refresh(…)");
-    let auth = self.authenticator.clone();
+    if self.fs_options.changes_sync {
+      self.start_changes_sync(interval);
+      return;
+    }
+    let auths = self.authenticators.clone();
     let tree = self.file_tree.clone();
+    let fs_options = self.fs_options.clone();
     thread::Builder::new()
       .name(String::from("dir_refresh"))
       .spawn(move || {
         let mut queue: VecDeque<u64> = VecDeque::new();
         queue.push_back(ROOT_INODE);
-        let mut hub = google_drive3::Drive::new(common::new_hyper_tls_client(), auth);
+        let mut hubs = new_hubs(&auths);
+        // map of inode -> when the folder was last refreshed, and the
+        // number of differential refreshes since it was last listed in full.
+        let mut refreshed: HashMap<u64, (time::Timespec, u32)> = HashMap::new();
         loop {
           if queue.is_empty() {
             queue.push_back(ROOT_INODE);
             thread::sleep(interval);
           }
           if let Some(inode) = queue.pop_front() {
-            let id = match tree.read().unwrap().get_file(&inode) {
-              Some(attr) => attr.file_id.clone(),
+            let gfile = match tree.read().unwrap().get_file(&inode) {
+              Some(attr) => attr.clone(),
               None => continue,
             };
-            debug!("refreshing dir id {}", id);
-            match list_gdrive_dir(&id, &mut hub) {
+            let started = time::get_time();
+            let previous = refreshed.get(&inode).cloned();
+            let differential = match (fs_options.differential_refresh, previous) {
+              (Some(full_every), Some((_, count))) => {
+                count + 1 < full_every && refreshable_by_changes(&gfile, &fs_options)
+              }
+              _ => false,
+            };
+            if differential {
+              let (since, count) = previous.unwrap();
+              debug!("refreshing changes of dir id {}", gfile.file_id);
+              let since = time::Timespec::new(since.sec - REFRESH_CLOCK_MARGIN_SECS, 0);
+              let team_drive_id = gfile.team_drive_id.as_ref().map(|id| &**id);
+              let mut auth = auths[gfile.account].clone();
+              match list_gdrive_dir(
+                &gfile.file_id,
+                team_drive_id,
+                Some(since),
+                &mut auth,
+                &fs_options,
+              ) {
+                Ok(mut files) => {
+                  for file in &mut files {
+                    file.account = gfile.account;
+                  }
+                  refreshed.insert(inode, (started, count + 1));
+                  queue.extend(tree.write().unwrap().update_children(&inode, files));
+                }
+                Err(err) => {
+                  warn!("list_drive_dir: {:?}", err);
+                }
+              }
+              thread::sleep(std::time::Duration::from_millis(500));
+              continue;
+            }
+            debug!("refreshing dir id {}", gfile.file_id);
+            match list_dir(&gfile, &mut hubs, &auths, &fs_options) {
               Ok(files) => {
+                let ignore_rules = if inode == ROOT_INODE && fs_options.ignore_file {
+                  load_ignore_rules(&files, &auths)
+                } else {
+                  None
+                };
                 let mut tree_guard = tree.write().unwrap();
-                tree_guard.clear_children(&inode);
-                for file in files {
-                  if file.is_dir() {
-                    queue.push_back(file.inode());
-                  }
-                  tree_guard.insert_node(Some(inode), file);
+                if let Some(ignore_rules) = ignore_rules {
+                  tree_guard.ignore_rules = ignore_rules;
                 }
+                refreshed.insert(inode, (started, 0));
+                queue.extend(tree_guard.set_children(&inode, files));
               }
               Err(err) => {
                 warn!("list_drive_dir: {:?}", err);
@@ -324,129 +2829,954 @@ impl GDriveFS {
       })
       .unwrap();
   }
+
+  // starts a background thread applying the changes made to the drive every
+  // |interval|, see FileSystemOptions::changes_sync.
+  fn start_changes_sync(&self, interval: std::time::Duration) {
+    let auths = self.authenticators.clone();
+    let tree = self.file_tree.clone();
+    let fs_options = self.fs_options.clone();
+    let changes_resets = self.changes_resets.clone();
+    thread::Builder::new()
+      .name(String::from("changes_sync"))
+      .spawn(move || {
+        let mut auth = auths[0].clone();
+        let mut root_id = tree.read().unwrap().get_file(&ROOT_INODE).unwrap().file_id.clone();
+        // changes name the parents of items by id rather than by alias.
+        while root_id == ROOT_ID {
+          match listing::get_file(&mut auth, ROOT_ID, "id") {
+            Ok(root) => root_id = root.id.unwrap_or(root_id),
+            Err(err) => {
+              warn!("changes sync: resolving the root folder: {}", err);
+              thread::sleep(interval);
+            }
+          }
+        }
+        let mut page_token: Option<String> = None;
+        let mut synced = false;
+        // page tokens refused in a row.
+        let mut refused = 0;
+        loop {
+          let token = match page_token.take() {
+            Some(token) => token,
+            None => match listing::get_start_page_token(&mut auth) {
+              Ok(token) => {
+                // the changes made before the token aren't reported, so
+                // the folders listed meanwhile are listed again.
+                if synced {
+                  let listed = tree.read().unwrap().listed_dirs();
+                  relist_dirs(&tree, &auths, &fs_options, listed);
+                }
+                token
+              }
+              Err(err) => {
+                warn!("changes sync: {}", err);
+                thread::sleep(interval);
+                continue;
+              }
+            },
+          };
+          synced = true;
+          thread::sleep(interval);
+          page_token = match sync_changes(&tree, &auths, &fs_options, &root_id, &token) {
+            Ok(next) => {
+              refused = 0;
+              Some(next)
+            }
+            // the token expired or is otherwise refused, so changes are
+            // followed again from a fresh one. Starting over lists every
+            // folder again, so it's done less and less often if fresh
+            // tokens keep being refused.
+            Err(ref err) if drive_error::is_invalid_page_token(&**err) => {
+              let backoff = cmp::min(
+                interval
+                  .as_secs()
+                  .saturating_mul(1 << cmp::min(refused, 16)),
+                MAX_CHANGES_RESET_BACKOFF_SECS,
+              );
+              refused += 1;
+              warn!(
+                "changes sync: {}, starting over in {} seconds",
+                err, backoff
+              );
+              {
+                let mut resets = changes_resets.lock().unwrap();
+                resets.0 += 1;
+                resets.1 = Some(format!("{}", err));
+              }
+              thread::sleep(std::time::Duration::from_secs(backoff));
+              None
+            }
+            Err(err) => {
+              warn!("changes sync: {}", err);
+              Some(token)
+            }
+          };
+        }
+      })
+      .unwrap();
+  }
 }
 
 impl fuse::Filesystem for GDriveFS {
-  fn statfs(&mut self, _req: &fuse::Request, _ino: u64, reply: fuse::ReplyStatfs) {
+  fn statfs(&mut self, req: &fuse::Request, _ino: u64, reply: fuse::ReplyStatfs) {
+    if !self.check_access(req) {
+      reply.error(libc::EACCES);
+      return;
+    }
     let tree = self.file_tree.read().unwrap();
     reply.statfs(0, 0, 0, tree.file_count(), 0, constants::BLOCK_SIZE, 256, 0);
   }
 
-  fn lookup(&mut self, _req: &fuse::Request, parent: u64, name: &OsStr, reply: fuse::ReplyEntry) {
-    let tree = self.file_tree.read().unwrap();
-    if let Some(children) = tree.get_children(&parent) {
-      for child in children {
-        if let Some(attr) = tree.get_file(child) {
-          if Some(attr.name().as_ref()) == name.to_str() {
-            reply.entry(&TTL, &attr.file_attr, 0);
-            return;
+  fn lookup(&mut self, req: &fuse::Request, parent: u64, name: &OsStr, reply: fuse::ReplyEntry) {
+    if !self.check_access(req) {
+      reply.error(libc::EACCES);
+      return;
+    }
+    let snapshot = {
+      let tree = self.file_tree.read().unwrap();
+      let child = name
+        .to_str()
+        .and_then(|name| tree.find_child(&parent, name))
+        .and_then(|child| tree.get_file(&child));
+      if let Some(attr) = child {
+        reply.entry(&self.attr_ttl(attr.inode()), &attr.file_attr, 0);
+        return;
+      }
+      // snapshots are created as they're looked up.
+      match (tree.get_file(&parent), name.to_str()) {
+        (Some(attr), Some(name)) => attr.snapshot_dir(name),
+        _ => None,
+      }
+    };
+    match snapshot {
+      Some(dir) => {
+        reply.entry(&TTL, &dir.file_attr, 0);
+        self
+          .file_tree
+          .write()
+          .unwrap()
+          .insert_node(Some(parent), dir);
+      }
+      None => reply.error(libc::ENOENT),
+    }
+  }
+
+  fn getattr(&mut self, req: &fuse::Request, ino: u64, reply: fuse::ReplyAttr) {
+    debug!("getattr(ino:{})", ino);
+    if !self.check_access(req) {
+      reply.error(libc::EACCES);
+      return;
+    }
+    match self.file_tree.read().unwrap().get_file(&ino) {
+      Some(attr) => {
+        reply.attr(&self.attr_ttl(ino), &attr.file_attr);
+      }
+      None => {
+        reply.error(libc::ENOATTR);
+      }
+    }
+  }
+
+  fn mkdir(
+    &mut self,
+    req: &fuse::Request,
+    parent: u64,
+    name: &OsStr,
+    _mode: u32,
+    reply: fuse::ReplyEntry,
+  ) {
+    debug!("mkdir(parent:{}, name:{:?})", parent, name);
+    if !self.check_access(req) {
+      reply.error(libc::EACCES);
+      return;
+    }
+    let (in_shared_drives, account) = match self.file_tree.read().unwrap().get_file(&parent) {
+      Some(attr) => (attr.file_id == SHARED_DRIVES_ID, attr.account),
+      None => {
+        reply.error(libc::ENOENT);
+        return;
+      }
+    };
+    // the filesystem is otherwise read-only.
+    if !in_shared_drives {
+      reply.error(libc::ENOSYS);
+      return;
+    }
+    if !self.fs_options.allow_shared_drive_create {
+      reply.error(libc::EPERM);
+      return;
+    }
+    let name = match name.to_str() {
+      Some(name) => String::from(name),
+      None => {
+        reply.error(libc::EINVAL);
+        return;
+      }
+    };
+    let file_tree = self.file_tree.clone();
+    let auth = self.authenticators[account].clone();
+    let fs_options = self.fs_options.clone();
+    self.list_dir_pool.execute(move || {
+      let mut hub = google_drive3::Drive::new(common::new_hyper_tls_client(), auth);
+      match create_gdrive_shared_drive(&name, &mut hub, &fs_options) {
+        Ok(mut gfile) => {
+          gfile.account = account;
+          reply.entry(&TTL, &gfile.file_attr, 0);
+          let mut tree = file_tree.write().unwrap();
+          // only link the new drive into listings that were already fetched,
+          // otherwise its parent would look fully listed.
+          if tree.has_children(&parent) {
+            tree.insert_node(Some(parent), gfile);
+          } else {
+            tree.insert_node(None, gfile);
+          }
+        }
+        Err(err) => reply.error(
+          err
+            .downcast_ref::<std::io::Error>()
+            .and_then(|err| err.raw_os_error())
+            .unwrap_or_else(|| drive_error::errno(&*err)),
+        ),
+      }
+    });
+  }
+
+  fn setattr(
+    &mut self,
+    req: &fuse::Request,
+    ino: u64,
+    mode: Option<u32>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    size: Option<u64>,
+    atime: Option<time::Timespec>,
+    mtime: Option<time::Timespec>,
+    fh: Option<u64>,
+    _crtime: Option<time::Timespec>,
+    _chgtime: Option<time::Timespec>,
+    _bkuptime: Option<time::Timespec>,
+    _flags: Option<u32>,
+    reply: fuse::ReplyAttr,
+  ) {
+    debug!("setattr(ino:{}, mode:{:?})", ino, mode);
+    if !self.check_access(req) {
+      reply.error(libc::EACCES);
+      return;
+    }
+    let mut gfile = match self.file_tree.read().unwrap().get_file(&ino) {
+      Some(attr) => attr.clone(),
+      None => {
+        reply.error(libc::ENOENT);
+        return;
+      }
+    };
+    // writable mounts truncate files and set their modification time.
+    if self.writable() && mode.is_none() && uid.is_none() && gid.is_none() {
+      if let Some(size) = size {
+        self.truncate(gfile, fh, size, reply);
+        return;
+      }
+      match mtime {
+        Some(mtime) => self.set_mtime(gfile, fh, mtime, reply),
+        // access times aren't kept.
+        None => reply.attr(&TTL, &gfile.file_attr),
+      }
+      return;
+    }
+    // otherwise only the mode can be changed.
+    let other_changes =
+      uid.is_some() || gid.is_some() || size.is_some() || atime.is_some() || mtime.is_some();
+    let mode = match mode {
+      Some(mode) if !other_changes && self.fs_options.store_local_metadata => mode,
+      _ => {
+        reply.error(libc::ENOSYS);
+        return;
+      }
+    };
+    let changeable = match gfile.kind() {
+      fuse::FileType::RegularFile => true,
+      fuse::FileType::Directory => self.fs_options.store_full_mode,
+      _ => false,
+    };
+    if !changeable || gfile.file_id.starts_with(VIRTUAL_ID_PREFIX) {
+      reply.error(libc::EPERM);
+      return;
+    }
+    // by default only the executable bit survives, as a 755 or 644 mode.
+    let mode = if self.fs_options.store_full_mode {
+      mode & STORED_MODE_BITS as u32
+    } else if mode & 0o111 != 0 {
+      0o755
+    } else {
+      0o644
+    };
+    let file_tree = self.file_tree.clone();
+    let mut auth = self.authenticators[gfile.account].clone();
+    self.list_dir_pool.execute(move || {
+      match app_properties::patch(
+        &mut auth,
+        &gfile.file_id,
+        &[(app_properties::MODE_KEY, &format!("{:o}", mode))],
+      ) {
+        Ok(()) => {
+          gfile.file_attr.perm = mode as u16;
+          reply.attr(&TTL, &gfile.file_attr);
+          file_tree.write().unwrap().insert_node(None, gfile);
+        }
+        Err(err) => reply.error(drive_error::errno(&*err)),
+      }
+    });
+  }
+
+  fn readlink(&mut self, req: &fuse::Request, ino: u64, reply: fuse::ReplyData) {
+    debug!("readlink(ino:{})", ino);
+    if !self.check_access(req) {
+      reply.error(libc::EACCES);
+      return;
+    }
+    let (file_id, account) = match self.file_tree.read().unwrap().get_file(&ino) {
+      Some(attr) => match attr.symlink_target {
+        Some(ref target) if !target.is_empty() => {
+          reply.data(target.as_bytes());
+          return;
+        }
+        Some(_) => (attr.file_id.clone(), attr.account),
+        None => {
+          reply.error(libc::EINVAL);
+          return;
+        }
+      },
+      None => {
+        reply.error(libc::ENOENT);
+        return;
+      }
+    };
+    // the target is stored as the content, fetch and remember it.
+    let file_tree = self.file_tree.clone();
+    let mut auth = self.authenticators[account].clone();
+    self.list_dir_pool.execute(
+      move || match app_properties::download(&mut auth, &file_id) {
+        Ok(content) => {
+          reply.data(&content);
+          let mut tree = file_tree.write().unwrap();
+          let gfile = tree.get_file(&ino).cloned();
+          if let Some(mut gfile) = gfile {
+            gfile.symlink_target = Some(Box::new(String::from_utf8_lossy(&content).into_owned()));
+            tree.insert_node(None, gfile);
+          }
+        }
+        Err(err) => reply.error(drive_error::errno(&*err)),
+      },
+    );
+  }
+
+  fn symlink(
+    &mut self,
+    req: &fuse::Request,
+    parent: u64,
+    name: &OsStr,
+    link: &std::path::Path,
+    reply: fuse::ReplyEntry,
+  ) {
+    debug!(
+      "symlink(parent:{}, name:{:?}, link:{:?})",
+      parent, name, link
+    );
+    if !self.check_access(req) {
+      reply.error(libc::EACCES);
+      return;
+    }
+    if !self.fs_options.store_local_metadata {
+      reply.error(libc::ENOSYS);
+      return;
+    }
+    let (parent_id, account) = match self.file_tree.read().unwrap().get_file(&parent) {
+      Some(attr) => (attr.file_id.clone(), attr.account),
+      None => {
+        reply.error(libc::ENOENT);
+        return;
+      }
+    };
+    // the virtual folders can't hold drive items.
+    if parent_id.starts_with(VIRTUAL_ID_PREFIX) {
+      reply.error(libc::EPERM);
+      return;
+    }
+    let (name, target) = match (name.to_str(), link.to_str()) {
+      (Some(name), Some(target)) => (String::from(name), String::from(target)),
+      _ => {
+        reply.error(libc::EINVAL);
+        return;
+      }
+    };
+    // targets too long for appProperties are stored as the file content.
+    let (property, content) =
+      if app_properties::SYMLINK_KEY.len() + target.len() > app_properties::MAX_ENTRY_LEN {
+        (String::new(), target.into_bytes())
+      } else {
+        (target, Vec::new())
+      };
+    let file_tree = self.file_tree.clone();
+    let mut auth = self.authenticators[account].clone();
+    let fs_options = self.fs_options.clone();
+    self.list_dir_pool.execute(move || {
+      match app_properties::create_file(
+        &mut auth,
+        &parent_id,
+        &name,
+        SYMLINK_MIME_TYPE,
+        &[(app_properties::SYMLINK_KEY, &property)],
+        &content,
+        FILE_FIELDS,
+      ) {
+        Ok(api_file) => {
+          let mut gfile = GoogleFile::from_api_file(api_file, &fs_options);
+          gfile.account = account;
+          reply.entry(&TTL, &gfile.file_attr, 0);
+          let mut tree = file_tree.write().unwrap();
+          if tree.has_children(&parent) {
+            tree.insert_node(Some(parent), gfile);
+          } else {
+            tree.insert_node(None, gfile);
           }
         }
+        Err(err) => reply.error(drive_error::errno(&*err)),
       }
-    }
-    reply.error(libc::ENOENT);
+    });
   }
 
-  fn getattr(&mut self, _req: &fuse::Request, ino: u64, reply: fuse::ReplyAttr) {
-    debug!("getattr(ino:{})", ino);
-    match self.file_tree.read().unwrap().get_file(&ino) {
-      Some(attr) => {
-        reply.attr(&TTL, &attr.file_attr);
+  fn rename(
+    &mut self,
+    req: &fuse::Request,
+    parent: u64,
+    name: &OsStr,
+    newparent: u64,
+    newname: &OsStr,
+    reply: fuse::ReplyEmpty,
+  ) {
+    debug!(
+      "rename(parent:{}, name:{:?}, newparent:{}, newname:{:?})",
+      parent, name, newparent, newname
+    );
+    if !self.check_access(req) {
+      reply.error(libc::EACCES);
+      return;
+    }
+    let (name, newname) = match (name.to_str(), newname.to_str()) {
+      (Some(name), Some(newname)) => (name, String::from(newname)),
+      _ => {
+        reply.error(libc::EINVAL);
+        return;
       }
-      None => {
-        reply.error(libc::ENOATTR);
+    };
+    let (file, old_parent_id, new_parent_id) = {
+      let tree = self.file_tree.read().unwrap();
+      let find_child = |dir: u64, name: &str| {
+        tree
+          .find_child(&dir, name)
+          .and_then(|child| tree.get_file(&child))
+          .cloned()
+      };
+      let file = match find_child(parent, name) {
+        Some(file) => file,
+        None => {
+          reply.error(libc::ENOENT);
+          return;
+        }
+      };
+      // the filesystem is otherwise read-only.
+      if !file.is_dir() || !self.fs_options.allow_dir_move {
+        reply.error(libc::ENOSYS);
+        return;
+      }
+      // renaming a folder onto itself succeeds without changing anything.
+      let existing = find_child(newparent, &newname).map(|existing| existing.inode());
+      if existing == Some(file.inode()) {
+        reply.ok();
+        return;
+      }
+      let (old_dir, new_dir) = match (tree.get_file(&parent), tree.get_file(&newparent)) {
+        (Some(old_dir), Some(new_dir)) => (old_dir, new_dir),
+        _ => {
+          reply.error(libc::ENOENT);
+          return;
+        }
+      };
+      // virtual folders don't exist in drive, and renaming a shared drive
+      // isn't a move.
+      if file.file_id.starts_with(VIRTUAL_ID_PREFIX)
+        || new_dir.file_id.starts_with(VIRTUAL_ID_PREFIX)
+        || file.team_drive_id.as_ref().map(|id| &**id) == Some(file.file_id.as_str())
+      {
+        reply.error(libc::EPERM);
+        return;
+      }
+      if new_dir.team_drive_id != file.team_drive_id {
+        warn!(
+          "not moving {}: folders can't be moved between drives, only copied",
+          file.name()
+        );
+        reply.error(libc::EXDEV);
+        return;
+      }
+      if tree.is_under(newparent, file.inode()) {
+        reply.error(libc::EINVAL);
+        return;
+      }
+      if existing.is_some() {
+        reply.error(libc::EEXIST);
+        return;
+      }
+      match (
+        GDriveFS::folder_id_in(old_dir, file.account),
+        GDriveFS::folder_id_in(new_dir, file.account),
+      ) {
+        (Some(old_parent_id), Some(new_parent_id)) => (file, old_parent_id, new_parent_id),
+        // the target folder doesn't exist in the folder's account.
+        _ => {
+          reply.error(libc::EXDEV);
+          return;
+        }
+      }
+    };
+    let file_tree = self.file_tree.clone();
+    let mut auth = self.authenticators[file.account].clone();
+    self.list_dir_pool.execute(move || {
+      match app_properties::move_file(
+        &mut auth,
+        &file.file_id,
+        &newname,
+        &old_parent_id,
+        &new_parent_id,
+      ) {
+        Ok(()) => {
+          file_tree
+            .write()
+            .unwrap()
+            .move_node(file.inode(), parent, newparent, newname);
+          reply.ok();
+        }
+        Err(err) => {
+          warn!("moving {} failed: {}", file.file_id, err);
+          reply.error(drive_error::errno(&*err))
+        }
       }
+    });
+  }
+
+  fn opendir(&mut self, req: &fuse::Request, ino: u64, _flags: u32, reply: fuse::ReplyOpen) {
+    debug!("opendir(ino:{})", ino);
+    if !self.check_access(req) {
+      reply.error(libc::EACCES);
+      return;
     }
+    let file_tree = self.file_tree.clone();
+    let auths = self.authenticators.clone();
+    let dir_handles = self.dir_handles.clone();
+    let fs_options = self.fs_options.clone();
+    let fh = self.new_handle();
+    self.list_dir_pool.execute(move || {
+      if let Err(err) = ensure_dir_listed(&file_tree, &auths, &fs_options, ino) {
+        reply.error(err);
+        return;
+      }
+      // snapshot the listing, so that concurrent changes to the directory
+      // don't shift offsets under an in-progress readdir.
+      let tree = file_tree.read().unwrap();
+      let mut entries = vec![DirEntry {
+        inode: ino,
+        kind: fuse::FileType::Directory,
+        name: String::from("."),
+      }];
+      if let Some(children) = tree.get_children(&ino) {
+        let mut attrs: Vec<&GoogleFile> = children
+          .iter()
+          .map(|child| tree.get_file(child).expect("Missing attr for file id"))
+          .collect();
+        match fs_options.dir_order {
+          DirOrder::Listed => {}
+          DirOrder::Name => attrs.sort_by(|a, b| a.name().cmp(b.name())),
+          DirOrder::Modified => attrs.sort_by(|a, b| {
+            (a.file_attr.mtime, a.name()).cmp(&(b.file_attr.mtime, b.name()))
+          }),
+          DirOrder::Newest => attrs.sort_by(|a, b| {
+            b.file_attr
+              .mtime
+              .cmp(&a.file_attr.mtime)
+              .then_with(|| a.name().cmp(b.name()))
+          }),
+        }
+        for attr in attrs {
+          entries.push(DirEntry {
+            inode: attr.inode(),
+            kind: attr.kind(),
+            name: attr.name().to_string(),
+          });
+        }
+      }
+      dir_handles.lock().unwrap().insert(fh, entries);
+      reply.opened(fh, 0);
+    });
   }
 
   fn readdir(
     &mut self,
     _req: &fuse::Request,
     ino: u64,
-    _fh: u64,
+    fh: u64,
     offset: i64,
     mut reply: fuse::ReplyDirectory,
   ) {
-    debug!("readdir(ino:{}, offset:{})", ino, offset);
-    let file_tree = self.file_tree.clone();
-    let auth = self.authenticator.clone();
-    self.list_dir_pool.execute(move || {
-      let mut fileid = String::new();
-      let has_children = {
-        let tree = file_tree.read().unwrap();
+    debug!("readdir(ino:{}, fh:{}, offset:{})", ino, fh, offset);
+    let dir_handles = self.dir_handles.lock().unwrap();
+    let entries = match dir_handles.get(&fh) {
+      Some(entries) => entries,
+      None => {
+        warn!("no open directory handle {} for inode: {}", fh, ino);
+        reply.error(libc::EBADF);
+        return;
+      }
+    };
+    // the offset of each entry is its (1-based) index in the snapshot, so
+    // |offset| is the number of entries already returned.
+    for (i, entry) in entries.iter().enumerate().skip(offset as usize) {
+      if reply.add(entry.inode, (i + 1) as i64, entry.kind, &entry.name) {
+        // reply buffer is full.
+        break;
+      }
+    }
+    reply.ok();
+  }
+
+  fn releasedir(
+    &mut self,
+    _req: &fuse::Request,
+    ino: u64,
+    fh: u64,
+    _flags: u32,
+    reply: fuse::ReplyEmpty,
+  ) {
+    debug!("releasedir(ino:{}, fh:{})", ino, fh);
+    if self.dir_handles.lock().unwrap().remove(&fh).is_none() {
+      warn!("no open directory handle {} for inode: {}", fh, ino);
+    }
+    reply.ok();
+  }
+
+  fn getxattr(
+    &mut self,
+    req: &fuse::Request,
+    ino: u64,
+    name: &OsStr,
+    size: u32,
+    reply: fuse::ReplyXattr,
+  ) {
+    debug!("getxattr(ino:{}, name:{:?})", ino, name);
+    if !self.check_access(req) {
+      reply.error(libc::EACCES);
+      return;
+    }
+    let (file_id, account, md5_checksum) = match self.file_tree.read().unwrap().get_file(&ino) {
+      Some(attr) => (
+        attr.file_id.clone(),
+        attr.account,
+        attr.md5_hex(),
+      ),
+      None => {
+        reply.error(libc::ENOENT);
+        return;
+      }
+    };
+    match name.to_str() {
+      Some(xattr::TREESIZE) if self.fs_options.treesize_xattr => {
+        let tree = self.file_tree.read().unwrap();
         match tree.get_file(&ino) {
-          Some(attr) => {
-            if !attr.is_dir() {
-              reply.error(libc::ENOTDIR);
-              return;
-            }
-            fileid.push_str(&attr.file_id)
-          }
-          None => {
-            reply.error(libc::ENOENT);
-            return;
+          Some(attr) if attr.is_dir() => {
+            let (bytes, files, unlisted) = tree.tree_size(&ino);
+            let value = format!("{} {} {}", bytes, files, unlisted);
+            xattr::reply_value(reply, size, value.as_bytes());
           }
-        }
-        tree.has_children(&ino)
-      };
-      // need to list the directory
-      if !has_children {
-        let mut hub = google_drive3::Drive::new(common::new_hyper_tls_client(), auth);
-        let result = list_gdrive_dir(&fileid, &mut hub);
-        if result.is_err() {
-          reply.error(libc::EIO);
-          return;
-        }
-        let mut tree = file_tree.write().unwrap();
-        tree.clear_children(&ino);
-        for file in result.unwrap() {
-          tree.insert_node(Some(ino), file);
+          _ => reply.error(libc::ENOATTR),
         }
       }
-      let tree = file_tree.read().unwrap();
-      if let Some(children) = tree.get_children(&ino) {
-        let mut foffset = offset + 1;
-        let split_at = if offset == 0 { offset } else { foffset };
-        if split_at >= children.len() as i64 {
-          // all done.
-          reply.ok();
+      Some(xattr::CACHED_RANGES) if self.fs_options.cached_ranges_xattr => {
+        // only files with a read handle have anything cached.
+        let ranges = match self.read_handles.lock().unwrap().get(&ino) {
+          Some(handle) => handle.cached_ranges(),
+          None => Vec::new(),
+        };
+        let value: String = ranges
+          .iter()
+          .map(|&(start, end)| format!("{}-{}\n", start, end))
+          .collect();
+        xattr::reply_value(reply, size, value.as_bytes());
+      }
+      Some(xattr::READSTATS) if self.fs_options.readstats_xattr => {
+        // files without a read handle haven't been read recently.
+        let stats = match self.read_handles.lock().unwrap().get(&ino) {
+          Some(handle) => handle.stats(),
+          None => http::ReadStats::default(),
+        };
+        let average_fetch_ms = stats
+          .average_fetch_latency()
+          .map(|latency| latency.as_secs() * 1000 + latency.subsec_millis() as u64)
+          .unwrap_or(0);
+        let value = format!(
+          "reads {}\ncache_hits {}\ndisk_cache_hits {}\nchunks_fetched {}\nbytes_fetched {}\n\
+           readahead_chunks {}\nreadahead_wasted_bytes {}\nfetch_errors {}\n\
+           average_fetch_ms {}\n",
+          stats.reads,
+          stats.cache_hits,
+          stats.disk_cache_hits,
+          stats.chunks_fetched,
+          stats.bytes_fetched,
+          stats.readahead_chunks,
+          stats.readahead_wasted_bytes,
+          stats.fetch_errors,
+          average_fetch_ms
+        );
+        xattr::reply_value(reply, size, value.as_bytes());
+      }
+      Some(xattr::MD5) if self.fs_options.checksums_xattr => match md5_checksum {
+        Some(md5) => xattr::reply_value(reply, size, md5.as_bytes()),
+        None => reply.error(libc::ENOATTR),
+      },
+      Some(name)
+        if self.fs_options.checksums_xattr
+          && md5_checksum.is_some()
+          && (name == xattr::SHA1 || name == xattr::SHA256) =>
+      {
+        let field = if name == xattr::SHA1 {
+          "sha1Checksum"
+        } else {
+          "sha256Checksum"
+        };
+        let mut auth = self.authenticators[account].clone();
+        self.list_dir_pool.execute(move || {
+          match checksums::get_checksum(&mut auth, &file_id, field) {
+            Ok(Some(checksum)) => xattr::reply_value(reply, size, checksum.as_bytes()),
+            Ok(None) => reply.error(libc::ENOATTR),
+            Err(err) => reply.error(drive_error::errno(&*err)),
+          }
+        });
+      }
+      Some(xattr::THUMBNAIL) if self.fs_options.thumbnails_xattr => {
+        let mut auth = self.authenticators[account].clone();
+        self.list_dir_pool.execute(
+          move || match thumbnails::get_thumbnail(&mut auth, &file_id) {
+            Ok(Some(thumbnail)) => xattr::reply_value(reply, size, &thumbnail),
+            Ok(None) => reply.error(libc::ENOATTR),
+            Err(err) => reply.error(drive_error::errno(&*err)),
+          },
+        );
+      }
+      Some(xattr::PERMISSIONS) if self.fs_options.permissions_xattr => {
+        let auth = self.authenticators[account].clone();
+        self.list_dir_pool.execute(move || {
+          let mut hub = google_drive3::Drive::new(common::new_hyper_tls_client(), auth);
+          match get_gdrive_permissions(&file_id, &mut hub) {
+            Ok(perms) => xattr::reply_value(reply, size, perms.as_bytes()),
+            Err(err) => reply.error(drive_error::errno(&*err)),
+          }
+        });
+      }
+      Some(name) if self.fs_options.labels_xattr && name.starts_with(xattr::LABEL_PREFIX) => {
+        let label_id = String::from(&name[xattr::LABEL_PREFIX.len()..]);
+        let mut auth = self.authenticators[account].clone();
+        self
+          .list_dir_pool
+          .execute(move || match labels::list_labels(&mut auth, &file_id) {
+            Ok(labels) => match labels.into_iter().find(|&(ref id, _)| *id == label_id) {
+              Some((_, fields)) => xattr::reply_value(reply, size, fields.as_bytes()),
+              None => reply.error(libc::ENOATTR),
+            },
+            Err(err) => reply.error(drive_error::errno(&*err)),
+          });
+      }
+      _ => reply.error(libc::ENOATTR),
+    }
+  }
+
+  fn listxattr(&mut self, req: &fuse::Request, ino: u64, size: u32, reply: fuse::ReplyXattr) {
+    debug!("listxattr(ino:{})", ino);
+    if !self.check_access(req) {
+      reply.error(libc::EACCES);
+      return;
+    }
+    let (file_id, account, has_checksums, is_drive_file, is_dir) =
+      match self.file_tree.read().unwrap().get_file(&ino) {
+        Some(attr) => (
+          attr.file_id.clone(),
+          attr.account,
+          attr.md5_checksum.is_some(),
+          attr.kind() == fuse::FileType::RegularFile && !attr.is_virtual_file(),
+          attr.is_dir(),
+        ),
+        None => {
+          reply.error(libc::ENOENT);
           return;
         }
-        if offset == 0 {
-          reply.add(ino, 0, fuse::FileType::Directory, ".");
-        }
-        let (_, children) = children.split_at(split_at as usize);
-        for child in children {
-          let attr = tree.get_file(child).expect("Missing attr for file id");
-          if reply.add(attr.inode(), foffset, attr.kind(), attr.name()) {
-            // reply buffer is full.
-            break;
+      };
+    let mut names: Vec<String> = Vec::new();
+    if self.fs_options.permissions_xattr {
+      names.push(xattr::PERMISSIONS.into());
+    }
+    if self.fs_options.checksums_xattr && has_checksums {
+      names.push(xattr::MD5.into());
+      names.push(xattr::SHA1.into());
+      names.push(xattr::SHA256.into());
+    }
+    if self.fs_options.cached_ranges_xattr && is_drive_file {
+      names.push(xattr::CACHED_RANGES.into());
+    }
+    if self.fs_options.readstats_xattr && is_drive_file {
+      names.push(xattr::READSTATS.into());
+    }
+    if self.fs_options.thumbnails_xattr && is_drive_file {
+      names.push(xattr::THUMBNAIL.into());
+    }
+    if self.fs_options.treesize_xattr && is_dir {
+      names.push(xattr::TREESIZE.into());
+    }
+    if !self.fs_options.labels_xattr || file_id.starts_with(VIRTUAL_ID_PREFIX) {
+      xattr::reply_names(reply, size, &names);
+      return;
+    }
+    // the label xattrs depend on the labels currently applied to the file.
+    let mut auth = self.authenticators[account].clone();
+    self
+      .list_dir_pool
+      .execute(move || match labels::list_labels(&mut auth, &file_id) {
+        Ok(labels) => {
+          for (label_id, _) in labels {
+            names.push(format!("{}{}", xattr::LABEL_PREFIX, label_id));
           }
-          foffset += 1;
+          xattr::reply_names(reply, size, &names);
         }
-      }
-      reply.ok();
-    });
+        Err(err) => reply.error(drive_error::errno(&*err)),
+      });
   }
 
-  fn open(&mut self, _req: &fuse::Request, ino: u64, _flags: u32, reply: fuse::ReplyOpen) {
+  fn open(&mut self, req: &fuse::Request, ino: u64, flags: u32, reply: fuse::ReplyOpen) {
     debug!("open for inode {}", ino);
-    let download_url = self
+    if !self.check_access(req) {
+      reply.error(libc::EACCES);
+      return;
+    }
+    if flags as i32 & libc::O_ACCMODE != libc::O_RDONLY {
+      let gfile = match self.file_tree.read().unwrap().get_file(&ino) {
+        Some(attr) => attr.clone(),
+        None => {
+          reply.error(libc::ENOENT);
+          return;
+        }
+      };
+      self.open_for_write(gfile, flags as i32 & libc::O_TRUNC != 0, reply);
+      return;
+    }
+    let virtual_file = match self.file_tree.read().unwrap().get_file(&ino) {
+      Some(attr) if attr.is_virtual_file() => Some(attr.clone()),
+      _ => None,
+    };
+    if let Some(gfile) = virtual_file {
+      self.open_virtual_file(gfile, reply);
+      return;
+    }
+    if self.fs_options.no_content {
+      reply.error(libc::EACCES);
+      return;
+    }
+    let export = self
+      .file_tree
+      .read()
+      .unwrap()
+      .get_file(&ino)
+      .and_then(|attr| {
+        self
+          .fs_options
+          .export_format(attr)
+          .map(|format| (attr.clone(), format.clone()))
+      });
+    if let Some((gfile, format)) = export {
+      self.open_export(gfile, format, reply);
+      return;
+    }
+    if let Some(max_size) = self.fs_options.max_read_file_size {
+      let file_size = self
+        .file_tree
+        .read()
+        .unwrap()
+        .get_file(&ino)
+        .map(|attr| attr.file_attr.size)
+        .unwrap_or(0);
+      if file_size > max_size {
+        warn!(
+          "refusing to open inode {} of {} bytes, over --max-read-file-size",
+          ino, file_size
+        );
+        reply.error(libc::EFBIG);
+        return;
+      }
+    }
+    let download = self
       .file_tree
       .read()
       .unwrap()
       .get_file(&ino)
-      .and_then(|attr| attr.download_url());
-    if download_url.is_none() {
+      .and_then(|attr| {
+        attr.content_source().map(|(file_id, source)| {
+          let options = self.options.read().unwrap().for_file(
+            &attr.file_name,
+            attr.mime_type.as_ref().map(|mime_type| &**mime_type),
+          );
+          (file_id, source, attr.account, attr.md5_hex(), options)
+        })
+      });
+    if download.is_none() {
       reply.error(libc::ENOSYS);
       return;
     }
-    let download_url = download_url.unwrap();
+    let (file_id, source, account, md5_checksum, options) = download.unwrap();
+    if disk_cache::is_enabled() {
+      let path = self.file_tree.read().unwrap().path_of(&ino);
+      disk_cache::file_opened(&file_id, &path);
+    }
+    let version = md5_checksum.as_ref().map(|md5| md5.as_str());
     let mut reader_map = self.read_handles.lock().unwrap();
-    let handle = reader_map.entry(ino).or_insert_with(|| {
-      http::FileReadHandle::spawn(&download_url, &self.authenticator, &self.options)
-    });
-    handle.incref();
+    let auth = &self.authenticators[account];
+    let options = &options;
+    let scheduler = &self.download_scheduler;
+    let new_handle = !reader_map.contains_key(&ino);
+    {
+      let handle = reader_map.entry(ino).or_insert_with(|| {
+        http::FileReadHandle::spawn(&file_id, version, &source, auth, options, scheduler)
+      });
+      handle.incref();
+      // a new handle has nothing cached yet: fetch the first chunk while the
+      // caller gets to its first read, and check the metadata meanwhile.
+      let prefetch = new_handle && options.prefetch_on_open;
+      if prefetch {
+        scheduler.record_read(&file_id);
+        if let Err(err) = handle.prefetch(0) {
+          warn!("prefetch of inode {} failed: {}", ino, err);
+        }
+      }
+      // the metadata of an old revision doesn't change.
+      if (prefetch || self.fs_options.shallow_stat)
+        && source == resolver::ContentSource::Media
+        && !scheduler.is_overloaded()
+      {
+        self.revalidate(ino, &file_id, account);
+      }
+    }
+    // an idle handle is reused along with its cache.
+    self
+      .idle_read_handles
+      .lock()
+      .unwrap()
+      .retain(|&idle| idle != ino);
+    self.recycle_read_handles(&mut reader_map);
     reply.opened(0, 0);
   }
 
@@ -454,20 +3784,36 @@ impl fuse::Filesystem for GDriveFS {
     &mut self,
     _req: &fuse::Request,
     ino: u64,
-    _fh: u64,
+    fh: u64,
     _flags: u32,
     _lock_owner: u64,
     _flush: bool,
     reply: fuse::ReplyEmpty,
   ) {
     debug!("release: inode({})", ino);
+    if self.virtual_files.lock().unwrap().remove(&fh).is_some() {
+      reply.ok();
+      return;
+    }
+    let write_handle = self.write_handles.lock().unwrap().remove(&fh);
+    if let Some(handle) = write_handle {
+      self.upload_staged(handle, reply);
+      return;
+    }
     let mut handles = self.read_handles.lock().unwrap();
-    if let Some(handle) = handles.remove(&ino) {
-      if let Some(handle) = handle.decref() {
-        handles.insert(ino, handle);
+    let idle = match handles.get_mut(&ino) {
+      Some(handle) => {
+        handle.decref();
+        !handle.is_open()
       }
-    } else {
-      warn!("no open handle found for inode: {}", ino);
+      None => {
+        warn!("no open handle found for inode: {}", ino);
+        false
+      }
+    };
+    if idle {
+      self.idle_read_handles.lock().unwrap().push_back(ino);
+      self.recycle_read_handles(&mut handles);
     }
     reply.ok();
   }
@@ -476,20 +3822,289 @@ impl fuse::Filesystem for GDriveFS {
     &mut self,
     _req: &fuse::Request,
     ino: u64,
-    _fh: u64,
+    fh: u64,
     offset: i64,
     size: u32,
     reply: fuse::ReplyData,
   ) {
-    let handle_map = self.read_handles.lock().unwrap();
-    match handle_map.get(&ino) {
+    if offset < 0 {
+      reply.error(libc::EINVAL);
+      return;
+    }
+    if size == 0 {
+      reply.data(&[]);
+      return;
+    }
+    let offset = offset as u64;
+    if let Some(contents) = self.virtual_files.lock().unwrap().get(&fh) {
+      // compared as u64, since usize may be narrower than the offset.
+      let start = cmp::min(offset, contents.len() as u64) as usize;
+      let end = cmp::min(start + size as usize, contents.len());
+      reply.data(&contents[start..end]);
+      return;
+    }
+    let write_handle = self.write_handles.lock().unwrap().get(&fh).cloned();
+    if let Some(handle) = write_handle {
+      match handle.staged.lock().unwrap().read_at(offset, size) {
+        Ok(data) => reply.data(&data),
+        Err(err) => reply.error(err.raw_os_error().unwrap_or(libc::EIO)),
+      }
+      return;
+    }
+    let (past_eof, lost_access) = {
+      let tree = self.file_tree.read().unwrap();
+      let past_eof = match tree.get_file(&ino) {
+        Some(attr) => offset >= attr.file_attr.size,
+        None => false,
+      };
+      (past_eof, tree.lost_access.contains(&ino))
+    };
+    if lost_access {
+      reply.error(libc::EACCES);
+      return;
+    }
+    // reads at or past the end of a file return nothing, without a request.
+    if past_eof {
+      reply.data(&[]);
+      return;
+    }
+    let mut handle_map = self.read_handles.lock().unwrap();
+    let dead_worker = match handle_map.get(&ino) {
+      Some(handle) if self.refuse_uncached_read(ino, handle, offset, size) => {
+        warn!(
+          "refusing uncached read of inode {} at {}, the file is too large",
+          ino, offset
+        );
+        reply.error(self.options.read().unwrap().uncached_read_action.errno());
+        false
+      }
       Some(handle) => {
-        handle.do_read(offset as u64, size, reply);
+        let reply = match self.read_deadlines {
+          Some(ref watcher) => watcher.watch(reply),
+          None => deadline::PendingReply::new(reply),
+        };
+        // the reply is failed with EIO when dropped with the request.
+        handle.do_read(offset, size, reply).is_err()
       }
       None => {
         error!("no download thread found");
         reply.error(libc::EIO);
+        false
+      }
+    };
+    if dead_worker {
+      warn!("read worker for inode {} is gone, respawning it", ino);
+      health::record_dead_worker();
+      let download = self
+        .file_tree
+        .read()
+        .unwrap()
+        .get_file(&ino)
+        .and_then(|attr| {
+          attr
+            .content_source()
+            .map(|(file_id, source)| (file_id, source, attr.account, attr.md5_hex()))
+        });
+      if let (Some((file_id, source, account, md5_checksum)), Some(handle)) =
+        (download, handle_map.get_mut(&ino))
+      {
+        handle.respawn(
+          &file_id,
+          md5_checksum.as_ref().map(|md5| md5.as_str()),
+          &source,
+          &self.authenticators[account],
+          &self.options.read().unwrap(),
+          &self.download_scheduler,
+        );
+      }
+    }
+  }
+
+  fn create(
+    &mut self,
+    req: &fuse::Request,
+    parent: u64,
+    name: &OsStr,
+    _mode: u32,
+    _flags: u32,
+    reply: fuse::ReplyCreate,
+  ) {
+    debug!("create(parent:{}, name:{:?})", parent, name);
+    if !self.check_access(req) {
+      reply.error(libc::EACCES);
+      return;
+    }
+    if !self.writable() {
+      reply.error(libc::EROFS);
+      return;
+    }
+    let (parent_id, account) = match self.file_tree.read().unwrap().get_file(&parent) {
+      Some(attr) => (attr.file_id.clone(), attr.account),
+      None => {
+        reply.error(libc::ENOENT);
+        return;
+      }
+    };
+    // the virtual folders can't hold drive items.
+    if parent_id.starts_with(VIRTUAL_ID_PREFIX) {
+      reply.error(libc::EPERM);
+      return;
+    }
+    let name = match name.to_str() {
+      Some(name) => String::from(name),
+      None => {
+        reply.error(libc::EINVAL);
+        return;
+      }
+    };
+    let fh = self.new_handle();
+    let file_tree = self.file_tree.clone();
+    let mut auth = self.authenticators[account].clone();
+    let fs_options = self.fs_options.clone();
+    let write_handles = self.write_handles.clone();
+    self.upload_pool.execute(move || {
+      let mime_type = upload::infer_mime_type(&name, &fs_options.upload_mime_types);
+      let created = upload::create_empty(
+        &mut auth,
+        &parent_id,
+        &name,
+        mime_type.as_ref().map(|mime_type| mime_type.as_str()),
+        FILE_FIELDS,
+      )
+      .and_then(|api_file| {
+        let staged = try!(upload::StagedFile::create(&fs_options.staging_dir));
+        Ok((api_file, staged))
+      });
+      match created {
+        Ok((api_file, staged)) => {
+          let mut gfile = GoogleFile::from_api_file(api_file, &fs_options);
+          gfile.account = account;
+          write_handles.lock().unwrap().insert(
+            fh,
+            sync::Arc::new(WriteHandle {
+              ino: gfile.inode(),
+              file_id: gfile.file_id.clone(),
+              account: account,
+              staged: sync::Mutex::new(staged),
+              uploading: sync::Mutex::new(()),
+              // a new file has no previous content.
+              previous_kept: sync::atomic::AtomicBool::new(true),
+            }),
+          );
+          reply.created(&TTL, &gfile.file_attr, 0, fh, fuse::consts::FOPEN_DIRECT_IO);
+          let mut tree = file_tree.write().unwrap();
+          if tree.has_children(&parent) {
+            tree.insert_node(Some(parent), gfile);
+          } else {
+            tree.insert_node(None, gfile);
+          }
+        }
+        Err(err) => {
+          warn!("creating {} failed: {}", name, err);
+          reply.error(drive_error::errno(&*err));
+        }
+      }
+    });
+  }
+
+  fn write(
+    &mut self,
+    _req: &fuse::Request,
+    ino: u64,
+    fh: u64,
+    offset: i64,
+    data: &[u8],
+    _flags: u32,
+    reply: fuse::ReplyWrite,
+  ) {
+    if offset < 0 {
+      reply.error(libc::EINVAL);
+      return;
+    }
+    let end = (offset as u64).saturating_add(data.len() as u64);
+    if self.over_max_write_size(end) {
+      reply.error(libc::EFBIG);
+      return;
+    }
+    let handle = match self.write_handles.lock().unwrap().get(&fh) {
+      Some(handle) => handle.clone(),
+      None => {
+        reply.error(libc::EBADF);
+        return;
+      }
+    };
+    let size = {
+      let mut staged = handle.staged.lock().unwrap();
+      if let Err(err) = staged.write_at(offset as u64, data) {
+        reply.error(err.raw_os_error().unwrap_or(libc::EIO));
+        return;
       }
+      staged.size()
+    };
+    // the new size shows right away, while the content is uploaded on close.
+    if let Some(gfile) = self.file_tree.write().unwrap().file_attrs.get_mut(&ino) {
+      gfile.file_attr.size = size;
+      gfile.file_attr.blocks = size / constants::BLOCK_SIZE as u64;
+      gfile.file_attr.mtime = time::get_time();
+    }
+    reply.written(data.len() as u32);
+  }
+
+  fn flush(
+    &mut self,
+    _req: &fuse::Request,
+    ino: u64,
+    fh: u64,
+    _lock_owner: u64,
+    reply: fuse::ReplyEmpty,
+  ) {
+    debug!("flush: inode({})", ino);
+    let write_handle = self.write_handles.lock().unwrap().get(&fh).cloned();
+    match write_handle {
+      Some(handle) => self.upload_staged(handle, reply),
+      None => reply.ok(),
+    }
+  }
+
+  fn fsync(
+    &mut self,
+    _req: &fuse::Request,
+    ino: u64,
+    fh: u64,
+    _datasync: bool,
+    reply: fuse::ReplyEmpty,
+  ) {
+    debug!("fsync: inode({})", ino);
+    let write_handle = self.write_handles.lock().unwrap().get(&fh).cloned();
+    match write_handle {
+      Some(handle) => self.upload_staged(handle, reply),
+      None => reply.ok(),
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn md5_round_trips_through_bytes() {
+    let hex = "d41d8cd98f00b204e9800998ecf8427e";
+    let md5 = parse_md5(hex).unwrap();
+    assert_eq!(md5[0], 0xd4);
+    assert_eq!(md5[15], 0x7e);
+    let mut api_file = google_drive3::File::default();
+    api_file.id = Some("id".into());
+    api_file.md5_checksum = Some(hex.into());
+    assert_eq!(GoogleFile::from(api_file).md5_hex(), Some(hex.to_string()));
+  }
+
+  #[test]
+  fn invalid_md5s_are_dropped() {
+    assert_eq!(parse_md5(""), None);
+    assert_eq!(parse_md5("d41d8cd98f00b204e9800998ecf8427"), None);
+    assert_eq!(parse_md5("z41d8cd98f00b204e9800998ecf8427e"), None);
+    assert_eq!(parse_md5("+41d8cd98f00b204e9800998ecf8427e"), None);
+    assert_eq!(parse_md5("é1d8cd98f00b204e9800998ecf8427e"), None);
+  }
+}
@@ -0,0 +1,217 @@
+extern crate flate2;
+extern crate google_drive3;
+extern crate hyper;
+extern crate serde_json;
+
+use common;
+use drive_error;
+use oauth;
+use oauth::GetToken;
+use std::error::Error;
+use std::io::Read;
+
+const FILES_LIST_URL: &'static str = "https://www.googleapis.com/drive/v3/files";
+const CHANGES_URL: &'static str = "https://www.googleapis.com/drive/v3/changes";
+// Google only compresses responses for user agents containing "gzip".
+const USER_AGENT: &'static str = "gdrivefs (gzip)";
+
+/// Fetches one page of files.list with the query parameters `params`,
+/// asking for a gzip compressed response. Listing pages are large and
+/// compress well, which speeds up scans on slow links.
+pub fn list_files(
+  auth: &mut oauth::GoogleAuthenticator,
+  params: &[(&str, &str)],
+) -> Result<google_drive3::FileList, Box<Error>> {
+  let url = try!(hyper::Url::parse_with_params(FILES_LIST_URL, params));
+  let token = auth.api_key().unwrap();
+  let resp = try!(common::new_hyper_tls_client()
+    .get(url)
+    .header(hyper::header::Authorization(hyper::header::Bearer {
+      token: token,
+    }))
+    .header(hyper::header::AcceptEncoding(vec![hyper::header::qitem(
+      hyper::header::Encoding::Gzip,
+    )]))
+    .header(hyper::header::UserAgent(USER_AGENT.into()))
+    .send());
+  let gzipped = match resp.headers.get::<hyper::header::ContentEncoding>() {
+    Some(&hyper::header::ContentEncoding(ref encodings)) => {
+      encodings.contains(&hyper::header::Encoding::Gzip)
+    }
+    None => false,
+  };
+  let status = resp.status;
+  let mut body: Box<Read> = if gzipped {
+    Box::new(flate2::read::GzDecoder::new(resp))
+  } else {
+    Box::new(resp)
+  };
+  if !status.is_success() {
+    let mut err: String = String::new();
+    try!(body.read_to_string(&mut err));
+    let err = drive_error::DriveError::parse(status, &err);
+    warn!("Files list error: {}", err);
+    auth.rotate_on_quota_error(&err);
+    return Err(Box::new(err));
+  }
+  let result = try!(serde_json::from_reader(&mut body));
+  Ok(result)
+}
+
+/// Fetches the metadata `fields` of `file_id`.
+pub fn get_file(
+  auth: &mut oauth::GoogleAuthenticator,
+  file_id: &str,
+  fields: &str,
+) -> Result<google_drive3::File, Box<Error>> {
+  let url = try!(hyper::Url::parse_with_params(
+    &format!("{}/{}", FILES_LIST_URL, file_id),
+    &[("fields", fields), ("supportsAllDrives", "true")]
+  ));
+  let token = auth.api_key().unwrap();
+  let mut resp = try!(common::new_hyper_tls_client()
+    .get(url)
+    .header(hyper::header::Authorization(hyper::header::Bearer {
+      token: token,
+    }))
+    .send());
+  if !resp.status.is_success() {
+    let mut body: String = String::new();
+    try!(resp.read_to_string(&mut body));
+    let err = drive_error::DriveError::parse(resp.status, &body);
+    warn!("File get error: {}", err);
+    auth.rotate_on_quota_error(&err);
+    return Err(Box::new(err));
+  }
+  let result = try!(serde_json::from_reader(&mut resp));
+  Ok(result)
+}
+
+/// Fetches the token from which changes.list reports the changes made to the
+/// drive from now on.
+pub fn get_start_page_token(auth: &mut oauth::GoogleAuthenticator) -> Result<String, Box<Error>> {
+  let url = try!(hyper::Url::parse_with_params(
+    &format!("{}/startPageToken", CHANGES_URL),
+    &[("supportsAllDrives", "true")]
+  ));
+  let mut resp = try!(get_changes(auth, url));
+  let result: google_drive3::StartPageToken = try!(serde_json::from_reader(&mut resp));
+  match result.start_page_token {
+    Some(token) => Ok(token),
+    None => Err(From::from("changes.getStartPageToken returned no token")),
+  }
+}
+
+/// Fetches one page of the changes made to the drive since `page_token`,
+/// with the `fields` of each change, e.g. `changes(fileId,file(name))`.
+/// Changes to items of shared drives are included.
+pub fn list_changes(
+  auth: &mut oauth::GoogleAuthenticator,
+  page_token: &str,
+  fields: &str,
+) -> Result<google_drive3::ChangeList, Box<Error>> {
+  let url = try!(hyper::Url::parse_with_params(
+    CHANGES_URL,
+    &[
+      ("pageToken", page_token),
+      ("fields", fields),
+      ("pageSize", "1000"),
+      ("supportsAllDrives", "true"),
+      ("includeItemsFromAllDrives", "true"),
+    ]
+  ));
+  let mut resp = try!(get_changes(auth, url));
+  let result = try!(serde_json::from_reader(&mut resp));
+  Ok(result)
+}
+
+// sends a GET request for |url| of the changes API, failing with a
+// DriveError if it's refused.
+fn get_changes(
+  auth: &mut oauth::GoogleAuthenticator,
+  url: hyper::Url,
+) -> Result<hyper::client::Response, Box<Error>> {
+  let token = auth.api_key().unwrap();
+  let mut resp = try!(common::new_hyper_tls_client()
+    .get(url)
+    .header(hyper::header::Authorization(hyper::header::Bearer {
+      token: token,
+    }))
+    .send());
+  if !resp.status.is_success() {
+    let mut body: String = String::new();
+    try!(resp.read_to_string(&mut body));
+    let err = drive_error::DriveError::parse(resp.status, &body);
+    warn!("Changes error: {}", err);
+    auth.rotate_on_quota_error(&err);
+    return Err(Box::new(err));
+  }
+  Ok(resp)
+}
+
+/// Lists the `fields` of the untrashed children of the folder `folder_id`,
+/// going through all the pages.
+pub fn list_children(
+  auth: &mut oauth::GoogleAuthenticator,
+  folder_id: &str,
+  fields: &str,
+) -> Result<Vec<google_drive3::File>, Box<Error>> {
+  let query = format!("'{}' in parents and trashed = false", folder_id);
+  let page_fields = format!("nextPageToken,files({})", fields);
+  let mut files = Vec::new();
+  let mut page_token: Option<String> = None;
+  loop {
+    let token = page_token.take();
+    let mut params = vec![
+      ("q", query.as_str()),
+      ("fields", page_fields.as_str()),
+      ("pageSize", "1000"),
+      ("supportsAllDrives", "true"),
+      ("includeItemsFromAllDrives", "true"),
+    ];
+    if let Some(ref token) = token {
+      params.push(("pageToken", token.as_str()));
+    }
+    let result = try!(list_files(auth, &params));
+    files.extend(result.files.unwrap_or(Vec::new()));
+    page_token = result.next_page_token;
+    if page_token.is_none() {
+      return Ok(files);
+    }
+  }
+}
+
+/// Finds the untrashed item at `path`, relative to the root of the drive,
+/// and fetches its `fields`. Fails if any component of the path is missing;
+/// if several items share a name, the first one listed is picked.
+pub fn find_by_path(
+  auth: &mut oauth::GoogleAuthenticator,
+  path: &str,
+  fields: &str,
+) -> Result<google_drive3::File, Box<Error>> {
+  let mut file = try!(get_file(auth, "root", fields));
+  for name in path.split('/').filter(|name| !name.is_empty()) {
+    let parent_id = file.id.clone().unwrap_or_default();
+    let query = format!(
+      "'{}' in parents and name = '{}' and trashed = false",
+      parent_id,
+      name.replace('\\', "\\\\").replace('\'', "\\'")
+    );
+    let page_fields = format!("files({})", fields);
+    let result = try!(list_files(
+      auth,
+      &[
+        ("q", query.as_str()),
+        ("fields", page_fields.as_str()),
+        ("pageSize", "1"),
+        ("supportsAllDrives", "true"),
+        ("includeItemsFromAllDrives", "true"),
+      ]
+    ));
+    file = match result.files.and_then(|files| files.into_iter().next()) {
+      Some(child) => child,
+      None => return Err(From::from(format!("No such file: {}", path))),
+    };
+  }
+  Ok(file)
+}
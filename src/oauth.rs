@@ -7,6 +7,7 @@ extern crate yup_oauth2;
 
 use self::inth_oauth2::token::Token;
 use common;
+use drive_error;
 use std;
 use std::error::Error;
 use std::sync;
@@ -17,6 +18,10 @@ pub use self::yup_oauth2::GetToken;
 pub type GoogleToken = inth_oauth2::token::Bearer<inth_oauth2::token::Refresh>;
 pub type GoogleClient = inth_oauth2::client::Client<inth_oauth2::provider::google::Installed>;
 
+// the client in use is switched at most this often, so that the requests
+// in flight when it ran out of quota don't switch it again.
+const MIN_ROTATION_INTERVAL_SECS: u64 = 30;
+
 pub fn new_google_client(
   client_id: &str,
   client_secret: &str,
@@ -43,12 +48,22 @@ pub fn save_token(path: &str, tok: &GoogleToken) -> std::io::Result<()> {
     .and_then(|encoded| common::set_contents(path, encoded.as_bytes(), 0o600 as libc::mode_t))
 }
 
+// an oauth client and the token it issued.
+struct Credentials {
+  oauth_client: GoogleClient,
+  inth_token: GoogleToken,
+}
+
 /// GoogleAuthenticator implements the yup_oauth2::GetToken trait, for use
 /// with the Google drive api.
 struct GoogleAuthenticatorImpl {
   http_client: hyper::client::Client,
-  oauth_client: inth_oauth2::client::Client<inth_oauth2::provider::google::Installed>,
-  inth_token: GoogleToken,
+  // the client the token file was issued to, then the standby clients.
+  credentials: Vec<Credentials>,
+  // index in |credentials| of the client requests are made with.
+  active: usize,
+  // when |active| last changed.
+  last_rotation: Option<std::time::Instant>,
 }
 
 impl GoogleAuthenticatorImpl {
@@ -58,18 +73,23 @@ impl GoogleAuthenticatorImpl {
   ) -> GoogleAuthenticatorImpl {
     GoogleAuthenticatorImpl {
       http_client: common::new_hyper_tls_client(),
-      oauth_client: oauth_client,
-      inth_token: initial_token,
+      credentials: vec![Credentials {
+        oauth_client: oauth_client,
+        inth_token: initial_token,
+      }],
+      active: 0,
+      last_rotation: None,
     }
   }
 
   fn ensure_token(&mut self) {
-    match self
+    let credentials = &mut self.credentials[self.active];
+    match credentials
       .oauth_client
-      .ensure_token(&self.http_client, self.inth_token.clone())
+      .ensure_token(&self.http_client, credentials.inth_token.clone())
     {
       Ok(token) => {
-        self.inth_token = token;
+        credentials.inth_token = token;
       }
       Err(err) => {
         warn!("token refresh error: {:?}", err);
@@ -79,7 +99,47 @@ impl GoogleAuthenticatorImpl {
 
   pub fn get_token(&mut self) -> &GoogleToken {
     self.ensure_token();
-    &self.inth_token
+    &self.credentials[self.active].inth_token
+  }
+
+  // the token of the client the token file was issued to, refreshed if it's
+  // the one in use.
+  fn get_primary_token(&mut self) -> &GoogleToken {
+    if self.active == 0 {
+      self.ensure_token();
+    }
+    &self.credentials[0].inth_token
+  }
+
+  // refreshes the token over a new connection, even if it hasn't expired.
+  fn reset(&mut self) {
+    self.http_client = common::new_unpooled_hyper_tls_client();
+    let credentials = &mut self.credentials[self.active];
+    match credentials
+      .oauth_client
+      .refresh_token(&self.http_client, credentials.inth_token.clone(), None)
+    {
+      Ok(token) => {
+        credentials.inth_token = token;
+      }
+      Err(err) => {
+        warn!("token refresh error: {:?}", err);
+      }
+    }
+  }
+
+  // switches to the next client, unless there's only one or it was
+  // switched less than MIN_ROTATION_INTERVAL_SECS ago.
+  fn rotate(&mut self) -> bool {
+    let recent = self.last_rotation.map_or(false, |last| {
+      last.elapsed() < std::time::Duration::from_secs(MIN_ROTATION_INTERVAL_SECS)
+    });
+    if self.credentials.len() < 2 || recent {
+      return false;
+    }
+    self.active = (self.active + 1) % self.credentials.len();
+    self.last_rotation = Some(std::time::Instant::now());
+    true
   }
 }
 
@@ -101,9 +161,47 @@ impl GoogleAuthenticator {
     Ok(GoogleAuthenticator::new(oauth_client, init_token))
   }
 
+  /// Saves the token of the client given to `new` to `path`, whichever
+  /// client is in use.
   pub fn save_to_file(&self, path: &str) -> std::io::Result<()> {
     let mut auth_impl = self.auth_impl.lock().unwrap();
-    save_token(path, auth_impl.get_token())
+    save_token(path, auth_impl.get_primary_token())
+  }
+
+  /// Adds a standby client authorized for the same account, with the token
+  /// `token` it issued. Requests switch to the next client when the one in
+  /// use runs out of quota, see rotate_on_quota_error. Tokens of standby
+  /// clients are refreshed in memory only.
+  pub fn add_standby(&self, oauth_client: GoogleClient, token: GoogleToken) {
+    let mut auth_impl = self.auth_impl.lock().unwrap();
+    auth_impl.credentials.push(Credentials {
+      oauth_client: oauth_client,
+      inth_token: token,
+    });
+  }
+
+  /// Switches to the next client if `err` is Drive refusing a request for
+  /// exceeding a quota of the client in use, which another client may still
+  /// have. Returns whether it switched, in which case the request is worth
+  /// retrying.
+  pub fn rotate_on_quota_error(&self, err: &(Error + 'static)) -> bool {
+    let quota_error = err
+      .downcast_ref::<drive_error::DriveError>()
+      .map_or(false, |err| err.is_client_quota());
+    if !quota_error {
+      return false;
+    }
+    let mut auth_impl = self.auth_impl.lock().unwrap();
+    if !auth_impl.rotate() {
+      return false;
+    }
+    warn!(
+      "{}, switching to oauth client {} of {}",
+      err,
+      auth_impl.active + 1,
+      auth_impl.credentials.len()
+    );
+    true
   }
 
   pub fn start_auto_save(&self, path: &str, interval: std::time::Duration) {
@@ -128,6 +226,13 @@ impl GoogleAuthenticator {
     let mut auth_impl = self.auth_impl.lock().unwrap();
     auth_impl.get_token().clone()
   }
+
+  /// Refreshes the token over a new connection, to recover from a token or
+  /// connection that stopped working before it expired.
+  pub fn reset(&self) {
+    let mut auth_impl = self.auth_impl.lock().unwrap();
+    auth_impl.reset();
+  }
 }
 
 impl std::clone::Clone for GoogleAuthenticator {
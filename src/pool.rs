@@ -0,0 +1,35 @@
+extern crate threadpool;
+
+use std::sync;
+
+/// A thread pool whose threads are only started along with its first job,
+/// so that a filesystem can be mounted and sandboxed before any of them
+/// start, see sandbox::restrict_paths.
+#[derive(Clone)]
+pub struct LazyPool {
+  name: &'static str,
+  threads: usize,
+  pool: sync::Arc<sync::Mutex<Option<threadpool::ThreadPool>>>,
+}
+
+impl LazyPool {
+  pub fn new(name: &'static str, threads: usize) -> LazyPool {
+    LazyPool {
+      name: name,
+      threads: threads,
+      pool: sync::Arc::new(sync::Mutex::new(None)),
+    }
+  }
+
+  /// Runs `job` on one of the threads, starting them if it's the first job.
+  pub fn execute<F: FnOnce() + Send + 'static>(&self, job: F) {
+    let mut pool = self.pool.lock().unwrap();
+    if pool.is_none() {
+      *pool = Some(threadpool::ThreadPool::with_name(
+        self.name.into(),
+        self.threads,
+      ));
+    }
+    pool.as_ref().unwrap().execute(job);
+  }
+}
@@ -0,0 +1,112 @@
+extern crate serde_json;
+
+use self::serde_json::Value;
+use common;
+use oauth;
+use std::error::Error;
+
+// rclone marks encrypted configs with this first line.
+const ENCRYPTED_CONFIG_HEADER: &'static str = "# Encrypted rclone configuration File";
+const DRIVE_REMOTE_TYPE: &'static str = "drive";
+// settings of a drive remote that change what it shows, which a mount of
+// the whole drive can't honor.
+const UNSUPPORTED_SETTINGS: &'static [&'static str] =
+  &["root_folder_id", "team_drive", "service_account_file"];
+
+/// The credentials of a drive remote in an rclone config.
+pub struct RcloneRemote {
+  /// Name of the remote, without the trailing colon.
+  pub name: String,
+  pub client_id: String,
+  pub client_secret: String,
+  pub token: oauth::GoogleToken,
+}
+
+/// Reads the credentials of the drive remote `remote` (e.g. "gdrive:") from
+/// the rclone config file at `path`. Without `remote`, the config must have a
+/// single drive remote. The remote must have its own client id, since tokens
+/// only refresh with the client they were issued to.
+pub fn import_remote(path: &str, remote: Option<&str>) -> Result<RcloneRemote, Box<Error>> {
+  let config = try!(common::get_contents(path));
+  if config.starts_with(ENCRYPTED_CONFIG_HEADER) {
+    return Err(From::from(
+      "The rclone config is encrypted, decrypt it with rclone config first",
+    ));
+  }
+  let sections = common::parse_ini(&config);
+  let wanted = remote.map(|remote| remote.trim_right_matches(':'));
+  let mut drives = sections
+    .into_iter()
+    .filter(|&(_, ref settings)| common::ini_setting(settings, "type") == Some(DRIVE_REMOTE_TYPE));
+  let (name, settings) = match wanted {
+    Some(wanted) => match drives.find(|&(ref name, _)| name == wanted) {
+      Some(section) => section,
+      None => {
+        return Err(From::from(format!(
+          "{} has no drive remote named {}",
+          path, wanted
+        )))
+      }
+    },
+    None => match (drives.next(), drives.next()) {
+      (Some(section), None) => section,
+      (None, _) => return Err(From::from(format!("{} has no drive remote", path))),
+      (Some(_), Some(_)) => {
+        return Err(From::from(format!(
+          "{} has several drive remotes, pick one with --remote",
+          path
+        )))
+      }
+    },
+  };
+
+  for key in UNSUPPORTED_SETTINGS {
+    if common::ini_setting(&settings, key).map_or(false, |value| !value.is_empty()) {
+      warn!(
+        "rclone remote {}: {} is not supported and ignored",
+        name, key
+      );
+    }
+  }
+  let client_id = common::ini_setting(&settings, "client_id").unwrap_or("");
+  let client_secret = common::ini_setting(&settings, "client_secret").unwrap_or("");
+  if client_id.is_empty() || client_secret.is_empty() {
+    return Err(From::from(format!(
+      "rclone remote {} uses rclone's own client id, whose tokens can't be reused; \
+       set up the remote with your own client id first",
+      name
+    )));
+  }
+  let rclone_token: Value = match common::ini_setting(&settings, "token") {
+    Some(token) => try!(serde_json::from_str(token)),
+    None => return Err(From::from(format!("rclone remote {} has no token", name))),
+  };
+  let refresh_token = match rclone_token["refresh_token"].as_str() {
+    Some(refresh_token) => refresh_token,
+    None => {
+      return Err(From::from(format!(
+        "rclone remote {} has no refresh token",
+        name
+      )))
+    }
+  };
+  // the token is marked as expired, so it gets refreshed on first use
+  // rather than trusting rclone's expiry time.
+  let mut lifetime = serde_json::Map::new();
+  lifetime.insert("refresh_token".into(), refresh_token.into());
+  lifetime.insert("expires".into(), "1970-01-01T00:00:00Z".into());
+  let mut bearer = serde_json::Map::new();
+  bearer.insert(
+    "access_token".into(),
+    rclone_token["access_token"].as_str().unwrap_or("").into(),
+  );
+  bearer.insert("scope".into(), Value::Null);
+  bearer.insert("lifetime".into(), Value::Object(lifetime));
+  let token = try!(serde_json::from_value(Value::Object(bearer)));
+  Ok(RcloneRemote {
+    name: name,
+    client_id: client_id.into(),
+    client_secret: client_secret.into(),
+    token: token,
+  })
+}
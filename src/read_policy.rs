@@ -0,0 +1,141 @@
+use constants;
+use std::cmp;
+use std::error::Error;
+use std::str::FromStr;
+
+/// Read settings for the files matching a pattern, overriding the
+/// corresponding `FileReadOptions` when their read handle is spawned.
+#[derive(Debug, Clone)]
+pub struct ReadPolicy {
+  pattern: Pattern,
+  /// Overrides `FileReadOptions::readahead_queue_size`.
+  pub readahead_queue_size: Option<usize>,
+  /// Overrides `FileReadOptions::read_block_multiplier`.
+  pub read_block_multiplier: Option<u32>,
+  /// Overrides `FileReadOptions::file_read_cache_blocks`.
+  pub file_read_cache_blocks: Option<usize>,
+}
+
+#[derive(Debug, Clone)]
+enum Pattern {
+  // a lowercase file extension, without the dot.
+  Extension(String),
+  // a mime type, or a type prefix such as "video/" for "video/*".
+  MimeType(String),
+  MimeTypePrefix(String),
+}
+
+impl ReadPolicy {
+  /// Whether the policy applies to a file named `name` of type `mime_type`.
+  pub fn matches(&self, name: &str, mime_type: Option<&str>) -> bool {
+    match self.pattern {
+      Pattern::Extension(ref extension) => name
+        .rsplitn(2, '.')
+        .next()
+        .map(|suffix| suffix.len() < name.len() && suffix.to_lowercase() == *extension)
+        .unwrap_or(false),
+      Pattern::MimeType(ref pattern) => mime_type == Some(pattern.as_str()),
+      Pattern::MimeTypePrefix(ref prefix) => mime_type
+        .map(|mime_type| mime_type.starts_with(prefix.as_str()))
+        .unwrap_or(false),
+    }
+  }
+}
+
+impl FromStr for ReadPolicy {
+  type Err = Box<Error>;
+
+  /// Parses a `<pattern>:<setting>,...` rule, where the pattern is `*.<ext>`,
+  /// a mime type or `<type>/*`, and the settings are `readahead=<chunks>`,
+  /// `chunk=<bytes>` (with an optional K, M or G suffix) and
+  /// `cache=<chunks>`. For example `*.mkv:readahead=8,chunk=16M`.
+  fn from_str(spec: &str) -> Result<ReadPolicy, Box<Error>> {
+    let mut parts = spec.splitn(2, ':');
+    let pattern = match parts.next().unwrap_or("") {
+      pattern if pattern.starts_with("*.") && pattern.len() > 2 => {
+        Pattern::Extension(pattern[2..].to_lowercase())
+      }
+      pattern if pattern.ends_with("/*") && pattern.len() > 2 => {
+        Pattern::MimeTypePrefix(pattern[..pattern.len() - 1].into())
+      }
+      pattern if pattern.contains('/') => Pattern::MimeType(pattern.into()),
+      pattern => {
+        return Err(From::from(format!(
+          "invalid read policy pattern: {}",
+          pattern
+        )))
+      }
+    };
+    let mut policy = ReadPolicy {
+      pattern: pattern,
+      readahead_queue_size: None,
+      read_block_multiplier: None,
+      file_read_cache_blocks: None,
+    };
+    for setting in parts.next().unwrap_or("").split(',') {
+      let mut setting_parts = setting.trim().splitn(2, '=');
+      match (setting_parts.next(), setting_parts.next()) {
+        (Some("readahead"), Some(value)) => {
+          policy.readahead_queue_size = Some(try!(value.parse()));
+        }
+        (Some("chunk"), Some(value)) => {
+          policy.read_block_multiplier = Some(try!(parse_chunk_size(value)));
+        }
+        (Some("cache"), Some(value)) => {
+          policy.file_read_cache_blocks = Some(try!(value.parse()));
+        }
+        _ => {
+          return Err(From::from(format!(
+            "invalid read policy setting: {}",
+            setting
+          )))
+        }
+      }
+    }
+    Ok(policy)
+  }
+}
+
+/// Parses a chunk size in bytes, with an optional K, M or G suffix, into a
+/// read block multiplier.
+pub fn parse_chunk_size(value: &str) -> Result<u32, Box<Error>> {
+  let blocks = try!(parse_size(value)) / constants::BLOCK_SIZE as u64;
+  // chunks are at least one block.
+  Ok(cmp::min(cmp::max(blocks, 1), u32::max_value() as u64) as u32)
+}
+
+// parses a byte count with an optional K, M or G (binary) suffix.
+fn parse_size(value: &str) -> Result<u64, Box<Error>> {
+  let value = value.trim();
+  let (digits, unit) = match value.chars().last() {
+    Some('K') | Some('k') => (&value[..value.len() - 1], 1 << 10),
+    Some('M') | Some('m') => (&value[..value.len() - 1], 1 << 20),
+    Some('G') | Some('g') => (&value[..value.len() - 1], 1 << 30),
+    _ => (value, 1),
+  };
+  match try!(digits.parse::<u64>()).checked_mul(unit) {
+    Some(size) => Ok(size),
+    None => Err(From::from(format!("size too large: {}", value))),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parses_sizes_with_units() {
+    assert_eq!(parse_size("512").unwrap(), 512);
+    assert_eq!(parse_size("4K").unwrap(), 4096);
+    assert_eq!(parse_size("8m").unwrap(), 8 << 20);
+    assert_eq!(parse_size("1G").unwrap(), 1 << 30);
+  }
+
+  #[test]
+  fn rejects_sizes_that_overflow() {
+    assert!(parse_size("18446744073709551615").is_ok());
+    assert!(parse_size("18446744073709551615K").is_err());
+    assert!(parse_size("17179869184G").is_err());
+    assert!(parse_size("18446744073709551616").is_err());
+  }
+}
@@ -0,0 +1,56 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Values fetched from Drive in the last `ttl`, by key, so that the requests
+/// a single command makes in a row, such as getfattr asking for the size of
+/// an xattr then its value, don't each fetch it again.
+pub struct RecentCache<V> {
+  ttl: Duration,
+  // map of key -> when the value was fetched, and the value.
+  entries: HashMap<String, (Instant, V)>,
+}
+
+impl<V: Clone> RecentCache<V> {
+  pub fn new(ttl: Duration) -> RecentCache<V> {
+    RecentCache {
+      ttl: ttl,
+      entries: HashMap::new(),
+    }
+  }
+
+  /// The value of `key`, unless it's missing or older than the ttl.
+  pub fn get(&self, key: &str) -> Option<V> {
+    match self.entries.get(key) {
+      Some(&(fetched, ref value)) if fetched.elapsed() < self.ttl => Some(value.clone()),
+      _ => None,
+    }
+  }
+
+  /// Keeps `value` for `key`, dropping the values older than the ttl.
+  pub fn insert(&mut self, key: &str, value: V) {
+    let ttl = self.ttl;
+    self
+      .entries
+      .retain(|_, &mut (fetched, _)| fetched.elapsed() < ttl);
+    self.entries.insert(key.into(), (Instant::now(), value));
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn values_expire_after_the_ttl() {
+    let mut cache = RecentCache::new(Duration::from_secs(60));
+    cache.insert("a", 1);
+    assert_eq!(cache.get("a"), Some(1));
+    assert_eq!(cache.get("b"), None);
+
+    let mut expired = RecentCache::new(Duration::from_secs(0));
+    expired.insert("a", 1);
+    assert_eq!(expired.get("a"), None);
+    expired.insert("b", 2);
+    assert!(!expired.entries.contains_key("a"));
+  }
+}
@@ -0,0 +1,57 @@
+extern crate hyper;
+
+const FILE_GET_URL: &'static str = "https://www.googleapis.com/drive/v3/files";
+
+/// Where the content of a file is read from.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ContentSource {
+  /// The stored content of the file, read by file id with alt=media, which
+  /// unlike a v2 downloadUrl doesn't expire. supportsAllDrives also covers
+  /// files in shared drives.
+  Media,
+  /// The content of the file at the revision with this id.
+  Revision(String),
+  /// The native Google item exported as this mime type. Drive only exports
+  /// items up to 10MB.
+  Export(String),
+}
+
+impl ContentSource {
+  /// The url to read the content of `file_id` from.
+  pub fn url(&self, file_id: &str) -> String {
+    match *self {
+      ContentSource::Media => format!(
+        "{}/{}?alt=media&supportsAllDrives=true",
+        FILE_GET_URL, file_id
+      ),
+      ContentSource::Revision(ref revision_id) => format!(
+        "{}/{}/revisions/{}?alt=media",
+        FILE_GET_URL, file_id, revision_id
+      ),
+      ContentSource::Export(ref mime_type) => {
+        // mime types such as application/epub+zip need escaping.
+        let mut url = hyper::Url::parse(FILE_GET_URL).unwrap();
+        url
+          .path_segments_mut()
+          .unwrap()
+          .push(file_id)
+          .push("export");
+        url.query_pairs_mut().append_pair("mimeType", mime_type);
+        url.into_string()
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn export_urls_escape_mime_types() {
+    assert_eq!(
+      ContentSource::Export("application/epub+zip".into()).url("abc-123"),
+      "https://www.googleapis.com/drive/v3/files/abc-123/export?mimeType=application%2Fepub%2Bzip"
+    );
+  }
+}
@@ -0,0 +1,111 @@
+use std::sync::atomic;
+
+// the budget is kept in tenths of a retry, so that each successful request
+// can earn a fraction of one.
+const UNITS_PER_RETRY: usize = 10;
+// each successful request earns this many units: retries may add at most
+// 10% to the requests made.
+const UNITS_PER_SUCCESS: usize = 1;
+// retries that can be saved up, which is also the starting budget.
+const MAX_RETRIES: usize = 20;
+const MAX_UNITS: usize = MAX_RETRIES * UNITS_PER_RETRY;
+
+// number of budgets currently exhausted, across all mounts, for the error
+// reports.
+static EXHAUSTED_BUDGETS: atomic::AtomicUsize = atomic::ATOMIC_USIZE_INIT;
+
+/// The retries the reads of one mount may make. Once it's exhausted, failing
+/// requests fail without being retried, so an outage doesn't get amplified
+/// by retries, until successful requests earn the budget back.
+pub struct RetryBudget {
+  // units of the budget currently spent, up to MAX_UNITS.
+  spent_units: atomic::AtomicUsize,
+  exhausted: atomic::AtomicBool,
+}
+
+impl RetryBudget {
+  pub fn new() -> RetryBudget {
+    RetryBudget {
+      spent_units: atomic::AtomicUsize::new(0),
+      exhausted: atomic::AtomicBool::new(false),
+    }
+  }
+
+  /// Records a successful request, adding to the budget.
+  pub fn record_success(&self) {
+    let mut spent = self.spent_units.load(atomic::Ordering::SeqCst);
+    while spent > 0 {
+      let previous = self.spent_units.compare_and_swap(
+        spent,
+        spent.saturating_sub(UNITS_PER_SUCCESS),
+        atomic::Ordering::SeqCst,
+      );
+      if previous == spent {
+        break;
+      }
+      spent = previous;
+    }
+    if self.exhausted.swap(false, atomic::Ordering::SeqCst) {
+      EXHAUSTED_BUDGETS.fetch_sub(1, atomic::Ordering::SeqCst);
+      info!("retry budget: requests succeed again, retrying failed reads");
+    }
+  }
+
+  /// Takes one retry from the budget, returning false if it's exhausted.
+  pub fn acquire(&self) -> bool {
+    let mut spent = self.spent_units.load(atomic::Ordering::SeqCst);
+    while spent + UNITS_PER_RETRY <= MAX_UNITS {
+      let previous =
+        self
+          .spent_units
+          .compare_and_swap(spent, spent + UNITS_PER_RETRY, atomic::Ordering::SeqCst);
+      if previous == spent {
+        return true;
+      }
+      spent = previous;
+    }
+    if !self.exhausted.swap(true, atomic::Ordering::SeqCst) {
+      EXHAUSTED_BUDGETS.fetch_add(1, atomic::Ordering::SeqCst);
+      warn!("retry budget exhausted, failing reads without retrying");
+    }
+    false
+  }
+}
+
+impl Drop for RetryBudget {
+  fn drop(&mut self) {
+    if *self.exhausted.get_mut() {
+      EXHAUSTED_BUDGETS.fetch_sub(1, atomic::Ordering::SeqCst);
+    }
+  }
+}
+
+/// Whether the budget of any mount ran out, and its failed reads aren't
+/// being retried.
+pub fn any_exhausted() -> bool {
+  EXHAUSTED_BUDGETS.load(atomic::Ordering::SeqCst) > 0
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn successes_earn_back_retries() {
+    let budget = RetryBudget::new();
+    for _ in 0..MAX_RETRIES {
+      assert!(budget.acquire());
+    }
+    assert!(!budget.acquire());
+    assert!(budget.exhausted.load(atomic::Ordering::SeqCst));
+    for _ in 0..UNITS_PER_RETRY / UNITS_PER_SUCCESS {
+      budget.record_success();
+    }
+    assert!(!budget.exhausted.load(atomic::Ordering::SeqCst));
+    assert!(budget.acquire());
+    assert!(!budget.acquire());
+
+    // another mount's budget is untouched.
+    assert!(RetryBudget::new().acquire());
+  }
+}
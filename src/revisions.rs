@@ -0,0 +1,133 @@
+extern crate hyper;
+extern crate serde_json;
+
+use common;
+use drive_error;
+use oauth;
+use oauth::GetToken;
+use std::error::Error;
+use std::io::Read;
+
+const FILE_GET_URL: &'static str = "https://www.googleapis.com/drive/v3/files";
+const FILE_UPLOAD_URL: &'static str = "https://www.googleapis.com/upload/drive/v3/files";
+
+/// Makes the revision `revision_id` of `file_id` its current content, by
+/// uploading that revision's content as a new revision. The content is
+/// streamed from the download to the upload rather than held in memory.
+pub fn restore_revision(
+  auth: &mut oauth::GoogleAuthenticator,
+  file_id: &str,
+  revision_id: &str,
+) -> Result<(), Box<Error>> {
+  let client = common::new_hyper_tls_client();
+  let url = format!(
+    "{}/{}/revisions/{}?alt=media",
+    FILE_GET_URL, file_id, revision_id
+  );
+  let token = auth.api_key().unwrap();
+  let mut content = try!(client
+    .get(&url)
+    .header(hyper::header::Authorization(hyper::header::Bearer {
+      token: token,
+    }))
+    .send());
+  if !content.status.is_success() {
+    let mut body: String = String::new();
+    try!(content.read_to_string(&mut body));
+    let err = drive_error::DriveError::parse(content.status, &body);
+    warn!("Revision get error: {}", err);
+    return Err(Box::new(err));
+  }
+  let content_type = content
+    .headers
+    .get::<hyper::header::ContentType>()
+    .cloned()
+    .unwrap_or(hyper::header::ContentType::plaintext());
+  let content_length = content
+    .headers
+    .get::<hyper::header::ContentLength>()
+    .map(|length| length.0);
+
+  let url = format!(
+    "{}/{}?uploadType=media&supportsAllDrives=true",
+    FILE_UPLOAD_URL, file_id
+  );
+  let token = auth.api_key().unwrap();
+  let body = match content_length {
+    Some(length) => hyper::client::Body::SizedBody(&mut content, length),
+    None => hyper::client::Body::ChunkedBody(&mut content),
+  };
+  let mut resp = try!(client
+    .request(hyper::method::Method::Patch, &url)
+    .header(content_type)
+    .header(hyper::header::Authorization(hyper::header::Bearer {
+      token: token,
+    }))
+    .body(body)
+    .send());
+  if !resp.status.is_success() {
+    let mut body: String = String::new();
+    try!(resp.read_to_string(&mut body));
+    let err = drive_error::DriveError::parse(resp.status, &body);
+    warn!("Revision restore error: {}", err);
+    return Err(Box::new(err));
+  }
+  Ok(())
+}
+
+/// Marks the current revision of `file_id` to be kept forever, rather than
+/// pruned from its history by Drive, so that its content can still be
+/// restored once replaced. Drive keeps at most 200 such revisions per file.
+/// Does nothing for items without revisions of their content, like Docs.
+pub fn keep_head_revision(
+  auth: &mut oauth::GoogleAuthenticator,
+  file_id: &str,
+) -> Result<(), Box<Error>> {
+  let client = common::new_hyper_tls_client();
+  let url = format!(
+    "{}/{}?fields=headRevisionId&supportsAllDrives=true",
+    FILE_GET_URL, file_id
+  );
+  let token = auth.api_key().unwrap();
+  let mut resp = try!(client
+    .get(&url)
+    .header(hyper::header::Authorization(hyper::header::Bearer {
+      token: token,
+    }))
+    .send());
+  let mut body: String = String::new();
+  try!(resp.read_to_string(&mut body));
+  if !resp.status.is_success() {
+    let err = drive_error::DriveError::parse(resp.status, &body);
+    warn!("Head revision get error: {}", err);
+    return Err(Box::new(err));
+  }
+  let file: serde_json::Value = try!(serde_json::from_str(&body));
+  let revision_id = match file["headRevisionId"].as_str() {
+    Some(revision_id) => revision_id.to_string(),
+    None => return Ok(()),
+  };
+
+  let url = format!(
+    "{}/{}/revisions/{}?fields=id",
+    FILE_GET_URL, file_id, revision_id
+  );
+  let token = auth.api_key().unwrap();
+  let mut resp = try!(client
+    .request(hyper::method::Method::Patch, &url)
+    .header(hyper::header::Authorization(hyper::header::Bearer {
+      token: token,
+    }))
+    .header(hyper::header::ContentType::json())
+    .body(r#"{"keepForever":true}"#)
+    .send());
+  if !resp.status.is_success() {
+    let mut body: String = String::new();
+    try!(resp.read_to_string(&mut body));
+    let err = drive_error::DriveError::parse(resp.status, &body);
+    warn!("Revision keep error: {}", err);
+    return Err(Box::new(err));
+  }
+  debug!("kept revision {} of {}", revision_id, file_id);
+  Ok(())
+}
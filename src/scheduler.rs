@@ -0,0 +1,182 @@
+use retry;
+use std::collections::VecDeque;
+use std::sync;
+
+// number of foreground downloads started in a row before a waiting
+// background download gets a turn, so readahead isn't starved forever.
+const FOREGROUND_WEIGHT: usize = 4;
+
+/// The kind of a download, used to order downloads between files.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Priority {
+  /// A read a process is blocked on.
+  Foreground,
+  /// Readahead, which nothing is waiting on yet.
+  Background,
+}
+
+struct SchedulerState {
+  // number of downloads in progress.
+  active: usize,
+  // tickets of the downloads waiting to start, in arrival order.
+  foreground: VecDeque<u64>,
+  background: VecDeque<u64>,
+  next_ticket: u64,
+  // foreground downloads started since the last background one.
+  foreground_streak: usize,
+}
+
+/// Limits the number of concurrent chunk downloads across all files. Waiting
+/// downloads start in arrival order, with foreground reads ahead of readahead,
+/// so one file's readahead can't hold up reads of another. Readahead is also
+/// limited to the most recently read files, so that opening many files in
+/// quick succession (e.g. a media library scan) doesn't start readahead on
+/// all of them. While too many reads are waiting for a download, speculative
+/// work is shed rather than queued behind them.
+pub struct DownloadScheduler {
+  max_downloads: usize,
+  // number of waiting foreground downloads from which the scheduler counts
+  // as overloaded, or 0 to never.
+  overload_backlog: usize,
+  state: sync::Mutex<SchedulerState>,
+  cond: sync::Condvar,
+  max_readahead_files: usize,
+  // ids of the files read from, most recently read first.
+  recent_reads: sync::Mutex<VecDeque<String>>,
+  retry_budget: retry::RetryBudget,
+}
+
+/// A running download. The slot is given back to the scheduler on drop.
+pub struct DownloadSlot<'a> {
+  scheduler: &'a DownloadScheduler,
+}
+
+impl DownloadScheduler {
+  /// Creates a scheduler allowing `max_downloads` concurrent downloads, and
+  /// readahead on the `max_readahead_files` most recently read files, which
+  /// is overloaded once `overload_backlog` reads are waiting for a download.
+  /// Any of these is disabled if 0.
+  pub fn new(
+    max_downloads: usize,
+    max_readahead_files: usize,
+    overload_backlog: usize,
+  ) -> DownloadScheduler {
+    DownloadScheduler {
+      max_downloads: max_downloads,
+      overload_backlog: overload_backlog,
+      state: sync::Mutex::new(SchedulerState {
+        active: 0,
+        foreground: VecDeque::new(),
+        background: VecDeque::new(),
+        next_ticket: 0,
+        foreground_streak: 0,
+      }),
+      cond: sync::Condvar::new(),
+      max_readahead_files: max_readahead_files,
+      recent_reads: sync::Mutex::new(VecDeque::new()),
+      retry_budget: retry::RetryBudget::new(),
+    }
+  }
+
+  /// The retries the downloads may make, shared by all the files of the
+  /// mount.
+  pub fn retry_budget(&self) -> &retry::RetryBudget {
+    &self.retry_budget
+  }
+
+  /// Records a read of the file `file_id`, making it the most recently read.
+  pub fn record_read(&self, file_id: &str) {
+    if self.max_readahead_files == 0 {
+      return;
+    }
+    let mut recent_reads = self.recent_reads.lock().unwrap();
+    if recent_reads.front().map(|recent| recent.as_str()) == Some(file_id) {
+      return;
+    }
+    recent_reads.retain(|recent| recent != file_id);
+    recent_reads.push_front(file_id.into());
+    recent_reads.truncate(self.max_readahead_files);
+  }
+
+  /// Whether the file `file_id` is among the files readahead is limited to.
+  pub fn may_readahead(&self, file_id: &str) -> bool {
+    self.max_readahead_files == 0
+      || self
+        .recent_reads
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|recent| recent == file_id)
+  }
+
+  /// Whether so many reads are waiting for a download that speculative work
+  /// (readahead, metadata refreshes) should be dropped, to let the backlog
+  /// drain instead of growing.
+  pub fn is_overloaded(&self) -> bool {
+    self.overload_backlog > 0
+      && self.state.lock().unwrap().foreground.len() >= self.overload_backlog
+  }
+
+  /// Forgets the reads of the file `file_id`, once it stops being read.
+  pub fn forget_reads(&self, file_id: &str) {
+    self
+      .recent_reads
+      .lock()
+      .unwrap()
+      .retain(|recent| recent != file_id);
+  }
+
+  /// Blocks until a download of `priority` may start.
+  pub fn acquire(&self, priority: Priority) -> DownloadSlot {
+    let mut state = self.state.lock().unwrap();
+    let ticket = state.next_ticket;
+    state.next_ticket += 1;
+    match priority {
+      Priority::Foreground => state.foreground.push_back(ticket),
+      Priority::Background => state.background.push_back(ticket),
+    }
+    while !self.is_next(&state, ticket) {
+      state = self.cond.wait(state).unwrap();
+    }
+    match priority {
+      Priority::Foreground => {
+        state.foreground.pop_front();
+        state.foreground_streak += 1;
+      }
+      Priority::Background => {
+        state.background.pop_front();
+        state.foreground_streak = 0;
+      }
+    }
+    state.active += 1;
+    // another waiter may be next in line if there are free slots.
+    self.cond.notify_all();
+    DownloadSlot { scheduler: self }
+  }
+
+  // whether the download with |ticket| is the next one to start.
+  fn is_next(&self, state: &SchedulerState, ticket: u64) -> bool {
+    if self.max_downloads > 0 && state.active >= self.max_downloads {
+      return false;
+    }
+    let background_turn = state.foreground.is_empty()
+      || (state.foreground_streak >= FOREGROUND_WEIGHT && !state.background.is_empty());
+    if background_turn {
+      state.background.front() == Some(&ticket)
+    } else {
+      state.foreground.front() == Some(&ticket)
+    }
+  }
+
+  fn release(&self) {
+    let mut state = self.state.lock().unwrap();
+    state.active -= 1;
+    self.cond.notify_all();
+  }
+}
+
+impl<'a> Drop for DownloadSlot<'a> {
+  fn drop(&mut self) {
+    self.scheduler.release();
+  }
+}
@@ -0,0 +1,154 @@
+extern crate hyper;
+extern crate serde_json;
+
+use self::serde_json::Value;
+use common;
+use oauth;
+use oauth::GetToken;
+use std::error::Error;
+use std::io::Read;
+use std::os::unix::fs::MetadataExt;
+
+const ABOUT_URL: &'static str =
+  "https://www.googleapis.com/drive/v3/about?fields=user(emailAddress)";
+const FUSE_DEVICE: &'static str = "/dev/fuse";
+
+/// Checks the files `token_files` before mounting and returns their
+/// canonical paths, so that a symlink swapped in later can't redirect the
+/// token saves. Fails if a token file is a symlink, or if it is readable by
+/// other users, unless `allow_insecure_perms`.
+pub fn validate_token_files(
+  token_files: &[String],
+  allow_insecure_perms: bool,
+) -> Result<Vec<String>, Box<Error>> {
+  let mut canonical = Vec::new();
+  for token_file in token_files {
+    canonical.push(try!(validate_token_file(token_file, allow_insecure_perms)));
+  }
+  Ok(canonical)
+}
+
+fn validate_token_file(path: &str, allow_insecure_perms: bool) -> Result<String, Box<Error>> {
+  let metadata = try!(std::fs::symlink_metadata(path)
+    .map_err(|err| format!("Could not read token file {}: {}", path, err)));
+  if metadata.file_type().is_symlink() {
+    return Err(From::from(format!("Token file {} is a symlink", path)));
+  }
+  if !metadata.is_file() {
+    return Err(From::from(format!(
+      "Token file {} is not a regular file",
+      path
+    )));
+  }
+  if metadata.mode() & 0o004 != 0 && !allow_insecure_perms {
+    return Err(From::from(format!(
+      "Token file {} is readable by other users, chmod it to 600 or pass --insecure-token-perms",
+      path
+    )));
+  }
+  let canonical = try!(std::fs::canonicalize(path));
+  match canonical.to_str() {
+    Some(canonical) => Ok(canonical.into()),
+    None => Err(From::from(format!(
+      "Token file {} has a non-UTF-8 path",
+      path
+    ))),
+  }
+}
+
+/// Checks that the fuse device can be opened, which mounting needs. Returns
+/// a description of the device on success.
+pub fn check_fuse_device() -> Result<String, Box<Error>> {
+  match std::fs::OpenOptions::new()
+    .read(true)
+    .write(true)
+    .open(FUSE_DEVICE)
+  {
+    Ok(_) => Ok(format!("{} is usable", FUSE_DEVICE)),
+    Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => Err(From::from(format!(
+      "{} is missing, load the fuse kernel module (e.g. modprobe fuse)",
+      FUSE_DEVICE
+    ))),
+    Err(ref err) if err.kind() == std::io::ErrorKind::PermissionDenied => Err(From::from(format!(
+      "{} can't be opened by this user, add it to the fuse group or run as root",
+      FUSE_DEVICE
+    ))),
+    Err(err) => Err(From::from(format!(
+      "{} can't be opened: {}",
+      FUSE_DEVICE, err
+    ))),
+  }
+}
+
+/// Checks that files can be written to the disk cache folder `dir`, or to
+/// the nearest existing folder above it if `dir` doesn't exist yet, since
+/// the mount creates it. Returns a description of the folder on success.
+pub fn check_disk_cache_dir(dir: &str) -> Result<String, Box<Error>> {
+  let mut existing = std::path::Path::new(dir);
+  while !existing.exists() {
+    existing = match existing.parent() {
+      Some(parent) if parent != std::path::Path::new("") => parent,
+      _ => std::path::Path::new("."),
+    };
+  }
+  if !existing.is_dir() {
+    return Err(From::from(format!(
+      "{} is not a folder",
+      existing.display()
+    )));
+  }
+  // a file is created and removed rather than the permissions checked, so
+  // that read-only filesystems and acls are accounted for.
+  let probe = existing.join(format!(".gdrivefs-check.{}", std::process::id()));
+  try!(std::fs::OpenOptions::new()
+    .write(true)
+    .create_new(true)
+    .open(&probe)
+    .and_then(|_| std::fs::remove_file(&probe))
+    .map_err(|err| format!("{} is not writable: {}", existing.display(), err)));
+  if existing == std::path::Path::new(dir) {
+    Ok(format!("{} is writable", dir))
+  } else {
+    Ok(format!(
+      "{} will be created in {}, which is writable",
+      dir,
+      existing.display()
+    ))
+  }
+}
+
+/// Checks that the Drive API is reachable and accepts the token of `auth`.
+/// Returns the account's email address on success.
+pub fn check_drive_access(auth: &mut oauth::GoogleAuthenticator) -> Result<String, Box<Error>> {
+  let token = auth.api_key().unwrap();
+  let mut resp = try!(common::new_hyper_tls_client()
+    .get(ABOUT_URL)
+    .header(hyper::header::Authorization(hyper::header::Bearer {
+      token: token,
+    }))
+    .send()
+    .map_err(|err| format!(
+      "Could not reach the Drive API, check the network and DNS: {}",
+      err
+    )));
+  if resp.status == hyper::status::StatusCode::Unauthorized {
+    return Err(From::from(
+      "The token was rejected, generate a new one with init_token",
+    ));
+  }
+  if !resp.status.is_success() {
+    let mut err: String = String::new();
+    try!(resp.read_to_string(&mut err));
+    return Err(From::from(format!(
+      "The Drive API returned {}: {}",
+      resp.status, err
+    )));
+  }
+  let result: Value = try!(serde_json::from_reader(&mut resp));
+  Ok(
+    result["user"]["emailAddress"]
+      .as_str()
+      .unwrap_or("unknown user")
+      .into(),
+  )
+}
@@ -0,0 +1,92 @@
+extern crate hyper;
+extern crate serde_json;
+
+use self::serde_json::Value;
+use common;
+use drive_error;
+use oauth;
+use oauth::GetToken;
+use recent::RecentCache;
+use std::error::Error;
+use std::io::Read;
+use std::sync;
+use std::time::Duration;
+
+const FILE_GET_URL: &'static str = "https://www.googleapis.com/drive/v3/files";
+
+// how long a thumbnail is reused: getxattr asks for the size of the value,
+// then reads it, in two calls.
+const THUMBNAIL_TTL_SECS: u64 = 60;
+
+lazy_static! {
+  // map of file id -> thumbnail, or None if it has none, see get_thumbnail.
+  static ref THUMBNAILS: sync::Mutex<RecentCache<Option<Vec<u8>>>> =
+    sync::Mutex::new(RecentCache::new(Duration::from_secs(THUMBNAIL_TTL_SECS)));
+}
+
+/// Downloads the thumbnail Drive generated for `file_id`, or returns `None`
+/// if it has none (e.g. for most non-media files). The thumbnail is reused
+/// for THUMBNAIL_TTL_SECS.
+///
+/// Linux limits xattr values to 64KB, so reading a larger thumbnail through
+/// getxattr fails with E2BIG. Drive's default thumbnails are well under it.
+pub fn get_thumbnail(
+  auth: &mut oauth::GoogleAuthenticator,
+  file_id: &str,
+) -> Result<Option<Vec<u8>>, Box<Error>> {
+  if let Some(thumbnail) = THUMBNAILS.lock().unwrap().get(file_id) {
+    return Ok(thumbnail);
+  }
+  let thumbnail = try!(fetch_thumbnail(auth, file_id));
+  THUMBNAILS
+    .lock()
+    .unwrap()
+    .insert(file_id, thumbnail.clone());
+  Ok(thumbnail)
+}
+
+// downloads the thumbnail of |file_id|, see get_thumbnail.
+fn fetch_thumbnail(
+  auth: &mut oauth::GoogleAuthenticator,
+  file_id: &str,
+) -> Result<Option<Vec<u8>>, Box<Error>> {
+  let client = common::new_hyper_tls_client();
+  let url = format!(
+    "{}/{}?fields=thumbnailLink&supportsAllDrives=true",
+    FILE_GET_URL, file_id
+  );
+  let mut resp = try!(send(&client, auth, &url));
+  let result: Value = try!(serde_json::from_reader(&mut resp));
+  let link = match result["thumbnailLink"].as_str() {
+    Some(link) => String::from(link),
+    None => return Ok(None),
+  };
+  // the link is short-lived, and needs credentials unless the file is public.
+  let mut resp = try!(send(&client, auth, &link));
+  let mut thumbnail = Vec::new();
+  try!(resp.read_to_end(&mut thumbnail));
+  Ok(Some(thumbnail))
+}
+
+// sends an authenticated GET request for |url|.
+fn send(
+  client: &hyper::Client,
+  auth: &mut oauth::GoogleAuthenticator,
+  url: &str,
+) -> Result<hyper::client::Response, Box<Error>> {
+  let token = auth.api_key().unwrap();
+  let mut resp = try!(client
+    .get(url)
+    .header(hyper::header::Authorization(hyper::header::Bearer {
+      token: token,
+    }))
+    .send());
+  if !resp.status.is_success() {
+    let mut body: String = String::new();
+    try!(resp.read_to_string(&mut body));
+    let err = drive_error::DriveError::parse(resp.status, &body);
+    warn!("Thumbnail get error: {}", err);
+    return Err(Box::new(err));
+  }
+  Ok(resp)
+}
@@ -0,0 +1,692 @@
+extern crate google_drive3;
+extern crate hyper;
+extern crate md5;
+extern crate serde_json;
+extern crate time;
+
+use common;
+use drive_error;
+use oauth;
+use oauth::GetToken;
+use std;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::sync::atomic;
+use std::thread;
+use std::time::Duration;
+
+const FILE_GET_URL: &'static str = "https://www.googleapis.com/drive/v3/files";
+const FILE_UPLOAD_URL: &'static str = "https://www.googleapis.com/upload/drive/v3/files";
+
+// times an upload interrupted by a network or server error is resumed.
+const UPLOAD_RETRIES: u32 = 3;
+// wait before resuming an upload, doubled on each retry.
+const RETRY_BACKOFF_MILLIS: u64 = 500;
+
+// consecutive failed uploads after which a mount stops accepting writes.
+const MAX_CONSECUTIVE_FAILURES: u32 = 5;
+
+// numbers the staging files of this process.
+static NEXT_STAGING_FILE: atomic::AtomicUsize = atomic::ATOMIC_USIZE_INIT;
+
+// mime types given to new files by lowercase extension, unless overridden,
+// so that Drive shows and previews them as what they are.
+const MIME_TYPES: &'static [(&'static str, &'static str)] = &[
+  ("txt", "text/plain"),
+  ("md", "text/markdown"),
+  ("csv", "text/csv"),
+  ("tsv", "text/tab-separated-values"),
+  ("html", "text/html"),
+  ("htm", "text/html"),
+  ("css", "text/css"),
+  ("js", "application/javascript"),
+  ("json", "application/json"),
+  ("xml", "application/xml"),
+  ("pdf", "application/pdf"),
+  ("rtf", "application/rtf"),
+  ("zip", "application/zip"),
+  ("gz", "application/gzip"),
+  ("tar", "application/x-tar"),
+  ("doc", "application/msword"),
+  (
+    "docx",
+    "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+  ),
+  ("xls", "application/vnd.ms-excel"),
+  (
+    "xlsx",
+    "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+  ),
+  ("ppt", "application/vnd.ms-powerpoint"),
+  (
+    "pptx",
+    "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+  ),
+  ("odt", "application/vnd.oasis.opendocument.text"),
+  ("ods", "application/vnd.oasis.opendocument.spreadsheet"),
+  ("odp", "application/vnd.oasis.opendocument.presentation"),
+  ("epub", "application/epub+zip"),
+  ("png", "image/png"),
+  ("jpg", "image/jpeg"),
+  ("jpeg", "image/jpeg"),
+  ("gif", "image/gif"),
+  ("webp", "image/webp"),
+  ("svg", "image/svg+xml"),
+  ("heic", "image/heic"),
+  ("mp3", "audio/mpeg"),
+  ("flac", "audio/flac"),
+  ("wav", "audio/wav"),
+  ("ogg", "audio/ogg"),
+  ("m4a", "audio/mp4"),
+  ("mp4", "video/mp4"),
+  ("m4v", "video/mp4"),
+  ("mkv", "video/x-matroska"),
+  ("webm", "video/webm"),
+  ("mov", "video/quicktime"),
+  ("avi", "video/x-msvideo"),
+];
+
+/// Parses comma-separated `<extension>=<mime type>` pairs, e.g.
+/// `md=text/markdown,log=text/plain`, into lowercase extensions and their
+/// mime types.
+pub fn parse_mime_types(spec: &str) -> Result<Vec<(String, String)>, Box<Error>> {
+  let mut mime_types = Vec::new();
+  for pair in spec.split(',').filter(|pair| !pair.is_empty()) {
+    let mut parts = pair.splitn(2, '=');
+    match (parts.next(), parts.next()) {
+      (Some(extension), Some(mime_type)) if !extension.is_empty() && mime_type.contains('/') => {
+        mime_types.push((
+          extension.trim_left_matches('.').to_lowercase(),
+          mime_type.into(),
+        ))
+      }
+      _ => {
+        return Err(From::from(format!(
+          "Invalid mime type {}, expected <extension>=<mime type>",
+          pair
+        )))
+      }
+    }
+  }
+  Ok(mime_types)
+}
+
+/// The mime type of a new file named `name`, from its extension: the type
+/// for it in `overrides`, as parsed by parse_mime_types, or else the
+/// built-in one. None leaves it for Drive to guess.
+pub fn infer_mime_type(name: &str, overrides: &[(String, String)]) -> Option<String> {
+  let extension = match name.rfind('.') {
+    Some(dot) if dot > 0 && dot + 1 < name.len() => name[dot + 1..].to_lowercase(),
+    _ => return None,
+  };
+  overrides
+    .iter()
+    .find(|&&(ref known, _)| *known == extension)
+    .map(|&(_, ref mime_type)| mime_type.clone())
+    .or_else(|| {
+      MIME_TYPES
+        .iter()
+        .find(|&&(known, _)| known == extension)
+        .map(|&(_, mime_type)| mime_type.into())
+    })
+}
+
+/// Counts the consecutive failures of the uploads of a mount. Once there have
+/// been MAX_CONSECUTIVE_FAILURES in a row, as when the quota is exhausted or
+/// the token lacks the scope to write, the mount turns read-only for good so
+/// that applications stop writing content that can't be saved.
+#[derive(Default)]
+pub struct FailureTracker {
+  failures: u32,
+  // why the mount turned read-only, if it did.
+  reason: Option<String>,
+}
+
+impl FailureTracker {
+  /// Records a successful upload.
+  pub fn succeeded(&mut self) {
+    self.failures = 0;
+  }
+
+  /// Records an upload of `file_id` that failed with `err`.
+  pub fn failed(&mut self, file_id: &str, err: &Error) {
+    self.failures += 1;
+    if self.reason.is_none() && self.failures >= MAX_CONSECUTIVE_FAILURES {
+      let reason = format!(
+        "{} uploads failed in a row, the last of {}: {}",
+        self.failures, file_id, err
+      );
+      error!("turning the mount read-only: {}", reason);
+      self.reason = Some(reason);
+    }
+  }
+
+  /// Returns why the mount turned read-only, or None if it still accepts
+  /// writes.
+  pub fn read_only_reason(&self) -> Option<&str> {
+    self.reason.as_ref().map(|reason| reason.as_str())
+  }
+}
+
+/// The content of a file being written, staged in a local file until it's
+/// uploaded as a whole. The local file is unlinked as soon as it's created,
+/// so nothing is left behind if the process dies.
+pub struct StagedFile {
+  file: fs::File,
+  size: u64,
+  // whether the content changed since it was last uploaded.
+  dirty: bool,
+  // modification time to give the file on the next upload, instead of the
+  // time of the upload.
+  modified_time: Option<time::Timespec>,
+}
+
+/// Checks that files can be staged in `dir`, by staging an empty one.
+pub fn check_staging_dir(dir: &str) -> io::Result<()> {
+  StagedFile::create(dir).map(|_| ())
+}
+
+impl StagedFile {
+  /// Creates an empty staging file in the folder `dir`.
+  pub fn create(dir: &str) -> io::Result<StagedFile> {
+    let path = format!(
+      "{}/.gdrivefs-staging-{}-{}",
+      dir.trim_right_matches('/'),
+      std::process::id(),
+      NEXT_STAGING_FILE.fetch_add(1, atomic::Ordering::SeqCst)
+    );
+    let file = try!(fs::OpenOptions::new()
+      .read(true)
+      .write(true)
+      .create_new(true)
+      .mode(0o600)
+      .open(&path));
+    try!(fs::remove_file(&path));
+    Ok(StagedFile {
+      file: file,
+      size: 0,
+      dirty: false,
+      modified_time: None,
+    })
+  }
+
+  pub fn size(&self) -> u64 {
+    self.size
+  }
+
+  /// Whether the content changed since it was last uploaded.
+  pub fn is_dirty(&self) -> bool {
+    self.dirty
+  }
+
+  /// Replaces the staged content with the content of `file_id`.
+  pub fn fill_from(
+    &mut self,
+    auth: &mut oauth::GoogleAuthenticator,
+    file_id: &str,
+  ) -> Result<(), Box<Error>> {
+    let url = format!(
+      "{}/{}?alt=media&supportsAllDrives=true",
+      FILE_GET_URL, file_id
+    );
+    let token = auth.api_key().unwrap();
+    let mut resp = try!(common::new_hyper_tls_client()
+      .get(&url)
+      .header(hyper::header::Authorization(hyper::header::Bearer {
+        token: token,
+      }))
+      .send());
+    if !resp.status.is_success() {
+      let mut body: String = String::new();
+      try!(resp.read_to_string(&mut body));
+      let err = drive_error::DriveError::parse(resp.status, &body);
+      warn!("Staging download error: {}", err);
+      return Err(Box::new(err));
+    }
+    try!(self.file.set_len(0));
+    try!((&self.file).seek(SeekFrom::Start(0)));
+    self.size = try!(io::copy(&mut resp, &mut self.file));
+    Ok(())
+  }
+
+  /// Reads up to `size` bytes at `offset`.
+  pub fn read_at(&self, offset: u64, size: u32) -> io::Result<Vec<u8>> {
+    if offset >= self.size {
+      return Ok(Vec::new());
+    }
+    let mut buf = vec![0; std::cmp::min(size as u64, self.size - offset) as usize];
+    let mut read = 0;
+    while read < buf.len() {
+      match try!(self.file.read_at(&mut buf[read..], offset + read as u64)) {
+        0 => break,
+        n => read += n,
+      }
+    }
+    buf.truncate(read);
+    Ok(buf)
+  }
+
+  /// Writes `data` at `offset`, growing the file if needed.
+  pub fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+    let mut written = 0;
+    while written < data.len() {
+      written += try!(self
+        .file
+        .write_at(&data[written..], offset + written as u64));
+    }
+    self.size = std::cmp::max(self.size, offset + data.len() as u64);
+    self.dirty = true;
+    Ok(())
+  }
+
+  /// Truncates or extends the file to `size` bytes.
+  pub fn set_len(&mut self, size: u64) -> io::Result<()> {
+    try!(self.file.set_len(size));
+    self.size = size;
+    self.dirty = true;
+    Ok(())
+  }
+
+  /// Has the next upload set the modification time of the file to `mtime`.
+  pub fn set_modified_time(&mut self, mtime: time::Timespec) {
+    self.modified_time = Some(mtime);
+  }
+
+  /// Takes the content to upload next, marking it as uploaded. The snapshot
+  /// reads the staging file through its own descriptor, so the file can be
+  /// written meanwhile without waiting for the upload: writes made during
+  /// the upload may or may not be part of it, but mark the content changed
+  /// again, so the next upload carries them.
+  pub fn snapshot(&mut self) -> io::Result<Snapshot> {
+    let snapshot = Snapshot {
+      file: try!(self.file.try_clone()),
+      size: self.size,
+      modified_time: self.modified_time,
+    };
+    self.dirty = false;
+    self.modified_time = None;
+    Ok(snapshot)
+  }
+
+  /// Marks the content of `snapshot`, whose upload failed, as still to be
+  /// uploaded.
+  pub fn upload_failed(&mut self, snapshot: &Snapshot) {
+    self.dirty = true;
+    if self.modified_time.is_none() {
+      self.modified_time = snapshot.modified_time;
+    }
+  }
+}
+
+/// The content of a `StagedFile` at some point, see `StagedFile::snapshot`.
+pub struct Snapshot {
+  file: fs::File,
+  size: u64,
+  modified_time: Option<time::Timespec>,
+}
+
+impl Snapshot {
+  /// The modification time the upload gives the file, if one was set.
+  pub fn modified_time(&self) -> Option<time::Timespec> {
+    self.modified_time
+  }
+
+  /// The md5 of the staged content.
+  pub fn md5(&self) -> io::Result<[u8; 16]> {
+    let mut content = ReaderAt {
+      file: &self.file,
+      position: 0,
+    }
+    .take(self.size);
+    let mut context = md5::Context::new();
+    let mut buf = vec![0; 1 << 20];
+    loop {
+      match try!(content.read(&mut buf)) {
+        0 => break,
+        read => context.consume(&buf[..read]),
+      }
+    }
+    Ok(context.compute().0)
+  }
+
+  /// Uploads the staged content as the new content of `file_id`, through a
+  /// resumable upload that picks up where it broke off after a network or
+  /// server error. Returns the metadata `fields` of the updated file.
+  pub fn upload(
+    &self,
+    auth: &mut oauth::GoogleAuthenticator,
+    file_id: &str,
+    fields: &str,
+  ) -> Result<google_drive3::File, Box<Error>> {
+    let url = format!(
+      "{}/{}?uploadType=resumable&supportsAllDrives=true&fields={}",
+      FILE_UPLOAD_URL, file_id, fields
+    );
+    let mut metadata = serde_json::Map::new();
+    if let Some(mtime) = self.modified_time {
+      metadata.insert("modifiedTime".into(), rfc3339(mtime).into());
+    }
+    let session_url = try!(start_session(
+      auth,
+      hyper::method::Method::Patch,
+      &url,
+      &try!(serde_json::to_string(&metadata))
+    ));
+    let mut attempt = 0;
+    // bytes the server has received so far.
+    let mut received = 0;
+    loop {
+      let err = match self.send_from(auth, &session_url, received) {
+        Ok(Sent::Done(api_file)) => return Ok(api_file),
+        // the server only kept part of the content.
+        Ok(Sent::Partial(kept)) => {
+          From::from(format!("upload stopped at {} of {} bytes", kept, self.size))
+        }
+        Err(err) => err,
+      };
+      let transient = err
+        .downcast_ref::<drive_error::DriveError>()
+        .map_or(true, |err| err.is_transient());
+      if !transient || attempt >= UPLOAD_RETRIES {
+        return Err(err);
+      }
+      thread::sleep(Duration::from_millis(RETRY_BACKOFF_MILLIS << attempt));
+      attempt += 1;
+      warn!(
+        "Resuming upload of {} (attempt {}): {}",
+        file_id, attempt, err
+      );
+      match try!(query_session(auth, &session_url, self.size)) {
+        Sent::Done(api_file) => return Ok(api_file),
+        Sent::Partial(kept) => received = kept,
+      }
+    }
+  }
+
+  // sends the content from |start| on to the upload session at |session_url|.
+  fn send_from(
+    &self,
+    auth: &mut oauth::GoogleAuthenticator,
+    session_url: &str,
+    start: u64,
+  ) -> Result<Sent, Box<Error>> {
+    let remaining = self.size - start;
+    // the descriptor shares its offset with the staging file's, so the
+    // content is read at explicit offsets rather than after a seek.
+    let mut content = ReaderAt {
+      file: &self.file,
+      position: start,
+    }
+    .take(remaining);
+    let token = auth.api_key().unwrap();
+    let client = common::new_hyper_tls_client();
+    let mut request = client
+      .put(session_url)
+      .header(hyper::header::Authorization(hyper::header::Bearer {
+        token: token,
+      }))
+      .body(hyper::client::Body::SizedBody(&mut content, remaining));
+    // the first request needs no range, which an empty file couldn't have.
+    if start > 0 {
+      request = request.header(hyper::header::ContentRange(
+        hyper::header::ContentRangeSpec::Bytes {
+          range: Some((start, self.size - 1)),
+          instance_length: Some(self.size),
+        },
+      ));
+    }
+    let resp = try!(request.send());
+    read_session_reply(resp)
+  }
+}
+
+// reads |file| from |position| on with pread, leaving its offset alone.
+struct ReaderAt<'a> {
+  file: &'a fs::File,
+  position: u64,
+}
+
+impl<'a> Read for ReaderAt<'a> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let read = try!(self.file.read_at(buf, self.position));
+    self.position += read as u64;
+    Ok(read)
+  }
+}
+
+// the outcome of a request to an upload session.
+enum Sent {
+  // the upload completed, with the resulting metadata.
+  Done(google_drive3::File),
+  // the server has this many bytes of the content so far.
+  Partial(u64),
+}
+
+// starts a resumable upload session by sending the metadata |body| to |url|
+// with |method|, returning the url to send the content to.
+fn start_session(
+  auth: &mut oauth::GoogleAuthenticator,
+  method: hyper::method::Method,
+  url: &str,
+  body: &str,
+) -> Result<String, Box<Error>> {
+  let token = auth.api_key().unwrap();
+  let mut resp = try!(common::new_hyper_tls_client()
+    .request(method, url)
+    .header(hyper::header::Authorization(hyper::header::Bearer {
+      token: token,
+    }))
+    .header(hyper::header::ContentType::json())
+    .body(body)
+    .send());
+  if !resp.status.is_success() {
+    let mut body: String = String::new();
+    try!(resp.read_to_string(&mut body));
+    let err = drive_error::DriveError::parse(resp.status, &body);
+    warn!("Upload session error: {}", err);
+    return Err(Box::new(err));
+  }
+  match resp.headers.get::<hyper::header::Location>() {
+    Some(location) => Ok(location.to_string()),
+    None => Err(From::from("upload session has no location")),
+  }
+}
+
+// asks the upload session at |session_url| how much of the |size| bytes of
+// content it has.
+fn query_session(
+  auth: &mut oauth::GoogleAuthenticator,
+  session_url: &str,
+  size: u64,
+) -> Result<Sent, Box<Error>> {
+  let token = auth.api_key().unwrap();
+  let resp = try!(common::new_hyper_tls_client()
+    .put(session_url)
+    .header(hyper::header::Authorization(hyper::header::Bearer {
+      token: token,
+    }))
+    .header(hyper::header::ContentRange(
+      hyper::header::ContentRangeSpec::Bytes {
+        range: None,
+        instance_length: Some(size),
+      },
+    ))
+    .body("")
+    .send());
+  read_session_reply(resp)
+}
+
+// interprets the reply of an upload session: 308 with the range received so
+// far while incomplete, or the metadata of the file once complete.
+fn read_session_reply(mut resp: hyper::client::Response) -> Result<Sent, Box<Error>> {
+  if resp.status == hyper::status::StatusCode::PermanentRedirect {
+    let kept = match resp.headers.get::<hyper::header::Range>() {
+      Some(&hyper::header::Range::Bytes(ref ranges)) => match ranges.first() {
+        Some(&hyper::header::ByteRangeSpec::FromTo(_, last)) => last + 1,
+        _ => 0,
+      },
+      _ => 0,
+    };
+    return Ok(Sent::Partial(kept));
+  }
+  if !resp.status.is_success() {
+    let mut body: String = String::new();
+    try!(resp.read_to_string(&mut body));
+    let err = drive_error::DriveError::parse(resp.status, &body);
+    warn!("Upload error: {}", err);
+    return Err(Box::new(err));
+  }
+  Ok(Sent::Done(try!(serde_json::from_reader(&mut resp))))
+}
+
+/// Creates an empty file `name` of type `mime_type` in the folder
+/// `parent_id`, returning its metadata `fields`. Without a type, Drive
+/// guesses one from the name. Later uploads of its content keep the type.
+pub fn create_empty(
+  auth: &mut oauth::GoogleAuthenticator,
+  parent_id: &str,
+  name: &str,
+  mime_type: Option<&str>,
+  fields: &str,
+) -> Result<google_drive3::File, Box<Error>> {
+  let mut metadata = serde_json::Map::new();
+  metadata.insert("name".into(), name.into());
+  if let Some(mime_type) = mime_type {
+    metadata.insert("mimeType".into(), mime_type.into());
+  }
+  metadata.insert(
+    "parents".into(),
+    serde_json::Value::Array(vec![parent_id.into()]),
+  );
+  let url = format!(
+    "{}?uploadType=resumable&supportsAllDrives=true&fields={}",
+    FILE_UPLOAD_URL, fields
+  );
+  let session_url = try!(start_session(
+    auth,
+    hyper::method::Method::Post,
+    &url,
+    &try!(serde_json::to_string(&metadata))
+  ));
+  let token = auth.api_key().unwrap();
+  let resp = try!(common::new_hyper_tls_client()
+    .put(&session_url)
+    .header(hyper::header::Authorization(hyper::header::Bearer {
+      token: token,
+    }))
+    .body("")
+    .send());
+  match try!(read_session_reply(resp)) {
+    Sent::Done(api_file) => Ok(api_file),
+    Sent::Partial(_) => Err(From::from("upload of an empty file is incomplete")),
+  }
+}
+
+/// Sets the modification time of `file_id` to `mtime`, returning its metadata
+/// `fields`.
+pub fn set_modified_time(
+  auth: &mut oauth::GoogleAuthenticator,
+  file_id: &str,
+  mtime: time::Timespec,
+  fields: &str,
+) -> Result<google_drive3::File, Box<Error>> {
+  let mut metadata = serde_json::Map::new();
+  metadata.insert("modifiedTime".into(), rfc3339(mtime).into());
+  let url = format!(
+    "{}/{}?supportsAllDrives=true&fields={}",
+    FILE_GET_URL, file_id, fields
+  );
+  let token = auth.api_key().unwrap();
+  let mut resp = try!(common::new_hyper_tls_client()
+    .request(hyper::method::Method::Patch, &url)
+    .header(hyper::header::Authorization(hyper::header::Bearer {
+      token: token,
+    }))
+    .header(hyper::header::ContentType::json())
+    .body(&try!(serde_json::to_string(&metadata)) as &str)
+    .send());
+  if !resp.status.is_success() {
+    let mut body: String = String::new();
+    try!(resp.read_to_string(&mut body));
+    let err = drive_error::DriveError::parse(resp.status, &body);
+    warn!("Modified time error: {}", err);
+    return Err(Box::new(err));
+  }
+  Ok(try!(serde_json::from_reader(&mut resp)))
+}
+
+// formats |mtime| the way Drive takes times, e.g. 2019-01-31T12:00:00.000Z.
+fn rfc3339(mtime: time::Timespec) -> String {
+  format!(
+    "{}.{:03}Z",
+    time::at_utc(mtime).strftime("%Y-%m-%dT%H:%M:%S").unwrap(),
+    mtime.nsec / 1_000_000
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn infers_mime_types_from_extensions() {
+    assert_eq!(infer_mime_type("notes.TXT", &[]), Some("text/plain".into()));
+    assert_eq!(
+      infer_mime_type("movie.final.mkv", &[]),
+      Some("video/x-matroska".into())
+    );
+    assert_eq!(infer_mime_type("Makefile", &[]), None);
+    assert_eq!(infer_mime_type(".bashrc", &[]), None);
+    assert_eq!(infer_mime_type("trailing.", &[]), None);
+    assert_eq!(infer_mime_type("data.unknown", &[]), None);
+  }
+
+  #[test]
+  fn overrides_come_first() {
+    let overrides = parse_mime_types("txt=text/x-log,.Log=text/plain").unwrap();
+    assert_eq!(
+      infer_mime_type("a.txt", &overrides),
+      Some("text/x-log".into())
+    );
+    assert_eq!(
+      infer_mime_type("b.log", &overrides),
+      Some("text/plain".into())
+    );
+    assert_eq!(
+      infer_mime_type("c.pdf", &overrides),
+      Some("application/pdf".into())
+    );
+  }
+
+  #[test]
+  fn rejects_invalid_mime_types() {
+    assert!(parse_mime_types("").unwrap().is_empty());
+    assert!(parse_mime_types("txt").is_err());
+    assert!(parse_mime_types("=text/plain").is_err());
+    assert!(parse_mime_types("txt=plain").is_err());
+  }
+
+  #[test]
+  fn turns_read_only_after_consecutive_failures() {
+    let err = io::Error::new(io::ErrorKind::Other, "quota exceeded");
+    let mut tracker = FailureTracker::default();
+    for _ in 1..MAX_CONSECUTIVE_FAILURES {
+      tracker.failed("a", &err);
+    }
+    tracker.succeeded();
+    for _ in 1..MAX_CONSECUTIVE_FAILURES {
+      tracker.failed("a", &err);
+    }
+    assert_eq!(tracker.read_only_reason(), None);
+    tracker.failed("b", &err);
+    assert_eq!(
+      tracker.read_only_reason(),
+      Some("5 uploads failed in a row, the last of b: quota exceeded")
+    );
+    tracker.succeeded();
+    assert!(tracker.read_only_reason().is_some());
+  }
+}
@@ -0,0 +1,49 @@
+extern crate fuse;
+extern crate libc;
+
+// name of the xattr listing the drive sharing permissions of a file.
+pub const PERMISSIONS: &'static str = "user.gdrive.permissions";
+
+// prefix of the xattrs holding the fields of each drive label on a file,
+// followed by the label id.
+pub const LABEL_PREFIX: &'static str = "user.gdrive.label.";
+
+// names of the xattrs holding the checksums of a file's content.
+pub const MD5: &'static str = "user.gdrive.md5";
+pub const SHA1: &'static str = "user.gdrive.sha1";
+pub const SHA256: &'static str = "user.gdrive.sha256";
+
+// name of the xattr listing the byte ranges of a file held in the cache.
+pub const CACHED_RANGES: &'static str = "user.gdrive.cached_ranges";
+
+// name of the xattr holding the read counters of a file.
+pub const READSTATS: &'static str = "user.gdrive.readstats";
+
+// name of the xattr holding the image of the drive-generated thumbnail.
+pub const THUMBNAIL: &'static str = "user.gdrive.thumbnail";
+
+// name of the xattr holding the total size of the files under a folder.
+pub const TREESIZE: &'static str = "user.gdrive.treesize";
+
+/// Replies to a getxattr request with `value`. A `size` of 0 asks for the
+/// size of the value rather than its contents.
+pub fn reply_value(reply: fuse::ReplyXattr, size: u32, value: &[u8]) {
+  if size == 0 {
+    reply.size(value.len() as u32);
+  } else if (size as usize) < value.len() {
+    reply.error(libc::ERANGE);
+  } else {
+    reply.data(value);
+  }
+}
+
+/// Replies to a listxattr request with `names`, encoded as a sequence of
+/// nul-terminated strings.
+pub fn reply_names<S: AsRef<str>>(reply: fuse::ReplyXattr, size: u32, names: &[S]) {
+  let mut data: Vec<u8> = Vec::new();
+  for name in names {
+    data.extend_from_slice(name.as_ref().as_bytes());
+    data.push(0);
+  }
+  reply_value(reply, size, &data);
+}